@@ -1,22 +1,82 @@
 use std::ffi::OsString;
-use std::fs::read;
 use std::path::PathBuf;
-use std::process::{exit, Command, ExitStatus, Stdio};
+use std::process::{Command, ExitStatus, Stdio};
+use std::time::Duration;
 
 use cargo_project::{Artifact, Profile, Project};
 use color_eyre::{eyre::WrapErr, Report, Result};
-use espflash::{Chip, Config, Flasher};
+use espflash::cli::{
+    self, exit_with, open_serial, parse_flash_size, parse_spi_clock_divider, parse_spi_connection,
+    print_board_info, wait_for_serial, AdapterQuirks, CliConfig, ExitCode, MachineProgress,
+    Monitor, MonitorOptions, Watcher,
+};
+use espflash::{
+    AppDescriptor, Chip, ConnectOptions, Error, Flasher, InputFile, ResetAfterFlash,
+    ResetBeforeConnect,
+};
 use pico_args::Arguments;
-use serial::{BaudRate, SerialPort};
+use serial::{BaudRate, SystemPort};
 
-fn main() -> Result<()> {
-    let args = parse_args().expect("Unable to parse command-line arguments");
-    let config = Config::load();
+fn main() {
+    exit_with(run())
+}
+
+fn run() -> Result<()> {
+    cli::install()?;
+
+    let mut args = parse_args().wrap_err("Unable to parse command-line arguments")?;
+    let mut config = CliConfig::load();
+    args.monitor_options
+        .hooks
+        .extend(config.monitor.hooks.clone());
+    config.connection.timings |= args.timings;
+    if args.retries.is_some() {
+        config.connection.retries = args.retries;
+    }
+    config.connection.flow_control |= args.flow_control;
+    if args.pacing_chunk_size.is_some() {
+        config.connection.pacing_chunk_size = args.pacing_chunk_size;
+    }
+    if args.pacing_delay_us.is_some() {
+        config.connection.pacing_delay_us = args.pacing_delay_us;
+    }
+    if args.stub_path.is_some() {
+        config.connection.stub = args.stub_path.clone();
+    }
+    config.connection.no_reset |= args.no_reset;
+    config.connection.no_adapter_quirks |= args.no_adapter_quirks;
+    config.capture = args.capture.clone();
+    config.progress = args.machine_progress.clone();
+    if args.spi_connection.is_some() {
+        config.connection.spi_connection = args.spi_connection.clone();
+    }
+    if args.spi_clock_divider.is_some() {
+        config.connection.spi_clock_divider = args.spi_clock_divider;
+    }
+    if args.flash_size.is_some() {
+        config.build.flash_size = args.flash_size.clone();
+    }
+    if args.sdkconfig.is_some() {
+        config.build.sdkconfig = args.sdkconfig.clone();
+    }
+    if args.bootloader.is_some() {
+        config.build.bootloader = args.bootloader.clone();
+    }
+    let reset = if config.connection.no_reset {
+        ResetBeforeConnect::NoReset
+    } else {
+        ResetBeforeConnect::DefaultReset
+    };
 
-    if args.help || (args.serial.is_none() && config.connection.serial.is_none()) {
+    if args.help {
         return usage();
     }
 
+    if args.serial.is_none() && config.connection.serial.is_none() {
+        usage()?;
+        ExitCode::Usage.exit();
+    }
+
     #[allow(clippy::or_fun_call)]
     let tool = args
         .build_tool
@@ -28,54 +88,164 @@ fn main() -> Result<()> {
         Some("xargo") | Some("cargo") | Some("xbuild") => tool.unwrap(),
         Some(_) => {
             eprintln!("Only 'xargo', 'cargo' and 'xbuild' are valid build types.");
-            return Ok(());
+            ExitCode::Usage.exit();
         }
         None => return usage(),
     };
 
-    let port = args.serial.or(config.connection.serial).unwrap();
-
-    let speed = args.speed.map(|v| BaudRate::from_speed(v as usize));
-
-    let chip = match args.chip.as_ref() {
-        Some(chip) => chip.parse()?,
-        None => chip_detect(&port).wrap_err("Unable to detect chip type, ensure your device is connected or manually specify the chip")?
+    let port = args
+        .serial
+        .or_else(|| config.connection.serial.clone())
+        .unwrap();
+    let wait = args
+        .wait
+        .or(config.connection.wait)
+        .map(Duration::from_secs);
+
+    let quirks = AdapterQuirks::for_port(&port, &config.connection);
+    let speed = args
+        .speed
+        .map(|v| BaudRate::from_speed(v as usize))
+        .map(|speed| quirks.map_or(speed, |quirks| quirks.limit_baud(speed)));
+
+    let requested_chip: Option<Chip> = args.chip.as_deref().map(str::parse).transpose()?;
+    let chip = match requested_chip {
+        Some(chip) => chip,
+        None => chip_detect(&port, wait, reset, &config).wrap_err("Unable to detect chip type, ensure your device is connected or manually specify the chip")?
     };
 
     let target = chip.target();
 
-    // Since the application exits without flashing the device when '--board-info'
-    // is passed, we will not waste time building if said flag was set.
-    if !args.board_info {
+    if args.board_info {
+        let mut flasher = connect(&port, wait, speed, reset, requested_chip, &config)?;
+        print_board_info(&flasher.board_info()?);
+        return Ok(());
+    }
+
+    let mut monitor = if args.monitor {
+        Some(Monitor::new(&args.monitor_options)?)
+    } else {
+        None
+    };
+    let mut watcher = Watcher::new(watch_paths(&args.example));
+    loop {
         let status = build(args.release, &args.example, &args.features, tool, target);
-        if !status.success() {
-            exit_with_process_status(status)
+        if status.success() {
+            let path = get_artifact_path(target, args.release, &args.example)
+                .expect("Could not find the build artifact path");
+            let elf_data = InputFile::open(&path)?;
+
+            let mut flasher = connect(&port, wait, speed, reset, requested_chip, &config)?;
+            flasher.set_image_settings(cli::image_settings(&config.build)?);
+            cli::warn_crystal_mismatch(&mut flasher)?;
+            if let Some(monitor) = &mut monitor {
+                monitor.set_boot_baud(flasher.boot_log_baud()?);
+            }
+            cli::configure_connection(&mut flasher, &config.connection)?;
+            if let Some(quirks) = quirks {
+                quirks.apply(&mut flasher, &port, &config.connection);
+            }
+            if args.ram {
+                flasher.load_elf_to_ram(&elf_data)?;
+            } else {
+                // the bootloader only checks the secure version of apps booted from flash
+                let app = AppDescriptor::from_elf(&elf_data);
+                cli::warn_anti_rollback(&mut flasher, app.as_ref())?;
+                if args.no_run {
+                    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+                }
+                flasher.set_resume(args.resume);
+                let stats = flasher.load_elf_to_flash(&elf_data)?;
+                cli::print_flash_stats(&config.connection, &stats);
+            }
+            if let Some(monitor) = &mut monitor {
+                monitor.load_elf(&elf_data);
+            }
+        } else {
+            eprintln!("Build failed: {}", status);
+            if !args.watch {
+                ExitCode::ImageBuild.exit();
+            }
         }
-    }
 
-    let mut serial =
-        serial::open(&port).wrap_err_with(|| format!("Failed to open serial port {}", port))?;
-    serial.reconfigure(&|settings| {
-        settings.set_baud_rate(BaudRate::Baud115200)?;
-        Ok(())
-    })?;
+        match (args.watch, &mut monitor) {
+            (false, None) => return Ok(()),
+            (false, Some(monitor)) => return monitor.run(open(&port, wait)?, &port),
+            (true, None) => watcher.wait(),
+            (true, Some(monitor)) => {
+                monitor.run_until(open(&port, wait)?, &port, || watcher.changed())?
+            }
+        }
 
-    let mut flasher = Flasher::connect(serial, speed)?;
-    if args.board_info {
-        return board_info(&flasher);
+        eprintln!("Sources changed, building again");
     }
+}
 
-    let path = get_artifact_path(target, args.release, &args.example)
-        .expect("Could not find the build artifact path");
-    let elf_data = read(&path)?;
+fn open(port: &str, wait: Option<Duration>) -> Result<SystemPort> {
+    match wait {
+        Some(wait) => wait_for_serial(port, wait),
+        None => open_serial(port),
+    }
+}
 
-    if args.ram {
-        flasher.load_elf_to_ram(&elf_data)?;
-    } else {
-        flasher.load_elf_to_flash(&elf_data)?;
+fn connect(
+    port: &str,
+    wait: Option<Duration>,
+    speed: Option<BaudRate>,
+    reset: ResetBeforeConnect,
+    requested_chip: Option<Chip>,
+    config: &CliConfig,
+) -> Result<Flasher> {
+    let progress = config
+        .progress
+        .as_deref()
+        .map(|target| MachineProgress::open(target, port))
+        .transpose()?;
+    if let Some(progress) = &progress {
+        progress.connecting();
+    }
+    let serial = open(port, wait)?;
+    let spi = cli::spi_connection(&config.connection)?;
+    let flash_size = cli::flash_size(&config.build)?;
+    let events = progress.clone();
+    let options = ConnectOptions::new()
+        .speed(speed)
+        .reset(reset)
+        .spi_connection(spi)
+        .flash_size(flash_size)
+        .event_hook(move |event| {
+            if let Some(progress) = &events {
+                progress.event(event);
+            }
+        });
+    let mut flasher = match &config.capture {
+        Some(path) => Flasher::connect_with_options(cli::capture_port(serial, path)?, options)?,
+        None => Flasher::connect_with_options(serial, options)?,
+    };
+    if let Some(progress) = progress {
+        progress.connected(flasher.chip());
+        flasher.set_progress_callbacks(progress);
+    }
+    if let Some(expected) = requested_chip {
+        if flasher.chip() != expected {
+            return Err(Error::ChipMismatch {
+                expected,
+                detected: flasher.chip(),
+            }
+            .into());
+        }
     }
 
-    Ok(())
+    Ok(flasher)
+}
+
+/// The paths that can affect the build output
+fn watch_paths(example: &Option<String>) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from("src"), PathBuf::from("Cargo.toml")];
+    if example.is_some() {
+        paths.push(PathBuf::from("examples"));
+    }
+    paths
 }
 
 #[derive(Debug)]
@@ -83,12 +253,33 @@ struct AppArgs {
     help: bool,
     board_info: bool,
     ram: bool,
+    no_run: bool,
+    resume: bool,
+    monitor: bool,
+    monitor_options: MonitorOptions,
+    watch: bool,
     release: bool,
     example: Option<String>,
     features: Option<String>,
     chip: Option<String>,
     build_tool: Option<String>,
     speed: Option<u32>,
+    wait: Option<u64>,
+    timings: bool,
+    retries: Option<usize>,
+    flow_control: bool,
+    pacing_chunk_size: Option<usize>,
+    pacing_delay_us: Option<u64>,
+    no_reset: bool,
+    no_adapter_quirks: bool,
+    spi_connection: Option<String>,
+    spi_clock_divider: Option<u8>,
+    capture: Option<String>,
+    machine_progress: Option<String>,
+    stub_path: Option<String>,
+    flash_size: Option<String>,
+    sdkconfig: Option<String>,
+    bootloader: Option<String>,
     serial: Option<String>,
 }
 
@@ -97,11 +288,40 @@ fn usage() -> Result<()> {
     let usage = "Usage: cargo espflash \
       [--board-info] \
       [--ram] \
+      [--no-run] \
+      [--resume] \
+      [--monitor] \
+      [--mirror {{ADDRESS,pty}}] \
+      [--output {{text,raw,hex}}] \
+      [--strip-ansi] \
+      [--no-reconnect] \
+      [--gdb-listen ADDRESS] \
+      [--core-dump-dir DIR] \
+      [--exit-on PATTERN] \
+      [--fail-on PATTERN] \
+      [--highlight PATTERN] \
+      [--watch] \
       [--release] \
       [--example EXAMPLE] \
       [--tool {{cargo,xargo,xbuild}}] \
       [--chip {{esp32,esp8266}}] \
       [--speed BAUD] \
+      [--wait SECONDS] \
+      [--timings] \
+      [--retries N] \
+      [--flow-control] \
+      [--pacing-chunk-size N] \
+      [--pacing-delay-us US] \
+      [--no-reset] \
+      [--no-adapter-quirks] \
+      [--spi-connection {{SPI,HSPI,CLK,Q,D,HD,CS}}] \
+      [--spi-clock-divider N] \
+      [--capture FILE] \
+      [--machine-progress {{stdout,stderr,FILE}}] \
+      [--stub-path FILE] \
+      [--flash-size SIZE] \
+      [--sdkconfig FILE] \
+      [--bootloader FILE] \
       <serial>";
 
     println!("{}", usage);
@@ -109,14 +329,6 @@ fn usage() -> Result<()> {
     Ok(())
 }
 
-#[allow(clippy::unnecessary_wraps)]
-fn board_info(flasher: &Flasher) -> Result<()> {
-    println!("Chip type:  {:?}", flasher.chip());
-    println!("Flash size: {:?}", flasher.flash_size());
-
-    Ok(())
-}
-
 fn parse_args() -> Result<AppArgs> {
     // Skip the command and subcommand (ie. 'cargo espflash') and convert the
     // remaining arguments to the expected type.
@@ -128,11 +340,37 @@ fn parse_args() -> Result<AppArgs> {
         help: args.contains("--help"),
         board_info: args.contains("--board-info"),
         ram: args.contains("--ram"),
+        no_run: args.contains("--no-run"),
+        resume: args.contains("--resume"),
+        monitor: args.contains("--monitor"),
+        monitor_options: MonitorOptions::from_args(&mut args)?,
+        watch: args.contains("--watch"),
         release: args.contains("--release"),
         example: args.opt_value_from_str("--example")?,
         features: args.opt_value_from_str("--features")?,
         chip: args.opt_value_from_str("--chip")?,
         speed: args.opt_value_from_str("--speed")?,
+        wait: args.opt_value_from_str("--wait")?,
+        timings: args.contains("--timings"),
+        retries: args.opt_value_from_str("--retries")?,
+        flow_control: args.contains("--flow-control"),
+        pacing_chunk_size: args.opt_value_from_str("--pacing-chunk-size")?,
+        pacing_delay_us: args.opt_value_from_str("--pacing-delay-us")?,
+        no_reset: args.contains("--no-reset"),
+        no_adapter_quirks: args.contains("--no-adapter-quirks"),
+        spi_connection: args.opt_value_from_fn("--spi-connection", |value: &str| {
+            parse_spi_connection(value).map(|_| value.to_string())
+        })?,
+        spi_clock_divider: args
+            .opt_value_from_fn("--spi-clock-divider", parse_spi_clock_divider)?,
+        capture: args.opt_value_from_str("--capture")?,
+        machine_progress: args.opt_value_from_str("--machine-progress")?,
+        stub_path: args.opt_value_from_str("--stub-path")?,
+        flash_size: args.opt_value_from_fn("--flash-size", |value: &str| {
+            parse_flash_size(value).map(|_| value.to_string())
+        })?,
+        sdkconfig: args.opt_value_from_str("--sdkconfig")?,
+        bootloader: args.opt_value_from_str("--bootloader")?,
         build_tool: args.opt_value_from_str("--tool")?,
         serial: args.opt_free_from_str()?,
     };
@@ -173,20 +411,14 @@ fn build(
         args.push("--release".to_string());
     }
 
-    match example {
-        Some(example) => {
-            args.push("--example".to_string());
-            args.push(example.to_string());
-        }
-        None => {}
+    if let Some(example) = example {
+        args.push("--example".to_string());
+        args.push(example.to_string());
     }
 
-    match features {
-        Some(features) => {
-            args.push("--features".to_string());
-            args.push(features.to_string());
-        }
-        None => {}
+    if let Some(features) = features {
+        args.push("--features".to_string());
+        args.push(features.to_string());
     }
 
     let mut command = match tool {
@@ -219,30 +451,19 @@ fn build(
         .unwrap()
 }
 
-fn chip_detect(port: &str) -> Result<Chip> {
-    let mut serial =
-        serial::open(port).wrap_err_with(|| format!("Failed to open serial port {}", port))?;
-    serial.reconfigure(&|settings| {
-        settings.set_baud_rate(BaudRate::Baud115200)?;
-
-        Ok(())
-    })?;
-    let flasher = Flasher::connect(serial, None)?;
+fn chip_detect(
+    port: &str,
+    wait: Option<Duration>,
+    reset: ResetBeforeConnect,
+    config: &CliConfig,
+) -> Result<Chip> {
+    let spi = cli::spi_connection(&config.connection)?;
+    let flash_size = cli::flash_size(&config.build)?;
+    let options = ConnectOptions::new()
+        .reset(reset)
+        .spi_connection(spi)
+        .flash_size(flash_size);
+    let flasher = Flasher::connect_with_options(open(port, wait)?, options)?;
 
     Ok(flasher.chip())
 }
-
-#[cfg(unix)]
-fn exit_with_process_status(status: ExitStatus) -> ! {
-    use std::os::unix::process::ExitStatusExt;
-    let code = status.code().or_else(|| status.signal()).unwrap_or(1);
-
-    exit(code)
-}
-
-#[cfg(not(unix))]
-fn exit_with_process_status(status: ExitStatus) -> ! {
-    let code = status.code().unwrap_or(1);
-
-    exit(code)
-}
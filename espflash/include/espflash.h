@@ -0,0 +1,41 @@
+/* C api of espflash, built with `cargo rustc --lib --features cdylib --crate-type cdylib` */
+
+#ifndef ESPFLASH_H
+#define ESPFLASH_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct espflash_flasher espflash_flasher;
+
+/* called with the address of the segment being written, the blocks written so far and the blocks in the segment */
+typedef void (*espflash_progress_callback)(void *user_data, uint32_t addr, size_t current, size_t total);
+
+/* the description of the last failure on this thread, valid until the next call that fails */
+const char *espflash_last_error(void);
+
+/* connect to the chip on `port`, switching to `baud` afterwards when it isn't 0, returns NULL on failure */
+espflash_flasher *espflash_connect(const char *port, uint32_t baud);
+
+/* close the connection and free the flasher, the chip isn't reset, writes to the flash reset it when they finish */
+void espflash_free(espflash_flasher *flasher);
+
+/* the functions below return 0 on success and -1 on failure, including when a pointer is NULL */
+
+int espflash_set_progress_callback(espflash_flasher *flasher, espflash_progress_callback callback, void *user_data);
+
+int espflash_flash_elf(espflash_flasher *flasher, const uint8_t *data, size_t len);
+
+int espflash_flash_bin(espflash_flasher *flasher, uint32_t addr, const uint8_t *data, size_t len);
+
+int espflash_read_flash(espflash_flasher *flasher, uint32_t addr, uint8_t *buffer, uint32_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,117 @@
+use std::convert::TryInto;
+
+use xmas_elf::program::Type;
+use xmas_elf::ElfFile;
+
+use crate::chip::Chip;
+use crate::error::Error;
+
+/// Magic word at the start of the descriptor
+const APP_DESC_MAGIC: u32 = 0xabcd5432;
+const APP_DESC_LENGTH: usize = 256;
+const SECURE_VERSION_OFFSET: usize = 4;
+
+/// The application description esp-idf places at the start of the rodata of an app, `esp_app_desc_t`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AppDescriptor {
+    /// Version used by the anti-rollback feature of the bootloader
+    pub secure_version: u32,
+    pub version: String,
+    pub project_name: String,
+    /// Compile time and date
+    pub time: String,
+    pub date: String,
+    pub idf_version: String,
+}
+
+impl AppDescriptor {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let data = data.get(..APP_DESC_LENGTH)?;
+        if u32::from_le_bytes(data[0..4].try_into().unwrap()) != APP_DESC_MAGIC {
+            return None;
+        }
+        Some(AppDescriptor {
+            secure_version: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            version: string(&data[16..48]),
+            project_name: string(&data[48..80]),
+            time: string(&data[80..96]),
+            date: string(&data[96..112]),
+            idf_version: string(&data[112..144]),
+        })
+    }
+
+    /// Read the descriptor from a binary image, it's stored at the start of the first segment
+    pub fn from_image(chip: Chip, data: &[u8]) -> Option<Self> {
+        // the image header and the header of the first segment
+        let offset = match chip {
+            Chip::Esp8266 => 8 + 8,
+            Chip::Esp32 => 24 + 8,
+        };
+        Self::parse(data.get(offset..)?)
+    }
+
+    /// Read the descriptor from an elf image
+    pub fn from_elf(data: &[u8]) -> Option<Self> {
+        Self::parse(&data[elf_offset(data)?..])
+    }
+}
+
+/// Set the secure version in the descriptor of an elf image, before it's flashed
+pub fn set_secure_version(elf_data: &mut [u8], version: u32) -> Result<(), Error> {
+    let offset = elf_offset(elf_data).ok_or_else(|| {
+        Error::InvalidImage(String::from(
+            "the elf image doesn't contain an esp-idf app descriptor",
+        ))
+    })? + SECURE_VERSION_OFFSET;
+    elf_data[offset..offset + 4].copy_from_slice(&version.to_le_bytes());
+    Ok(())
+}
+
+/// Find the file offset of the descriptor, at the start of one of the loaded segments
+fn elf_offset(data: &[u8]) -> Option<usize> {
+    let elf = ElfFile::new(data).ok()?;
+    elf.program_iter()
+        .filter(|header| header.get_type() == Ok(Type::Load))
+        .filter(|header| header.file_size() as usize >= APP_DESC_LENGTH)
+        .map(|header| header.offset() as usize)
+        .find(|&offset| data.get(offset..offset + 4) == Some(&APP_DESC_MAGIC.to_le_bytes()[..]))
+}
+
+/// A zero terminated string
+fn string(data: &[u8]) -> String {
+    let end = data
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+#[test]
+fn test_app_descriptor() {
+    let mut image = vec![0xe9, 1, 2, 0x20];
+    image.resize(32, 0);
+    image.extend_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+    image.extend_from_slice(&3u32.to_le_bytes());
+    image.resize(32 + 16, 0);
+    image.extend_from_slice(b"v1.2.0\0");
+    image.resize(32 + 48, 0);
+    image.extend_from_slice(b"blink\0");
+    image.resize(32 + 112, 0);
+    image.extend_from_slice(b"v4.4\0");
+    image.resize(32 + APP_DESC_LENGTH, 0);
+
+    let desc = AppDescriptor::from_image(Chip::Esp32, &image).unwrap();
+    assert_eq!(3, desc.secure_version);
+    assert_eq!("v1.2.0", desc.version);
+    assert_eq!("blink", desc.project_name);
+    assert_eq!("v4.4", desc.idf_version);
+    assert_eq!("", desc.date);
+
+    assert_eq!(None, AppDescriptor::from_image(Chip::Esp8266, &image));
+    assert_eq!(None, AppDescriptor::from_image(Chip::Esp32, &image[..100]));
+
+    // the test elf isn't built with esp-idf
+    let mut elf = std::fs::read("./tests/data/esp32").unwrap();
+    assert_eq!(None, AppDescriptor::from_elf(&elf));
+    assert!(set_secure_version(&mut elf, 1).is_err());
+}
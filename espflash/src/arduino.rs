@@ -0,0 +1,191 @@
+use std::fs::{read, read_dir};
+use std::path::{Path, PathBuf};
+
+use crate::chip::{Chip, PartitionTable};
+use crate::flash_args::ImageFile;
+use crate::image::ImageInfo;
+use crate::Error;
+
+const BOOTLOADER_OFFSET: u32 = 0x1000;
+const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+
+/// The build output of a sketch from the Arduino IDE or `arduino-cli compile --output-dir`
+///
+/// The chip is detected from the header of the app image. For the esp32 the output contains the app, the
+/// bootloader and the partition table of the sketch. The arduino core also writes its `boot_app0.bin` to the
+/// otadata partition, which is an erased otadata partition that makes the bootloader start the first app in the
+/// partition table. The core doesn't copy it to the output directory, so it's only taken from there when it was
+/// copied by hand and an erased partition is written otherwise. The esp8266 only has the app, which contains the
+/// bootloader.
+#[derive(Debug)]
+pub struct ArduinoBuild {
+    pub chip: Chip,
+    /// The files to write, in flash order
+    pub files: Vec<ImageFile>,
+    /// The elf image of the sketch, for decoding backtraces
+    pub elf: Option<PathBuf>,
+}
+
+impl ArduinoBuild {
+    /// Find the binaries of the sketch in an arduino build directory
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        let entries = read_dir(dir).map_err(|err| {
+            Error::InvalidArduinoBuild(format!("failed to read \"{}\": {}", dir.display(), err))
+        })?;
+        let mut sketches: Vec<String> = entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter_map(|name| Some(name.strip_suffix(".ino.bin")?.to_string()))
+            .collect();
+        sketches.sort();
+        let sketch = match sketches.as_slice() {
+            [sketch] => sketch,
+            [] => {
+                return Err(Error::InvalidArduinoBuild(format!(
+                    "no <sketch>.ino.bin found in \"{}\"",
+                    dir.display()
+                )))
+            }
+            _ => {
+                return Err(Error::InvalidArduinoBuild(format!(
+                    "\"{}\" contains the builds of several sketches: {}",
+                    dir.display(),
+                    sketches.join(", ")
+                )))
+            }
+        };
+
+        let path = |suffix: &str| dir.join(format!("{}.ino{}", sketch, suffix));
+        let app = read_file(&path(".bin"))?;
+        let elf = Some(path(".elf")).filter(|elf| elf.is_file());
+
+        let (chip, _) = ImageInfo::detect(&app)?;
+        if chip == Chip::Esp8266 {
+            return Ok(ArduinoBuild {
+                chip,
+                files: vec![file(0, &path(".bin"), app)],
+                elf,
+            });
+        }
+
+        let bootloader_path = path(".bootloader.bin");
+        let partitions = read_file(&path(".partitions.bin"))?;
+        let table = PartitionTable::from_bytes(&partitions)?;
+        let mut files = vec![
+            file(
+                BOOTLOADER_OFFSET,
+                &bootloader_path,
+                read_file(&bootloader_path)?,
+            ),
+            file(PARTITION_TABLE_OFFSET, &path(".partitions.bin"), partitions),
+        ];
+        if let Some((offset, size)) = table.ota_data() {
+            let boot_app0 = dir.join("boot_app0.bin");
+            let data = if boot_app0.is_file() {
+                read_file(&boot_app0)?
+            } else {
+                vec![0xff; size as usize]
+            };
+            files.push(ImageFile {
+                addr: offset,
+                name: "boot_app0.bin".into(),
+                role: Some("otadata"),
+                data,
+            });
+        }
+        // from_bytes only accepts tables with an app partition
+        let app_offset = table.app_offset().unwrap();
+        files.push(file(app_offset, &path(".bin"), app));
+
+        Ok(ArduinoBuild { chip, files, elf })
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    read(path).map_err(|err| {
+        Error::InvalidArduinoBuild(format!("failed to read \"{}\": {}", path.display(), err))
+    })
+}
+
+fn file(addr: u32, path: &Path, data: Vec<u8>) -> ImageFile {
+    let name = path.file_name().unwrap().to_string_lossy().into_owned();
+    let role = if name.ends_with(".bootloader.bin") {
+        Some("bootloader")
+    } else if name.ends_with(".partitions.bin") {
+        Some("partition-table")
+    } else {
+        Some("app")
+    };
+    ImageFile {
+        addr,
+        name,
+        role,
+        data,
+    }
+}
+
+#[test]
+fn test_arduino_build() {
+    use std::fs::{create_dir_all, remove_dir_all, remove_file, write};
+
+    let dir = std::env::temp_dir().join(format!("espflash-arduino-{}", std::process::id()));
+    create_dir_all(&dir).unwrap();
+    let app = read("./tests/data/esp32.bin").unwrap();
+    let table = PartitionTable::from_csv(
+        "nvs, data, nvs, , 0x5000,\notadata, data, ota, , 0x2000,\napp0, app, ota_0, , 0x140000,\n",
+        PARTITION_TABLE_OFFSET,
+    )
+    .unwrap();
+    write(dir.join("Blink.ino.bin"), &app).unwrap();
+    write(dir.join("Blink.ino.bootloader.bin"), [0xe9; 16]).unwrap();
+    write(dir.join("Blink.ino.partitions.bin"), table.to_bytes()).unwrap();
+
+    let build = ArduinoBuild::open(&dir).unwrap();
+    assert_eq!(Chip::Esp32, build.chip);
+    let files: Vec<(u32, &str)> = build
+        .files
+        .iter()
+        .map(|file| (file.addr, file.name.as_str()))
+        .collect();
+    assert_eq!(
+        vec![
+            (0x1000, "Blink.ino.bootloader.bin"),
+            (0x8000, "Blink.ino.partitions.bin"),
+            (0xe000, "boot_app0.bin"),
+            (0x10000, "Blink.ino.bin"),
+        ],
+        files
+    );
+    assert!(build.files[2].data.iter().all(|&byte| byte == 0xff));
+    assert_eq!(app, build.files[3].data);
+
+    // an esp32 build without the bootloader isn't mistaken for an esp8266 build
+    remove_file(dir.join("Blink.ino.bootloader.bin")).unwrap();
+    assert!(matches!(
+        ArduinoBuild::open(&dir),
+        Err(Error::InvalidArduinoBuild(_))
+    ));
+
+    write(dir.join("Other.ino.bin"), &app).unwrap();
+    assert!(ArduinoBuild::open(&dir).is_err());
+
+    remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_arduino_build_esp8266() {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    let dir = std::env::temp_dir().join(format!("espflash-arduino-8266-{}", std::process::id()));
+    create_dir_all(&dir).unwrap();
+    let app = read("./tests/data/esp8266.bin").unwrap();
+    write(dir.join("Blink.ino.bin"), &app).unwrap();
+
+    let build = ArduinoBuild::open(&dir).unwrap();
+    assert_eq!(Chip::Esp8266, build.chip);
+    assert_eq!(1, build.files.len());
+    assert_eq!(0, build.files[0].addr);
+    assert_eq!(app, build.files[0].data);
+
+    remove_dir_all(&dir).unwrap();
+}
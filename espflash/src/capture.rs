@@ -0,0 +1,186 @@
+use std::io::{self, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serial::{PortSettings, SerialPort, SerialPortSettings};
+
+use crate::encoder::SlipDecoder;
+
+/// pcap link type reserved for private use, the data of each packet is a direction byte followed by a frame
+const LINKTYPE_USER0: u32 = 147;
+const SNAPLEN: u32 = 0x10000;
+
+/// Direction byte of data sent to the chip
+pub const DIRECTION_TO_CHIP: u8 = 0;
+/// Direction byte of data received from the chip
+pub const DIRECTION_FROM_CHIP: u8 = 1;
+
+/// A serial port that records the frames sent and received to a pcap file
+///
+/// Every slip frame is stored as a timestamped packet with link type `USER0`, independent of how the data was
+/// split over reads and writes, so a capture can be replayed frame by frame. The first byte of each packet is
+/// the direction, [`DIRECTION_TO_CHIP`] or [`DIRECTION_FROM_CHIP`], followed by the decoded frame, a command or
+/// response without the slip framing. Data outside of frames, like boot messages, and malformed frames aren't
+/// recorded.
+pub struct WireCapture<P, W: Write> {
+    port: P,
+    output: W,
+    sent: SlipDecoder,
+    received: SlipDecoder,
+}
+
+impl<P: SerialPort, W: Write> WireCapture<P, W> {
+    /// Wrap `port`, writing the capture to `output`
+    pub fn new(port: P, mut output: W) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+        output.write_all(&header)?;
+
+        Ok(WireCapture {
+            port,
+            output,
+            sent: SlipDecoder::new(),
+            received: SlipDecoder::new(),
+        })
+    }
+
+    /// Record the frames completed by `data`
+    fn record(&mut self, direction: u8, data: &[u8]) -> io::Result<()> {
+        let decoder = if direction == DIRECTION_TO_CHIP {
+            &mut self.sent
+        } else {
+            &mut self.received
+        };
+        for frame in decoder.feed(data).into_iter().flatten() {
+            self.record_frame(direction, &frame)?;
+        }
+        Ok(())
+    }
+
+    fn record_frame(&mut self, direction: u8, data: &[u8]) -> io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0));
+        let len = (data.len() + 1) as u32;
+
+        let mut packet = Vec::with_capacity(16 + len as usize);
+        packet.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        packet.extend_from_slice(&time.subsec_micros().to_le_bytes());
+        packet.extend_from_slice(&len.to_le_bytes());
+        packet.extend_from_slice(&len.to_le_bytes());
+        packet.push(direction);
+        packet.extend_from_slice(data);
+        self.output.write_all(&packet)?;
+        self.output.flush()
+    }
+}
+
+impl<P: SerialPort, W: Write> Read for WireCapture<P, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.port.read(buf)?;
+        self.record(DIRECTION_FROM_CHIP, &buf[..count])?;
+        Ok(count)
+    }
+}
+
+impl<P: SerialPort, W: Write> Write for WireCapture<P, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.port.write(buf)?;
+        self.record(DIRECTION_TO_CHIP, &buf[..count])?;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl<P: SerialPort, W: Write> SerialPort for WireCapture<P, W> {
+    fn timeout(&self) -> Duration {
+        self.port.timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serial::Result<()> {
+        self.port.set_timeout(timeout)
+    }
+
+    fn configure(&mut self, settings: &PortSettings) -> serial::Result<()> {
+        self.port.configure(settings)
+    }
+
+    fn reconfigure(
+        &mut self,
+        setup: &dyn Fn(&mut dyn SerialPortSettings) -> serial::Result<()>,
+    ) -> serial::Result<()> {
+        self.port.reconfigure(setup)
+    }
+
+    fn set_rts(&mut self, level: bool) -> serial::Result<()> {
+        self.port.set_rts(level)
+    }
+
+    fn set_dtr(&mut self, level: bool) -> serial::Result<()> {
+        self.port.set_dtr(level)
+    }
+
+    fn read_cts(&mut self) -> serial::Result<bool> {
+        self.port.read_cts()
+    }
+
+    fn read_dsr(&mut self) -> serial::Result<bool> {
+        self.port.read_dsr()
+    }
+
+    fn read_ri(&mut self) -> serial::Result<bool> {
+        self.port.read_ri()
+    }
+
+    fn read_cd(&mut self) -> serial::Result<bool> {
+        self.port.read_cd()
+    }
+}
+
+#[test]
+fn test_capture() {
+    use std::convert::TryInto;
+    use std::sync::{Arc, Mutex};
+
+    use crate::testing::MockTransport;
+    use crate::{Chip, FlashSize, Flasher};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let output = Shared::default();
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    Flasher::connect(WireCapture::new(mock, output.clone()).unwrap(), None).unwrap();
+    let capture = output.0.lock().unwrap();
+
+    assert_eq!(&[0xd4, 0xc3, 0xb2, 0xa1], &capture[..4]);
+    let mut packets = Vec::new();
+    let mut pos = 24;
+    while pos < capture.len() {
+        let len = u32::from_le_bytes(capture[pos + 8..pos + 12].try_into().unwrap()) as usize;
+        packets.push(&capture[pos + 16..pos + 16 + len]);
+        pos += 16 + len;
+    }
+    // the sync command, with its 36 bytes of data, and the first of the responses to it
+    assert_eq!(&[DIRECTION_TO_CHIP, 0x00, 0x08, 36, 0], &packets[0][..5]);
+    assert_eq!(1 + 8 + 36, packets[0].len());
+    assert_eq!(&[DIRECTION_FROM_CHIP, 0x01, 0x08], &packets[1][..3]);
+}
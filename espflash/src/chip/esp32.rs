@@ -1,20 +1,27 @@
 use std::borrow::Cow;
 use std::io::Write;
 use std::iter::once;
+use std::ops::Range;
 
 use crate::chip::esp32::partition_table::PartitionTable;
-use crate::chip::{Chip, ChipType, EspCommonHeader, SegmentHeader, SpiRegisters, ESP_MAGIC};
+use crate::chip::{
+    Chip, ChipFeature, ChipType, EspCommonHeader, FlashVoltage, SegmentHeader, SpiRegisters,
+    Workarounds, ESP_MAGIC,
+};
+use crate::efuse::{EfuseBlocks, EfuseField};
 use crate::elf::{update_checksum, CodeSegment, FirmwareImage, RomSegment, ESP_CHECKSUM_MAGIC};
 use crate::flasher::FlashSize;
-use crate::Error;
+use crate::{Error, Flasher, Psram, PsramStatus};
 use bytemuck::{bytes_of, Pod, Zeroable};
 use sha2::{Digest, Sha256};
 
-mod partition_table;
+pub mod partition_table;
 
 pub struct Esp32;
 
 const WP_PIN_DISABLED: u8 = 0xEE;
+/// The chip id in the extended image header, see [`Chip::from_chip_id`]
+const IMAGE_CHIP_ID: u16 = 0;
 
 const IROM_MAP_START: u32 = 0x400d0000;
 const IROM_MAP_END: u32 = 0x40400000;
@@ -22,6 +29,53 @@ const IROM_MAP_END: u32 = 0x40400000;
 const DROM_MAP_START: u32 = 0x3F400000;
 const DROM_MAP_END: u32 = 0x3F800000;
 
+const EFUSE_RD_REG_BASE: u32 = 0x3ff5a000;
+const EFUSE_CLK_REG: u32 = EFUSE_RD_REG_BASE + 0xf8;
+const EFUSE_CONF_REG: u32 = EFUSE_RD_REG_BASE + 0xfc;
+const EFUSE_CMD_REG: u32 = EFUSE_RD_REG_BASE + 0x104;
+const EFUSE_DAC_CONF_REG: u32 = EFUSE_RD_REG_BASE + 0x118;
+/// The write registers of the efuse blocks
+const EFUSE_WR_REGS: [u32; 4] = [
+    EFUSE_RD_REG_BASE + 0x1c,
+    EFUSE_RD_REG_BASE + 0x98,
+    EFUSE_RD_REG_BASE + 0xb8,
+    EFUSE_RD_REG_BASE + 0xd8,
+];
+const EFUSE_CONF_WRITE: u32 = 0x5a5a;
+const EFUSE_CONF_READ: u32 = 0x5aa5;
+const EFUSE_CMD_READ: u32 = 0x1;
+const EFUSE_CMD_WRITE: u32 = 0x2;
+const APB_CTL_DATE_ADDR: u32 = 0x3ff6607c;
+
+const GPIO_ENABLE_REG: u32 = 0x3ff44020;
+/// The levels of the strapping pins at reset
+const GPIO_STRAP_REG: u32 = 0x3ff44038;
+/// MTDI selects a 1.8V flash supply when it's high at reset
+const STRAP_MTDI: u32 = 1 << 5;
+// the bits of the flash supply efuses in the fifth word of block 0
+const EFUSE_XPD_SDIO_REG: u32 = 1 << 14;
+const EFUSE_XPD_SDIO_TIEH: u32 = 1 << 15;
+const EFUSE_XPD_SDIO_FORCE: u32 = 1 << 16;
+const GPIO_FUNC0_OUT_SEL_CFG_REG: u32 = 0x3ff44530;
+/// Let the GPIO enable register control the output enable, instead of the peripheral
+const GPIO_OEN_SEL: u32 = 1 << 10;
+const IO_MUX_BASE: u32 = 0x3ff49000;
+const IO_MUX_MCU_SEL_SHIFT: u32 = 12;
+const IO_MUX_MCU_SEL_MASK: u32 = 0x7 << IO_MUX_MCU_SEL_SHIFT;
+/// The function of the io mux that connects a pin to the GPIO matrix
+const IO_MUX_FUNC_GPIO: u32 = 2;
+/// The output signals of the flash spi peripheral in the GPIO matrix
+const SPICLK_OUT_IDX: u32 = 0;
+const SPICS1_OUT_IDX: u32 = 6;
+/// The pin register bits that disable the chip select lines of the flash and the psram
+const SPI_CS0_DIS: u32 = 1 << 0;
+const SPI_CS1_DIS: u32 = 1 << 1;
+/// The flash clock pin, which is connected to the spi peripheral by the io mux
+const SPI_CLK_PIN: u8 = 6;
+const PSRAM_READ_ID: u8 = 0x9f;
+/// The known good die byte of the psram id, which the psram chips used with the esp32 answer with
+const PSRAM_ID_KGD: u32 = 0x5d;
+
 const BOOT_ADDR: u32 = 0x1000;
 const PARTION_ADDR: u32 = 0x8000;
 const APP_ADDR: u32 = 0x10000;
@@ -34,37 +88,218 @@ struct ExtendedHeader {
     d_cs_drv: u8,
     gd_wp_drv: u8,
     chip_id: u16,
+    /// Only the major revision, for bootloaders that predate the full revisions
     min_rev: u8,
-    padding: [u8; 8],
+    min_rev_full: [u8; 2],
+    max_rev_full: [u8; 2],
+    reserved: [u8; 4],
     append_digest: u8,
 }
 
 impl ChipType for Esp32 {
     const DATE_REG1_VALUE: u32 = 0x15122500;
-    const DATE_REG2_VALUE: u32 = 0;
     const SPI_REGISTERS: SpiRegisters = SpiRegisters {
         base: 0x3ff42000,
         usr_offset: 0x1c,
+        clock_offset: 0x18,
+        pin_offset: 0x34,
         usr1_offset: 0x20,
         usr2_offset: 0x24,
         w0_offset: 0x80,
         mosi_length_offset: Some(0x28),
         miso_length_offset: Some(0x2c),
     };
+    const UART_CLKDIV_REG: u32 = 0x3ff40014;
+    const XTAL_CLK_DIVIDER: u32 = 1;
+    const EFUSES: EfuseBlocks = EfuseBlocks {
+        blocks: &[
+            (EFUSE_RD_REG_BASE, 7),
+            (EFUSE_RD_REG_BASE + 0x38, 8),
+            (EFUSE_RD_REG_BASE + 0x58, 8),
+            (EFUSE_RD_REG_BASE + 0x78, 8),
+        ],
+        fields: EFUSE_FIELDS,
+    };
+    const REGISTER_RANGES: &'static [Range<u32>] = &[
+        // the peripherals, and their alias on the ahb bus
+        0x3ff00000..0x3ff80000,
+        0x60000000..0x60040000,
+    ];
+    const RAM_RANGES: &'static [Range<u32>] = &[
+        // rtc fast memory, on the data and the instruction bus
+        0x3ff80000..0x3ff82000,
+        0x400c0000..0x400c2000,
+        // internal sram, on the data and the instruction bus
+        0x3ffae000..0x40000000,
+        0x40070000..0x400c0000,
+        // rtc slow memory
+        0x50000000..0x50002000,
+    ];
 
     fn addr_is_flash(addr: u32) -> bool {
         (IROM_MAP_START..IROM_MAP_END).contains(&addr)
             || (DROM_MAP_START..DROM_MAP_END).contains(&addr)
     }
 
+    fn chip_revision(flasher: &mut Flasher) -> Result<Option<u32>, Error> {
+        let rev_bit0 = (read_efuse(flasher, 3)? >> 15) & 1 == 1;
+        let rev_bit1 = (read_efuse(flasher, 5)? >> 20) & 1 == 1;
+        let rev_bit2 = (flasher.read_reg(APB_CTL_DATE_ADDR)? >> 31) & 1 == 1;
+
+        let revision = match (rev_bit0, rev_bit1, rev_bit2) {
+            (true, true, true) => 3,
+            (true, true, false) => 2,
+            (true, false, _) => 1,
+            (false, _, _) => 0,
+        };
+        Ok(Some(revision))
+    }
+
+    fn chip_features(flasher: &mut Flasher) -> Result<Vec<ChipFeature>, Error> {
+        let word3 = read_efuse(flasher, 3)?;
+
+        let mut features = vec![ChipFeature::Wifi];
+
+        let disable_bt = word3 & (1 << 1) != 0;
+        if !disable_bt {
+            features.push(ChipFeature::Bluetooth);
+        }
+
+        let disable_app_cpu = word3 & (1 << 0) != 0;
+        if disable_app_cpu {
+            features.push(ChipFeature::SingleCore);
+        } else {
+            features.push(ChipFeature::DualCore);
+        }
+
+        let rated_160mhz = word3 & (1 << 13) != 0;
+        if rated_160mhz {
+            features.push(ChipFeature::Cpu160Mhz);
+        } else {
+            features.push(ChipFeature::Cpu240Mhz);
+        }
+
+        let package = package_version(word3);
+        if [2, 4, 5, 6].contains(&package) {
+            features.push(ChipFeature::EmbeddedFlash);
+        }
+        if package == 6 {
+            features.push(ChipFeature::EmbeddedPsram);
+        }
+
+        Ok(features)
+    }
+
+    fn workarounds(revision: Option<u32>) -> Workarounds {
+        Workarounds {
+            // high baud rates are only a problem on revision 0, unknown revisions are treated like it
+            unreliable_high_baud: revision.unwrap_or(0) == 0,
+        }
+    }
+
+    fn flash_voltage(flasher: &mut Flasher) -> Result<Option<FlashVoltage>, Error> {
+        let word4 = read_efuse(flasher, 4)?;
+        // the efuses take precedence over the strapping pin, the regulator is off when they only set the force bit
+        let high = if word4 & EFUSE_XPD_SDIO_FORCE != 0 {
+            if word4 & EFUSE_XPD_SDIO_REG == 0 {
+                return Ok(None);
+            }
+            word4 & EFUSE_XPD_SDIO_TIEH != 0
+        } else {
+            flasher.read_reg(GPIO_STRAP_REG)? & STRAP_MTDI == 0
+        };
+        Ok(Some(if high {
+            FlashVoltage::V3_3
+        } else {
+            FlashVoltage::V1_8
+        }))
+    }
+
+    fn psram(flasher: &mut Flasher, probe_external: bool) -> Result<PsramStatus, Error> {
+        let package = package_version(read_efuse(flasher, 3)?);
+        let embedded = [6, 7].contains(&package);
+        // probing drives the pins of external psram, which a board without it might use for something else
+        if !embedded && !probe_external {
+            return Ok(PsramStatus::Unknown);
+        }
+        // the psram pins of the esp-idf for the package, the D0WD modules use GPIO16 and GPIO17
+        let (cs_pin, clk_pin) = match package {
+            2 => (10, 9),
+            5 => (10, SPI_CLK_PIN),
+            6 => (9, 10),
+            _ => (16, 17),
+        };
+        let id = read_psram_id(flasher, cs_pin, clk_pin)?;
+        if (id >> 8) & 0xff != PSRAM_ID_KGD {
+            return Ok(PsramStatus::Absent);
+        }
+        // the size is in the top bits of the eid byte, 16Mbit, 32Mbit or 64Mbit
+        let size = match (id >> 21) & 0x7 {
+            0 => 2 * 1024 * 1024,
+            1 => 4 * 1024 * 1024,
+            2 => 8 * 1024 * 1024,
+            _ => return Ok(PsramStatus::Unknown),
+        };
+        Ok(PsramStatus::Present(Psram {
+            size,
+            embedded,
+            cs_pin,
+            clk_pin,
+        }))
+    }
+
+    fn mac_address(flasher: &mut Flasher) -> Result<[u8; 6], Error> {
+        let word1 = read_efuse(flasher, 1)?.to_be_bytes();
+        let word2 = read_efuse(flasher, 2)?.to_be_bytes();
+
+        Ok([word2[2], word2[3], word1[0], word1[1], word1[2], word1[3]])
+    }
+
+    fn burn_efuses(flasher: &mut Flasher, block: usize, words: &[u32]) -> Result<(), Error> {
+        // the programming timing depends on the apb clock, which runs from the crystal in the bootloader
+        let (clk_sel0, clk_sel1, dac_clk_div) = match Chip::Esp32.crystal_frequency(flasher)? {
+            26 => (250, 255, 52),
+            _ => (160, 255, 80),
+        };
+        flasher.write_reg(EFUSE_CLK_REG, clk_sel1 << 8 | clk_sel0, Some(0xffff))?;
+        flasher.write_reg(EFUSE_DAC_CONF_REG, dac_clk_div, Some(0xff))?;
+
+        for (i, word) in words.iter().enumerate() {
+            flasher.write_reg(EFUSE_WR_REGS[block] + 4 * i as u32, *word, None)?;
+        }
+        efuse_command(flasher, EFUSE_CONF_WRITE, EFUSE_CMD_WRITE)?;
+        for i in 0..words.len() {
+            flasher.write_reg(EFUSE_WR_REGS[block] + 4 * i as u32, 0, None)?;
+        }
+        // update the read registers with the new values
+        efuse_command(flasher, EFUSE_CONF_READ, EFUSE_CMD_READ)
+    }
+
     fn get_flash_segments<'a>(
         image: &'a FirmwareImage,
     ) -> Box<dyn Iterator<Item = Result<RomSegment<'a>, Error>> + 'a> {
-        let bootloader = include_bytes!("../../bootloader/bootloader.bin");
+        // the bundled bootloader only reads the partition table from the default offset
+        let custom_offsets = image.partition_table_offset.unwrap_or(PARTION_ADDR) != PARTION_ADDR
+            || image.bootloader_offset.unwrap_or(BOOT_ADDR) != BOOT_ADDR;
+        let bootloader: &[u8] = match &image.bootloader {
+            Some(bootloader) => bootloader,
+            None if custom_offsets => {
+                return Box::new(once(Err(Error::InvalidImage(format!(
+                "the bundled bootloader expects the partition table at {:#x} and itself at {:#x}, \
+                     a bootloader built for the configured offsets is needed",
+                PARTION_ADDR, BOOT_ADDR
+            )))))
+            }
+            None => include_bytes!("../../bootloader/bootloader.bin"),
+        };
 
-        let partition_table = PartitionTable::basic(0x10000, 0x3f0000).to_bytes();
+        let partition_table = image
+            .partition_table
+            .clone()
+            .unwrap_or_else(|| PartitionTable::basic(APP_ADDR, 0x3f0000));
+        let app_addr = partition_table.app_offset().unwrap_or(APP_ADDR);
 
-        fn get_data<'a>(image: &'a FirmwareImage) -> Result<RomSegment<'a>, Error> {
+        fn get_data<'a>(image: &'a FirmwareImage, app_addr: u32) -> Result<RomSegment<'a>, Error> {
             let mut data = Vec::new();
 
             let header = EspCommonHeader {
@@ -81,22 +316,19 @@ impl ChipType for Esp32 {
                 clk_q_drv: 0,
                 d_cs_drv: 0,
                 gd_wp_drv: 0,
-                chip_id: 0,
-                min_rev: 0,
-                padding: [0; 8],
+                chip_id: IMAGE_CHIP_ID,
+                min_rev: (image.min_chip_revision / 100) as u8,
+                min_rev_full: image.min_chip_revision.to_le_bytes(),
+                max_rev_full: image.max_chip_revision.to_le_bytes(),
+                reserved: [0; 4],
                 append_digest: 1,
             };
             data.write_all(bytes_of(&extended_header))?;
 
             let mut checksum = ESP_CHECKSUM_MAGIC;
 
-            let _ = image.segments().collect::<Vec<_>>();
-
-            let mut flash_segments: Vec<_> = image.rom_segments(Chip::Esp32).collect();
-            flash_segments.sort();
-            let mut ram_segments: Vec<_> = image.ram_segments(Chip::Esp32).collect();
-            ram_segments.sort();
-            let mut ram_segments = ram_segments.into_iter();
+            let flash_segments = image.rom_segments(Chip::Esp32);
+            let mut ram_segments = image.ram_segments(Chip::Esp32);
 
             let mut segment_count = 0;
 
@@ -113,7 +345,7 @@ impl ChipType for Esp32 {
                         }
                         let pad_header = SegmentHeader {
                             addr: 0,
-                            length: pad_len as u32,
+                            length: pad_len,
                         };
                         data.write_all(bytes_of(&pad_header))?;
                         for _ in 0..pad_len {
@@ -134,7 +366,7 @@ impl ChipType for Esp32 {
             }
 
             let padding = 15 - (data.len() % 16);
-            let padding = &[0u8; 16][0..padding as usize];
+            let padding = &[0u8; 16][0..padding];
             data.write_all(padding)?;
 
             data.write_all(&[checksum])?;
@@ -148,25 +380,515 @@ impl ChipType for Esp32 {
             data.write_all(&hash)?;
 
             Ok(RomSegment {
-                addr: APP_ADDR,
+                addr: app_addr,
                 data: Cow::Owned(data),
             })
         }
 
         Box::new(
             once(Ok(RomSegment {
-                addr: BOOT_ADDR,
+                addr: image.bootloader_offset.unwrap_or(BOOT_ADDR),
                 data: Cow::Borrowed(bootloader),
             }))
             .chain(once(Ok(RomSegment {
-                addr: PARTION_ADDR,
-                data: Cow::Owned(partition_table),
+                addr: image.partition_table_offset.unwrap_or(PARTION_ADDR),
+                data: Cow::Owned(partition_table.to_bytes()),
             })))
-            .chain(once(get_data(image))),
+            .chain(once(get_data(image, app_addr))),
         )
     }
 }
 
+const EFUSE_FIELDS: &[EfuseField] = &[
+    EfuseField {
+        name: "WR_DIS",
+        block: 0,
+        bit: 0,
+        bits: 16,
+        description: "Disables writing to efuse fields",
+        write_disable: Some(1),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "RD_DIS",
+        block: 0,
+        bit: 16,
+        bits: 4,
+        description: "Disables reading efuse blocks by software",
+        write_disable: Some(0),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "FLASH_CRYPT_CNT",
+        block: 0,
+        bit: 20,
+        bits: 7,
+        description: "Flash encryption is enabled when an odd number of bits is set",
+        write_disable: Some(2),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "UART_DOWNLOAD_DIS",
+        block: 0,
+        bit: 27,
+        bits: 1,
+        description: "Disables the uart download mode",
+        write_disable: Some(2),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "MAC",
+        block: 0,
+        bit: 32,
+        bits: 48,
+        description: "Factory programmed MAC address",
+        write_disable: Some(3),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "MAC_CRC",
+        block: 0,
+        bit: 80,
+        bits: 8,
+        description: "CRC8 of the factory programmed MAC address",
+        write_disable: Some(3),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "CHIP_VER_DIS_APP_CPU",
+        block: 0,
+        bit: 96,
+        bits: 1,
+        description: "Disables the app cpu",
+        write_disable: Some(3),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "CHIP_VER_DIS_BT",
+        block: 0,
+        bit: 97,
+        bits: 1,
+        description: "Disables bluetooth",
+        write_disable: Some(3),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "CHIP_VER_PKG_4BIT",
+        block: 0,
+        bit: 98,
+        bits: 1,
+        description: "Most significant bit of the chip package version",
+        write_disable: Some(3),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "CHIP_VER_DIS_CACHE",
+        block: 0,
+        bit: 99,
+        bits: 1,
+        description: "Disables the cache",
+        write_disable: Some(3),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "SPI_PAD_CONFIG_HD",
+        block: 0,
+        bit: 100,
+        bits: 5,
+        description: "Gpio used for the flash HD pin",
+        write_disable: Some(6),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "CHIP_VER_PKG",
+        block: 0,
+        bit: 105,
+        bits: 3,
+        description: "Chip package version",
+        write_disable: Some(3),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "CHIP_CPU_FREQ_LOW",
+        block: 0,
+        bit: 108,
+        bits: 1,
+        description: "The cpu is rated for a lower frequency",
+        write_disable: Some(3),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "CHIP_CPU_FREQ_RATED",
+        block: 0,
+        bit: 109,
+        bits: 1,
+        description: "The cpu frequency rating is set",
+        write_disable: Some(3),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "BLK3_PART_RESERVE",
+        block: 0,
+        bit: 110,
+        bits: 1,
+        description: "Block 3 is partially used for adc calibration",
+        write_disable: Some(10),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "CHIP_VER_REV1",
+        block: 0,
+        bit: 111,
+        bits: 1,
+        description: "Set for chip revision 1 and later",
+        write_disable: Some(3),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "CLK8M_FREQ",
+        block: 0,
+        bit: 128,
+        bits: 8,
+        description: "Calibration of the 8MHz oscillator",
+        write_disable: Some(4),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "ADC_VREF",
+        block: 0,
+        bit: 136,
+        bits: 5,
+        description: "Calibrated adc reference voltage",
+        write_disable: Some(4),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "XPD_SDIO_REG",
+        block: 0,
+        bit: 142,
+        bits: 1,
+        description: "Powers up the sdio regulator at boot",
+        write_disable: Some(5),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "XPD_SDIO_TIEH",
+        block: 0,
+        bit: 143,
+        bits: 1,
+        description: "Sdio regulator voltage, 3.3V when set and 1.8V otherwise",
+        write_disable: Some(5),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "XPD_SDIO_FORCE",
+        block: 0,
+        bit: 144,
+        bits: 1,
+        description: "Ignore the MTDI strapping pin for the sdio regulator",
+        write_disable: Some(5),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "SPI_PAD_CONFIG_CLK",
+        block: 0,
+        bit: 160,
+        bits: 5,
+        description: "Gpio used for the flash CLK pin",
+        write_disable: Some(6),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "SPI_PAD_CONFIG_Q",
+        block: 0,
+        bit: 165,
+        bits: 5,
+        description: "Gpio used for the flash Q pin",
+        write_disable: Some(6),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "SPI_PAD_CONFIG_D",
+        block: 0,
+        bit: 170,
+        bits: 5,
+        description: "Gpio used for the flash D pin",
+        write_disable: Some(6),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "SPI_PAD_CONFIG_CS0",
+        block: 0,
+        bit: 175,
+        bits: 5,
+        description: "Gpio used for the flash CS0 pin",
+        write_disable: Some(6),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "FLASH_CRYPT_CONFIG",
+        block: 0,
+        bit: 188,
+        bits: 4,
+        description: "Flash encryption key tweak configuration",
+        write_disable: Some(10),
+        read_disable: Some(3),
+    },
+    EfuseField {
+        name: "CODING_SCHEME",
+        block: 0,
+        bit: 192,
+        bits: 2,
+        description: "Efuse coding scheme, 0 none, 1 3/4 and 2 repeat",
+        write_disable: Some(10),
+        read_disable: Some(3),
+    },
+    EfuseField {
+        name: "CONSOLE_DEBUG_DISABLE",
+        block: 0,
+        bit: 194,
+        bits: 1,
+        description: "Disables the rom basic console",
+        write_disable: Some(15),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "DISABLE_SDIO_HOST",
+        block: 0,
+        bit: 195,
+        bits: 1,
+        description: "Disables the sdio host",
+        write_disable: None,
+        read_disable: None,
+    },
+    EfuseField {
+        name: "ABS_DONE_0",
+        block: 0,
+        bit: 196,
+        bits: 1,
+        description: "Secure boot v1 is enabled",
+        write_disable: Some(12),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "ABS_DONE_1",
+        block: 0,
+        bit: 197,
+        bits: 1,
+        description: "Secure boot v2 is enabled",
+        write_disable: Some(13),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "JTAG_DISABLE",
+        block: 0,
+        bit: 198,
+        bits: 1,
+        description: "Disables jtag",
+        write_disable: Some(14),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "DISABLE_DL_ENCRYPT",
+        block: 0,
+        bit: 199,
+        bits: 1,
+        description: "Disables flash encryption in download mode",
+        write_disable: Some(15),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "DISABLE_DL_DECRYPT",
+        block: 0,
+        bit: 200,
+        bits: 1,
+        description: "Disables flash decryption in download mode",
+        write_disable: Some(15),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "DISABLE_DL_CACHE",
+        block: 0,
+        bit: 201,
+        bits: 1,
+        description: "Disables the flash cache in download mode",
+        write_disable: Some(15),
+        read_disable: None,
+    },
+    EfuseField {
+        name: "KEY_STATUS",
+        block: 0,
+        bit: 202,
+        bits: 1,
+        description: "Block 3 is not used for a key",
+        write_disable: Some(10),
+        read_disable: Some(3),
+    },
+    EfuseField {
+        name: "BLOCK1",
+        block: 1,
+        bit: 0,
+        bits: 256,
+        description: "Flash encryption key",
+        write_disable: Some(7),
+        read_disable: Some(0),
+    },
+    EfuseField {
+        name: "BLOCK2",
+        block: 2,
+        bit: 0,
+        bits: 256,
+        description: "Secure boot key",
+        write_disable: Some(8),
+        read_disable: Some(1),
+    },
+    EfuseField {
+        name: "CUSTOM_MAC_CRC",
+        block: 3,
+        bit: 0,
+        bits: 8,
+        description: "CRC8 of the custom MAC address",
+        write_disable: Some(9),
+        read_disable: Some(2),
+    },
+    EfuseField {
+        name: "CUSTOM_MAC",
+        block: 3,
+        bit: 8,
+        bits: 48,
+        description: "Custom MAC address",
+        write_disable: Some(9),
+        read_disable: Some(2),
+    },
+    EfuseField {
+        name: "MAC_VERSION",
+        block: 3,
+        bit: 184,
+        bits: 8,
+        description: "Version of the custom MAC address, 1 when it's used",
+        write_disable: Some(9),
+        read_disable: Some(2),
+    },
+    EfuseField {
+        name: "SECURE_VERSION",
+        block: 3,
+        bit: 128,
+        bits: 32,
+        description: "Anti-rollback counter, the number of set bits",
+        write_disable: Some(9),
+        read_disable: Some(2),
+    },
+];
+
+fn read_efuse(flasher: &mut Flasher, word: u32) -> Result<u32, Error> {
+    flasher.read_reg(EFUSE_RD_REG_BASE + 4 * word)
+}
+
+/// Read the id of the psram on `cs_pin`
+///
+/// The chip select and clock of the spi peripheral are routed to the psram pins through the GPIO matrix, and the
+/// chip select of the flash is disabled while the id is read. The pins and the spi peripheral are restored
+/// afterwards, also when reading the id fails, so the flash keeps working.
+fn read_psram_id(flasher: &mut Flasher, cs_pin: u8, clk_pin: u8) -> Result<u32, Error> {
+    let spi_pin = Esp32::SPI_REGISTERS.pin();
+    let mut outputs = vec![(cs_pin, SPICS1_OUT_IDX)];
+    if clk_pin != SPI_CLK_PIN {
+        outputs.push((clk_pin, SPICLK_OUT_IDX));
+    }
+
+    let mut guard = RegisterGuard {
+        flasher,
+        saved: Vec::new(),
+    };
+    let pin = guard.save(spi_pin)?;
+    let mut enable = guard.save(GPIO_ENABLE_REG)?;
+    for &(pin, signal) in &outputs {
+        let io_mux = io_mux_reg(pin);
+        let out_sel = GPIO_FUNC0_OUT_SEL_CFG_REG + 4 * pin as u32;
+        let mux = guard.save(io_mux)?;
+        guard.save(out_sel)?;
+        guard.flasher.write_reg(
+            io_mux,
+            mux & !IO_MUX_MCU_SEL_MASK | IO_MUX_FUNC_GPIO << IO_MUX_MCU_SEL_SHIFT,
+            None,
+        )?;
+        guard
+            .flasher
+            .write_reg(out_sel, signal | GPIO_OEN_SEL, None)?;
+        enable |= 1 << pin;
+    }
+    guard.flasher.write_reg(GPIO_ENABLE_REG, enable, None)?;
+    guard
+        .flasher
+        .write_reg(spi_pin, pin & !SPI_CS1_DIS | SPI_CS0_DIS, None)?;
+
+    // the read id command is followed by a 24 bit address
+    let id = guard.flasher.spi_command(PSRAM_READ_ID, &[0; 3], 24)?;
+    guard.restore()?;
+    Ok(id)
+}
+
+/// Restores the saved registers when dropped, like [`SpiGuard`](crate::SpiGuard) does for the spi
+/// registers
+struct RegisterGuard<'a> {
+    flasher: &'a mut Flasher,
+    saved: Vec<(u32, u32)>,
+}
+
+impl RegisterGuard<'_> {
+    /// Read a register that's restored later
+    fn save(&mut self, reg: u32) -> Result<u32, Error> {
+        let value = self.flasher.read_reg(reg)?;
+        self.saved.push((reg, value));
+        Ok(value)
+    }
+
+    /// Restore the registers, reporting errors unlike dropping the guard
+    fn restore(mut self) -> Result<(), Error> {
+        while let Some((reg, value)) = self.saved.pop() {
+            self.flasher.write_reg(reg, value, None)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RegisterGuard<'_> {
+    fn drop(&mut self) {
+        for &(reg, value) in self.saved.iter().rev() {
+            let _ = self.flasher.write_reg(reg, value, None);
+        }
+    }
+}
+
+/// The io mux register of one of the psram pins, the registers aren't in the order of the pins
+fn io_mux_reg(pin: u8) -> u32 {
+    let offset = match pin {
+        9 => 0x54,
+        10 => 0x58,
+        16 => 0x4c,
+        17 => 0x50,
+        _ => unreachable!("GPIO{} isn't a psram pin", pin),
+    };
+    IO_MUX_BASE + offset
+}
+
+/// Start an efuse controller command and wait for it to finish
+fn efuse_command(flasher: &mut Flasher, conf: u32, command: u32) -> Result<(), Error> {
+    flasher.write_reg(EFUSE_CONF_REG, conf, None)?;
+    flasher.write_reg(EFUSE_CMD_REG, command, None)?;
+    for _ in 0..100 {
+        if flasher.read_reg(EFUSE_CMD_REG)? & 0x3 == 0 {
+            return Ok(());
+        }
+    }
+    Err(Error::Timeout)
+}
+
+/// Decode the package version from the 3rd efuse word
+fn package_version(word3: u32) -> u32 {
+    ((word3 >> 9) & 0x07) + (((word3 >> 2) & 0x1) << 3)
+}
+
 fn encode_flash_size(size: FlashSize) -> Result<u8, Error> {
     match size {
         FlashSize::Flash256Kb => Err(Error::UnsupportedFlash(size as u8)),
@@ -1,11 +1,18 @@
+use std::convert::TryInto;
 use std::io::Write;
 
 use md5::{Context, Digest};
 
+use crate::Error;
+
 const MAX_PARTITION_LENGTH: usize = 0xC00;
 const PARTITION_TABLE_SIZE: usize = 0x1000;
+const APP_ALIGNMENT: u32 = 0x10000;
+const DATA_ALIGNMENT: u32 = 0x1000;
 
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "lowercase"))]
 #[repr(u8)]
 #[allow(dead_code)]
 pub enum Type {
@@ -14,30 +21,50 @@ pub enum Type {
 }
 
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "lowercase"))]
 #[repr(u8)]
 #[allow(dead_code)]
 pub enum AppType {
     Factory = 0x00,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_0"))]
     Ota0 = 0x10,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_1"))]
     Ota1 = 0x11,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_2"))]
     Ota2 = 0x12,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_3"))]
     Ota3 = 0x13,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_4"))]
     Ota4 = 0x14,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_5"))]
     Ota5 = 0x15,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_6"))]
     Ota6 = 0x16,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_7"))]
     Ota7 = 0x17,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_8"))]
     Ota8 = 0x18,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_9"))]
     Ota9 = 0x19,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_10"))]
     Ota10 = 0x1a,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_11"))]
     Ota11 = 0x1b,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_12"))]
     Ota12 = 0x1c,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_13"))]
     Ota13 = 0x1d,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_14"))]
     Ota14 = 0x1e,
+    #[cfg_attr(feature = "serialize", serde(rename = "ota_15"))]
     Ota15 = 0x1f,
     Test = 0x20,
 }
 
 #[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "lowercase"))]
 #[repr(u8)]
 #[allow(dead_code)]
 pub enum DataType {
@@ -45,6 +72,7 @@ pub enum DataType {
     Phy = 0x01,
     Nvs = 0x02,
     CoreDump = 0x03,
+    #[cfg_attr(feature = "serialize", serde(rename = "nvs_keys"))]
     NvsKeys = 0x04,
     EFuse = 0x05,
     EspHttpd = 0x80,
@@ -52,10 +80,15 @@ pub enum DataType {
     Spiffs = 0x82,
 }
 
+#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(untagged))]
 #[allow(dead_code)]
 pub enum SubType {
     App(AppType),
     Data(DataType),
+    /// A custom data subtype, like the `eeprom` partition of older arduino cores
+    Custom(u8),
 }
 
 impl SubType {
@@ -63,10 +96,14 @@ impl SubType {
         match self {
             SubType::App(ty) => *ty as u8,
             SubType::Data(ty) => *ty as u8,
+            SubType::Custom(ty) => *ty,
         }
     }
 }
 
+/// The partition table written to the flash of the esp32, in front of the app
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct PartitionTable {
     partitions: Vec<Partition>,
 }
@@ -86,11 +123,221 @@ impl PartitionTable {
         }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let table = PartitionTable::basic(0x10000, 0x3f0000);
+    /// Parse a partition table in the csv format of esp-idf
+    ///
+    /// Partitions without an offset are placed after the previous one, the first one after the partition table
+    /// at `table_offset`. Sizes can have a `K` or `M` suffix.
+    pub fn from_csv(csv: &str, table_offset: u32) -> Result<Self, Error> {
+        let mut partitions = Vec::new();
+        let mut next_offset = table_offset + PARTITION_TABLE_SIZE as u32;
+        for (i, line) in csv.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |reason: String| {
+                Error::InvalidPartitionTable(format!("line {}: {}", i + 1, reason))
+            };
+
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if fields.len() < 5 {
+                return Err(invalid(format!(
+                    "expected at least 5 fields, found {}",
+                    fields.len()
+                )));
+            }
+            let (ty, sub_type, alignment) = match fields[1] {
+                "app" => (
+                    Type::App,
+                    parse_app_type(fields[2]).map(SubType::App),
+                    APP_ALIGNMENT,
+                ),
+                "data" => (
+                    Type::Data,
+                    parse_data_type(fields[2]).map(SubType::Data).or_else(|| {
+                        let custom = fields[2].strip_prefix("0x")?;
+                        u8::from_str_radix(custom, 16).ok().map(SubType::Custom)
+                    }),
+                    DATA_ALIGNMENT,
+                ),
+                ty => return Err(invalid(format!("unsupported partition type \"{}\"", ty))),
+            };
+            let sub_type = sub_type.ok_or_else(|| {
+                invalid(format!(
+                    "unsupported {} subtype \"{}\"",
+                    fields[1], fields[2]
+                ))
+            })?;
+            let offset = match fields[3] {
+                "" => next_offset.div_ceil(alignment) * alignment,
+                offset => parse_size(offset)
+                    .ok_or_else(|| invalid(format!("invalid offset \"{}\"", offset)))?,
+            };
+            if offset % alignment != 0 {
+                return Err(invalid(format!(
+                    "offset {:#x} isn't aligned to {:#x}",
+                    offset, alignment
+                )));
+            }
+            let size = parse_size(fields[4])
+                .ok_or_else(|| invalid(format!("invalid size \"{}\"", fields[4])))?;
+            let flags = match fields.get(5).copied().unwrap_or("") {
+                "" => 0,
+                "encrypted" => 1,
+                flags => return Err(invalid(format!("unsupported flags \"{}\"", flags))),
+            };
+
+            next_offset = offset + size;
+            partitions.push(Partition::new(
+                fields[0].to_string(),
+                ty,
+                sub_type,
+                offset,
+                size,
+                flags,
+            ));
+        }
+
+        let table = PartitionTable { partitions };
+        if table.app_offset().is_none() {
+            return Err(Error::InvalidPartitionTable(
+                "the table doesn't have an app partition".into(),
+            ));
+        }
+        Ok(table)
+    }
+
+    /// Parse a partition table in the binary format written to the flash
+    ///
+    /// The table ends at the md5 checksum or the first erased entry.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let mut partitions = Vec::new();
+        for (i, entry) in data[..data.len().min(MAX_PARTITION_LENGTH)]
+            .chunks_exact(PARTITION_SIZE)
+            .enumerate()
+        {
+            match entry[..2] {
+                [0xAA, 0x50] => {}
+                [0xEB, 0xEB] | [0xFF, 0xFF] => break,
+                _ => {
+                    return Err(Error::InvalidPartitionTable(format!(
+                        "entry {} doesn't start with the partition magic",
+                        i
+                    )))
+                }
+            }
+            let (ty, sub_type) = match entry[2] {
+                0x00 => (Type::App, app_type_from_u8(entry[3]).map(SubType::App)),
+                0x01 => (
+                    Type::Data,
+                    Some(
+                        data_type_from_u8(entry[3])
+                            .map(SubType::Data)
+                            .unwrap_or(SubType::Custom(entry[3])),
+                    ),
+                ),
+                ty => {
+                    return Err(Error::InvalidPartitionTable(format!(
+                        "entry {} has the unsupported type {:#x}",
+                        i, ty
+                    )))
+                }
+            };
+            let sub_type = sub_type.ok_or_else(|| {
+                Error::InvalidPartitionTable(format!(
+                    "entry {} has the unsupported app subtype {:#x}",
+                    i, entry[3]
+                ))
+            })?;
+            let name = entry[12..28]
+                .iter()
+                .take_while(|&&byte| byte != 0)
+                .map(|&byte| byte as char)
+                .collect();
+            let read_u32 = |pos: usize| u32::from_le_bytes(entry[pos..pos + 4].try_into().unwrap());
+            partitions.push(Partition::new(
+                name,
+                ty,
+                sub_type,
+                read_u32(4),
+                read_u32(8),
+                read_u32(28),
+            ));
+        }
+
+        let table = PartitionTable { partitions };
+        if table.app_offset().is_none() {
+            return Err(Error::InvalidPartitionTable(
+                "the table doesn't have an app partition".into(),
+            ));
+        }
+        Ok(table)
+    }
+
+    /// The offset of the partition the app is written to, the factory app or else the first app partition
+    pub fn app_offset(&self) -> Option<u32> {
+        let apps = || {
+            self.partitions
+                .iter()
+                .filter(|partition| matches!(partition.ty, Type::App))
+        };
+        apps()
+            .find(|partition| matches!(partition.sub_type, SubType::App(AppType::Factory)))
+            .or_else(|| apps().next())
+            .map(|partition| partition.offset)
+    }
+
+    /// The offset and size of the partition that selects the ota app to boot, if the table has one
+    pub fn ota_data(&self) -> Option<(u32, u32)> {
+        self.partitions
+            .iter()
+            .find(|partition| matches!(partition.sub_type, SubType::Data(DataType::Ota)))
+            .map(|partition| (partition.offset, partition.size))
+    }
+
+    /// The offset and size of the partitions that store the nvs settings, not including the nvs keys
+    pub fn nvs(&self) -> Vec<(u32, u32)> {
+        self.partitions
+            .iter()
+            .filter(|partition| matches!(partition.sub_type, SubType::Data(DataType::Nvs)))
+            .map(|partition| (partition.offset, partition.size))
+            .collect()
+    }
+
+    /// Whether the table has a factory app, which the bootloader boots when the otadata is erased
+    pub fn has_factory_app(&self) -> bool {
+        self.partitions
+            .iter()
+            .any(|partition| matches!(partition.sub_type, SubType::App(AppType::Factory)))
+    }
 
+    /// The table in the csv format of esp-idf
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("# Name, Type, SubType, Offset, Size, Flags\n");
+        for partition in &self.partitions {
+            let (ty, sub_type) = match partition.sub_type {
+                SubType::App(AppType::Factory) => ("app", "factory".to_string()),
+                SubType::App(AppType::Test) => ("app", "test".to_string()),
+                SubType::App(ty) => ("app", format!("ota_{}", ty as u8 - AppType::Ota0 as u8)),
+                SubType::Data(ty) => ("data", data_type_name(ty).to_string()),
+                SubType::Custom(ty) => ("data", format!("{:#x}", ty)),
+            };
+            let flags = if partition.flags & 1 != 0 {
+                "encrypted"
+            } else {
+                ""
+            };
+            csv.push_str(&format!(
+                "{}, {}, {}, {:#x}, {:#x}, {}\n",
+                partition.name, ty, sub_type, partition.offset, partition.size, flags
+            ));
+        }
+        csv
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
         let mut result = Vec::with_capacity(PARTITION_TABLE_SIZE);
-        table.save(&mut result).unwrap();
+        self.save(&mut result).unwrap();
         result
     }
 
@@ -117,8 +364,108 @@ impl PartitionTable {
     }
 }
 
+fn parse_app_type(name: &str) -> Option<AppType> {
+    const OTA: [AppType; 16] = [
+        AppType::Ota0,
+        AppType::Ota1,
+        AppType::Ota2,
+        AppType::Ota3,
+        AppType::Ota4,
+        AppType::Ota5,
+        AppType::Ota6,
+        AppType::Ota7,
+        AppType::Ota8,
+        AppType::Ota9,
+        AppType::Ota10,
+        AppType::Ota11,
+        AppType::Ota12,
+        AppType::Ota13,
+        AppType::Ota14,
+        AppType::Ota15,
+    ];
+    match name {
+        "factory" => Some(AppType::Factory),
+        "test" => Some(AppType::Test),
+        _ => {
+            let index: usize = name.strip_prefix("ota_")?.parse().ok()?;
+            OTA.get(index).copied()
+        }
+    }
+}
+
+fn app_type_from_u8(value: u8) -> Option<AppType> {
+    match value {
+        0x00 => Some(AppType::Factory),
+        0x20 => Some(AppType::Test),
+        0x10..=0x1f => parse_app_type(&format!("ota_{}", value - 0x10)),
+        _ => None,
+    }
+}
+
+fn data_type_from_u8(value: u8) -> Option<DataType> {
+    [
+        DataType::Ota,
+        DataType::Phy,
+        DataType::Nvs,
+        DataType::CoreDump,
+        DataType::NvsKeys,
+        DataType::EFuse,
+        DataType::EspHttpd,
+        DataType::Fat,
+        DataType::Spiffs,
+    ]
+    .iter()
+    .copied()
+    .find(|&ty| ty as u8 == value)
+}
+
+fn data_type_name(ty: DataType) -> &'static str {
+    match ty {
+        DataType::Ota => "ota",
+        DataType::Phy => "phy",
+        DataType::Nvs => "nvs",
+        DataType::CoreDump => "coredump",
+        DataType::NvsKeys => "nvs_keys",
+        DataType::EFuse => "efuse",
+        DataType::EspHttpd => "esphttpd",
+        DataType::Fat => "fat",
+        DataType::Spiffs => "spiffs",
+    }
+}
+
+fn parse_data_type(name: &str) -> Option<DataType> {
+    match name {
+        "ota" => Some(DataType::Ota),
+        "phy" => Some(DataType::Phy),
+        "nvs" => Some(DataType::Nvs),
+        "coredump" => Some(DataType::CoreDump),
+        "nvs_keys" => Some(DataType::NvsKeys),
+        "efuse" => Some(DataType::EFuse),
+        "esphttpd" => Some(DataType::EspHttpd),
+        "fat" => Some(DataType::Fat),
+        "spiffs" => Some(DataType::Spiffs),
+        _ => None,
+    }
+}
+
+/// Parse a decimal or hexadecimal size or offset, with an optional `K` or `M` suffix
+fn parse_size(value: &str) -> Option<u32> {
+    let (value, multiplier) = match value.chars().last()? {
+        'K' | 'k' => (&value[..value.len() - 1], 1024),
+        'M' | 'm' => (&value[..value.len() - 1], 1024 * 1024),
+        _ => (value, 1),
+    };
+    let value = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+        None => value.parse().ok()?,
+    };
+    value.checked_mul(multiplier)
+}
+
 const PARTITION_SIZE: usize = 32;
 
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 struct Partition {
     name: String,
     ty: Type,
@@ -205,3 +552,69 @@ fn test_basic() {
     assert_eq!(expected.len(), result.len());
     assert_eq!(expected, result.as_slice());
 }
+
+#[test]
+fn test_from_csv() {
+    use std::fs::read;
+
+    let table = PartitionTable::from_csv(
+        "# Name, Type, SubType, Offset, Size, Flags\nfactory, app, factory, 0x10000, 0x3f0000,\n",
+        0x8000,
+    )
+    .unwrap();
+    assert_eq!(
+        read("./tests/data/partitions.bin").unwrap(),
+        table.to_bytes()
+    );
+
+    // offsets are filled in after the table, apps are aligned to 64KB
+    let table = PartitionTable::from_csv(
+        "nvs, data, nvs, , 0x6000,\nphy_init, data, phy, , 4K,\nota_0, app, ota_0, , 1M,\n",
+        0x8000,
+    )
+    .unwrap();
+    let offsets: Vec<u32> = table.partitions.iter().map(|p| p.offset).collect();
+    assert_eq!(vec![0x9000, 0xf000, 0x10000], offsets);
+    assert_eq!(Some(0x10000), table.app_offset());
+
+    assert!(PartitionTable::from_csv("nvs, data, nvs, , 0x6000,\n", 0x8000).is_err());
+    assert!(PartitionTable::from_csv("factory, app, factory, 0x10001, 1M,\n", 0x8000).is_err());
+    assert!(PartitionTable::from_csv("factory, app, ota_16, , 1M,\n", 0x8000).is_err());
+}
+
+#[test]
+fn test_from_bytes() {
+    let csv =
+        "nvs, data, nvs, , 0x5000,\notadata, data, ota, , 0x2000,\napp0, app, ota_0, , 0x140000,\n\
+               app1, app, ota_1, , 0x140000,\nspiffs, data, spiffs, , 0x170000,\n";
+    let bytes = PartitionTable::from_csv(csv, 0x8000).unwrap().to_bytes();
+    let table = PartitionTable::from_bytes(&bytes).unwrap();
+    assert_eq!(bytes, table.to_bytes());
+    assert_eq!(Some(0x10000), table.app_offset());
+    assert_eq!(Some((0xe000, 0x2000)), table.ota_data());
+    assert_eq!(vec![(0x9000, 0x5000)], table.nvs());
+    assert!(!table.has_factory_app());
+    assert!(PartitionTable::basic(0x10000, 0x3f0000).has_factory_app());
+    assert!(table
+        .to_csv()
+        .contains("app1, app, ota_1, 0x150000, 0x140000, \n"));
+
+    assert!(PartitionTable::from_bytes(&[0xff; 0x1000]).is_err());
+    assert!(PartitionTable::from_bytes(&[0x12; 0x1000]).is_err());
+}
+
+#[cfg(feature = "serialize")]
+#[test]
+fn test_serialize_partition_table() {
+    let csv = "nvs, data, nvs, , 0x5000,\nkeys, data, nvs_keys, , 0x1000,\notadata, data, ota, , 0x2000,\n\
+               app0, app, ota_0, , 0x140000,\napp1, app, ota_1, , 0x140000,\n";
+    let table = PartitionTable::from_csv(csv, 0x8000).unwrap();
+    let json = serde_json::to_string(&table).unwrap();
+    // the subtypes use the names of the esp-idf csv files
+    assert!(json.contains("\"nvs_keys\""));
+    assert!(json.contains("\"ota_0\""));
+    assert!(json.contains("\"ota_1\""));
+
+    let deserialized: PartitionTable = serde_json::from_str(&json).unwrap();
+    assert_eq!(table.to_bytes(), deserialized.to_bytes());
+}
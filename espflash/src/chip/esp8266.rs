@@ -2,36 +2,111 @@ use std::borrow::Cow;
 use std::io::Write;
 use std::iter::once;
 use std::mem::size_of;
+use std::ops::Range;
 
 use super::{ChipType, EspCommonHeader, SegmentHeader, ESP_MAGIC};
-use crate::chip::{Chip, SpiRegisters};
+use crate::chip::{Chip, ChipFeature, FlashVoltage, SpiRegisters};
+use crate::efuse::{EfuseBlocks, EfuseField};
 use crate::elf::{update_checksum, CodeSegment, FirmwareImage, RomSegment, ESP_CHECKSUM_MAGIC};
 use crate::flasher::FlashSize;
-use crate::Error;
+use crate::{Error, Flasher, PsramStatus};
 use bytemuck::bytes_of;
 
 pub const IROM_MAP_START: u32 = 0x40200000;
 const IROM_MAP_END: u32 = 0x40300000;
 
+const OTP_MAC0_ADDR: u32 = 0x3ff00050;
+const OTP_MAC1_ADDR: u32 = 0x3ff00054;
+const OTP_MAC3_ADDR: u32 = 0x3ff0005c;
+
 pub struct Esp8266;
 
 impl ChipType for Esp8266 {
     const DATE_REG1_VALUE: u32 = 0x00062000;
-    const DATE_REG2_VALUE: u32 = 0;
     const SPI_REGISTERS: SpiRegisters = SpiRegisters {
         base: 0x60000200,
         usr_offset: 0x1c,
+        clock_offset: 0x18,
+        pin_offset: 0x2c,
         usr1_offset: 0x20,
         usr2_offset: 0x24,
         w0_offset: 0x40,
         mosi_length_offset: None,
         miso_length_offset: None,
     };
+    const UART_CLKDIV_REG: u32 = 0x60000014;
+    const XTAL_CLK_DIVIDER: u32 = 2;
+    const EFUSES: EfuseBlocks = EfuseBlocks {
+        blocks: &[(OTP_MAC0_ADDR, 4)],
+        fields: EFUSE_FIELDS,
+    };
+    const REGISTER_RANGES: &'static [Range<u32>] = &[
+        // dport, with the otp, and the io registers
+        0x3ff00000..0x3ff00100,
+        0x60000000..0x60001000,
+    ];
+    const RAM_RANGES: &'static [Range<u32>] = &[
+        // dram, iram and the rtc memory
+        0x3ffe8000..0x40000000,
+        0x40100000..0x40108000,
+        0x60001000..0x60001400,
+    ];
 
     fn addr_is_flash(addr: u32) -> bool {
         (IROM_MAP_START..IROM_MAP_END).contains(&addr)
     }
 
+    fn chip_revision(_flasher: &mut Flasher) -> Result<Option<u32>, Error> {
+        Ok(None)
+    }
+
+    fn chip_features(flasher: &mut Flasher) -> Result<Vec<ChipFeature>, Error> {
+        let mut features = vec![ChipFeature::Wifi];
+
+        // the esp8285 is an esp8266 with embedded flash
+        let efuse0 = flasher.read_reg(OTP_MAC0_ADDR)?;
+        let efuse2 = flasher.read_reg(OTP_MAC0_ADDR + 8)?;
+        if efuse0 & (1 << 4) != 0 || efuse2 & (1 << 16) != 0 {
+            features.push(ChipFeature::EmbeddedFlash);
+        }
+
+        Ok(features)
+    }
+
+    fn mac_address(flasher: &mut Flasher) -> Result<[u8; 6], Error> {
+        let mac0 = flasher.read_reg(OTP_MAC0_ADDR)?.to_be_bytes();
+        let mac1 = flasher.read_reg(OTP_MAC1_ADDR)?.to_be_bytes();
+        let mac3 = flasher.read_reg(OTP_MAC3_ADDR)?.to_be_bytes();
+
+        let oui = if mac3 != [0; 4] {
+            [mac3[1], mac3[2], mac3[3]]
+        } else if mac1[1] == 0 {
+            [0x18, 0xfe, 0x34]
+        } else if mac1[1] == 1 {
+            [0xac, 0xd0, 0x74]
+        } else {
+            return Err(Error::UnrecognizedChip);
+        };
+
+        Ok([oui[0], oui[1], oui[2], mac1[2], mac1[3], mac0[0]])
+    }
+
+    fn flash_voltage(_flasher: &mut Flasher) -> Result<Option<FlashVoltage>, Error> {
+        // the flash is powered from the same 3.3V supply as the chip
+        Ok(None)
+    }
+
+    fn psram(_flasher: &mut Flasher, _probe_external: bool) -> Result<PsramStatus, Error> {
+        Ok(PsramStatus::Absent)
+    }
+
+    fn burn_efuses(_flasher: &mut Flasher, _block: usize, _words: &[u32]) -> Result<(), Error> {
+        Err(Error::UnsupportedOperation {
+            operation: "burning efuses",
+            chip: Chip::Esp8266,
+        })
+    }
+
     fn get_flash_segments<'a>(
         image: &'a FirmwareImage,
     ) -> Box<dyn Iterator<Item = Result<RomSegment<'a>, Error>> + 'a> {
@@ -94,6 +169,63 @@ impl ChipType for Esp8266 {
     }
 }
 
+const EFUSE_FIELDS: &[EfuseField] = &[
+    EfuseField {
+        name: "EMBEDDED_FLASH",
+        block: 0,
+        bit: 4,
+        bits: 1,
+        description: "Flash is embedded in the package, on the esp8285",
+        write_disable: None,
+        read_disable: None,
+    },
+    EfuseField {
+        name: "MAC_NIC_LOW",
+        block: 0,
+        bit: 24,
+        bits: 8,
+        description: "Last byte of the MAC address",
+        write_disable: None,
+        read_disable: None,
+    },
+    EfuseField {
+        name: "MAC_NIC_HIGH",
+        block: 0,
+        bit: 32,
+        bits: 16,
+        description: "Fourth and fifth byte of the MAC address",
+        write_disable: None,
+        read_disable: None,
+    },
+    EfuseField {
+        name: "MAC_OUI_SELECT",
+        block: 0,
+        bit: 48,
+        bits: 8,
+        description: "Selects the Espressif OUI of the MAC address when no custom OUI is set",
+        write_disable: None,
+        read_disable: None,
+    },
+    EfuseField {
+        name: "EMBEDDED_FLASH_2",
+        block: 0,
+        bit: 80,
+        bits: 1,
+        description: "Flash is embedded in the package, on later esp8285 revisions",
+        write_disable: None,
+        read_disable: None,
+    },
+    EfuseField {
+        name: "CUSTOM_OUI",
+        block: 0,
+        bit: 96,
+        bits: 24,
+        description: "Custom OUI of the MAC address",
+        write_disable: None,
+        read_disable: None,
+    },
+];
+
 fn encode_flash_size(size: FlashSize) -> Result<u8, Error> {
     match size {
         FlashSize::Flash256Kb => Ok(0x10),
@@ -1,8 +1,12 @@
+use crate::efuse::EfuseBlocks;
 use crate::elf::{FirmwareImage, RomSegment};
-use crate::Error;
+use crate::{Error, Flasher, PsramStatus};
 use bytemuck::{Pod, Zeroable};
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
 use std::str::FromStr;
 
+pub use esp32::partition_table::PartitionTable;
 pub use esp32::Esp32;
 pub use esp8266::Esp8266;
 
@@ -13,8 +17,14 @@ const ESP_MAGIC: u8 = 0xe9;
 
 pub trait ChipType {
     const DATE_REG1_VALUE: u32;
-    const DATE_REG2_VALUE: u32;
     const SPI_REGISTERS: SpiRegisters;
+    const UART_CLKDIV_REG: u32;
+    const XTAL_CLK_DIVIDER: u32;
+    const EFUSES: EfuseBlocks;
+    /// The address ranges of the peripheral registers
+    const REGISTER_RANGES: &'static [Range<u32>];
+    /// The address ranges of the internal ram, which the bootloader can read and write a word at a time
+    const RAM_RANGES: &'static [Range<u32>];
 
     /// Get the firmware segments for writing an image to flash
     fn get_flash_segments<'a>(
@@ -22,11 +32,36 @@ pub trait ChipType {
     ) -> Box<dyn Iterator<Item = Result<RomSegment<'a>, Error>> + 'a>;
 
     fn addr_is_flash(addr: u32) -> bool;
+
+    /// Read the revision of the connected chip, if the chip type has multiple revisions
+    fn chip_revision(flasher: &mut Flasher) -> Result<Option<u32>, Error>;
+
+    /// Get the list of features supported by the connected chip
+    fn chip_features(flasher: &mut Flasher) -> Result<Vec<ChipFeature>, Error>;
+
+    /// Read the factory programmed MAC address of the connected chip
+    fn mac_address(flasher: &mut Flasher) -> Result<[u8; 6], Error>;
+
+    /// The workarounds for bugs of a revision of the chip, or of the oldest revision when it's unknown
+    fn workarounds(_revision: Option<u32>) -> Workarounds {
+        Workarounds::default()
+    }
+
+    /// Read the voltage the chip supplies the flash with, `None` when the flash isn't powered by the chip
+    fn flash_voltage(flasher: &mut Flasher) -> Result<Option<FlashVoltage>, Error>;
+
+    /// Probe for psram embedded in the package of the connected chip, or on the module when `probe_external` is set
+    fn psram(flasher: &mut Flasher, probe_external: bool) -> Result<PsramStatus, Error>;
+
+    /// Program the bits set in `words` into an efuse block
+    fn burn_efuses(flasher: &mut Flasher, block: usize, words: &[u32]) -> Result<(), Error>;
 }
 
 pub struct SpiRegisters {
     base: u32,
     usr_offset: u32,
+    clock_offset: u32,
+    pin_offset: u32,
     usr1_offset: u32,
     usr2_offset: u32,
     w0_offset: u32,
@@ -39,6 +74,14 @@ impl SpiRegisters {
         self.base
     }
 
+    pub fn clock(&self) -> u32 {
+        self.base + self.clock_offset
+    }
+
+    pub fn pin(&self) -> u32 {
+        self.base + self.pin_offset
+    }
+
     pub fn usr(&self) -> u32 {
         self.base + self.usr_offset
     }
@@ -64,17 +107,94 @@ impl SpiRegisters {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Workarounds for bugs of specific chip revisions
+///
+/// These are only applied to the revisions that need them, see [`Flasher::workarounds`].
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct Workarounds {
+    /// The rom loader doesn't work reliably above 115200 baud, so the baud rate isn't raised when connecting
+    pub unreliable_high_baud: bool,
+}
+
+/// The voltage of the flash supply
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum FlashVoltage {
+    #[cfg_attr(feature = "serialize", serde(rename = "1.8V"))]
+    V1_8,
+    #[cfg_attr(feature = "serialize", serde(rename = "3.3V"))]
+    V3_3,
+}
+
+impl Display for FlashVoltage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FlashVoltage::V1_8 => f.write_str("1.8V"),
+            FlashVoltage::V3_3 => f.write_str("3.3V"),
+        }
+    }
+}
+
+/// A feature of the connected chip, read from the efuses
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChipFeature {
+    #[cfg_attr(feature = "serialize", serde(rename = "WiFi"))]
+    Wifi,
+    #[cfg_attr(feature = "serialize", serde(rename = "BT"))]
+    Bluetooth,
+    #[cfg_attr(feature = "serialize", serde(rename = "Single Core"))]
+    SingleCore,
+    #[cfg_attr(feature = "serialize", serde(rename = "Dual Core"))]
+    DualCore,
+    #[cfg_attr(feature = "serialize", serde(rename = "160MHz"))]
+    Cpu160Mhz,
+    #[cfg_attr(feature = "serialize", serde(rename = "240MHz"))]
+    Cpu240Mhz,
+    #[cfg_attr(feature = "serialize", serde(rename = "Embedded Flash"))]
+    EmbeddedFlash,
+    #[cfg_attr(feature = "serialize", serde(rename = "Embedded PSRAM"))]
+    EmbeddedPsram,
+}
+
+impl Display for ChipFeature {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChipFeature::Wifi => "WiFi",
+            ChipFeature::Bluetooth => "BT",
+            ChipFeature::SingleCore => "Single Core",
+            ChipFeature::DualCore => "Dual Core",
+            ChipFeature::Cpu160Mhz => "160MHz",
+            ChipFeature::Cpu240Mhz => "240MHz",
+            ChipFeature::EmbeddedFlash => "Embedded Flash",
+            ChipFeature::EmbeddedPsram => "Embedded PSRAM",
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "lowercase"))]
 pub enum Chip {
     Esp8266,
     Esp32,
 }
 
 impl Chip {
-    pub fn from_regs(value1: u32, value2: u32) -> Option<Self> {
-        match (value1, value2) {
-            (Esp8266::DATE_REG1_VALUE, _) => Some(Chip::Esp8266),
-            (Esp32::DATE_REG1_VALUE, _) => Some(Chip::Esp32),
+    /// The chip for the value of the uart date register, which differs between the chips
+    pub fn from_regs(date: u32) -> Option<Self> {
+        match date {
+            Esp8266::DATE_REG1_VALUE => Some(Chip::Esp8266),
+            Esp32::DATE_REG1_VALUE => Some(Chip::Esp32),
+            _ => None,
+        }
+    }
+
+    /// The chip for the id used in the extended header of images
+    pub fn from_chip_id(id: u32) -> Option<Self> {
+        match id {
+            0 => Some(Chip::Esp32),
             _ => None,
         }
     }
@@ -96,6 +216,36 @@ impl Chip {
         }
     }
 
+    /// Whether `addr` is a peripheral register
+    pub fn addr_is_register(&self, addr: u32) -> bool {
+        let ranges = match self {
+            Chip::Esp8266 => Esp8266::REGISTER_RANGES,
+            Chip::Esp32 => Esp32::REGISTER_RANGES,
+        };
+        ranges.iter().any(|range| range.contains(&addr))
+    }
+
+    /// Whether `addr` is in the internal ram
+    pub fn addr_is_ram(&self, addr: u32) -> bool {
+        let ranges = match self {
+            Chip::Esp8266 => Esp8266::RAM_RANGES,
+            Chip::Esp32 => Esp32::RAM_RANGES,
+        };
+        ranges.iter().any(|range| range.contains(&addr))
+    }
+
+    /// Check that `addr` can be accessed with [`Flasher::read_reg`] and [`Flasher::write_reg`]
+    ///
+    /// Accessing an address outside of the registers and the ram can hang the bootloader until the chip is reset,
+    /// and the bootloader only accesses whole words.
+    pub fn check_reg_addr(&self, addr: u32) -> Result<(), Error> {
+        if addr.is_multiple_of(4) && (self.addr_is_register(addr) || self.addr_is_ram(addr)) {
+            Ok(())
+        } else {
+            Err(Error::InvalidAddress { addr, chip: *self })
+        }
+    }
+
     pub fn spi_registers(&self) -> SpiRegisters {
         match self {
             Chip::Esp8266 => Esp8266::SPI_REGISTERS,
@@ -103,6 +253,122 @@ impl Chip {
         }
     }
 
+    /// The efuse blocks and the fields in them
+    pub fn efuses(&self) -> EfuseBlocks {
+        match self {
+            Chip::Esp8266 => Esp8266::EFUSES,
+            Chip::Esp32 => Esp32::EFUSES,
+        }
+    }
+
+    pub fn chip_revision(&self, flasher: &mut Flasher) -> Result<Option<u32>, Error> {
+        match self {
+            Chip::Esp8266 => Esp8266::chip_revision(flasher),
+            Chip::Esp32 => Esp32::chip_revision(flasher),
+        }
+    }
+
+    pub fn chip_features(&self, flasher: &mut Flasher) -> Result<Vec<ChipFeature>, Error> {
+        match self {
+            Chip::Esp8266 => Esp8266::chip_features(flasher),
+            Chip::Esp32 => Esp32::chip_features(flasher),
+        }
+    }
+
+    pub fn mac_address(&self, flasher: &mut Flasher) -> Result<[u8; 6], Error> {
+        match self {
+            Chip::Esp8266 => Esp8266::mac_address(flasher),
+            Chip::Esp32 => Esp32::mac_address(flasher),
+        }
+    }
+
+    pub fn workarounds(&self, revision: Option<u32>) -> Workarounds {
+        match self {
+            Chip::Esp8266 => Esp8266::workarounds(revision),
+            Chip::Esp32 => Esp32::workarounds(revision),
+        }
+    }
+
+    pub fn flash_voltage(&self, flasher: &mut Flasher) -> Result<Option<FlashVoltage>, Error> {
+        match self {
+            Chip::Esp8266 => Esp8266::flash_voltage(flasher),
+            Chip::Esp32 => Esp32::flash_voltage(flasher),
+        }
+    }
+
+    pub fn psram(&self, flasher: &mut Flasher, probe_external: bool) -> Result<PsramStatus, Error> {
+        match self {
+            Chip::Esp8266 => Esp8266::psram(flasher, probe_external),
+            Chip::Esp32 => Esp32::psram(flasher, probe_external),
+        }
+    }
+
+    pub fn burn_efuses(
+        &self,
+        flasher: &mut Flasher,
+        block: usize,
+        words: &[u32],
+    ) -> Result<(), Error> {
+        match self {
+            Chip::Esp8266 => Esp8266::burn_efuses(flasher, block, words),
+            Chip::Esp32 => Esp32::burn_efuses(flasher, block, words),
+        }
+    }
+
+    /// Estimate the crystal frequency in MHz from the uart clock divider used by the bootloader
+    pub fn crystal_frequency(&self, flasher: &mut Flasher) -> Result<u32, Error> {
+        let (clkdiv_reg, divider) = match self {
+            Chip::Esp8266 => (Esp8266::UART_CLKDIV_REG, Esp8266::XTAL_CLK_DIVIDER),
+            Chip::Esp32 => (Esp32::UART_CLKDIV_REG, Esp32::XTAL_CLK_DIVIDER),
+        };
+        let uart_div = flasher.read_reg(clkdiv_reg)? & 0xfffff;
+        let estimate = flasher.baud().speed() as u64 * uart_div as u64 / 1_000_000 / divider as u64;
+
+        // the estimate is rough, snap it to the closest supported frequency
+        if estimate > 33 {
+            Ok(40)
+        } else {
+            Ok(26)
+        }
+    }
+
+    /// Build the application image from an elf image, as written by an over-the-air update
+    ///
+    /// Unlike flashing over serial this doesn't include the bootloader or partition table.
+    pub fn app_image(&self, elf_data: &[u8]) -> Result<Vec<u8>, Error> {
+        let app_start = match self {
+            Chip::Esp8266 => 0,
+            Chip::Esp32 => 0x10000,
+        };
+
+        let image = FirmwareImage::from_data(elf_data).map_err(|_| Error::InvalidElf)?;
+        let mut data = Vec::new();
+        for segment in self.get_flash_segments(&image) {
+            let segment = segment?;
+            if segment.addr < app_start {
+                continue;
+            }
+            let offset = (segment.addr - app_start) as usize;
+            if data.len() < offset {
+                data.resize(offset, 0xff);
+            }
+            data.truncate(offset);
+            data.extend_from_slice(&segment.data);
+        }
+
+        Ok(data)
+    }
+
+    /// The flash region containing the bootloader and partition table
+    ///
+    /// Overwriting this region with anything but a valid bootloader leaves the device unable to boot.
+    pub fn boot_region(&self) -> Range<u32> {
+        match self {
+            Chip::Esp8266 => 0..0x1000,
+            Chip::Esp32 => 0..0x9000,
+        }
+    }
+
     /// Get the target triplet for the chip
     pub fn target(&self) -> &'static str {
         match self {
@@ -140,3 +406,18 @@ struct SegmentHeader {
     addr: u32,
     length: u32,
 }
+
+#[test]
+fn test_check_reg_addr() {
+    // the uart date register, which the chip detection reads on both chips
+    assert!(Chip::Esp32.check_reg_addr(0x60000078).is_ok());
+    assert!(Chip::Esp8266.check_reg_addr(0x60000078).is_ok());
+    assert!(Chip::Esp32.addr_is_register(0x3ff5a000));
+    assert!(!Chip::Esp8266.addr_is_register(0x3ff5a000));
+    assert!(Chip::Esp32.addr_is_ram(0x3ffb0000));
+    assert!(Chip::Esp8266.addr_is_ram(0x3fffc000));
+
+    assert!(Chip::Esp32.check_reg_addr(0x3ff5a002).is_err());
+    assert!(Chip::Esp32.check_reg_addr(0x400d0000).is_err());
+    assert!(Chip::Esp8266.check_reg_addr(0).is_err());
+}
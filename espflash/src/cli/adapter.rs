@@ -0,0 +1,392 @@
+use std::time::Duration;
+
+use serial::BaudRate;
+
+use crate::config::Connection;
+use crate::Flasher;
+
+/// Known problems of a usb serial adapter, and the settings that work around them
+#[derive(Debug)]
+pub struct AdapterQuirks {
+    pub name: &'static str,
+    pub vid: u16,
+    pub pid: u16,
+    /// The highest baud rate the adapter handles reliably
+    pub max_baud: Option<usize>,
+    /// Pause for the delay in microseconds after every chunk of this many bytes, see [`Flasher::set_write_pacing`]
+    pub pacing: Option<(usize, u64)>,
+    /// The driver batches received data for the latency timer, which slows down every command, a warning is printed
+    /// when it's set higher than 1ms
+    pub latency_timer: bool,
+}
+
+const QUIRKS: &[AdapterQuirks] = &[
+    AdapterQuirks {
+        name: "CH340",
+        vid: 0x1a86,
+        pid: 0x7523,
+        max_baud: Some(460_800),
+        pacing: Some((256, 1000)),
+        latency_timer: false,
+    },
+    AdapterQuirks {
+        name: "CP2102",
+        vid: 0x10c4,
+        pid: 0xea60,
+        max_baud: Some(921_600),
+        pacing: None,
+        latency_timer: false,
+    },
+    AdapterQuirks {
+        name: "FT232R",
+        vid: 0x0403,
+        pid: 0x6001,
+        max_baud: None,
+        pacing: None,
+        latency_timer: true,
+    },
+    AdapterQuirks {
+        name: "FT2232",
+        vid: 0x0403,
+        pid: 0x6010,
+        max_baud: None,
+        pacing: None,
+        latency_timer: true,
+    },
+    AdapterQuirks {
+        name: "FT232H",
+        vid: 0x0403,
+        pid: 0x6014,
+        max_baud: None,
+        pacing: None,
+        latency_timer: true,
+    },
+];
+
+impl AdapterQuirks {
+    /// The quirks of the adapter behind `port`, unless disabled in the config
+    ///
+    /// The adapter is recognized on linux, where its usb ids are available without opening the device, and FTDI
+    /// adapters on windows, from the registry entries of the FTDI driver.
+    pub fn for_port(port: &str, config: &Connection) -> Option<&'static AdapterQuirks> {
+        if config.no_adapter_quirks {
+            return None;
+        }
+        let (vid, pid) = usb_id(port)?;
+        Self::lookup(vid, pid)
+    }
+
+    /// The quirks of the adapter with the usb vendor and product id
+    pub fn lookup(vid: u16, pid: u16) -> Option<&'static AdapterQuirks> {
+        QUIRKS
+            .iter()
+            .find(|quirks| quirks.vid == vid && quirks.pid == pid)
+    }
+
+    /// Lower a requested baud rate to the highest one the adapter handles
+    pub fn limit_baud(&self, speed: BaudRate) -> BaudRate {
+        match self.max_baud {
+            Some(max) if speed.speed() > max => {
+                eprintln!(
+                    "The {} adapter is unreliable above {} baud, using {} baud",
+                    self.name, max, max
+                );
+                BaudRate::from_speed(max)
+            }
+            _ => speed,
+        }
+    }
+
+    /// Apply the settings for the adapter, settings from the config file take precedence
+    pub fn apply(&self, flasher: &mut Flasher, port: &str, config: &Connection) {
+        if let (Some((chunk_size, delay_us)), None) = (self.pacing, config.pacing_chunk_size) {
+            eprintln!(
+                "Pacing writes for the {} adapter, which drops data at high sustained rates",
+                self.name
+            );
+            flasher.set_write_pacing(chunk_size, Duration::from_micros(delay_us));
+        }
+        if self.latency_timer {
+            check_latency_timer(self.name, port);
+        }
+    }
+}
+
+/// The usb vendor and product id of the adapter behind a serial port
+#[cfg(target_os = "linux")]
+pub(super) fn usb_id(port: &str) -> Option<(u16, u16)> {
+    use std::fs::{canonicalize, read_to_string};
+
+    // ports are often given trough the /dev/serial/by-id links
+    let port = canonicalize(port).ok()?;
+    let name = port.file_name()?.to_str()?;
+    let mut device = canonicalize(format!("/sys/class/tty/{}/device", name)).ok()?;
+    // the ids are on the usb device, a few levels above the tty
+    loop {
+        if let (Ok(vid), Ok(pid)) = (
+            read_to_string(device.join("idVendor")),
+            read_to_string(device.join("idProduct")),
+        ) {
+            let vid = u16::from_str_radix(vid.trim(), 16).ok()?;
+            let pid = u16::from_str_radix(pid.trim(), 16).ok()?;
+            return Some((vid, pid));
+        }
+        if !device.pop() {
+            return None;
+        }
+    }
+}
+
+/// The usb vendor and product id of the FTDI adapter behind a COM port
+#[cfg(windows)]
+pub(super) fn usb_id(port: &str) -> Option<(u16, u16)> {
+    ftdi_usb_id(&ftdi_device(port)?)
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub(super) fn usb_id(_port: &str) -> Option<(u16, u16)> {
+    None
+}
+
+/// Explain how to lower the latency timer of an FTDI adapter to 1ms, when it's set higher
+///
+/// The timer is a setting of the system, so it's only changed by the user.
+#[cfg(target_os = "linux")]
+fn check_latency_timer(name: &str, port: &str) {
+    use std::fs::{canonicalize, read_to_string};
+
+    let tty = match canonicalize(port)
+        .ok()
+        .and_then(|port| Some(port.file_name()?.to_str()?.to_string()))
+    {
+        Some(tty) => tty,
+        None => return,
+    };
+    let path = format!("/sys/bus/usb-serial/devices/{}/latency_timer", tty);
+    let latency: u32 = match read_to_string(&path).map(|latency| latency.trim().parse()) {
+        Ok(Ok(latency)) => latency,
+        _ => return,
+    };
+    let fix = format!("with `echo 1 | sudo tee {}`", path);
+    if let Some(warning) = latency_warning(name, latency, &fix) {
+        eprintln!("{}", warning);
+    }
+}
+
+/// Explain how to lower the latency timer of an FTDI adapter to 1ms, when it's set higher
+///
+/// The FTDI driver keeps the timer in the registry, the default of 16ms is changed in the device manager.
+#[cfg(windows)]
+fn check_latency_timer(name: &str, port: &str) {
+    let latency = match ftdi_device(port)
+        .and_then(|device| registry::dword(&ftdi_parameters(&device), "LatencyTimer"))
+    {
+        Some(latency) => latency,
+        None => return,
+    };
+    let fix = "to 1ms under Port Settings, Advanced in the device manager";
+    if let Some(warning) = latency_warning(name, latency, fix) {
+        eprintln!("{}", warning);
+    }
+}
+
+#[cfg(any(target_os = "linux", windows))]
+fn latency_warning(name: &str, latency: u32, fix: &str) -> Option<String> {
+    (latency > 1).then(|| {
+        format!(
+            "The latency timer of the {} adapter is {}ms, which slows down flashing, lower it {}",
+            name, latency, fix
+        )
+    })
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn check_latency_timer(_name: &str, _port: &str) {}
+
+/// The registry key of the FTDI driver, with a key per adapter named after its usb ids and serial number
+#[cfg(windows)]
+const FTDIBUS: &str = r"SYSTEM\CurrentControlSet\Enum\FTDIBUS";
+
+/// The registry key of the FTDI adapter that is assigned to a COM port
+#[cfg(windows)]
+fn ftdi_device(port: &str) -> Option<String> {
+    let port = port.strip_prefix(r"\\.\").unwrap_or(port);
+    registry::subkeys(FTDIBUS).into_iter().find(|device| {
+        registry::string(&ftdi_parameters(device), "PortName")
+            .map_or(false, |name| name.eq_ignore_ascii_case(port))
+    })
+}
+
+#[cfg(windows)]
+fn ftdi_parameters(device: &str) -> String {
+    format!(r"{}\{}\0000\Device Parameters", FTDIBUS, device)
+}
+
+/// The usb ids from the registry key of an FTDI adapter, like `VID_0403+PID_6001+A50285BIA`
+#[cfg(any(windows, test))]
+fn ftdi_usb_id(device: &str) -> Option<(u16, u16)> {
+    let mut parts = device.split('+');
+    let vid = parts.next()?.strip_prefix("VID_")?;
+    let pid = parts.next()?.strip_prefix("PID_")?;
+    Some((
+        u16::from_str_radix(vid, 16).ok()?,
+        u16::from_str_radix(pid, 16).ok()?,
+    ))
+}
+
+/// Reading values from `HKEY_LOCAL_MACHINE`
+#[cfg(windows)]
+mod registry {
+    use std::iter::once;
+    use std::ptr::null_mut;
+
+    type Hkey = isize;
+
+    const HKEY_LOCAL_MACHINE: Hkey = 0x8000_0002u32 as i32 as Hkey;
+    const KEY_READ: u32 = 0x20019;
+    const RRF_RT_REG_SZ: u32 = 0x2;
+    const RRF_RT_REG_DWORD: u32 = 0x10;
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        fn RegOpenKeyExW(
+            key: Hkey,
+            sub_key: *const u16,
+            options: u32,
+            access: u32,
+            result: *mut Hkey,
+        ) -> i32;
+        fn RegEnumKeyExW(
+            key: Hkey,
+            index: u32,
+            name: *mut u16,
+            name_len: *mut u32,
+            reserved: *mut u32,
+            class: *mut u16,
+            class_len: *mut u32,
+            last_write: *mut u64,
+        ) -> i32;
+        fn RegCloseKey(key: Hkey) -> i32;
+        fn RegGetValueW(
+            key: Hkey,
+            sub_key: *const u16,
+            value: *const u16,
+            flags: u32,
+            kind: *mut u32,
+            data: *mut u8,
+            len: *mut u32,
+        ) -> i32;
+    }
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(once(0)).collect()
+    }
+
+    /// The names of the keys below `path`
+    pub fn subkeys(path: &str) -> Vec<String> {
+        let mut key = 0;
+        let status = unsafe {
+            RegOpenKeyExW(
+                HKEY_LOCAL_MACHINE,
+                wide(path).as_ptr(),
+                0,
+                KEY_READ,
+                &mut key,
+            )
+        };
+        if status != 0 {
+            return Vec::new();
+        }
+        let mut names = Vec::new();
+        let mut name = [0u16; 256];
+        loop {
+            let mut len = name.len() as u32;
+            let status = unsafe {
+                RegEnumKeyExW(
+                    key,
+                    names.len() as u32,
+                    name.as_mut_ptr(),
+                    &mut len,
+                    null_mut(),
+                    null_mut(),
+                    null_mut(),
+                    null_mut(),
+                )
+            };
+            if status != 0 {
+                break;
+            }
+            names.push(String::from_utf16_lossy(&name[..len as usize]));
+        }
+        unsafe { RegCloseKey(key) };
+        names
+    }
+
+    pub fn string(path: &str, value: &str) -> Option<String> {
+        let mut data = [0u16; 256];
+        // the length is in bytes, including the terminating nul
+        let mut len = (data.len() * 2) as u32;
+        let status = unsafe {
+            RegGetValueW(
+                HKEY_LOCAL_MACHINE,
+                wide(path).as_ptr(),
+                wide(value).as_ptr(),
+                RRF_RT_REG_SZ,
+                null_mut(),
+                data.as_mut_ptr() as *mut u8,
+                &mut len,
+            )
+        };
+        let chars = (len as usize / 2).saturating_sub(1);
+        (status == 0).then(|| String::from_utf16_lossy(&data[..chars]))
+    }
+
+    pub fn dword(path: &str, value: &str) -> Option<u32> {
+        let mut data = 0u32;
+        let mut len = 4;
+        let status = unsafe {
+            RegGetValueW(
+                HKEY_LOCAL_MACHINE,
+                wide(path).as_ptr(),
+                wide(value).as_ptr(),
+                RRF_RT_REG_DWORD,
+                null_mut(),
+                &mut data as *mut u32 as *mut u8,
+                &mut len,
+            )
+        };
+        (status == 0).then_some(data)
+    }
+}
+
+#[test]
+fn test_adapter_quirks() {
+    let ch340 = AdapterQuirks::lookup(0x1a86, 0x7523).unwrap();
+    assert_eq!("CH340", ch340.name);
+    assert_eq!(Some((256, 1000)), ch340.pacing);
+    assert!(AdapterQuirks::lookup(0x0403, 0x6014).unwrap().latency_timer);
+    assert!(AdapterQuirks::lookup(0x303a, 0x1001).is_none());
+
+    assert_eq!(
+        460_800,
+        ch340.limit_baud(BaudRate::BaudOther(921_600)).speed()
+    );
+    assert_eq!(BaudRate::Baud115200, ch340.limit_baud(BaudRate::Baud115200));
+    let ft232r = AdapterQuirks::lookup(0x0403, 0x6001).unwrap();
+    assert_eq!(
+        3_000_000,
+        ft232r.limit_baud(BaudRate::BaudOther(3_000_000)).speed()
+    );
+
+    #[cfg(any(target_os = "linux", windows))]
+    {
+        assert!(latency_warning("FT232R", 1, "").is_none());
+        assert!(latency_warning("FT232R", 16, "").unwrap().contains("16ms"));
+    }
+
+    assert_eq!(
+        Some((0x0403, 0x6010)),
+        ftdi_usb_id("VID_0403+PID_6010+FT4ZQ8A")
+    );
+    assert_eq!(None, ftdi_usb_id("VID_0403&PID_6001"));
+}
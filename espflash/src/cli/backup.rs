@@ -0,0 +1,99 @@
+use std::fs::{read, read_to_string, write};
+use std::path::{Path, PathBuf};
+
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Report,
+};
+use serde::{Deserialize, Serialize};
+
+use super::confirm;
+use crate::Flasher;
+
+/// Metadata stored next to a flash backup, in `<backup>.toml`
+#[derive(Debug, Deserialize, Serialize)]
+struct BackupInfo {
+    chip: String,
+    flash_size: String,
+    mac_address: String,
+    size: u32,
+    md5: String,
+}
+
+/// Read the entire flash into `path`, with the metadata in `<path>.toml`
+pub fn backup(flasher: &mut Flasher, path: &Path) -> Result<(), Report> {
+    let board = flasher.board_info()?;
+    let size = flasher.flash_size_in_bytes()?;
+    if !flasher.stub_active() {
+        eprintln!("Reading the flash without the flasher stub, this will take a long time");
+    }
+
+    let data = flasher.read_flash(0, size)?;
+
+    let info = BackupInfo {
+        chip: format!("{:?}", board.chip),
+        flash_size: board.flash_size.to_string(),
+        mac_address: board
+            .mac_address
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<Vec<_>>()
+            .join(":"),
+        size,
+        md5: format!("{:x}", md5::compute(&data)),
+    };
+
+    write(path, &data).wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+    let info_path = info_path(path);
+    write(&info_path, toml::to_string(&info)?)
+        .wrap_err_with(|| format!("Failed to write {}", info_path.display()))?;
+
+    eprintln!("Saved {} bytes of flash to {}", data.len(), path.display());
+    Ok(())
+}
+
+/// Write a backup made by [`backup`] back to the flash
+///
+/// When the metadata is available, the backup is checked against it and the connected chip first.
+pub fn restore(flasher: &mut Flasher, path: &Path, force: bool) -> Result<(), Report> {
+    let data = read(path).wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+
+    let info_path = info_path(path);
+    match read_to_string(&info_path) {
+        Ok(info) => {
+            let info: BackupInfo = toml::from_str(&info)
+                .wrap_err_with(|| format!("Failed to parse {}", info_path.display()))?;
+            if info.md5 != format!("{:x}", md5::compute(&data)) || info.size as usize != data.len()
+            {
+                bail!("The backup doesn't match its metadata, it might be corrupted");
+            }
+            if info.chip != format!("{:?}", flasher.chip()) {
+                bail!(
+                    "The backup was made from an {}, but an {:?} is connected",
+                    info.chip,
+                    flasher.chip()
+                );
+            }
+        }
+        Err(_) => eprintln!("No metadata found for the backup, skipping checks"),
+    }
+
+    if data.len() > flasher.flash_size_in_bytes()? as usize {
+        bail!(
+            "The backup ({} bytes) is larger than the flash ({})",
+            data.len(),
+            flasher.flash_size()
+        );
+    }
+
+    confirm("overwrite the entire flash with the backup", force)?;
+    flasher.write_bin_to_flash(0, &data)?;
+
+    Ok(())
+}
+
+fn info_path(path: &Path) -> PathBuf {
+    let mut info = path.as_os_str().to_owned();
+    info.push(".toml");
+    info.into()
+}
@@ -0,0 +1,212 @@
+use std::fs::write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use xmas_elf::program::Type;
+use xmas_elf::ElfFile;
+
+use crate::elf::Symbols;
+
+const START_MARKER: &str = "================= CORE DUMP START =================";
+const END_MARKER: &str = "================= CORE DUMP END =================";
+
+/// Size of the header esp-idf puts in front of the core dump
+const HEADER_LENGTH: usize = 20;
+// core dump versions in the low byte of the version field, and the size of their checksum
+const VERSION_ELF_CRC32: u32 = 2;
+const VERSION_ELF_SHA256: u32 = 3;
+
+const NT_PRSTATUS: u32 = 1;
+/// Offset of the registers in the prstatus note written by esp-idf, the pc is the first register
+const PRSTATUS_REGISTERS_OFFSET: usize = 72;
+
+/// Finds esp-idf core dumps printed to the uart and saves them as elf core files
+pub struct CoreDumpDecoder {
+    dir: PathBuf,
+    dump: Option<String>,
+}
+
+impl CoreDumpDecoder {
+    /// Save the core dumps in `dir`
+    pub fn new(dir: PathBuf) -> Self {
+        CoreDumpDecoder { dir, dump: None }
+    }
+
+    /// Process a line of output, returns the file the core dump was saved to when the line completed one
+    pub fn line(&mut self, line: &str, symbols: &Symbols) -> Option<PathBuf> {
+        let line = line.trim();
+        match &mut self.dump {
+            None if line == START_MARKER => self.dump = Some(String::new()),
+            None => {}
+            Some(dump) if line == END_MARKER => {
+                let dump = std::mem::take(dump);
+                self.dump = None;
+                return self.save(&dump, symbols);
+            }
+            Some(dump) => dump.push_str(line),
+        }
+        None
+    }
+
+    fn save(&self, encoded: &str, symbols: &Symbols) -> Option<PathBuf> {
+        let data = match STANDARD.decode(encoded) {
+            Ok(data) if data.len() > HEADER_LENGTH => data,
+            _ => {
+                eprintln!("\nReceived a core dump that isn't valid base64");
+                return None;
+            }
+        };
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default();
+        let (name, content) = match elf_core(&data) {
+            Some(core) => (format!("core-{}.elf", time), core),
+            // older esp-idf versions use their own binary format
+            None => (format!("core-{}.bin", time), &data[..]),
+        };
+        let path = self.dir.join(name);
+
+        if let Err(e) = write(&path, content) {
+            eprintln!(
+                "\nFailed to save the core dump to {}: {}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+        eprintln!("\nSaved the core dump to {}", path.display());
+        match crashed_pc(content).map(|pc| (pc, symbols.lookup(pc))) {
+            Some((pc, Some((name, offset)))) => eprintln!(
+                "The crashed task was at pc {:#010x} ({}+{:#x})",
+                pc, name, offset
+            ),
+            Some((pc, None)) => eprintln!("The crashed task was at pc {:#010x}", pc),
+            None => {}
+        }
+        Some(path)
+    }
+}
+
+/// Extract the elf core file from a core dump in the elf format
+fn elf_core(data: &[u8]) -> Option<&[u8]> {
+    let word =
+        |pos: usize| u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]);
+    let total_length = (word(0) as usize).min(data.len());
+    let checksum_length = match word(4) & 0xff {
+        VERSION_ELF_CRC32 => 4,
+        VERSION_ELF_SHA256 => 32,
+        _ => return None,
+    };
+    let core = data.get(HEADER_LENGTH..total_length.checked_sub(checksum_length)?)?;
+    core.starts_with(b"\x7fELF").then_some(core)
+}
+
+/// Read the pc of the crashed task, which is the first task in the core dump
+fn crashed_pc(core: &[u8]) -> Option<u32> {
+    let elf = ElfFile::new(core).ok()?;
+    let notes = elf
+        .program_iter()
+        .filter(|header| header.get_type() == Ok(Type::Note))
+        .filter_map(|header| {
+            core.get(header.offset() as usize..(header.offset() + header.file_size()) as usize)
+        });
+    for mut notes in notes {
+        while notes.len() >= 12 {
+            let word = |pos: usize| {
+                u32::from_le_bytes([notes[pos], notes[pos + 1], notes[pos + 2], notes[pos + 3]])
+            };
+            let (name_size, desc_size, kind) = (word(0) as usize, word(4) as usize, word(8));
+            let desc_start = 12 + align4(name_size);
+            let desc = notes.get(desc_start..desc_start + desc_size)?;
+            if kind == NT_PRSTATUS {
+                let pc = desc.get(PRSTATUS_REGISTERS_OFFSET..PRSTATUS_REGISTERS_OFFSET + 4)?;
+                return Some(u32::from_le_bytes([pc[0], pc[1], pc[2], pc[3]]));
+            }
+            notes = notes.get(desc_start + align4(desc_size)..)?;
+        }
+    }
+    None
+}
+
+fn align4(size: usize) -> usize {
+    (size + 3) & !3
+}
+
+/// A core file with a prstatus note for a task at `pc`, in the format esp-idf writes
+#[cfg(test)]
+fn test_core(pc: u32) -> Vec<u8> {
+    let mut desc = vec![0; PRSTATUS_REGISTERS_OFFSET];
+    desc.extend_from_slice(&pc.to_le_bytes());
+    let mut note = Vec::new();
+    for word in [5, desc.len() as u32, NT_PRSTATUS] {
+        note.extend_from_slice(&word.to_le_bytes());
+    }
+    note.extend_from_slice(b"CORE\0\0\0\0");
+    note.extend_from_slice(&desc);
+
+    let mut core = b"\x7fELF\x01\x01\x01".to_vec();
+    core.resize(16, 0);
+    // a 32 bit xtensa core file with one program header, for the notes after it
+    for half in [4u16, 94] {
+        core.extend_from_slice(&half.to_le_bytes());
+    }
+    for word in [1u32, 0, 52, 0, 0] {
+        core.extend_from_slice(&word.to_le_bytes());
+    }
+    for half in [52u16, 32, 1, 40, 0, 0] {
+        core.extend_from_slice(&half.to_le_bytes());
+    }
+    // a PT_NOTE segment
+    for word in [4, 84, 0, 0, note.len() as u32, 0, 0, 4] {
+        core.extend_from_slice(&word.to_le_bytes());
+    }
+    core.extend_from_slice(&note);
+    core
+}
+
+#[test]
+fn test_core_dump() {
+    let core = test_core(0x400d1b2a);
+    assert_eq!(Some(0x400d1b2a), crashed_pc(&core));
+
+    let mut data = Vec::new();
+    let total_length = (HEADER_LENGTH + core.len() + 4) as u32;
+    for word in [total_length, VERSION_ELF_CRC32, 0, 0, 0] {
+        data.extend_from_slice(&word.to_le_bytes());
+    }
+    data.extend_from_slice(&core);
+    data.extend_from_slice(&[0; 4]);
+    assert_eq!(Some(&core[..]), elf_core(&data));
+
+    let dir = std::env::temp_dir().join(format!("espflash-coredump-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut decoder = CoreDumpDecoder::new(dir.clone());
+    let symbols = Symbols::default();
+    let encoded = STANDARD.encode(&data);
+    let mut lines = vec![
+        String::from("I (312) wifi: connected"),
+        format!("{}\r", START_MARKER),
+    ];
+    lines.extend(
+        encoded
+            .as_bytes()
+            .chunks(64)
+            .map(|chunk| String::from_utf8(chunk.to_vec()).unwrap()),
+    );
+    lines.push(String::from(END_MARKER));
+    let saved: Vec<PathBuf> = lines
+        .iter()
+        .filter_map(|line| decoder.line(line, &symbols))
+        .collect();
+    let content = saved.first().map(|path| std::fs::read(path).unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(1, saved.len());
+    assert!(saved[0].starts_with(&dir));
+    assert_eq!("elf", saved[0].extension().unwrap());
+    assert_eq!(Some(core), content);
+}
@@ -0,0 +1,333 @@
+use std::collections::HashSet;
+use std::fs::read;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Report,
+};
+use serial::SerialPort;
+
+use super::{check_boot_region, open_serial, parse_u32, serial_ports, write_board_info};
+use crate::{Flasher, InputFile, ProgressCallbacks};
+
+type Connect = Box<dyn Fn(&str) -> Result<Flasher, Report> + Send + Sync>;
+
+/// Serve the daemon api on `addr` until the process is stopped
+///
+/// Clients send one command per line and receive any output followed by a line containing
+/// either `ok` or `error: <message>`:
+///
+/// - `auth <token>`: authenticate, this has to be the first command
+/// - `devices`: list the available serial ports
+/// - `board-info <serial>`: print information about the connected board
+/// - `flash <serial> <elf image>`: flash an elf image, reporting progress
+/// - `write-bin [--force] <serial> <offset> <bin file>`: write a binary to the flash, reporting progress
+/// - `monitor <serial>`: stream the serial output until the client disconnects
+///
+/// Without a `token` a random one is generated and printed. Every client is handled on its own thread, a serial
+/// port can only be used by one client at a time.
+pub fn serve(addr: &str, token: Option<String>) -> Result<(), Report> {
+    let listener =
+        TcpListener::bind(addr).wrap_err_with(|| format!("Failed to listen on {}", addr))?;
+    eprintln!("Listening on {}", listener.local_addr()?);
+    let token = match token {
+        Some(token) => token,
+        None => {
+            let token = generate_token()?;
+            eprintln!("Clients authenticate with \"auth {}\"", token);
+            token
+        }
+    };
+
+    let daemon = Arc::new(Daemon::new(
+        token,
+        Box::new(|serial| Ok(Flasher::connect(open_serial(serial)?, None)?)),
+    ));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let daemon = daemon.clone();
+        thread::spawn(move || {
+            if let Err(e) = daemon.handle_client(stream) {
+                eprintln!("Client error: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn generate_token() -> Result<String, Report> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).map_err(std::io::Error::from)?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+struct Daemon {
+    token: String,
+    connect: Connect,
+    /// The serial ports that are in use by a client
+    busy: Mutex<HashSet<String>>,
+}
+
+/// Marks a serial port as in use until dropped
+struct PortLock<'a> {
+    busy: &'a Mutex<HashSet<String>>,
+    port: String,
+}
+
+impl Drop for PortLock<'_> {
+    fn drop(&mut self) {
+        self.busy.lock().unwrap().remove(&self.port);
+    }
+}
+
+impl Daemon {
+    fn new(token: String, connect: Connect) -> Self {
+        Daemon {
+            token,
+            connect,
+            busy: Mutex::default(),
+        }
+    }
+
+    fn check_token(&self, token: &str) -> bool {
+        // compare every byte, so the time taken doesn't tell how much of the token is right
+        token.len() == self.token.len()
+            && token
+                .bytes()
+                .zip(self.token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    fn lock(&self, port: &str) -> Result<PortLock<'_>, Report> {
+        if !self.busy.lock().unwrap().insert(port.to_string()) {
+            bail!("{} is in use by another client", port);
+        }
+        Ok(PortLock {
+            busy: &self.busy,
+            port: port.to_string(),
+        })
+    }
+
+    fn handle_client(&self, stream: TcpStream) -> std::io::Result<()> {
+        let mut output = stream.try_clone()?;
+        let mut authenticated = false;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            let args: Vec<&str> = line.split_whitespace().collect();
+            let result = match args.as_slice() {
+                [] => continue,
+                ["auth", token] => {
+                    authenticated = self.check_token(token);
+                    if authenticated {
+                        Ok(())
+                    } else {
+                        Err(eyre!("invalid token"))
+                    }
+                }
+                _ if !authenticated => Err(eyre!("not authenticated, send \"auth <token>\" first")),
+                args => self.handle_command(args, &mut output),
+            };
+
+            match result {
+                Ok(()) => writeln!(output, "ok")?,
+                Err(e) => writeln!(output, "error: {:#}", e)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_command(&self, args: &[&str], output: &mut TcpStream) -> Result<(), Report> {
+        match args {
+            ["devices"] => {
+                for port in serial_ports() {
+                    writeln!(output, "{}", port)?;
+                }
+            }
+            ["board-info", serial] => {
+                let _lock = self.lock(serial)?;
+                let info = (self.connect)(serial)?.board_info()?;
+                write_board_info(output, &info)?;
+            }
+            ["flash", serial, elf] => {
+                let data = InputFile::open(elf)
+                    .wrap_err_with(|| format!("Failed to open elf image \"{}\"", elf))?;
+                let _lock = self.lock(serial)?;
+                let mut flasher = (self.connect)(serial)?;
+                flasher.set_progress_callbacks(StreamProgress::new(output.try_clone()?));
+                flasher.load_elf_to_flash(&data)?;
+            }
+            ["write-bin", "--force", serial, offset, bin] => {
+                self.write_bin(serial, offset, bin, true, output)?
+            }
+            ["write-bin", serial, offset, bin] => {
+                self.write_bin(serial, offset, bin, false, output)?
+            }
+            ["monitor", serial] => {
+                let _lock = self.lock(serial)?;
+                stream_serial(open_serial(serial)?, output)?
+            }
+            _ => return Err(eyre!("unknown command \"{}\"", args.join(" "))),
+        }
+
+        Ok(())
+    }
+
+    fn write_bin(
+        &self,
+        serial: &str,
+        offset: &str,
+        bin: &str,
+        force: bool,
+        output: &mut TcpStream,
+    ) -> Result<(), Report> {
+        let offset = parse_u32(offset).wrap_err("Invalid offset")?;
+        let data = read(bin).wrap_err_with(|| format!("Failed to open binary \"{}\"", bin))?;
+        let _lock = self.lock(serial)?;
+        let mut flasher = (self.connect)(serial)?;
+        // the client can't be asked, it has to pass --force instead
+        check_boot_region(flasher.chip(), offset, data.len() as u64, force, false)?;
+        flasher.set_progress_callbacks(StreamProgress::new(output.try_clone()?));
+        flasher.write_bin_to_flash(offset, &data)?;
+        Ok(())
+    }
+}
+
+/// Copy the serial output to the client until it disconnects
+fn stream_serial(mut serial: impl SerialPort, output: &mut TcpStream) -> Result<(), Report> {
+    serial.set_timeout(Duration::from_millis(100))?;
+    output.set_read_timeout(Some(Duration::from_millis(1)))?;
+
+    let mut buff = [0; 1024];
+    loop {
+        match serial.read(&mut buff) {
+            Ok(count) => {
+                if output.write_all(&buff[0..count]).is_err() {
+                    return Ok(());
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        // a read of 0 bytes means the client closed the connection
+        match output.read(&mut [0; 64]) {
+            Ok(0) => return Ok(()),
+            Err(e) if e.kind() != ErrorKind::WouldBlock && e.kind() != ErrorKind::TimedOut => {
+                return Ok(())
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Reports flashing progress as `progress <segment address> <blocks written>/<total blocks>` lines
+struct StreamProgress {
+    output: TcpStream,
+    addr: u32,
+    total: usize,
+}
+
+impl StreamProgress {
+    fn new(output: TcpStream) -> Self {
+        StreamProgress {
+            output,
+            addr: 0,
+            total: 0,
+        }
+    }
+}
+
+impl ProgressCallbacks for StreamProgress {
+    fn init(&mut self, addr: u32, total: usize) {
+        self.addr = addr;
+        self.total = total;
+        self.update(0);
+    }
+
+    fn update(&mut self, current: usize) {
+        // a client that went away will notice the result of the command instead
+        let _ = writeln!(
+            self.output,
+            "progress {:#x} {}/{}",
+            self.addr, current, self.total
+        );
+    }
+
+    fn finish(&mut self) {}
+}
+
+#[test]
+fn test_daemon() {
+    use crate::testing::MockTransport;
+    use crate::{Chip, FlashSize, ResetAfterFlash};
+
+    let daemon = Arc::new(Daemon::new(
+        String::from("secret"),
+        Box::new(|_| {
+            let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+            let mut flasher = Flasher::connect(simulator, None)?;
+            flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+            Ok(flasher)
+        }),
+    ));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = daemon.clone();
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        server.handle_client(stream).unwrap();
+    });
+
+    let bin = std::env::temp_dir().join(format!("espflash-daemon-{}.bin", std::process::id()));
+    std::fs::write(&bin, [0x5a; 0x100]).unwrap();
+    let bin = bin.to_str().unwrap().to_string();
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    let mut responses = BufReader::new(client.try_clone().unwrap()).lines();
+    let mut send = move |command: &str| {
+        writeln!(client, "{}", command).unwrap();
+        // skip the progress lines
+        responses
+            .by_ref()
+            .map(Result::unwrap)
+            .find(|line| !line.starts_with("progress "))
+            .unwrap()
+    };
+
+    assert!(send("devices").starts_with("error: not authenticated"));
+    assert_eq!("error: invalid token", send("auth wrong"));
+    assert_eq!("ok", send("auth secret"));
+
+    // writing over the bootloader needs --force
+    assert!(send(&format!("write-bin mock 0x1000 {}", bin)).contains("bootloader region"));
+    assert_eq!(
+        "ok",
+        send(&format!("write-bin --force mock 0x1000 {}", bin))
+    );
+    assert_eq!("ok", send(&format!("write-bin mock 0x10000 {}", bin)));
+
+    // a port that is in use by another client is refused
+    let lock = daemon.lock("mock").unwrap();
+    assert!(send(&format!("write-bin mock 0x10000 {}", bin)).contains("in use"));
+    drop(lock);
+    assert_eq!("ok", send(&format!("write-bin mock 0x10000 {}", bin)));
+
+    // closing the connection ends the client
+    drop(send);
+    handle.join().unwrap();
+    std::fs::remove_file(&bin).unwrap();
+}
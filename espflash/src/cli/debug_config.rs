@@ -0,0 +1,134 @@
+use std::fmt::Write;
+use std::str::FromStr;
+
+use color_eyre::{eyre::bail, Report};
+use thiserror::Error;
+
+use super::adapter::usb_id;
+use crate::{BoardInfo, Chip};
+
+/// The FT2232 of the ESP-PROG and similar boards has the jtag interface on the first channel and the serial port
+/// on the second
+const FT2232: (u16, u16) = (0x0403, 0x6010);
+
+/// The debugger to generate a configuration for
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DebugConfig {
+    /// A configuration file for the espressif fork of openocd
+    OpenOcd,
+    /// An `Embed.toml` for probe-rs and cargo-embed
+    ProbeRs,
+}
+
+/// The debugger wasn't recognized
+#[derive(Debug, Error)]
+#[error("unknown debugger {0}, expected one of openocd or probe-rs")]
+pub struct InvalidDebugConfig(String);
+
+impl FromStr for DebugConfig {
+    type Err = InvalidDebugConfig;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "openocd" => Ok(DebugConfig::OpenOcd),
+            "probe-rs" => Ok(DebugConfig::ProbeRs),
+            _ => Err(InvalidDebugConfig(s.to_string())),
+        }
+    }
+}
+
+impl DebugConfig {
+    /// The configuration for debugging the chip described by `info` over jtag
+    ///
+    /// When the serial port is the second channel of an FT2232, the jtag interface on its first channel is used.
+    pub fn generate(self, info: &BoardInfo, port: &str) -> Result<String, Report> {
+        if info.chip == Chip::Esp8266 {
+            bail!("the esp8266 doesn't have a jtag interface, it can only be debugged with the gdb stub");
+        }
+        let esp_prog = usb_id(port) == Some(FT2232);
+
+        let mut config = String::new();
+        let revision = info
+            .revision
+            .map(|revision| format!(" revision {}", revision))
+            .unwrap_or_default();
+        match self {
+            DebugConfig::OpenOcd => {
+                writeln!(
+                    config,
+                    "# openocd configuration for the esp32{} on {}, run with `openocd -f openocd.cfg`",
+                    revision, port
+                )?;
+                if esp_prog {
+                    writeln!(config, "# the FT2232 of the serial port provides the jtag interface, like on the ESP-PROG")?;
+                } else {
+                    writeln!(config, "# connect an ESP-PROG or other FT2232 based adapter to the jtag pins, GPIO12 to GPIO15")?;
+                }
+                writeln!(config, "source [find interface/ftdi/esp32_devkitj_v1.cfg]")?;
+                writeln!(config, "source [find target/esp32.cfg]")?;
+                writeln!(config, "adapter speed 5000")?;
+            }
+            DebugConfig::ProbeRs => {
+                writeln!(
+                    config,
+                    "# probe-rs configuration for the esp32{} on {}, save as Embed.toml",
+                    revision, port
+                )?;
+                writeln!(config, "[default.general]")?;
+                writeln!(config, "chip = \"esp32\"")?;
+                writeln!(config)?;
+                writeln!(config, "[default.probe]")?;
+                writeln!(config, "protocol = \"Jtag\"")?;
+                writeln!(config, "speed = 5000")?;
+                if esp_prog {
+                    writeln!(config, "# the FT2232 of the serial port provides the jtag interface, like on the ESP-PROG")?;
+                    writeln!(config, "usb_vid = \"{:04x}\"", FT2232.0)?;
+                    writeln!(config, "usb_pid = \"{:04x}\"", FT2232.1)?;
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[test]
+fn test_generate() {
+    use crate::{FlashSize, PsramStatus};
+
+    let mut info = BoardInfo {
+        chip: Chip::Esp32,
+        revision: Some(3),
+        crystal_frequency: 40,
+        mac_address: [0; 6],
+        features: Vec::new(),
+        flash_manufacturer: 0xef,
+        flash_device: 0x4016,
+        flash_size: FlashSize::Flash4Mb,
+        flash_voltage: None,
+        psram: PsramStatus::Unknown,
+    };
+
+    let embed = DebugConfig::ProbeRs
+        .generate(&info, "/dev/espflash-missing")
+        .unwrap();
+    let embed: toml::Value = toml::from_str(&embed).unwrap();
+    assert_eq!(
+        "esp32",
+        embed["default"]["general"]["chip"].as_str().unwrap()
+    );
+    assert_eq!(
+        "Jtag",
+        embed["default"]["probe"]["protocol"].as_str().unwrap()
+    );
+
+    let openocd = DebugConfig::OpenOcd
+        .generate(&info, "/dev/espflash-missing")
+        .unwrap();
+    assert!(openocd.starts_with("# openocd configuration for the esp32 revision 3"));
+    assert!(openocd.contains("source [find target/esp32.cfg]\n"));
+
+    info.chip = Chip::Esp8266;
+    assert!(DebugConfig::OpenOcd
+        .generate(&info, "/dev/espflash-missing")
+        .is_err());
+}
@@ -0,0 +1,68 @@
+use std::convert::TryFrom;
+
+use color_eyre::{eyre::bail, Report};
+
+use crate::Flasher;
+
+/// Print all efuse fields of the connected chip in a table
+pub fn efuse_summary(flasher: &mut Flasher) -> Result<(), Report> {
+    let values = flasher.read_efuses()?;
+    println!("Efuses of {:?}:", flasher.chip());
+
+    let width = values
+        .iter()
+        .map(|value| value.field.name.len())
+        .max()
+        .unwrap_or(0);
+    let formatted: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+    let value_width = formatted
+        .iter()
+        .map(String::len)
+        .filter(|len| *len <= 18)
+        .max()
+        .unwrap_or(0);
+
+    for (value, formatted) in values.iter().zip(formatted) {
+        // R: software can't read the field, W: the field can't be changed anymore
+        let protection = format!(
+            "{}{}",
+            if value.read_protected { "R" } else { "-" },
+            if value.write_protected { "W" } else { "-" }
+        );
+        println!(
+            "{:width$}  {:value_width$}  {}  {}",
+            value.field.name,
+            formatted,
+            protection,
+            value.field.description,
+            width = width,
+            value_width = value_width,
+        );
+    }
+    Ok(())
+}
+
+/// Parse a MAC address in the `aa:bb:cc:dd:ee:ff` format, `-` is accepted as separator as well
+pub fn parse_mac(input: &str) -> Result<[u8; 6], Report> {
+    let bytes = input
+        .split([':', '-'])
+        .map(|byte| match byte.len() {
+            2 => u8::from_str_radix(byte, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<u8>>>();
+    let mac: [u8; 6] = match bytes.map(<[u8; 6]>::try_from) {
+        Some(Ok(mac)) => mac,
+        _ => bail!(
+            "Invalid MAC address {}, expected six hex bytes like aa:bb:cc:dd:ee:ff",
+            input
+        ),
+    };
+    if mac[0] & 1 != 0 {
+        bail!(
+            "{} is a multicast address, the MAC address of a device must be unicast",
+            input
+        );
+    }
+    Ok(mac)
+}
@@ -0,0 +1,273 @@
+use std::io::{BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use color_eyre::{eyre::WrapErr, Report};
+
+use crate::Flasher;
+
+/// The largest memory transfer in a single packet, reads are done one word at a time
+const MAX_PACKET_SIZE: usize = 0x400;
+
+/// Serve a gdb remote stub on `addr` for inspecting the memory of the connected chip
+///
+/// The chip is halted in the bootloader, so only memory can be read and written,
+/// the cpu registers and execution control are not available.
+pub fn gdb_server(flasher: &mut Flasher, addr: &str) -> Result<(), Report> {
+    let listener =
+        TcpListener::bind(addr).wrap_err_with(|| format!("Failed to listen on {}", addr))?;
+    eprintln!(
+        "Waiting for gdb to connect on {}, use `target remote {}`",
+        listener.local_addr()?,
+        listener.local_addr()?
+    );
+
+    let (stream, peer) = listener.accept()?;
+    eprintln!("gdb connected from {}", peer);
+    GdbSession::new(stream, flasher)?.run()
+}
+
+struct GdbSession<'a> {
+    input: BufReader<TcpStream>,
+    output: TcpStream,
+    flasher: &'a mut Flasher,
+}
+
+impl<'a> GdbSession<'a> {
+    fn new(stream: TcpStream, flasher: &'a mut Flasher) -> Result<Self, Report> {
+        Ok(GdbSession {
+            output: stream.try_clone()?,
+            input: BufReader::new(stream),
+            flasher,
+        })
+    }
+
+    fn run(&mut self) -> Result<(), Report> {
+        while let Some(packet) = self.read_packet()? {
+            let response = match self.handle(&packet) {
+                Some(response) => response,
+                None => return Ok(()),
+            };
+            self.write_packet(&response)?;
+        }
+
+        Ok(())
+    }
+
+    /// Handle a packet, returning the response or `None` when the session should end
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let response = match packet.as_bytes().first() {
+            Some(b'?') => "S05".to_string(),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            // registers can't be read from the bootloader, report them as unavailable
+            Some(b'p') => "xxxxxxxx".to_string(),
+            Some(b'g') => "E01".to_string(),
+            Some(b'k') => return None,
+            Some(b'D') => {
+                let _ = self.write_packet("OK");
+                return None;
+            }
+            _ if packet.starts_with("qSupported") => {
+                format!("PacketSize={:x}", MAX_PACKET_SIZE * 2 + 16)
+            }
+            _ if packet == "qAttached" => "1".to_string(),
+            _ => String::new(),
+        };
+
+        Some(response)
+    }
+
+    fn read_memory(&mut self, args: &str) -> String {
+        let (addr, len) = match parse_range(args) {
+            Some((addr, len)) if len <= MAX_PACKET_SIZE => (addr, len),
+            _ => return "E01".to_string(),
+        };
+        if len == 0 {
+            return String::new();
+        }
+
+        let start = addr & !3;
+        let end = addr as u64 + len as u64;
+        let mut bytes = Vec::with_capacity(len + 8);
+        let mut word = start as u64;
+        while word < end {
+            match self.flasher.read_reg(word as u32) {
+                Ok(value) => bytes.extend_from_slice(&value.to_le_bytes()),
+                Err(_) => return "E02".to_string(),
+            }
+            word += 4;
+        }
+
+        let offset = (addr - start) as usize;
+        bytes[offset..offset + len]
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let (addr, len) = match parts.next().and_then(parse_range) {
+            Some(range) => range,
+            None => return "E01".to_string(),
+        };
+        let data = match parts.next().and_then(parse_hex_bytes) {
+            Some(data) if data.len() == len => data,
+            _ => return "E01".to_string(),
+        };
+
+        match self.write_bytes(addr, &data) {
+            Ok(()) => "OK".to_string(),
+            Err(_) => "E02".to_string(),
+        }
+    }
+
+    /// Write bytes one word at a time, reading back partially written words first
+    fn write_bytes(&mut self, addr: u32, data: &[u8]) -> Result<(), crate::Error> {
+        let end = addr as u64 + data.len() as u64;
+        let mut word = (addr & !3) as u64;
+        while word < end {
+            let covered = word >= addr as u64 && word + 4 <= end;
+            let mut bytes = if covered {
+                [0; 4]
+            } else {
+                self.flasher.read_reg(word as u32)?.to_le_bytes()
+            };
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                let pos = word + i as u64;
+                if pos >= addr as u64 && pos < end {
+                    *byte = data[(pos - addr as u64) as usize];
+                }
+            }
+            self.flasher
+                .write_reg(word as u32, u32::from_le_bytes(bytes), None)?;
+            word += 4;
+        }
+
+        Ok(())
+    }
+
+    /// Read the next packet, acknowledging it, or `None` when gdb disconnects
+    fn read_packet(&mut self) -> Result<Option<String>, Report> {
+        let mut byte = [0; 1];
+        loop {
+            if self.input.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+            // acks and interrupt requests don't need a response
+        }
+
+        let mut packet = Vec::new();
+        loop {
+            if self.input.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            packet.push(byte[0]);
+        }
+
+        let mut checksum = [0; 2];
+        self.input.read_exact(&mut checksum)?;
+        let valid = std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+            == Some(packet_checksum(&packet));
+
+        if valid {
+            self.output.write_all(b"+")?;
+            Ok(Some(String::from_utf8_lossy(&packet).into_owned()))
+        } else {
+            self.output.write_all(b"-")?;
+            self.read_packet()
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> Result<(), Report> {
+        write!(
+            self.output,
+            "${}#{:02x}",
+            data,
+            packet_checksum(data.as_bytes())
+        )?;
+        Ok(())
+    }
+}
+
+fn packet_checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Parse an `addr,length` pair in hex
+fn parse_range(args: &str) -> Option<(u32, usize)> {
+    let mut parts = args.splitn(2, ',');
+    let addr = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((addr, len))
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[test]
+fn test_parse_packets() {
+    assert_eq!(Some((0x3ff00000, 0x10)), parse_range("3ff00000,10"));
+    assert_eq!(Some((1, 0)), parse_range("1,0"));
+    assert_eq!(None, parse_range("1"));
+    assert_eq!(None, parse_range("x,4"));
+    assert_eq!(Some(vec![0x01, 0xab]), parse_hex_bytes("01ab"));
+    assert_eq!(Some(vec![]), parse_hex_bytes(""));
+    assert_eq!(None, parse_hex_bytes("abc"));
+    assert_eq!(None, parse_hex_bytes("zz"));
+    assert_eq!(0xfa, packet_checksum(b"m1,0"));
+}
+
+#[test]
+fn test_gdb_session() {
+    use crate::testing::MockTransport;
+    use crate::{Chip, FlashSize};
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    simulator.set_register(0x3ffb0000, 0x44332211);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    let mut session = GdbSession::new(stream, &mut flasher).unwrap();
+    let mut raw = client.try_clone().unwrap();
+
+    let mut request = |packet: &str| {
+        write!(
+            client,
+            "${}#{:02x}",
+            packet,
+            packet_checksum(packet.as_bytes())
+        )
+        .unwrap();
+        let packet = session.read_packet().unwrap().unwrap();
+        session.handle(&packet).unwrap()
+    };
+    assert_eq!("", request("m1,0"));
+    assert_eq!("2233", request("m3ffb0001,2"));
+    assert_eq!("E01", request("m3ffb0000"));
+    assert_eq!("OK", request("M3ffb0002,1:aa"));
+    assert_eq!(0x44aa2211, simulator.register(0x3ffb0000));
+    assert_eq!("E01", request("M3ffb0000,2:aa"));
+    assert_eq!("S05", request("?"));
+
+    // a packet with a bad checksum is refused and the next one is handled instead
+    write!(raw, "$?#00").unwrap();
+    assert_eq!("", request("m0,0"));
+}
@@ -0,0 +1,120 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpListener;
+use std::thread::sleep;
+use std::time::Duration;
+
+use color_eyre::{eyre::WrapErr, Report};
+use serial::SerialPort;
+
+/// Length of the stop packet the stub sends, `$T<signal>#<checksum>`
+const STOP_PACKET_LENGTH: usize = 7;
+
+/// Detects the gdb stub esp-idf firmware starts when it panics
+///
+/// The stub announces itself by sending a stop reply packet, the same way idf_monitor detects it.
+#[derive(Default)]
+pub struct StubDetector {
+    window: Vec<u8>,
+}
+
+impl StubDetector {
+    /// Process output of the device, returns true when the stub started
+    pub fn feed(&mut self, data: &[u8]) -> bool {
+        for &byte in data {
+            if self.window.len() == STOP_PACKET_LENGTH {
+                self.window.remove(0);
+            }
+            self.window.push(byte);
+            if is_stop_packet(&self.window) {
+                self.window.clear();
+                return true;
+            }
+        }
+        false
+    }
+}
+
+fn is_stop_packet(packet: &[u8]) -> bool {
+    match packet {
+        [b'$', b'T', signal @ .., b'#', high, low] if signal.len() == 2 => {
+            let checksum = b"T"
+                .iter()
+                .chain(signal)
+                .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            let expected = std::str::from_utf8(&[*high, *low])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            signal.iter().all(u8::is_ascii_hexdigit) && expected == Some(checksum)
+        }
+        _ => false,
+    }
+}
+
+/// Forward a gdb connection on `addr` to the stub on the serial port, until gdb disconnects
+///
+/// Gives up waiting for gdb to connect when `stop` returns true, in which case false is returned.
+pub fn proxy(
+    serial: &mut impl SerialPort,
+    addr: &str,
+    stop: &mut impl FnMut() -> bool,
+) -> Result<bool, Report> {
+    let listener =
+        TcpListener::bind(addr).wrap_err_with(|| format!("Failed to listen on {}", addr))?;
+    listener.set_nonblocking(true)?;
+    eprintln!(
+        "\nThe firmware is waiting for gdb, connect it with `target remote {}`",
+        listener.local_addr()?
+    );
+
+    let mut client = loop {
+        match listener.accept() {
+            Ok((client, _)) => break client,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if stop() {
+                    return Ok(false);
+                }
+                sleep(Duration::from_millis(100));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    client.set_nonblocking(false)?;
+    client.set_read_timeout(Some(Duration::from_millis(1)))?;
+    // gdb waits for every reply, so keep the latency down
+    serial.set_timeout(Duration::from_millis(10))?;
+
+    let mut buff = [0; 1024];
+    loop {
+        match serial.read(&mut buff) {
+            Ok(count) => {
+                if client.write_all(&buff[0..count]).is_err() {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        // a read of 0 bytes means gdb closed the connection
+        match client.read(&mut buff) {
+            Ok(0) => break,
+            Ok(count) => serial.write_all(&buff[0..count])?,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(_) => break,
+        }
+    }
+
+    eprintln!("gdb disconnected");
+    serial.set_timeout(Duration::from_millis(100))?;
+    Ok(true)
+}
+
+#[test]
+fn test_stub_detector() {
+    let mut detector = StubDetector::default();
+    assert!(!detector.feed(b"Guru Meditation Error\r\n$T0"));
+    assert!(detector.feed(b"b#e6"));
+    // the checksum doesn't match
+    assert!(!detector.feed(b"$T0b#e7"));
+    assert!(is_stop_packet(b"$T05#b9"));
+}
@@ -0,0 +1,125 @@
+use std::io::{stdout, Write};
+use std::process::{exit, Command};
+use std::thread;
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Report,
+};
+use regex::Regex;
+
+use crate::config::Hook;
+use crate::monitor::plain;
+
+/// Runs the actions of the hooks for every line of output that matches their pattern
+pub struct Hooks {
+    hooks: Vec<CompiledHook>,
+}
+
+struct CompiledHook {
+    pattern: Regex,
+    exit: Option<i32>,
+    run: Option<String>,
+    color: Option<&'static str>,
+}
+
+impl Hooks {
+    pub fn new(hooks: &[Hook]) -> Result<Self, Report> {
+        let hooks = hooks
+            .iter()
+            .map(|hook| {
+                Ok(CompiledHook {
+                    pattern: Regex::new(&hook.pattern)
+                        .wrap_err_with(|| format!("Invalid hook pattern \"{}\"", hook.pattern))?,
+                    exit: hook.exit,
+                    run: hook.run.clone(),
+                    color: hook.highlight.as_deref().map(color_code).transpose()?,
+                })
+            })
+            .collect::<Result<_, Report>>()?;
+        Ok(Hooks { hooks })
+    }
+
+    /// The patterns of lines to highlight, with the escape code for their color
+    pub fn highlights(&self) -> Vec<(Regex, &'static str)> {
+        self.hooks
+            .iter()
+            .filter_map(|hook| Some((hook.pattern.clone(), hook.color?)))
+            .collect()
+    }
+
+    /// Run the hooks matching a line of output
+    ///
+    /// Commands are started in the background, with the matching line in the `ESPFLASH_LINE`
+    /// environment variable. When an exit hook matches the process exits with its code.
+    pub fn line(&self, line: &str) {
+        let line = plain(line);
+        for hook in self
+            .hooks
+            .iter()
+            .filter(|hook| hook.pattern.is_match(&line))
+        {
+            if let Some(command) = &hook.run {
+                run(command, &line);
+            }
+            if let Some(code) = hook.exit {
+                let _ = stdout().flush();
+                eprintln!("\nOutput matched \"{}\", exiting", hook.pattern);
+                exit(code);
+            }
+        }
+    }
+}
+
+fn run(command: &str, line: &str) {
+    #[cfg(windows)]
+    let mut shell = Command::new("cmd");
+    #[cfg(windows)]
+    shell.arg("/C");
+    #[cfg(not(windows))]
+    let mut shell = Command::new("sh");
+    #[cfg(not(windows))]
+    shell.arg("-c");
+
+    match shell.arg(command).env("ESPFLASH_LINE", line).spawn() {
+        Ok(mut child) => {
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => eprintln!("\nFailed to run \"{}\": {}", command, e),
+    }
+}
+
+fn color_code(name: &str) -> Result<&'static str, Report> {
+    match name {
+        "red" => Ok("\x1b[31m"),
+        "green" => Ok("\x1b[32m"),
+        "yellow" => Ok("\x1b[33m"),
+        "blue" => Ok("\x1b[34m"),
+        "magenta" => Ok("\x1b[35m"),
+        "cyan" => Ok("\x1b[36m"),
+        _ => Err(eyre!(
+            "unknown highlight color {}, expected one of red, green, yellow, blue, magenta or cyan",
+            name
+        )),
+    }
+}
+
+#[test]
+fn test_hooks() {
+    let hook = |pattern: &str, highlight: Option<&str>| Hook {
+        pattern: pattern.to_string(),
+        exit: None,
+        run: None,
+        highlight: highlight.map(String::from),
+    };
+    let hooks = Hooks::new(&[hook("TEST PASSED", Some("green")), hook("PANIC", None)]).unwrap();
+    let highlights = hooks.highlights();
+    assert_eq!(1, highlights.len());
+    assert_eq!("\x1b[32m", highlights[0].1);
+    assert!(highlights[0].0.is_match("I (312) test: TEST PASSED"));
+    // lines that match no exit or run hook are ignored
+    hooks.line("\x1b[0;31mPANIC\x1b[0m");
+
+    assert!(Hooks::new(&[hook("(", None)]).is_err());
+    assert!(Hooks::new(&[hook("PASSED", Some("purple"))]).is_err());
+}
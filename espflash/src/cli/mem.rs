@@ -0,0 +1,60 @@
+use std::fs::write;
+
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Report,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::Flasher;
+
+/// Read a word of memory, or a register, and print it
+pub fn read_mem(flasher: &mut Flasher, addr: u32) -> Result<(), Report> {
+    let value = flasher.read_reg(addr)?;
+    println!("{:#010x}: {:#010x}", addr, value);
+    Ok(())
+}
+
+/// Write a word of memory, or a register
+///
+/// When a mask is given, only the bits set in the mask are changed.
+pub fn write_mem(
+    flasher: &mut Flasher,
+    addr: u32,
+    value: u32,
+    mask: Option<u32>,
+) -> Result<(), Report> {
+    flasher.write_reg(addr, value, mask)?;
+    Ok(())
+}
+
+/// Read a region of memory through the bootloader and save it to a file
+///
+/// Memory is read one word at a time, so this is only practical for small regions.
+pub fn dump_mem(flasher: &mut Flasher, addr: u32, size: u32, path: &str) -> Result<(), Report> {
+    if !addr.is_multiple_of(4) || !size.is_multiple_of(4) {
+        bail!("The address and size must be a multiple of 4");
+    }
+    let end = addr
+        .checked_add(size)
+        .ok_or_else(|| eyre!("The region extends past the end of the address space"))?;
+
+    let progress = ProgressBar::new(size as u64);
+    progress.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes:>7}/{total_bytes:7} {msg}")
+            .progress_chars("#>-"),
+    );
+    progress.set_message("reading");
+
+    let mut data = Vec::with_capacity(size as usize);
+    for word in (addr..end).step_by(4) {
+        data.extend_from_slice(&flasher.read_reg(word)?.to_le_bytes());
+        progress.inc(4);
+    }
+    progress.finish_with_message("done");
+
+    write(path, &data).wrap_err_with(|| format!("Failed to write {}", path))?;
+    eprintln!("Saved {} bytes from {:#010x} to {}", data.len(), addr, path);
+    Ok(())
+}
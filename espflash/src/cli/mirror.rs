@@ -0,0 +1,141 @@
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+
+use color_eyre::{eyre::WrapErr, Report};
+
+/// Copies the monitor output to other tools, while espflash keeps using the serial port
+pub enum Mirror {
+    /// Send the output to every client connected to a tcp socket
+    Tcp {
+        listener: TcpListener,
+        clients: Vec<TcpStream>,
+    },
+    /// Send the output to a pseudo-terminal that can be opened like a serial port
+    #[cfg(unix)]
+    Pty(pty::Pty),
+}
+
+impl Mirror {
+    /// Start mirroring to `target`, either `pty` or an address to listen on
+    pub fn open(target: &str) -> Result<Self, Report> {
+        #[cfg(unix)]
+        if target == "pty" {
+            let pty = pty::Pty::open().wrap_err("Failed to create a pseudo-terminal")?;
+            eprintln!("Mirroring the output to {}", pty.path);
+            return Ok(Mirror::Pty(pty));
+        }
+
+        let listener = TcpListener::bind(target)
+            .wrap_err_with(|| format!("Failed to listen on {}", target))?;
+        listener.set_nonblocking(true)?;
+        eprintln!("Mirroring the output to {}", listener.local_addr()?);
+        Ok(Mirror::Tcp {
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    /// Send output of the device to everything attached to the mirror
+    ///
+    /// Clients that can't keep up or disconnected are dropped, the monitor itself is never blocked.
+    pub fn write(&mut self, data: &[u8]) {
+        match self {
+            Mirror::Tcp { listener, clients } => {
+                while let Ok((client, _)) = listener.accept() {
+                    if client.set_nonblocking(true).is_ok() {
+                        clients.push(client);
+                    }
+                }
+                // a client that only got part of the data would see a corrupted stream, so it's dropped as well
+                clients.retain_mut(|client| client.write_all(data).is_ok());
+            }
+            #[cfg(unix)]
+            Mirror::Pty(pty) => pty.write(data),
+        }
+    }
+}
+
+#[cfg(unix)]
+mod pty {
+    use std::ffi::CStr;
+    use std::fs::File;
+    use std::io::{Error, Write};
+    use std::mem::MaybeUninit;
+    use std::os::unix::io::FromRawFd;
+
+    pub struct Pty {
+        master: File,
+        pub path: String,
+    }
+
+    impl Pty {
+        /// Create a pseudo-terminal in raw mode, so the output reaches the other side unchanged
+        pub fn open() -> Result<Self, Error> {
+            unsafe {
+                let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_NONBLOCK);
+                if fd < 0 {
+                    return Err(Error::last_os_error());
+                }
+                let master = File::from_raw_fd(fd);
+                if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                    return Err(Error::last_os_error());
+                }
+                let name = libc::ptsname(fd);
+                if name.is_null() {
+                    return Err(Error::last_os_error());
+                }
+                let path = CStr::from_ptr(name).to_string_lossy().into_owned();
+
+                let mut termios = MaybeUninit::uninit();
+                if libc::tcgetattr(fd, termios.as_mut_ptr()) != 0 {
+                    return Err(Error::last_os_error());
+                }
+                let mut termios = termios.assume_init();
+                libc::cfmakeraw(&mut termios);
+                if libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0 {
+                    return Err(Error::last_os_error());
+                }
+
+                Ok(Pty { master, path })
+            }
+        }
+
+        /// Output is discarded while nothing reads the other side
+        pub fn write(&mut self, data: &[u8]) {
+            let _ = self.master.write_all(data);
+        }
+    }
+}
+
+#[test]
+fn test_mirror_slow_client() {
+    use std::io::Read;
+
+    let mut mirror = Mirror::open("127.0.0.1:0").unwrap();
+    let addr = match &mirror {
+        Mirror::Tcp { listener, .. } => listener.local_addr().unwrap(),
+        #[cfg(unix)]
+        Mirror::Pty(_) => unreachable!(),
+    };
+    let clients = |mirror: &Mirror| match mirror {
+        Mirror::Tcp { clients, .. } => clients.len(),
+        #[cfg(unix)]
+        Mirror::Pty(_) => unreachable!(),
+    };
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    mirror.write(b"hello");
+    let mut received = [0; 5];
+    client.read_exact(&mut received).unwrap();
+    assert_eq!(b"hello", &received);
+    assert_eq!(1, clients(&mirror));
+
+    // the client stops reading, once the socket buffers are full it's dropped
+    for _ in 0..0x1000 {
+        mirror.write(&[0x55; 0x10000]);
+        if clients(&mirror) == 0 {
+            break;
+        }
+    }
+    assert_eq!(0, clients(&mirror));
+}
@@ -0,0 +1,899 @@
+//! Helpers shared between the `espflash` and `cargo-espflash` binaries
+
+use std::collections::BTreeMap;
+use std::fs::{read, read_to_string, File};
+use std::io::{stdin, stdout, IsTerminal, Write};
+use std::ops::{Deref, DerefMut};
+use std::process::exit;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use color_eyre::{eyre::WrapErr, Report, Section};
+use serial::{BaudRate, SerialPort, SystemPort};
+use thiserror::Error;
+
+use crate::config::{Build, Connection};
+use crate::{
+    AppDescriptor, BoardInfo, Chip, CommandTiming, Config, Error, FlashSize, FlashStats, Flasher,
+    ImageSettings, InvalidFlashSize, PsramStatus, SpiAttachParams, Stub, WireCapture,
+};
+
+pub use adapter::AdapterQuirks;
+pub use backup::{backup, restore};
+pub use daemon::serve;
+pub use debug_config::{DebugConfig, InvalidDebugConfig};
+pub use efuse::{efuse_summary, parse_mac};
+pub use gdb::gdb_server;
+pub use mem::{dump_mem, read_mem, write_mem};
+pub use monitor::{Monitor, MonitorOptions};
+pub use ota::ota_flash;
+pub use output::{InvalidOutputMode, OutputMode};
+#[cfg(target_os = "linux")]
+pub use permissions::UDEV_RULE;
+pub use plan::Plan;
+pub use progress::MachineProgress;
+pub use repl::repl;
+pub use watch::Watcher;
+
+mod adapter;
+mod backup;
+mod coredump;
+mod daemon;
+mod debug_config;
+mod efuse;
+mod gdb;
+mod gdbstub;
+mod hooks;
+mod mem;
+mod mirror;
+mod monitor;
+mod ota;
+mod output;
+#[cfg(target_os = "linux")]
+mod permissions;
+mod plan;
+mod progress;
+mod repl;
+mod symbols;
+mod watch;
+#[cfg(target_os = "linux")]
+mod wsl;
+
+/// Install the error report handler used by the command line tools
+pub fn install() -> Result<(), Report> {
+    color_eyre::install()
+}
+
+/// How long to retry opening a port that disappeared, usb devices briefly disappear on windows while
+/// they are re-enumerated after a reset
+#[cfg(windows)]
+const REENUMERATION_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Open a serial port, configured for communicating with the bootloader
+pub fn open_serial(port: &str) -> Result<SystemPort, Report> {
+    #[cfg(windows)]
+    return retry_open(port, REENUMERATION_TIMEOUT, false);
+    #[cfg(not(windows))]
+    open_port(port)
+}
+
+/// Record all communication over `port` to a pcap file at `path`
+pub fn capture_port(port: SystemPort, path: &str) -> Result<WireCapture<SystemPort, File>, Report> {
+    let file = File::create(path).wrap_err_with(|| format!("Failed to create {}", path))?;
+    Ok(WireCapture::new(port, file)?)
+}
+
+/// Open a serial port, waiting up to `timeout` for it to appear
+///
+/// This allows starting before the device is plugged in, or while it's being re-enumerated after a reset.
+pub fn wait_for_serial(port: &str, timeout: Duration) -> Result<SystemPort, Report> {
+    retry_open(port, timeout, true)
+}
+
+fn retry_open(port: &str, timeout: Duration, report_waiting: bool) -> Result<SystemPort, Report> {
+    let start = Instant::now();
+    let mut waiting = false;
+    loop {
+        match open_port(port) {
+            Err(report) if start.elapsed() < timeout && is_missing(&report) => {
+                if report_waiting && !waiting {
+                    eprintln!("Waiting for {}...", port);
+                    waiting = true;
+                }
+                sleep(Duration::from_millis(200));
+            }
+            result => return result,
+        }
+    }
+}
+
+fn open_port(port: &str) -> Result<SystemPort, Report> {
+    // COM10 and up need a `\\.\` prefix, which the serial library already adds
+    #[cfg(windows)]
+    let port = port.strip_prefix(r"\\.\").unwrap_or(port);
+    // wsl 1 has the windows COM ports as ttyS devices
+    #[cfg(target_os = "linux")]
+    let wsl = wsl::detect();
+    #[cfg(target_os = "linux")]
+    let translated = wsl.and_then(|wsl| wsl::translate_port(port, wsl));
+    #[cfg(target_os = "linux")]
+    let port = translated.as_deref().unwrap_or(port);
+
+    let mut serial = match serial::open(port) {
+        Ok(serial) => serial,
+        Err(err) => {
+            let report = Report::new(err);
+            #[cfg(target_os = "linux")]
+            if let Some((denied, advice)) = permissions::diagnose(port) {
+                return Err(report.wrap_err(denied).suggestion(advice));
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(wsl) = wsl.filter(|_| is_missing(&report)) {
+                let advice = wsl::missing_port_advice(port, wsl, &serial_ports());
+                return Err(report
+                    .wrap_err(format!("Failed to open serial port {}", port))
+                    .suggestion(advice));
+            }
+            return Err(report.wrap_err(format!("Failed to open serial port {}", port)));
+        }
+    };
+    serial.reconfigure(&|settings| {
+        settings.set_baud_rate(BaudRate::Baud115200)?;
+
+        Ok(())
+    })?;
+
+    Ok(serial)
+}
+
+/// List the serial ports that might have a device attached
+#[cfg(unix)]
+pub fn serial_ports() -> Vec<String> {
+    const PREFIXES: &[&str] = &[
+        "ttyUSB",
+        "ttyACM",
+        "cu.usbserial",
+        "cu.SLAB",
+        "cu.wchusbserial",
+    ];
+
+    let mut ports: Vec<String> = std::fs::read_dir("/dev")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|name| PREFIXES.iter().any(|prefix| name.starts_with(prefix)))
+                .map(|name| format!("/dev/{}", name))
+                .chain(wsl1_ports())
+                .collect()
+        })
+        .unwrap_or_default();
+    ports.sort();
+    ports
+}
+
+/// List the serial ports that might have a device attached
+///
+/// The ports are listed from the dos device names instead of opening them, which would toggle dtr and rts and
+/// reset the attached boards.
+#[cfg(windows)]
+pub fn serial_ports() -> Vec<String> {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn QueryDosDeviceW(device_name: *const u16, target_path: *mut u16, max: u32) -> u32;
+    }
+
+    let mut buffer = vec![0u16; 0x10000];
+    loop {
+        // without a device name all dos device names are listed, separated by nul characters
+        let len =
+            unsafe { QueryDosDeviceW(std::ptr::null(), buffer.as_mut_ptr(), buffer.len() as u32) };
+        if len > 0 {
+            return com_ports(String::from_utf16_lossy(&buffer[..len as usize]).split('\0'));
+        }
+        if buffer.len() >= 0x100000 {
+            return Vec::new();
+        }
+        buffer.resize(buffer.len() * 2, 0);
+    }
+}
+
+/// The COM ports in a list of dos device names, in numeric order
+#[cfg(any(windows, test))]
+fn com_ports<'a>(names: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut ports: Vec<(u32, &str)> = names
+        .into_iter()
+        .filter_map(|name| Some((name.strip_prefix("COM")?.parse().ok()?, name)))
+        .collect();
+    ports.sort_unstable();
+    ports
+        .into_iter()
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+/// The windows serial ports under wsl 1
+#[cfg(unix)]
+fn wsl1_ports() -> Vec<String> {
+    #[cfg(target_os = "linux")]
+    if wsl::detect() == Some(wsl::Wsl::V1) {
+        let driver = std::fs::read_to_string("/proc/tty/driver/serial").ok();
+        return wsl::v1_ports(driver.as_deref(), std::path::Path::new("/dev"));
+    }
+    Vec::new()
+}
+
+fn is_missing(report: &Report) -> bool {
+    report
+        .downcast_ref::<serial::Error>()
+        .map(|err| err.kind() == serial::ErrorKind::NoDevice)
+        .unwrap_or_default()
+}
+
+/// Process exit codes returned by the command line tools
+///
+/// These are part of the command line interface, scripts can rely on them staying the same.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(i32)]
+pub enum ExitCode {
+    Success = 0,
+    /// Any failure not covered by a more specific code
+    Failure = 1,
+    /// Invalid or missing command line arguments
+    Usage = 2,
+    /// Opening the serial port or communicating with the bootloader failed
+    Connection = 3,
+    /// The connected chip is not supported or doesn't match the requested chip
+    ChipMismatch = 4,
+    /// Building the project or the firmware image failed
+    ImageBuild = 5,
+    /// The flash contents don't match the expected image
+    Verification = 6,
+    /// The operation was aborted by the user
+    Abort = 130,
+}
+
+impl From<&Error> for ExitCode {
+    fn from(err: &Error) -> Self {
+        match err {
+            Error::Serial(_)
+            | Error::ConnectionFailed
+            | Error::WrongBootMode(_)
+            | Error::UnexpectedOutput(_)
+            | Error::Timeout
+            | Error::FramingError
+            | Error::OverSizedPacket
+            | Error::CorruptedRead => ExitCode::Connection,
+            Error::UnrecognizedChip | Error::UnsupportedChip(_) | Error::ChipMismatch { .. } => {
+                ExitCode::ChipMismatch
+            }
+            Error::InvalidElf
+            | Error::ElfNotRamLoadable
+            | Error::InvalidImage(_)
+            | Error::InvalidSignature(_)
+            | Error::InvalidKey(_)
+            | Error::InvalidPartitionTable(_)
+            | Error::InvalidSdkconfig(_)
+            | Error::InvalidArduinoBuild(_) => ExitCode::ImageBuild,
+            Error::RomError(_)
+            | Error::UnsupportedFlash(_)
+            | Error::QuadModeFailed(_)
+            | Error::UnsupportedOperation { .. }
+            | Error::StubFailed
+            | Error::InvalidStub(_)
+            | Error::EfuseProtected(_)
+            | Error::EfuseAlreadyBurned(_)
+            | Error::EfuseBurnFailed(_)
+            | Error::FlashProtected { .. }
+            | Error::InvalidAddress { .. }
+            | Error::InvalidSpiCommand { .. } => ExitCode::Failure,
+            Error::VerifyFailed(_) => ExitCode::Verification,
+        }
+    }
+}
+
+impl From<&Report> for ExitCode {
+    fn from(report: &Report) -> Self {
+        if let Some(err) = report.downcast_ref::<Error>() {
+            err.into()
+        } else if report.downcast_ref::<Aborted>().is_some() {
+            ExitCode::Abort
+        } else if report.downcast_ref::<serial::Error>().is_some() {
+            ExitCode::Connection
+        } else if report.downcast_ref::<pico_args::Error>().is_some()
+            || report.downcast_ref::<FlowControlWithReset>().is_some()
+        {
+            ExitCode::Usage
+        } else {
+            ExitCode::Failure
+        }
+    }
+}
+
+impl ExitCode {
+    /// Exit the process with this code
+    pub fn exit(self) -> ! {
+        exit(self as i32)
+    }
+}
+
+/// Report the outcome of a command and exit the process with the matching exit code
+///
+/// Errors are printed together with a hint on how to resolve them, when one is known.
+pub fn exit_with(result: Result<(), Report>) -> ! {
+    match result {
+        Ok(()) => ExitCode::Success.exit(),
+        Err(report) => {
+            let code = ExitCode::from(&report);
+            let report = match hint(&report) {
+                Some(hint) => report.suggestion(hint),
+                None => report,
+            };
+            match error_code(&report) {
+                Some(error_code) => eprintln!("Error [{}]: {:?}", error_code, report),
+                None => eprintln!("Error: {:?}", report),
+            }
+            code.exit()
+        }
+    }
+}
+
+/// The stable code identifying the kind of failure, see [`Error::code`]
+fn error_code(report: &Report) -> Option<&'static str> {
+    if let Some(err) = report.downcast_ref::<Error>() {
+        Some(err.code())
+    } else if report.downcast_ref::<Aborted>().is_some() {
+        Some("aborted")
+    } else if report.downcast_ref::<serial::Error>().is_some() {
+        Some("serial-io")
+    } else if report.downcast_ref::<pico_args::Error>().is_some() {
+        Some("usage")
+    } else {
+        None
+    }
+}
+
+/// Find a suggestion to resolve common failures
+fn hint(report: &Report) -> Option<&'static str> {
+    if let Some(err) = report.downcast_ref::<Error>() {
+        match err {
+            Error::ConnectionFailed => Some(
+                "make sure the chip is in download mode, hold the BOOT button while connecting \
+                or check that DTR and RTS of the serial adapter are connected to EN and GPIO0",
+            ),
+            Error::WrongBootMode(_) => Some(
+                "GPIO0 has to be low while the chip resets to enter the bootloader, hold the BOOT button \
+                while connecting or check that DTR and RTS of the serial adapter are connected to EN and GPIO0, \
+                on the esp8266 GPIO2 also has to be high and GPIO15 low",
+            ),
+            Error::UnexpectedOutput(_) => Some(
+                "the output above usually explains why the chip didn't start the bootloader, \
+                make sure it's in download mode and nothing else is using the serial port",
+            ),
+            Error::Timeout => Some(
+                "the chip stopped responding, check the connection or try a lower baud rate",
+            ),
+            Error::FramingError | Error::OverSizedPacket | Error::CorruptedRead => Some(
+                "the serial connection is unreliable, try a shorter cable or a lower baud rate",
+            ),
+            Error::UnrecognizedChip | Error::UnsupportedChip(_) => {
+                Some("only the esp8266 and esp32 are currently supported")
+            }
+            Error::ChipMismatch { .. } => Some(
+                "pass the correct chip with --chip, or leave it out to detect the chip automatically",
+            ),
+            Error::InvalidElf => {
+                Some("make sure the file is an elf image built for the chip, not a raw binary")
+            }
+            Error::InvalidImage(_) => {
+                Some("make sure the image is built for the chip and wasn't truncated")
+            }
+            Error::ElfNotRamLoadable => Some(
+                "the image contains code that runs from flash, flash it instead of loading it to ram",
+            ),
+            Error::UnsupportedFlash(_) => {
+                Some("the flash chip reported an unknown size, it might not be connected properly, \
+                    or pass --flash-size when it's a flash chip with a nonstandard id")
+            }
+            Error::QuadModeFailed(_) => Some(
+                "the status register of the flash chip might be write protected, \
+                build the bootloader with the dio flash mode instead of qio",
+            ),
+            Error::StubFailed => {
+                Some("make sure the stub was built for the connected chip and its addresses are correct")
+            }
+            Error::InvalidPartitionTable(_) => Some(
+                "partition tables use the esp-idf csv format: name, type, subtype, offset, size, flags",
+            ),
+            Error::InvalidSdkconfig(_) => {
+                Some("pass the sdkconfig generated by the esp-idf build of the project")
+            }
+            Error::InvalidArduinoBuild(_) => Some(
+                "pass the directory the sketch was exported to, with `Sketch > Export Compiled Binary` \
+                or `arduino-cli compile --output-dir`",
+            ),
+            Error::InvalidAddress { .. } => Some(
+                "the registers and ram of the chip are listed in the memory map of its technical reference manual",
+            ),
+            Error::InvalidSpiCommand { .. } => Some(
+                "longer transfers have to be split into several commands, like reading the flash page by page",
+            ),
+            Error::InvalidStub(_) => Some(
+                "stub files use the esptool json format, with base64 encoded `text` and `data` segments",
+            ),
+            Error::VerifyFailed(_) => Some(
+                "the device was flashed with a different image, or the flash didn't retain the data",
+            ),
+            Error::EfuseAlreadyBurned(_) => {
+                Some("efuse bits can't be cleared once they're programmed, so the value can't be changed anymore")
+            }
+            Error::EfuseBurnFailed(_) => Some(
+                "the chip might not have been powered with the programming voltage, check the value with `efuse summary`",
+            ),
+            Error::InvalidSignature(_) => Some(
+                "check that the image was signed with the key the devices are provisioned with, and not changed afterwards",
+            ),
+            Error::InvalidKey(_) => Some("secure boot v2 signing keys are RSA-3072 keys, as generated by `espsecure.py generate_signing_key`"),
+            Error::FlashProtected { .. } => Some(
+                "the flash chip ignores writes to protected regions, clear the block protection bits in its status register \
+                or write outside the protected region",
+            ),
+            Error::Serial(_)
+            | Error::RomError(_)
+            | Error::UnsupportedOperation { .. }
+            | Error::EfuseProtected(_) => None,
+        }
+    } else if is_permission_denied(report) {
+        // the diagnosis is already attached as a suggestion
+        None
+    } else if let Some(err) = report.downcast_ref::<serial::Error>() {
+        match err.kind() {
+            serial::ErrorKind::NoDevice => Some(
+                "check that the device is connected and the port name is correct, \
+                on linux make sure you are in the `dialout` (or `uucp`) group to access serial ports",
+            ),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_permission_denied(report: &Report) -> bool {
+    report
+        .downcast_ref::<permissions::PermissionDenied>()
+        .is_some()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_permission_denied(_report: &Report) -> bool {
+    false
+}
+
+/// A destructive operation was not confirmed
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct Aborted(String);
+
+/// Ask for confirmation before performing a destructive operation
+///
+/// If `force` is set (`-y`/`--force` on the command line) no confirmation is asked. Otherwise the
+/// user is prompted when running in a terminal, and the operation is refused when not.
+pub fn confirm(action: &str, force: bool) -> Result<(), Aborted> {
+    confirm_with(action, force, stdin().is_terminal())
+}
+
+/// Like [`confirm`], refusing the operation instead of asking when `interactive` is false
+fn confirm_with(action: &str, force: bool, interactive: bool) -> Result<(), Aborted> {
+    if force {
+        return Ok(());
+    }
+
+    if !interactive {
+        return Err(Aborted(format!(
+            "refusing to {} without confirmation, pass --force to continue",
+            action
+        )));
+    }
+
+    print!("This will {}, continue? [y/N] ", action);
+    stdout().flush().ok();
+
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).ok();
+    match answer.trim() {
+        "y" | "Y" | "yes" => Ok(()),
+        _ => Err(Aborted(String::from("aborted by user"))),
+    }
+}
+
+/// Ask for confirmation before writing `len` bytes at `offset` when that overlaps the bootloader region of the flash
+pub fn confirm_boot_region(chip: Chip, offset: u32, len: u64, force: bool) -> Result<(), Aborted> {
+    check_boot_region(chip, offset, len, force, stdin().is_terminal())
+}
+
+/// Like [`confirm_boot_region`], refusing the write instead of asking when `interactive` is false
+fn check_boot_region(
+    chip: Chip,
+    offset: u32,
+    len: u64,
+    force: bool,
+    interactive: bool,
+) -> Result<(), Aborted> {
+    let boot_region = chip.boot_region();
+    if offset < boot_region.end && offset as u64 + len > boot_region.start as u64 {
+        confirm_with(
+            "overwrite the bootloader region of the flash",
+            force,
+            interactive,
+        )?;
+    }
+    Ok(())
+}
+
+/// Print the information about a connected board
+pub fn print_board_info(info: &BoardInfo) {
+    // failing to write to stdout isn't worth reporting
+    let _ = write_board_info(&mut stdout(), info);
+}
+
+/// Write the information about a connected board
+pub fn write_board_info(out: &mut impl Write, info: &BoardInfo) -> std::io::Result<()> {
+    match info.revision {
+        Some(revision) => writeln!(
+            out,
+            "Chip type:         {:?} (revision {})",
+            info.chip, revision
+        )?,
+        None => writeln!(out, "Chip type:         {:?}", info.chip)?,
+    }
+    writeln!(out, "Crystal frequency: {}MHz", info.crystal_frequency)?;
+    writeln!(
+        out,
+        "Flash:             {}, manufacturer {:#04x} ({}), device {:#06x}",
+        info.flash_size,
+        info.flash_manufacturer,
+        flash_manufacturer_name(info.flash_manufacturer),
+        info.flash_device
+    )?;
+    if let Some(voltage) = info.flash_voltage {
+        writeln!(out, "Flash voltage:     {}", voltage)?;
+    }
+    let features: Vec<String> = info.features.iter().map(ToString::to_string).collect();
+    writeln!(out, "Features:          {}", features.join(", "))?;
+    match &info.psram {
+        PsramStatus::Present(psram) => writeln!(
+            out,
+            "PSRAM:             {}MB {}, quad spi on CS GPIO{} and CLK GPIO{}",
+            psram.size / (1024 * 1024),
+            if psram.embedded {
+                "embedded"
+            } else {
+                "external"
+            },
+            psram.cs_pin,
+            psram.clk_pin
+        )?,
+        PsramStatus::Absent => writeln!(out, "PSRAM:             none")?,
+        PsramStatus::Unknown => writeln!(out, "PSRAM:             unknown")?,
+    }
+    let mac = info
+        .mac_address
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":");
+    writeln!(out, "MAC address:       {}", mac)
+}
+
+/// The settings for building images, from the sdkconfig and bootloader set in the config file
+pub fn image_settings(config: &Build) -> Result<ImageSettings, Report> {
+    let mut settings = match &config.sdkconfig {
+        Some(path) => ImageSettings::from_sdkconfig(path)?,
+        None => ImageSettings::default(),
+    };
+    if let Some(flash_size) = &config.flash_size {
+        settings.flash_size = Some(parse_flash_size(flash_size)?);
+    }
+    if let Some(path) = &config.bootloader {
+        let bootloader =
+            read(path).wrap_err_with(|| format!("Failed to read bootloader \"{}\"", path))?;
+        settings.bootloader = Some(bootloader);
+    }
+    Ok(settings)
+}
+
+/// The flash size set with `--flash-size` or in the config file, for flash chips that report an unknown size
+///
+/// Unlike [`image_settings`] this doesn't take the size from the sdkconfig, which is the size the project was
+/// built for rather than the size of the connected flash.
+pub fn flash_size(config: &Build) -> Result<Option<FlashSize>, Report> {
+    match &config.flash_size {
+        Some(flash_size) => Ok(Some(parse_flash_size(flash_size)?)),
+        None => Ok(None),
+    }
+}
+
+/// The config file together with the options that are only given on the command line
+///
+/// Dereferences to the [`Config`], which the binaries apply the other command line options to.
+#[derive(Debug, Default)]
+pub struct CliConfig {
+    pub config: Config,
+    /// File to record the serial communication to
+    pub capture: Option<String>,
+    /// Where to report the progress in a machine readable format
+    pub progress: Option<String>,
+}
+
+impl CliConfig {
+    /// Load the config file, without command line options
+    pub fn load() -> Self {
+        CliConfig {
+            config: Config::load(),
+            ..CliConfig::default()
+        }
+    }
+}
+
+impl Deref for CliConfig {
+    type Target = Config;
+
+    fn deref(&self) -> &Config {
+        &self.config
+    }
+}
+
+impl DerefMut for CliConfig {
+    fn deref_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+}
+
+/// Apply the connection settings from the config file to a connected flasher
+pub fn configure_connection(flasher: &mut Flasher, config: &Connection) -> Result<(), Report> {
+    if let Some(retries) = config.retries {
+        flasher.set_block_retries(retries);
+    }
+    if config.flow_control {
+        if !config.no_reset {
+            return Err(Report::new(FlowControlWithReset).suggestion(
+                "The auto-reset circuit of most boards pulls EN low through rts, so flow control keeps the chip in \
+                reset, only use it with --no-reset on boards where rts isn't connected to EN",
+            ));
+        }
+        flasher.set_flow_control(true)?;
+    }
+    if let Some(chunk_size) = config.pacing_chunk_size {
+        let delay = Duration::from_micros(config.pacing_delay_us.unwrap_or(1000));
+        flasher.set_write_pacing(chunk_size, delay);
+    }
+    if let Some(divider) = config.spi_clock_divider {
+        flasher.set_spi_clock_divider(divider);
+    }
+    if config.timings {
+        let mut summary = TimingSummary::default();
+        flasher.set_command_hook(move |timing| summary.add(timing));
+    }
+    if let Some(path) = &config.stub {
+        let json = read_to_string(path)
+            .wrap_err_with(|| format!("Failed to open flasher stub \"{}\"", path))?;
+        flasher.load_stub(&Stub::from_json(&json)?)?;
+    }
+
+    Ok(())
+}
+
+/// Totals of the command timings per command, printed when the flasher is dropped
+#[derive(Default)]
+struct TimingSummary {
+    commands: BTreeMap<String, (usize, Duration, usize, usize)>,
+}
+
+impl TimingSummary {
+    fn add(&mut self, timing: &CommandTiming) {
+        let entry = self.commands.entry(timing.command.to_string()).or_default();
+        entry.0 += 1;
+        entry.1 += timing.duration;
+        entry.2 += timing.bytes_sent;
+        entry.3 += timing.bytes_received;
+    }
+}
+
+impl Drop for TimingSummary {
+    fn drop(&mut self) {
+        if self.commands.is_empty() {
+            return;
+        }
+        eprintln!(
+            "{:<16} {:>6} {:>10} {:>10} {:>10}",
+            "command", "count", "time (ms)", "sent", "received"
+        );
+        for (command, (count, duration, sent, received)) in &self.commands {
+            eprintln!(
+                "{:<16} {:>6} {:>10} {:>10} {:>10}",
+                command,
+                count,
+                duration.as_millis(),
+                sent,
+                received
+            );
+        }
+    }
+}
+
+/// Print how a flash operation went, when the command timings are enabled
+pub fn print_flash_stats(config: &Connection, stats: &FlashStats) {
+    if !config.timings {
+        return;
+    }
+    eprintln!(
+        "Flashed in {:.1}s, {} bytes sent at {} baud using {:.0}% of the link, {} block(s) retried",
+        stats.duration.as_secs_f64(),
+        stats.bytes_sent,
+        stats.baud,
+        stats.utilization() * 100.0,
+        stats.retries
+    );
+}
+
+/// Warn when the crystal of the board doesn't match the one the firmware is built for
+///
+/// Only the crystal frequency configured in the image settings, usually from the sdkconfig, is checked,
+/// without it there's nothing to compare against.
+pub fn warn_crystal_mismatch(flasher: &mut Flasher) -> Result<(), Error> {
+    let expected = match flasher.image_settings().crystal_frequency {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+    if let Some(warning) = crystal_warning(expected, flasher.crystal_frequency()?) {
+        eprintln!("{}", warning);
+    }
+
+    Ok(())
+}
+
+fn crystal_warning(expected: u32, crystal: u32) -> Option<String> {
+    if expected == crystal {
+        return None;
+    }
+    Some(format!(
+        "Warning: this board has a {}MHz crystal but the firmware is built for a {}MHz crystal, \
+        the uart output will be garbled and wifi won't work",
+        crystal, expected
+    ))
+}
+
+/// Warn when the bootloader would refuse an app because its secure version is below the anti-rollback counter
+pub fn warn_anti_rollback(flasher: &mut Flasher, app: Option<&AppDescriptor>) -> Result<(), Error> {
+    let app = match app {
+        Some(app) => app,
+        None => return Ok(()),
+    };
+    match flasher.secure_version()? {
+        Some(counter) if app.secure_version < counter => eprintln!(
+            "Warning: the secure version {} of the app is lower than the anti-rollback counter {} of the device, \
+            a bootloader with anti-rollback enabled will refuse to boot it",
+            app.secure_version, counter
+        ),
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Parse a number, either decimal or hexadecimal with a `0x` prefix
+pub fn parse_u32(input: &str) -> Result<u32, std::num::ParseIntError> {
+    match input.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => input.parse(),
+    }
+}
+
+/// The spi connection wasn't recognized
+#[derive(Debug, Error)]
+#[error("invalid spi connection {0}, expected SPI, HSPI or the gpio numbers of CLK,Q,D,HD,CS")]
+pub struct InvalidSpiConnection(String);
+
+/// Parse the pins of the spi flash like esptool: `SPI`, `HSPI` or the gpio numbers of `CLK,Q,D,HD,CS`
+pub fn parse_spi_connection(input: &str) -> Result<SpiAttachParams, InvalidSpiConnection> {
+    match input.to_ascii_uppercase().as_str() {
+        "SPI" => return Ok(SpiAttachParams::default()),
+        "HSPI" => return Ok(SpiAttachParams::hspi()),
+        _ => {}
+    }
+    let pins = input
+        .split(',')
+        .map(|pin| pin.trim().parse::<u8>().ok().filter(|&pin| pin < 40))
+        .collect::<Option<Vec<u8>>>();
+    match pins.as_deref() {
+        Some(&[clk, q, d, hd, cs]) => Ok(SpiAttachParams { clk, q, d, hd, cs }),
+        _ => Err(InvalidSpiConnection(input.to_string())),
+    }
+}
+
+/// The spi connection set in the config or on the command line, `None` to detect it
+pub fn spi_connection(
+    config: &Connection,
+) -> Result<Option<SpiAttachParams>, InvalidSpiConnection> {
+    config
+        .spi_connection
+        .as_deref()
+        .map(parse_spi_connection)
+        .transpose()
+}
+
+/// Rts/cts flow control was enabled for a chip that is reset through the rts line
+#[derive(Debug, Error)]
+#[error("rts/cts flow control can't be used while the chip is reset through rts")]
+pub struct FlowControlWithReset;
+
+/// The spi clock divider is out of range
+#[derive(Debug, Error)]
+#[error("invalid spi clock divider {0}, expected a number from 1 to 64")]
+pub struct InvalidSpiClockDivider(String);
+
+/// Parse a divider of the 80MHz spi flash clock
+pub fn parse_spi_clock_divider(input: &str) -> Result<u8, InvalidSpiClockDivider> {
+    match input.parse() {
+        Ok(divider) if (1..=64).contains(&divider) => Ok(divider),
+        _ => Err(InvalidSpiClockDivider(input.to_string())),
+    }
+}
+
+/// Parse a flash size like `4MB`
+pub fn parse_flash_size(input: &str) -> Result<FlashSize, InvalidFlashSize> {
+    input.parse()
+}
+
+fn flash_manufacturer_name(id: u8) -> &'static str {
+    match id {
+        0x1c => "EON",
+        0x20 => "XMC",
+        0x5e => "Zbit",
+        0x68 => "Boya",
+        0x85 => "Puya",
+        0x9d => "ISSI",
+        0xa1 => "Fudan",
+        0xc2 => "Macronix",
+        0xc8 => "GigaDevice",
+        0xef => "Winbond",
+        _ => "unknown",
+    }
+}
+
+#[test]
+fn test_com_ports() {
+    assert_eq!(
+        vec!["COM3", "COM10"],
+        com_ports(vec!["COM10", "LPT1", "COM3", "COMX", "C:", "", "NUL"])
+    );
+}
+
+#[test]
+fn test_crystal_warning() {
+    assert_eq!(None, crystal_warning(40, 40));
+    assert_eq!(None, crystal_warning(26, 26));
+    assert!(crystal_warning(26, 40)
+        .unwrap()
+        .contains("40MHz crystal but the firmware is built for a 26MHz"));
+}
+
+#[test]
+fn test_flow_control_with_reset() {
+    use crate::testing::MockTransport;
+
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(mock, None).unwrap();
+    let mut config = Connection {
+        flow_control: true,
+        ..Connection::default()
+    };
+    let report = configure_connection(&mut flasher, &config).unwrap_err();
+    assert!(report.downcast_ref::<FlowControlWithReset>().is_some());
+    assert_eq!(ExitCode::Usage as i32, ExitCode::from(&report) as i32);
+
+    config.no_reset = true;
+    configure_connection(&mut flasher, &config).unwrap();
+}
@@ -0,0 +1,456 @@
+use std::cell::Cell;
+use std::io::{stdout, ErrorKind, Write};
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use color_eyre::Report;
+use pico_args::Arguments;
+use serial::{BaudRate, SerialPort, SystemPort};
+
+use crate::cli::coredump::CoreDumpDecoder;
+use crate::cli::gdbstub::{self, StubDetector};
+use crate::cli::hooks::Hooks;
+use crate::cli::mirror::Mirror;
+use crate::cli::open_port;
+use crate::cli::output::{Output, OutputMode};
+use crate::cli::symbols::Symbolizer;
+use crate::config::Hook;
+use crate::monitor::MonitorEvent;
+
+/// Command line options for the serial monitor
+#[derive(Debug, Default)]
+pub struct MonitorOptions {
+    /// Address to listen on, or `pty`, to mirror the output to
+    pub mirror: Option<String>,
+    /// How the output is printed
+    pub output: OutputMode,
+    /// Remove ansi escape sequences from the output in text mode
+    pub strip_ansi: bool,
+    /// Exit instead of waiting for the port to come back when the connection is lost
+    pub no_reconnect: bool,
+    /// Address to accept a gdb connection on when the firmware starts its gdb stub
+    pub gdb_listen: Option<String>,
+    /// Actions to take when a line of output matches a pattern
+    pub hooks: Vec<Hook>,
+    /// Directory to save core dumps in, the current directory by default
+    pub core_dump_dir: Option<PathBuf>,
+}
+
+impl MonitorOptions {
+    /// Take the monitor options from the command line arguments
+    pub fn from_args(args: &mut Arguments) -> Result<Self, pico_args::Error> {
+        Ok(MonitorOptions {
+            mirror: args.opt_value_from_str("--mirror")?,
+            output: args.opt_value_from_str("--output")?.unwrap_or_default(),
+            strip_ansi: args.contains("--strip-ansi"),
+            no_reconnect: args.contains("--no-reconnect"),
+            gdb_listen: args.opt_value_from_str("--gdb-listen")?,
+            hooks: hook_args(args, "--exit-on", |hook| hook.exit = Some(0))?
+                .chain(hook_args(args, "--fail-on", |hook| hook.exit = Some(1))?)
+                .chain(hook_args(args, "--highlight", |hook| {
+                    hook.highlight = Some(String::from("yellow"))
+                })?)
+                .collect(),
+            core_dump_dir: args.opt_value_from_str("--core-dump-dir")?,
+        })
+    }
+}
+
+/// Create a hook for every pattern passed with `option`
+fn hook_args(
+    args: &mut Arguments,
+    option: &'static str,
+    action: impl Fn(&mut Hook),
+) -> Result<impl Iterator<Item = Hook>, pico_args::Error> {
+    let patterns: Vec<String> = args.values_from_str(option)?;
+    Ok(patterns.into_iter().map(move |pattern| {
+        let mut hook = Hook {
+            pattern,
+            exit: None,
+            run: None,
+            highlight: None,
+        };
+        action(&mut hook);
+        hook
+    }))
+}
+
+/// Prints everything the device writes to the serial port
+///
+/// The monitor is kept between runs, so tools attached to the mirror stay connected when the device is
+/// flashed again.
+pub struct Monitor {
+    mirror: Option<Mirror>,
+    output: Output,
+    reconnect: bool,
+    gdb_listen: Option<String>,
+    hooks: Hooks,
+    /// Splits the output into lines and keeps the symbols of the image
+    lines: crate::Monitor,
+    symbolizer: Symbolizer,
+    boot_baud: Option<BaudRate>,
+    core_dump_dir: PathBuf,
+}
+
+impl Monitor {
+    pub fn new(options: &MonitorOptions) -> Result<Self, Report> {
+        let hooks = Hooks::new(&options.hooks)?;
+        Ok(Monitor {
+            mirror: options.mirror.as_deref().map(Mirror::open).transpose()?,
+            output: Output::new(options.output, options.strip_ansi, hooks.highlights()),
+            reconnect: !options.no_reconnect,
+            gdb_listen: options.gdb_listen.clone(),
+            hooks,
+            lines: crate::Monitor::builder().build(),
+            symbolizer: Symbolizer::default(),
+            boot_baud: None,
+            core_dump_dir: options
+                .core_dump_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(".")),
+        })
+    }
+
+    /// Read the boot messages of the rom at `baud`, see [`Flasher::boot_log_baud`](crate::Flasher::boot_log_baud)
+    ///
+    /// When it differs from the baud rate of the port, the port is switched to it when monitoring starts, right
+    /// after the chip was reset, and when the firmware prints the end of a crash report, after which it resets.
+    /// The port is switched back once the boot messages end, so the output of the firmware is readable as well.
+    pub fn set_boot_baud(&mut self, baud: BaudRate) {
+        self.boot_baud = Some(baud);
+    }
+
+    /// Use the symbols of the elf image running on the device, to show the functions in backtraces
+    ///
+    /// This is called again every time the image is flashed, so the symbols keep matching the firmware.
+    pub fn load_elf(&mut self, data: &[u8]) {
+        self.lines.load_elf(data);
+    }
+
+    /// Print the output of the device on `port` until the process is interrupted
+    pub fn run(&mut self, serial: impl SerialPort, port: &str) -> Result<(), Report> {
+        self.run_until(serial, port, || false)
+    }
+
+    /// Print the output of the device on `port` until `stop` returns true
+    ///
+    /// When the connection is lost, because the device reset or was unplugged, the port is opened again
+    /// as soon as it's back, unless reconnecting is disabled.
+    ///
+    /// When the firmware panics and starts the esp-idf gdb stub, gdb connections are forwarded to it if
+    /// an address to listen on is set, otherwise the output is frozen until the monitor is stopped.
+    ///
+    /// The mirror always receives the output unchanged, regardless of the output mode.
+    /// Code addresses in the output are resolved to functions when the elf image is loaded.
+    /// Core dumps printed by esp-idf firmware are saved as elf core files in the core dump directory.
+    pub fn run_until(
+        &mut self,
+        serial: impl SerialPort,
+        port: &str,
+        mut stop: impl FnMut() -> bool,
+    ) -> Result<(), Report> {
+        eprintln!("Commands:");
+        eprintln!("    CTRL+C    Exit");
+        eprintln!();
+
+        let mut core_dumps = CoreDumpDecoder::new(self.core_dump_dir.clone());
+        let mut disconnected = self.read_until(serial, port, &mut core_dumps, &mut stop)?;
+        while let Some(err) = disconnected {
+            if !self.reconnect {
+                return Err(err.into());
+            }
+            eprintln!(
+                "\nLost the connection to {} ({}), waiting for it to come back...",
+                port, err
+            );
+            let serial = match reopen(port, &mut stop) {
+                Some(serial) => serial,
+                None => return Ok(()),
+            };
+            eprintln!("Reconnected to {}", port);
+            disconnected = self.read_until(serial, port, &mut core_dumps, &mut stop)?;
+        }
+
+        Ok(())
+    }
+
+    /// Print the output of the device until `stop` returns true or reading from the port fails
+    fn read_until(
+        &mut self,
+        mut serial: impl SerialPort,
+        port: &str,
+        core_dumps: &mut CoreDumpDecoder,
+        stop: &mut impl FnMut() -> bool,
+    ) -> Result<Option<std::io::Error>, Report> {
+        serial.set_timeout(Duration::from_millis(100))?;
+        let mut boot_baud = match self.boot_baud {
+            Some(baud) => BootBaud::start(&mut serial, baud)?,
+            None => None,
+        };
+
+        let stdout = stdout();
+        let mut stdout = stdout.lock();
+        let mut buff = [0; 1024];
+        let mut gdb_stub = StubDetector::default();
+        while !stop() {
+            match serial.read(&mut buff) {
+                Ok(count) => {
+                    self.output.write(&mut stdout, &buff[0..count])?;
+                    stdout.flush()?;
+                    if let Some(mirror) = &mut self.mirror {
+                        mirror.write(&buff[0..count]);
+                    }
+                    let lines: Vec<String> = self
+                        .lines
+                        .feed(&buff[0..count])
+                        .into_iter()
+                        .filter_map(|event| match event {
+                            MonitorEvent::Line(line) => Some(line),
+                            _ => None,
+                        })
+                        .collect();
+                    for line in &lines {
+                        core_dumps.line(line, self.lines.symbols());
+                        self.symbolizer.line(line, self.lines.symbols());
+                        self.hooks.line(line);
+                    }
+                    if let Some(boot_baud) = &mut boot_baud {
+                        boot_baud.feed(&mut serial, &lines)?;
+                    }
+                    if gdb_stub.feed(&buff[0..count])
+                        && !self.attach_gdb(&mut serial, port, stop)?
+                    {
+                        return Ok(None);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::TimedOut => {
+                    self.output.flush_line(&mut stdout)?;
+                    stdout.flush()?;
+                    if let Some(boot_baud) = &mut boot_baud {
+                        boot_baud.idle(&mut serial)?;
+                    }
+                }
+                Err(e) => return Ok(Some(e)),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Hand the serial port to gdb, returns false when `stop` returned true in the meantime
+    fn attach_gdb(
+        &mut self,
+        serial: &mut impl SerialPort,
+        port: &str,
+        stop: &mut impl FnMut() -> bool,
+    ) -> Result<bool, Report> {
+        match &self.gdb_listen {
+            Some(addr) => gdbstub::proxy(serial, addr, stop),
+            None => {
+                eprintln!(
+                    "\nThe firmware is waiting for gdb, exit with CTRL+C and connect gdb to {}, \
+                    or use --gdb-listen to connect it through espflash",
+                    port
+                );
+                while !stop() {
+                    sleep(Duration::from_millis(100));
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// The last lines the bootloaders of the esp8266 print before starting the firmware, eboot of the arduino core
+/// and the bootloader of the nonos sdk
+const BOOT_LOG_END: &[&str] = &["~ld", "jump to run user"];
+/// The last line of the crash report of the arduino core, after which the chip resets
+const RESET_START: &[&str] = &[">>>stack<<<"];
+/// Give up on the end of the boot messages after this long, the firmware might have been running already
+const BOOT_LOG_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Switches the port between the baud rate of the boot messages and the one of the firmware
+struct BootBaud {
+    boot: BaudRate,
+    app: BaudRate,
+    /// When the port was switched to the baud rate of the boot messages, `None` while reading the firmware
+    booting: Option<Instant>,
+    received: bool,
+}
+
+impl BootBaud {
+    /// Start reading the boot messages, unless they're printed at the baud rate of the port already
+    fn start(serial: &mut impl SerialPort, boot: BaudRate) -> Result<Option<Self>, Report> {
+        // the settings of the port can only be read while reconfiguring it
+        let app = Cell::new(None);
+        serial.reconfigure(&|settings| {
+            app.set(settings.baud_rate());
+            Ok(())
+        })?;
+        let app = match app.get() {
+            Some(app) if app != boot => app,
+            _ => return Ok(None),
+        };
+        let mut boot_baud = BootBaud {
+            boot,
+            app,
+            booting: None,
+            received: false,
+        };
+        boot_baud.switch(serial, true)?;
+        Ok(Some(boot_baud))
+    }
+
+    /// Output arrived, look for the end of the boot messages, or the start of a reset, in the lines it completed
+    fn feed(&mut self, serial: &mut impl SerialPort, lines: &[String]) -> Result<(), Report> {
+        self.received = true;
+        for line in lines {
+            let markers = match self.booting {
+                Some(_) => BOOT_LOG_END,
+                None => RESET_START,
+            };
+            if markers.iter().any(|marker| line.trim().starts_with(marker)) {
+                self.switch(serial, self.booting.is_none())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Nothing arrived for the timeout of the port, the boot messages are over when they were received
+    fn idle(&mut self, serial: &mut impl SerialPort) -> Result<(), Report> {
+        match self.booting {
+            Some(since) if self.received || since.elapsed() > BOOT_LOG_TIMEOUT => {
+                self.switch(serial, false)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn switch(&mut self, serial: &mut impl SerialPort, booting: bool) -> Result<(), Report> {
+        let baud = if booting { self.boot } else { self.app };
+        serial.reconfigure(&|settings| settings.set_baud_rate(baud))?;
+        self.booting = Some(Instant::now()).filter(|_| booting);
+        self.received = false;
+        Ok(())
+    }
+}
+
+/// Wait for a port that disappeared to come back, gives up when `stop` returns true
+fn reopen(port: &str, stop: &mut impl FnMut() -> bool) -> Option<SystemPort> {
+    while !stop() {
+        // the port can briefly exist without being usable while it's being re-enumerated
+        match open_port(port) {
+            Ok(serial) => return Some(serial),
+            Err(_) => sleep(Duration::from_millis(200)),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+fn baud_rate(serial: &mut impl SerialPort) -> Option<BaudRate> {
+    let baud = Cell::new(None);
+    serial
+        .reconfigure(&|settings| {
+            baud.set(settings.baud_rate());
+            Ok(())
+        })
+        .unwrap();
+    baud.get()
+}
+
+#[test]
+fn test_boot_baud_start() {
+    use crate::testing::MockTransport;
+
+    let mut serial = MockTransport::new();
+    assert!(BootBaud::start(&mut serial, BaudRate::Baud115200)
+        .unwrap()
+        .is_none());
+    assert_eq!(Some(BaudRate::Baud115200), baud_rate(&mut serial));
+
+    let boot_baud = BootBaud::start(&mut serial, BaudRate::BaudOther(74880))
+        .unwrap()
+        .unwrap();
+    assert_eq!(BaudRate::Baud115200, boot_baud.app);
+    assert!(boot_baud.booting.is_some());
+    assert_eq!(Some(BaudRate::BaudOther(74880)), baud_rate(&mut serial));
+}
+
+#[test]
+fn test_boot_baud_feed() {
+    use crate::testing::MockTransport;
+
+    let mut serial = MockTransport::new();
+    let mut boot_baud = BootBaud::start(&mut serial, BaudRate::BaudOther(74880))
+        .unwrap()
+        .unwrap();
+    let lines = |lines: &[&str]| {
+        lines
+            .iter()
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    boot_baud
+        .feed(
+            &mut serial,
+            &lines(&[" ets Jan  8 2013,rst cause:2, boot mode:(3,6)"]),
+        )
+        .unwrap();
+    assert_eq!(Some(BaudRate::BaudOther(74880)), baud_rate(&mut serial));
+
+    // the end of the boot messages switches to the baud rate of the firmware
+    boot_baud
+        .feed(
+            &mut serial,
+            &lines(&["load 0x4010f000, len 3584, room 16", " ~ld"]),
+        )
+        .unwrap();
+    assert!(boot_baud.booting.is_none());
+    assert_eq!(Some(BaudRate::Baud115200), baud_rate(&mut serial));
+
+    // boot markers printed by the firmware are ignored
+    boot_baud.feed(&mut serial, &lines(&["~ld"])).unwrap();
+    assert_eq!(Some(BaudRate::Baud115200), baud_rate(&mut serial));
+
+    // the end of a crash report switches back for the boot messages after the reset
+    boot_baud
+        .feed(&mut serial, &lines(&["<<<stack<<<", "", ">>>stack<<<"]))
+        .unwrap();
+    assert!(boot_baud.booting.is_some());
+    assert_eq!(Some(BaudRate::BaudOther(74880)), baud_rate(&mut serial));
+}
+
+#[test]
+fn test_boot_baud_idle() {
+    use crate::testing::MockTransport;
+
+    let mut serial = MockTransport::new();
+    let mut boot_baud = BootBaud::start(&mut serial, BaudRate::BaudOther(74880))
+        .unwrap()
+        .unwrap();
+
+    // nothing was received yet, the chip might still be resetting
+    boot_baud.idle(&mut serial).unwrap();
+    assert_eq!(Some(BaudRate::BaudOther(74880)), baud_rate(&mut serial));
+
+    // the boot messages were received and the chip went quiet
+    boot_baud
+        .feed(&mut serial, &["ets Jun  8 2016 00:22:57".to_string()])
+        .unwrap();
+    boot_baud.idle(&mut serial).unwrap();
+    assert!(boot_baud.booting.is_none());
+    assert_eq!(Some(BaudRate::Baud115200), baud_rate(&mut serial));
+
+    // idling while reading the firmware doesn't switch
+    boot_baud.idle(&mut serial).unwrap();
+    assert_eq!(Some(BaudRate::Baud115200), baud_rate(&mut serial));
+
+    // nothing arrived at the boot baud rate, the firmware was running already
+    boot_baud.switch(&mut serial, true).unwrap();
+    boot_baud.booting = Instant::now().checked_sub(BOOT_LOG_TIMEOUT * 2);
+    boot_baud.idle(&mut serial).unwrap();
+    assert_eq!(Some(BaudRate::Baud115200), baud_rate(&mut serial));
+}
@@ -0,0 +1,245 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpListener, ToSocketAddrs, UdpSocket};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Report,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+
+const COMMAND_FLASH: u32 = 0;
+const COMMAND_AUTH: u32 = 200;
+const INVITATION_ATTEMPTS: usize = 10;
+const CHUNK_SIZE: usize = 1024;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Send an application image to a device running the espota (Arduino OTA) updater
+///
+/// The device is invited over udp, after which it connects back to us over tcp to download the image.
+pub fn ota_flash(
+    host: &str,
+    port: u16,
+    password: Option<&str>,
+    name: &str,
+    image: &[u8],
+) -> Result<(), Report> {
+    let remote = (host, port)
+        .to_socket_addrs()
+        .wrap_err_with(|| format!("Failed to resolve {}", host))?
+        .next()
+        .ok_or_else(|| eyre!("Failed to resolve {}", host))?;
+
+    let listener = TcpListener::bind(("0.0.0.0", 0))?;
+    let local_port = listener.local_addr()?.port();
+
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    socket.connect(remote)?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    let image_md5 = format!("{:x}", md5::compute(image));
+    let invitation = format!(
+        "{} {} {} {}\n",
+        COMMAND_FLASH,
+        local_port,
+        image.len(),
+        image_md5
+    );
+
+    eprintln!("Inviting {}", remote);
+    let reply =
+        exchange(&socket, &invitation).wrap_err_with(|| format!("No response from {}", remote))?;
+
+    if let Some(nonce) = reply.strip_prefix("AUTH ") {
+        let password = match password {
+            Some(password) => password,
+            None => bail!("The device requires a password"),
+        };
+        let cnonce = md5_hex(&format!(
+            "{}{}{}{}",
+            name,
+            image.len(),
+            image_md5,
+            remote.ip()
+        ));
+        let response = md5_hex(&format!(
+            "{}:{}:{}",
+            md5_hex(password),
+            nonce.trim(),
+            cnonce
+        ));
+        let reply = exchange(
+            &socket,
+            &format!("{} {} {}\n", COMMAND_AUTH, cnonce, response),
+        )?;
+        if reply != "OK" {
+            bail!("Authentication failed");
+        }
+    } else if reply != "OK" {
+        bail!("Unexpected response from the device: {}", reply);
+    }
+
+    let mut connection = accept(&listener, remote.ip())?;
+    connection.set_read_timeout(Some(TIMEOUT))?;
+
+    let progress = ProgressBar::new(image.len() as u64);
+    progress.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes:>7}/{total_bytes:7} {msg}")
+            .progress_chars("#>-"),
+    );
+    progress.set_message("uploading");
+    let confirmed = upload(&mut connection, image, &progress)?;
+    progress.finish_with_message("uploaded");
+    if confirmed {
+        return Ok(());
+    }
+
+    // writing the image to flash can take a while after the upload completes
+    connection.set_read_timeout(Some(Duration::from_secs(60)))?;
+    wait_for_result(&mut connection)
+}
+
+/// Send the image to the device, returns whether the device already confirmed the update
+fn upload(
+    connection: &mut (impl Read + Write),
+    image: &[u8],
+    progress: &ProgressBar,
+) -> Result<bool, Report> {
+    let mut ack = [0; 32];
+    let mut confirmed = false;
+    for chunk in image.chunks(CHUNK_SIZE) {
+        connection.write_all(chunk)?;
+        // the device acknowledges every chunk with the number of bytes it received, the result of the update
+        // can arrive in the same read as the last acknowledgement
+        let count = connection
+            .read(&mut ack)
+            .wrap_err("The device stopped responding")?;
+        confirmed = String::from_utf8_lossy(&ack[..count]).contains("OK");
+        progress.inc(chunk.len() as u64);
+    }
+    Ok(confirmed)
+}
+
+/// Wait for the device to confirm the update after the upload
+fn wait_for_result(connection: &mut impl Read) -> Result<(), Report> {
+    let mut buff = [0; 32];
+    let mut response = Vec::new();
+    loop {
+        let count = connection
+            .read(&mut buff)
+            .wrap_err("No result from the device")?;
+        if count == 0 {
+            bail!("The device closed the connection without confirming the update");
+        }
+        response.extend_from_slice(&buff[..count]);
+        let text = String::from_utf8_lossy(&response);
+        if text.contains("OK") {
+            return Ok(());
+        }
+        if text.contains('E') {
+            bail!("The device reported an error: {}", text.trim());
+        }
+    }
+}
+
+/// Send a udp message until the device replies
+fn exchange(socket: &UdpSocket, message: &str) -> Result<String, Report> {
+    let mut buff = [0; 64];
+    for _ in 0..INVITATION_ATTEMPTS {
+        socket.send(message.as_bytes())?;
+        if let Ok(count) = socket.recv(&mut buff) {
+            return Ok(String::from_utf8_lossy(&buff[..count]).trim().to_string());
+        }
+    }
+
+    Err(eyre!("timed out"))
+}
+
+/// Wait for the device to connect back to us
+fn accept(listener: &TcpListener, device: IpAddr) -> Result<std::net::TcpStream, Report> {
+    listener.set_nonblocking(true)?;
+    let start = Instant::now();
+    while start.elapsed() < TIMEOUT {
+        match listener.accept() {
+            Ok((stream, addr)) if addr.ip() == device => {
+                stream.set_nonblocking(false)?;
+                return Ok(stream);
+            }
+            Ok(_) => continue,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                sleep(Duration::from_millis(50))
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(eyre!(
+        "The device didn't connect back, make sure it can reach this machine on tcp port {}",
+        listener.local_addr()?.port()
+    ))
+}
+
+fn md5_hex(input: &str) -> String {
+    format!("{:x}", md5::compute(input))
+}
+
+#[cfg(test)]
+struct MockDevice {
+    received: Vec<u8>,
+    responses: std::collections::VecDeque<&'static [u8]>,
+}
+
+#[cfg(test)]
+impl Read for MockDevice {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let response = self.responses.pop_front().unwrap_or_default();
+        buf[..response.len()].copy_from_slice(response);
+        Ok(response.len())
+    }
+}
+
+#[cfg(test)]
+impl Write for MockDevice {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.received.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_upload() {
+    let image = vec![0x5a; CHUNK_SIZE + 100];
+
+    // the result arrives together with the last acknowledgement
+    let mut device = MockDevice {
+        received: Vec::new(),
+        responses: vec![&b"1024"[..], &b"100OK"[..]].into(),
+    };
+    assert!(upload(&mut device, &image, &ProgressBar::hidden()).unwrap());
+    assert_eq!(image, device.received);
+
+    // the result arrives after the upload, possibly split over multiple reads
+    let mut device = MockDevice {
+        received: Vec::new(),
+        responses: vec![&b"1024"[..], &b"100"[..], &b"O"[..], &b"K"[..]].into(),
+    };
+    assert!(!upload(&mut device, &image, &ProgressBar::hidden()).unwrap());
+    wait_for_result(&mut device).unwrap();
+
+    let mut device = MockDevice {
+        received: Vec::new(),
+        responses: vec![&b"ERR"[..]].into(),
+    };
+    assert!(wait_for_result(&mut device).is_err());
+    let mut device = MockDevice {
+        received: Vec::new(),
+        responses: Default::default(),
+    };
+    assert!(wait_for_result(&mut device).is_err());
+}
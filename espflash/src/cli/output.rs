@@ -0,0 +1,227 @@
+use std::io::{Result, Write};
+use std::str::{from_utf8, FromStr};
+use std::time::Instant;
+
+use regex::Regex;
+use thiserror::Error;
+
+use crate::monitor::{plain, LineBuffer};
+
+/// How the monitor prints the output of the device
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum OutputMode {
+    /// Print the output as utf-8 text, invalid bytes are replaced
+    #[default]
+    Text,
+    /// Pass the output through unchanged
+    Raw,
+    /// Print a hexdump of the output, with the time since the monitor started
+    Hex,
+}
+
+/// The output mode wasn't recognized
+#[derive(Debug, Error)]
+#[error("unknown output mode {0}, expected one of text, raw or hex")]
+pub struct InvalidOutputMode(String);
+
+impl FromStr for OutputMode {
+    type Err = InvalidOutputMode;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputMode::Text),
+            "raw" => Ok(OutputMode::Raw),
+            "hex" => Ok(OutputMode::Hex),
+            _ => Err(InvalidOutputMode(s.to_string())),
+        }
+    }
+}
+
+/// State of an ansi escape sequence that continues in the next read
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Escape {
+    None,
+    /// An `ESC` was received
+    Start,
+    /// Inside a control sequence (`ESC [`), which ends with a byte in `0x40..=0x7e`
+    Csi,
+}
+
+/// Formats the output of the device according to the output mode
+pub struct Output {
+    mode: OutputMode,
+    strip_ansi: bool,
+    start: Instant,
+    /// Number of bytes printed in hex mode
+    offset: usize,
+    /// Start of a utf-8 character that was split between reads
+    partial: Vec<u8>,
+    escape: Escape,
+    /// Lines matching these patterns are printed with the escape code for their color
+    highlights: Vec<(Regex, &'static str)>,
+    /// Incomplete line that's held back in text mode until it's known if it needs highlighting
+    lines: LineBuffer,
+}
+
+impl Output {
+    pub fn new(mode: OutputMode, strip_ansi: bool, highlights: Vec<(Regex, &'static str)>) -> Self {
+        Output {
+            mode,
+            strip_ansi,
+            start: Instant::now(),
+            offset: 0,
+            partial: Vec::new(),
+            escape: Escape::None,
+            highlights,
+            lines: LineBuffer::default(),
+        }
+    }
+
+    pub fn write(&mut self, out: &mut impl Write, data: &[u8]) -> Result<()> {
+        match self.mode {
+            OutputMode::Raw => out.write_all(data),
+            OutputMode::Text => self.write_text(out, data),
+            OutputMode::Hex => self.write_hex(out, data),
+        }
+    }
+
+    fn write_text(&mut self, out: &mut impl Write, data: &[u8]) -> Result<()> {
+        let mut data = if self.strip_ansi {
+            self.strip(data)
+        } else {
+            data.to_vec()
+        };
+        if !self.partial.is_empty() {
+            data.splice(0..0, self.partial.drain(..));
+        }
+
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            match from_utf8(rest) {
+                Ok(text) => {
+                    self.write_str(out, text)?;
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    // the bytes up to the error are valid utf-8
+                    self.write_str(out, from_utf8(valid).unwrap_or_default())?;
+                    match e.error_len() {
+                        Some(len) => {
+                            self.write_str(out, &char::REPLACEMENT_CHARACTER.to_string())?;
+                            rest = &invalid[len..];
+                        }
+                        // the character continues in the next read
+                        None => {
+                            self.partial = invalid.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_str(&mut self, out: &mut impl Write, text: &str) -> Result<()> {
+        if self.highlights.is_empty() {
+            return out.write_all(text.as_bytes());
+        }
+
+        for line in self.lines.feed(text.as_bytes()) {
+            self.write_line(out, &line)?;
+        }
+        Ok(())
+    }
+
+    /// Print the line that's held back for highlighting, when no more output is coming for now
+    pub fn flush_line(&mut self, out: &mut impl Write) -> Result<()> {
+        let line = self.lines.take();
+        self.write_line(out, &line)
+    }
+
+    fn write_line(&self, out: &mut impl Write, line: &[u8]) -> Result<()> {
+        // lines are split from valid utf-8 at line endings, so they're valid utf-8 as well
+        let line = from_utf8(line).unwrap_or_default();
+        let color = self
+            .highlights
+            .iter()
+            .find(|(pattern, _)| pattern.is_match(&plain(line)))
+            .map(|(_, color)| *color);
+        let content = line.trim_end_matches(&['\r', '\n'][..]);
+        match color {
+            Some(color) => {
+                write!(out, "{}{}\x1b[0m", color, content)?;
+                out.write_all(&line.as_bytes()[content.len()..])
+            }
+            None => out.write_all(line.as_bytes()),
+        }
+    }
+
+    fn strip(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut stripped = Vec::with_capacity(data.len());
+        for &byte in data {
+            self.escape = match (self.escape, byte) {
+                (Escape::None, 0x1b) => Escape::Start,
+                (Escape::None, _) => {
+                    stripped.push(byte);
+                    Escape::None
+                }
+                (Escape::Start, b'[') => Escape::Csi,
+                (Escape::Csi, 0x40..=0x7e) => Escape::None,
+                (Escape::Csi, _) => Escape::Csi,
+                // two byte escape sequence
+                (Escape::Start, _) => Escape::None,
+            };
+        }
+        stripped
+    }
+
+    fn write_hex(&mut self, out: &mut impl Write, data: &[u8]) -> Result<()> {
+        let time = self.start.elapsed();
+        for row in data.chunks(16) {
+            write!(
+                out,
+                "[{:>5}.{:03}] {:08x}: ",
+                time.as_secs(),
+                time.subsec_millis(),
+                self.offset
+            )?;
+            for byte in row {
+                write!(out, "{:02x} ", byte)?;
+            }
+            let ascii: String = row
+                .iter()
+                .map(|&byte| match byte {
+                    0x20..=0x7e => byte as char,
+                    _ => '.',
+                })
+                .collect();
+            writeln!(
+                out,
+                "{:width$}|{}|",
+                "",
+                ascii,
+                width = (16 - row.len()) * 3
+            )?;
+            self.offset += row.len();
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_output_highlight() {
+    let highlights = vec![(Regex::new("PASSED").unwrap(), "\x1b[32m")];
+    let mut output = Output::new(OutputMode::Text, false, highlights);
+    let mut out = Vec::new();
+    output.write(&mut out, b"test 1 PAS").unwrap();
+    assert!(out.is_empty());
+    output.write(&mut out, b"SED\r\ntest 2 \xe2\x9c").unwrap();
+    assert_eq!(b"\x1b[32mtest 1 PASSED\x1b[0m\r\n".to_vec(), out);
+    output.write(&mut out, b"\x93").unwrap();
+    output.flush_line(&mut out).unwrap();
+    assert!(out.ends_with("test 2 \u{2713}".as_bytes()));
+}
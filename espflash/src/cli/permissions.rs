@@ -0,0 +1,137 @@
+use std::fs::{read_dir, read_to_string, OpenOptions};
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+
+use thiserror::Error;
+
+/// A udev rule giving the logged in user access to the usb serial adapters commonly found on
+/// esp boards, and stopping ModemManager from probing them
+pub const UDEV_RULE: &str = r#"# Serial adapters used on esp8266 and esp32 boards, install as /etc/udev/rules.d/99-espflash.rules
+# CP210x
+SUBSYSTEM=="tty", ATTRS{idVendor}=="10c4", ATTRS{idProduct}=="ea60", TAG+="uaccess", ENV{ID_MM_DEVICE_IGNORE}="1"
+# CH340 and CH9102
+SUBSYSTEM=="tty", ATTRS{idVendor}=="1a86", ATTRS{idProduct}=="7523", TAG+="uaccess", ENV{ID_MM_DEVICE_IGNORE}="1"
+SUBSYSTEM=="tty", ATTRS{idVendor}=="1a86", ATTRS{idProduct}=="55d4", TAG+="uaccess", ENV{ID_MM_DEVICE_IGNORE}="1"
+# FTDI FT232R, FT2232 and FT232H
+SUBSYSTEM=="tty", ATTRS{idVendor}=="0403", ATTRS{idProduct}=="6001", TAG+="uaccess", ENV{ID_MM_DEVICE_IGNORE}="1"
+SUBSYSTEM=="tty", ATTRS{idVendor}=="0403", ATTRS{idProduct}=="6010", TAG+="uaccess", ENV{ID_MM_DEVICE_IGNORE}="1"
+SUBSYSTEM=="tty", ATTRS{idVendor}=="0403", ATTRS{idProduct}=="6014", TAG+="uaccess", ENV{ID_MM_DEVICE_IGNORE}="1"
+# Espressif native usb
+SUBSYSTEM=="tty", ATTRS{idVendor}=="303a", TAG+="uaccess", ENV{ID_MM_DEVICE_IGNORE}="1"
+"#;
+
+/// The serial port exists, but can't be opened by the current user
+#[derive(Debug, Error)]
+#[error("No permission to open {port}")]
+pub struct PermissionDenied {
+    port: String,
+}
+
+/// Check whether a serial port failed to open because of its permissions, or because another
+/// process is using it, and explain how to fix it
+pub fn diagnose(port: &str) -> Option<(PermissionDenied, String)> {
+    let error = OpenOptions::new().read(true).write(true).open(port).err()?;
+
+    let mut advice = Vec::new();
+    match error.kind() {
+        ErrorKind::PermissionDenied => {
+            let gid = std::fs::metadata(port).ok()?.gid();
+            let group = group_name(gid).unwrap_or_else(|| gid.to_string());
+            let user = std::env::var("USER").unwrap_or_else(|_| String::from("$USER"));
+
+            if process_groups().contains(&gid) {
+                advice.push(format!(
+                    "{} is owned by the `{}` group, which you are in, but the group has no access",
+                    port, group
+                ));
+            } else if group_members(gid).contains(&user) {
+                advice.push(format!(
+                    "you were added to the `{}` group, but have to log out and back in for it to take effect",
+                    group
+                ));
+            } else {
+                advice.push(format!(
+                    "{} is owned by the `{}` group, add yourself to it with `sudo usermod -a -G {} {}` \
+                    and log out and back in",
+                    port, group, group, user
+                ));
+            }
+        }
+        ErrorKind::ResourceBusy => advice.push(format!("{} is in use by another program", port)),
+        _ => return None,
+    }
+
+    if modem_manager_running() {
+        advice.push(String::from(
+            "ModemManager is running and might be probing the port, \
+            stop it with `sudo systemctl stop ModemManager`",
+        ));
+    }
+    advice.push(String::from(
+        "alternatively install a udev rule for the serial adapter with \
+        `espflash udev-rule | sudo tee /etc/udev/rules.d/99-espflash.rules`",
+    ));
+
+    Some((
+        PermissionDenied {
+            port: port.to_string(),
+        },
+        advice.join(", "),
+    ))
+}
+
+/// Find the name of a group in /etc/group
+fn group_name(gid: u32) -> Option<String> {
+    group_entry(gid).map(|(name, _)| name)
+}
+
+fn group_members(gid: u32) -> Vec<String> {
+    group_entry(gid)
+        .map(|(_, members)| members)
+        .unwrap_or_default()
+}
+
+fn group_entry(gid: u32) -> Option<(String, Vec<String>)> {
+    let groups = read_to_string("/etc/group").ok()?;
+    groups.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.as_slice() {
+            [name, _, id, members] if id.parse() == Ok(gid) => Some((
+                name.to_string(),
+                members
+                    .split(',')
+                    .filter(|member| !member.is_empty())
+                    .map(String::from)
+                    .collect(),
+            )),
+            _ => None,
+        }
+    })
+}
+
+/// The groups the current process is in
+fn process_groups() -> Vec<u32> {
+    let status = read_to_string("/proc/self/status").unwrap_or_default();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| {
+            groups
+                .split_whitespace()
+                .filter_map(|gid| gid.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn modem_manager_running() -> bool {
+    let processes = match read_dir("/proc") {
+        Ok(processes) => processes,
+        Err(_) => return false,
+    };
+    processes.filter_map(Result::ok).any(|process| {
+        read_to_string(process.path().join("comm"))
+            .map(|name| name.trim() == "ModemManager")
+            .unwrap_or_default()
+    })
+}
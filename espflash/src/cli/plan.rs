@@ -0,0 +1,204 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::read;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use color_eyre::{eyre::eyre, eyre::WrapErr, Report};
+use serde::Deserialize;
+
+use crate::{Session, FLASH_SECTOR_SIZE};
+
+/// A sequence of operations to perform on a device
+///
+/// ```toml
+/// [[step]]
+/// action = "erase"
+/// offset = 0x9000
+/// size = 0x6000
+///
+/// [[step]]
+/// action = "write-bin"
+/// offset = 0x10000
+/// file = "app.bin"
+///
+/// [[step]]
+/// action = "run"
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Plan {
+    #[serde(rename = "step", default)]
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+enum Step {
+    /// Erase a region of the flash
+    Erase { offset: u32, size: u32 },
+    /// Write a binary file to the flash
+    WriteBin { offset: u32, file: PathBuf },
+    /// Write an elf image to the flash
+    WriteElf { file: PathBuf },
+    /// Check the flash against a binary file at `offset`, or an elf image
+    Verify { offset: Option<u32>, file: PathBuf },
+    /// Reset the chip into the application
+    Run,
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Erase { offset, size } => {
+                write!(f, "erase {:#x}..{:#x}", offset, offset + size)
+            }
+            Step::WriteBin { offset, file } => {
+                write!(f, "write {} at {:#x}", file.display(), offset)
+            }
+            Step::WriteElf { file } => write!(f, "write elf image {}", file.display()),
+            Step::Verify { offset: None, file } => write!(f, "verify {}", file.display()),
+            Step::Verify {
+                offset: Some(offset),
+                file,
+            } => write!(f, "verify {} at {:#x}", file.display(), offset),
+            Step::Run => write!(f, "run"),
+        }
+    }
+}
+
+impl Plan {
+    /// Load a plan from a toml file
+    ///
+    /// Relative file paths in the plan are resolved relative to the plan file.
+    pub fn load(path: &Path) -> Result<Self, Report> {
+        let data = read(path).wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        let mut plan: Plan = toml::from_slice(&data)
+            .wrap_err_with(|| format!("Failed to parse {}", path.display()))?;
+
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        for step in plan.steps.iter_mut() {
+            match step {
+                Step::WriteBin { file, .. }
+                | Step::WriteElf { file }
+                | Step::Verify { file, .. } => *file = base.join(&*file),
+                _ => {}
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// Check if any step writes to the given flash region
+    pub fn writes_to(&self, region: std::ops::Range<u32>) -> bool {
+        self.steps.iter().any(|step| match step {
+            Step::Erase { offset, size } => *offset < region.end && offset + size > region.start,
+            Step::WriteBin { offset, .. } => *offset < region.end,
+            Step::WriteElf { .. } => true,
+            Step::Verify { .. } | Step::Run => false,
+        })
+    }
+
+    /// Execute all steps in order, printing a report of the progress
+    ///
+    /// All steps are validated and all files are loaded before anything is written to the device,
+    /// execution stops at the first failing step. The chip stays in the bootloader between the steps.
+    pub fn execute(&self, session: &mut Session) -> Result<(), Report> {
+        let flash_size = session.flash_size_in_bytes()?;
+        let files = self
+            .steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| {
+                self.prepare(step, flash_size)
+                    .wrap_err(format!("Step {}", i + 1))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let count = self.steps.len();
+        for (i, (step, data)) in self.steps.iter().zip(files).enumerate() {
+            eprintln!("[{}/{}] {}", i + 1, count, step);
+            let start = Instant::now();
+
+            let result = match step {
+                Step::Erase { offset, size } => session.erase_region(*offset, *size),
+                Step::WriteBin { offset, .. } => {
+                    session.write_bin_to_flash(*offset, &data).map(drop)
+                }
+                Step::WriteElf { .. } => session.load_elf_to_flash(&data).map(drop),
+                Step::Verify {
+                    offset: Some(offset),
+                    ..
+                } => session.verify_bin(*offset, &data),
+                Step::Verify { offset: None, .. } => session.verify_elf(&data),
+                Step::Run => session.hard_reset(),
+            };
+
+            if let Err(e) = result {
+                eprintln!(
+                    "[{}/{}] failed, {} step(s) skipped",
+                    i + 1,
+                    count,
+                    count - i - 1
+                );
+                return Err(Report::new(e).wrap_err(format!("Step {} ({}) failed", i + 1, step)));
+            }
+            eprintln!(
+                "[{}/{}] done in {:.1}s",
+                i + 1,
+                count,
+                start.elapsed().as_secs_f32()
+            );
+        }
+
+        eprintln!("All {} step(s) completed", count);
+        Ok(())
+    }
+
+    /// Validate a step and load the file it needs
+    fn prepare(&self, step: &Step, flash_size: u32) -> Result<Vec<u8>, Report> {
+        match step {
+            Step::Erase { offset, size } => {
+                let sector_size = FLASH_SECTOR_SIZE as u32;
+                if offset % sector_size != 0 || size % sector_size != 0 {
+                    return Err(eyre!(
+                        "erase offset and size must be multiples of the sector size ({:#x})",
+                        FLASH_SECTOR_SIZE
+                    ));
+                }
+                check_bounds(*offset, *size, flash_size)?;
+                Ok(Vec::new())
+            }
+            Step::WriteBin { offset, file } => {
+                let data =
+                    read(file).wrap_err_with(|| format!("Failed to read {}", file.display()))?;
+                check_bounds(*offset, data.len() as u32, flash_size)?;
+                Ok(data)
+            }
+            Step::WriteElf { file } | Step::Verify { offset: None, file } => {
+                read(file).wrap_err_with(|| format!("Failed to read {}", file.display()))
+            }
+            Step::Verify {
+                offset: Some(offset),
+                file,
+            } => {
+                let data =
+                    read(file).wrap_err_with(|| format!("Failed to read {}", file.display()))?;
+                check_bounds(*offset, data.len() as u32, flash_size)?;
+                Ok(data)
+            }
+            Step::Run => Ok(Vec::new()),
+        }
+    }
+}
+
+fn check_bounds(offset: u32, size: u32, flash_size: u32) -> Result<(), Report> {
+    if offset as u64 + size as u64 > flash_size as u64 {
+        Err(eyre!(
+            "region {:#x}..{:#x} is outside of the flash ({:#x} bytes)",
+            offset,
+            offset as u64 + size as u64,
+            flash_size
+        ))
+    } else {
+        Ok(())
+    }
+}
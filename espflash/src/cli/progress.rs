@@ -0,0 +1,192 @@
+use std::fs::OpenOptions;
+use std::io::{stderr, stdout, Write};
+use std::sync::{Arc, Mutex};
+
+use color_eyre::{eyre::WrapErr, Report};
+use serde::Serialize;
+
+use crate::{Chip, ConnectionEvent, ProgressCallbacks};
+
+/// Reports progress as json lines, for IDE plugins that show their own progress instead of the progress bar
+///
+/// Every line is an object with the `stage` and the `port`:
+///
+/// - `{"stage":"connecting","port":"/dev/ttyUSB0"}`
+/// - `{"stage":"reset","port":"/dev/ttyUSB0"}`
+/// - `{"stage":"synced","port":"/dev/ttyUSB0"}`
+/// - `{"stage":"chip-detected","port":"/dev/ttyUSB0","chip":"esp32"}`
+/// - `{"stage":"baud-changed","port":"/dev/ttyUSB0","baud":921600}`
+/// - `{"stage":"connected","port":"/dev/ttyUSB0","chip":"esp32"}`
+/// - `{"stage":"stub-loaded","port":"/dev/ttyUSB0"}`
+/// - `{"stage":"writing","port":"/dev/ttyUSB0","address":"0x10000","percent":42}`
+/// - `{"stage":"written","port":"/dev/ttyUSB0","address":"0x10000"}`
+///
+/// Whether the command succeeded is reported by the exit code.
+#[derive(Clone)]
+pub struct MachineProgress {
+    output: Arc<Mutex<Box<dyn Write + Send>>>,
+    port: String,
+    addr: u32,
+    total: usize,
+    percent: Option<usize>,
+}
+
+impl MachineProgress {
+    /// Report the progress of the connection to `port` to `target`: `stdout`, `stderr` or a file
+    ///
+    /// On unix an inherited file descriptor can be used as the file, like `/dev/fd/3`.
+    pub fn open(target: &str, port: &str) -> Result<Self, Report> {
+        let output: Box<dyn Write + Send> = match target {
+            "stdout" => Box::new(stdout()),
+            "stderr" => Box::new(stderr()),
+            path => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .wrap_err_with(|| format!("Failed to open the progress output \"{}\"", path))?,
+            ),
+        };
+        Ok(MachineProgress {
+            output: Arc::new(Mutex::new(output)),
+            port: port.to_string(),
+            addr: 0,
+            total: 0,
+            percent: None,
+        })
+    }
+
+    pub fn connecting(&self) {
+        self.report("connecting", Fields::default());
+    }
+
+    pub fn connected(&self, chip: Chip) {
+        self.report("connected", Fields::chip(chip));
+    }
+
+    /// Report a milestone of the connection, see `ConnectOptions::event_hook`
+    pub fn event(&self, event: ConnectionEvent) {
+        match event {
+            ConnectionEvent::Reset => self.report("reset", Fields::default()),
+            ConnectionEvent::Synced => self.report("synced", Fields::default()),
+            ConnectionEvent::ChipDetected(chip) => self.report("chip-detected", Fields::chip(chip)),
+            ConnectionEvent::StubLoaded => self.report("stub-loaded", Fields::default()),
+            ConnectionEvent::BaudChanged(baud) => self.report(
+                "baud-changed",
+                Fields {
+                    baud: Some(baud),
+                    ..Fields::default()
+                },
+            ),
+        }
+    }
+
+    fn report(&self, stage: &str, fields: Fields) {
+        let line = Line {
+            stage,
+            port: &self.port,
+            fields,
+        };
+        // serializing strings and numbers doesn't fail
+        let json = serde_json::to_string(&line).unwrap();
+        let mut output = self.output.lock().unwrap();
+        // the tool keeps working when the reader went away, it still has the exit code
+        let _ = writeln!(output, "{}", json);
+        let _ = output.flush();
+    }
+}
+
+impl ProgressCallbacks for MachineProgress {
+    fn init(&mut self, addr: u32, total: usize) {
+        self.addr = addr;
+        self.total = total;
+        self.percent = None;
+        self.update(0);
+    }
+
+    fn update(&mut self, current: usize) {
+        let percent = (current * 100).checked_div(self.total).unwrap_or(100);
+        // only report changes, a large image has thousands of blocks
+        if self.percent != Some(percent) {
+            self.percent = Some(percent);
+            self.report(
+                "writing",
+                Fields {
+                    address: Some(format!("{:#x}", self.addr)),
+                    percent: Some(percent),
+                    ..Fields::default()
+                },
+            );
+        }
+    }
+
+    fn finish(&mut self) {
+        self.report(
+            "written",
+            Fields {
+                address: Some(format!("{:#x}", self.addr)),
+                ..Fields::default()
+            },
+        );
+    }
+}
+
+/// A line of progress
+#[derive(Serialize)]
+struct Line<'a> {
+    stage: &'a str,
+    port: &'a str,
+    #[serde(flatten)]
+    fields: Fields,
+}
+
+/// The fields that are only reported for some stages
+#[derive(Serialize, Default)]
+struct Fields {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    baud: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percent: Option<usize>,
+}
+
+impl Fields {
+    fn chip(chip: Chip) -> Self {
+        Fields {
+            chip: Some(format!("{:?}", chip).to_lowercase()),
+            ..Fields::default()
+        }
+    }
+}
+
+#[test]
+fn test_machine_progress() {
+    use std::fs::{read_to_string, remove_file};
+
+    let path = std::env::temp_dir().join(format!("espflash-progress-{}", std::process::id()));
+    let mut progress = MachineProgress::open(path.to_str().unwrap(), "COM\"3\\").unwrap();
+    progress.connecting();
+    progress.event(ConnectionEvent::BaudChanged(921600));
+    progress.init(0x10000, 4);
+    progress.update(2);
+    progress.finish();
+    let output = read_to_string(&path).unwrap();
+    remove_file(&path).unwrap();
+
+    let lines: Vec<serde_json::Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(5, lines.len());
+    assert_eq!("connecting", lines[0]["stage"]);
+    assert_eq!("COM\"3\\", lines[0]["port"]);
+    assert!(lines[0].get("chip").is_none());
+    assert_eq!(921600, lines[1]["baud"]);
+    assert_eq!("0x10000", lines[3]["address"]);
+    assert_eq!(50, lines[3]["percent"]);
+    assert_eq!("written", lines[4]["stage"]);
+    assert!(output.starts_with("{\"stage\":\"connecting\",\"port\":"));
+}
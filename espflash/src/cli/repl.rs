@@ -0,0 +1,146 @@
+use std::convert::TryFrom;
+use std::fs::write;
+use std::io::{stderr, stdin, BufRead, Write};
+
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Report,
+};
+
+use super::{confirm, parse_u32};
+use crate::Flasher;
+
+/// The spi peripheral sends at most 16 data words, minus the byte of the command
+const MAX_SPI_DATA: usize = 63;
+
+const HELP: &str = "\
+Commands:
+    readreg <addr>                          Read a register
+    writereg <addr> <value> [mask]          Write a register
+    spi <command> <read bytes> [data...]    Run a spi flash command with up to 63 data bytes, reading up to 4 bytes
+    readflash <offset> <size> [file]        Read a region of the flash, printing it or saving it to a file
+    erase <offset> <size>                   Erase a region of the flash
+    help                                    Show this help
+    quit                                    Exit";
+
+/// Run an interactive session for inspecting and modifying the connected chip
+pub fn repl(flasher: &mut Flasher) -> Result<(), Report> {
+    eprintln!(
+        "Connected to {:?}, type `help` for the available commands",
+        flasher.chip()
+    );
+
+    let stdin = stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        eprint!("> ");
+        stderr().flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        let args: Vec<&str> = line.split_whitespace().collect();
+
+        match args.first() {
+            None => {}
+            Some(&"quit") | Some(&"exit") => return Ok(()),
+            Some(&"help") => println!("{}", HELP),
+            Some(_) => {
+                if let Err(e) = run_command(flasher, &args) {
+                    eprintln!("Error: {:#}", e);
+                }
+            }
+        }
+    }
+}
+
+fn run_command(flasher: &mut Flasher, args: &[&str]) -> Result<(), Report> {
+    match args {
+        ["readreg", addr] => {
+            let value = flasher.read_reg(number(addr)?)?;
+            println!("{:#010x}", value);
+        }
+        ["writereg", addr, value] => flasher.write_reg(number(addr)?, number(value)?, None)?,
+        ["writereg", addr, value, mask] => {
+            flasher.write_reg(number(addr)?, number(value)?, Some(number(mask)?))?
+        }
+        ["spi", command, read, data @ ..] => {
+            let command = u8::try_from(number(command)?).wrap_err("Invalid spi command")?;
+            let read = number(read)?;
+            if read > 4 {
+                bail!("at most 4 bytes can be read");
+            }
+            let data = data
+                .iter()
+                .map(|byte| Ok(u8::try_from(number(byte)?)?))
+                .collect::<Result<Vec<u8>, Report>>()
+                .wrap_err("Invalid data byte")?;
+            if data.len() > MAX_SPI_DATA {
+                bail!("at most {} data bytes can be sent", MAX_SPI_DATA);
+            }
+
+            let result = flasher.spi_command(command, &data, read * 8)?;
+            if read > 0 {
+                println!("{}", hex(&result.to_le_bytes()[..read as usize]));
+            }
+        }
+        ["readflash", offset, size] => {
+            let offset = number(offset)?;
+            let data = flasher.read_flash(offset, number(size)?)?;
+            print!("{}", hexdump(offset, &data));
+        }
+        ["readflash", offset, size, file] => {
+            let data = flasher.read_flash(number(offset)?, number(size)?)?;
+            write(file, &data).wrap_err_with(|| format!("Failed to write {}", file))?;
+            println!("Saved {} bytes to {}", data.len(), file);
+        }
+        ["erase", offset, size] => {
+            let (offset, size) = (number(offset)?, number(size)?);
+            let boot_region = flasher.chip().boot_region();
+            if offset < boot_region.end && offset as u64 + size as u64 > boot_region.start as u64 {
+                confirm("erase the bootloader region of the flash", false)?;
+            }
+            flasher.erase_region(offset, size)?;
+        }
+        _ => bail!("invalid command, type `help` for the available commands"),
+    }
+
+    Ok(())
+}
+
+fn number(input: &str) -> Result<u32, Report> {
+    parse_u32(input).map_err(|_| eyre!("invalid number \"{}\"", input))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn hexdump(offset: u32, data: &[u8]) -> String {
+    data.chunks(16)
+        .enumerate()
+        .map(|(i, line)| format!("{:08x}  {}\n", offset as usize + i * 16, hex(line)))
+        .collect()
+}
+
+#[test]
+fn test_spi_command() {
+    use crate::testing::MockTransport;
+    use crate::{Chip, FlashSize};
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(simulator, None).unwrap();
+    run_command(&mut flasher, &["spi", "0x9f", "3"]).unwrap();
+    assert!(run_command(&mut flasher, &["spi", "0x9f", "5"]).is_err());
+
+    let mut args = vec!["spi", "0x02", "0"];
+    args.extend(std::iter::repeat_n("0", MAX_SPI_DATA));
+    run_command(&mut flasher, &args).unwrap();
+    args.push("0");
+    let error = run_command(&mut flasher, &args).unwrap_err();
+    assert_eq!("at most 63 data bytes can be sent", error.to_string());
+}
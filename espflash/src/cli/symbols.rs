@@ -0,0 +1,34 @@
+use regex::Regex;
+
+use crate::elf::Symbols;
+
+/// Prints the functions for the code addresses in the output, like the ones in esp-idf backtraces
+pub struct Symbolizer {
+    address: Regex,
+}
+
+impl Default for Symbolizer {
+    fn default() -> Self {
+        Symbolizer {
+            address: Regex::new("0x[0-9a-fA-F]{8}").unwrap(),
+        }
+    }
+}
+
+impl Symbolizer {
+    /// Print the functions for the addresses in a line of output
+    pub fn line(&self, line: &str, symbols: &Symbols) {
+        if symbols.is_empty() {
+            return;
+        }
+        for address in self.address.find_iter(line) {
+            let addr = match u32::from_str_radix(&address.as_str()[2..], 16) {
+                Ok(addr) => addr,
+                Err(_) => continue,
+            };
+            if let Some((name, offset)) = symbols.lookup(addr) {
+                eprintln!("    {:#010x}: {}+{:#x}", addr, name, offset);
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::fs::{metadata, read_dir};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Watches files and directories for changes by polling their modification times
+pub struct Watcher {
+    paths: Vec<PathBuf>,
+    last_modified: Option<SystemTime>,
+    last_check: Instant,
+}
+
+impl Watcher {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        let last_modified = latest_modification(&paths);
+        Watcher {
+            paths,
+            last_modified,
+            last_check: Instant::now(),
+        }
+    }
+
+    /// Check if any of the watched paths changed since the last call
+    ///
+    /// Checks are rate limited, so this can be called in a loop. When a change is found this waits
+    /// until the files stop changing, so a build that is still writing its output isn't picked up
+    /// halfway.
+    pub fn changed(&mut self) -> bool {
+        if self.last_check.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.last_check = Instant::now();
+
+        let mut modified = latest_modification(&self.paths);
+        if modified == self.last_modified {
+            return false;
+        }
+
+        loop {
+            sleep(POLL_INTERVAL);
+            let current = latest_modification(&self.paths);
+            if current == modified {
+                break;
+            }
+            modified = current;
+        }
+
+        self.last_modified = modified;
+        true
+    }
+
+    /// Block until any of the watched paths changes
+    pub fn wait(&mut self) {
+        while !self.changed() {
+            sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Find the most recent modification time of the paths, including the contents of directories
+fn latest_modification(paths: &[PathBuf]) -> Option<SystemTime> {
+    paths.iter().filter_map(|path| modified(path)).max()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    let meta = metadata(path).ok()?;
+    let own = meta.modified().ok();
+    if !meta.is_dir() {
+        return own;
+    }
+
+    let children = read_dir(path)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| modified(&entry.path()));
+    own.into_iter().chain(children).max()
+}
@@ -0,0 +1,116 @@
+use std::fs::read_to_string;
+use std::path::Path;
+
+/// The version of the windows subsystem for linux, which handle serial ports differently
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Wsl {
+    /// Windows serial ports are available as `/dev/ttyS<n>` for `COM<n>`
+    V1,
+    /// A virtual machine without access to usb devices, unless they're attached with usbipd-win
+    V2,
+}
+
+/// Detect whether we're running under wsl from the kernel release
+pub fn detect() -> Option<Wsl> {
+    let release = read_to_string("/proc/sys/kernel/osrelease").ok()?;
+    let lower = release.to_ascii_lowercase();
+    if lower.contains("wsl2") || lower.contains("microsoft-standard") {
+        Some(Wsl::V2)
+    } else if lower.contains("microsoft") {
+        Some(Wsl::V1)
+    } else {
+        None
+    }
+}
+
+/// The linux device for a windows port name like `COM3`, which wsl 1 makes available as `/dev/ttyS3`
+pub fn translate_port(port: &str, wsl: Wsl) -> Option<String> {
+    let number: u32 = port
+        .to_ascii_uppercase()
+        .strip_prefix("COM")?
+        .parse()
+        .ok()?;
+    match wsl {
+        Wsl::V1 => Some(format!("/dev/ttyS{}", number)),
+        Wsl::V2 => None,
+    }
+}
+
+/// The ttyS devices of the windows serial ports under wsl 1
+///
+/// `driver` is the content of `/proc/tty/driver/serial`, ports without a uart in it don't belong to a COM port.
+/// When it can't be read, the ttyS devices that exist in `dev` are used. The ports aren't opened, which would
+/// toggle dtr and rts and reset the attached boards.
+pub fn v1_ports(driver: Option<&str>, dev: &Path) -> Vec<String> {
+    match driver {
+        Some(driver) => driver
+            .lines()
+            .filter_map(|line| {
+                let (number, info) = line.split_once(':')?;
+                let number: u32 = number.trim().parse().ok()?;
+                let uart = info
+                    .split_whitespace()
+                    .find_map(|field| field.strip_prefix("uart:"))?;
+                Some(number).filter(|&number| number > 0 && uart != "unknown")
+            })
+            .map(|number| format!("/dev/ttyS{}", number))
+            .collect(),
+        None => (1..=32)
+            .map(|number| format!("ttyS{}", number))
+            .filter(|name| dev.join(name).exists())
+            .map(|name| format!("/dev/{}", name))
+            .collect(),
+    }
+}
+
+/// Explain why `port` can't be found under wsl, `ports` are the serial ports that were found
+pub fn missing_port_advice(port: &str, wsl: Wsl, ports: &[String]) -> String {
+    let mut advice = Vec::new();
+    if !ports.is_empty() {
+        advice.push(format!("the serial ports found are {}", ports.join(", ")));
+    }
+    match wsl {
+        Wsl::V1 => advice.push(String::from(
+            "wsl 1 makes windows port COM<n> available as /dev/ttyS<n>, \
+            check the port number in the windows device manager",
+        )),
+        Wsl::V2 => {
+            if port.to_ascii_uppercase().starts_with("COM") {
+                advice.push(String::from(
+                    "windows COM ports aren't available under wsl 2, attach the usb device instead",
+                ));
+            }
+            advice.push(String::from(
+                "wsl 2 doesn't see usb devices until they're attached from windows with usbipd-win: \
+                run `usbipd list` to find the busid of the serial adapter and \
+                `usbipd attach --wsl --busid <busid>`, after which it shows up as /dev/ttyUSB<n> or /dev/ttyACM<n>",
+            ));
+            advice.push(String::from(
+                "the device has to be attached again after it's unplugged or re-enumerated, \
+                `usbipd attach --auto-attach` does so automatically",
+            ));
+        }
+    }
+    advice.join(", ")
+}
+
+#[test]
+fn test_v1_ports() {
+    let driver = "serinfo:1.0 driver revision:\n\
+        0: uart:16550A port:000003F8 irq:4 tx:0 rx:0\n\
+        1: uart:unknown port:000002F8 irq:3\n\
+        3: uart:16550A port:000002E8 irq:3 tx:12 rx:40 RTS|DTR\n";
+    assert_eq!(
+        vec!["/dev/ttyS3"],
+        v1_ports(Some(driver), Path::new("/nonexistent"))
+    );
+
+    let dev = std::env::temp_dir().join(format!("espflash-wsl-{}", std::process::id()));
+    std::fs::create_dir_all(&dev).unwrap();
+    for name in ["ttyS0", "ttyS4", "ttyS12", "ttyUSB0"] {
+        std::fs::write(dev.join(name), b"").unwrap();
+    }
+    let ports = v1_ports(None, &dev);
+    std::fs::remove_dir_all(&dev).unwrap();
+    assert_eq!(vec!["/dev/ttyS4", "/dev/ttyS12"], ports);
+}
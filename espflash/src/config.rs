@@ -8,16 +8,69 @@ pub struct Config {
     pub connection: Connection,
     #[serde(default)]
     pub build: Build,
+    #[serde(default)]
+    pub monitor: Monitor,
 }
 
 #[derive(Debug, Deserialize, Default)]
 pub struct Connection {
     pub serial: Option<String>,
+    /// Number of seconds to wait for the serial port to appear
+    pub wait: Option<u64>,
+    /// Number of times a block that failed to write is retried
+    pub retries: Option<usize>,
+    /// Use rts/cts flow control
+    #[serde(default)]
+    pub flow_control: bool,
+    /// Number of bytes to write before pausing for `pacing_delay_us`
+    pub pacing_chunk_size: Option<usize>,
+    /// Microseconds to pause after every `pacing_chunk_size` bytes
+    pub pacing_delay_us: Option<u64>,
+    /// Print how long each kind of bootloader command took
+    #[serde(default)]
+    pub timings: bool,
+    /// Flasher stub in the esptool json format to load after connecting
+    pub stub: Option<String>,
+    /// The chip is already in the bootloader, don't reset it when connecting
+    #[serde(default)]
+    pub no_reset: bool,
+    /// The pins of the spi flash: `SPI`, `HSPI` or the `CLK,Q,D,HD,CS` gpio numbers, detected by default
+    pub spi_connection: Option<String>,
+    /// Divide the 80MHz spi flash clock by this while flashing, for boards where the default clock corrupts data
+    pub spi_clock_divider: Option<u8>,
+    /// Don't apply the settings for known usb serial adapters, like pacing the writes to CH340 adapters
+    #[serde(default)]
+    pub no_adapter_quirks: bool,
 }
 
 #[derive(Debug, Deserialize, Default)]
 pub struct Build {
     pub tool: Option<String>,
+    /// The sdkconfig of an esp-idf project to read the flash settings and partition table from
+    pub sdkconfig: Option<String>,
+    /// The bootloader to write instead of the bundled one, needed for sdkconfigs with other flash offsets
+    pub bootloader: Option<String>,
+    /// The size of the flash, like `4MB`, for the images and for flash chips that report an unknown size
+    pub flash_size: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Monitor {
+    /// Actions to take when the output matches a pattern
+    #[serde(default)]
+    pub hooks: Vec<Hook>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Hook {
+    /// Regular expression matched against every line of output
+    pub pattern: String,
+    /// Exit with this code when a line matches
+    pub exit: Option<i32>,
+    /// Command to run with the shell when a line matches
+    pub run: Option<String>,
+    /// Color to print matching lines in
+    pub highlight: Option<String>,
 }
 
 impl Config {
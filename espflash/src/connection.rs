@@ -1,17 +1,56 @@
-use std::io::Write;
-use std::thread::sleep;
-use std::time::Duration;
-
-use crate::encoder::SlipEncoder;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{sleep, spawn, yield_now, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::encoder::{SlipDecoder, SlipEncoder};
 use crate::error::{Error, RomError};
+use crate::flasher::Command;
+use crate::stats::FlashStats;
 use binread::io::Cursor;
 use binread::{BinRead, BinReaderExt};
-use serial::{BaudRate, SerialPort, SerialPortSettings};
-use slip_codec::Decoder;
+use serial::{BaudRate, FlowControl, SerialPort, SerialPortSettings};
+
+/// The duration and size of a single bootloader command
+#[derive(Copy, Clone, Debug)]
+pub struct CommandTiming {
+    pub command: Command,
+    /// Time from sending the command until the response was received
+    pub duration: Duration,
+    /// Bytes sent, including the slip framing
+    pub bytes_sent: usize,
+    /// Bytes received, after slip decoding
+    pub bytes_received: usize,
+}
+
+type CommandHook = Box<dyn FnMut(&CommandTiming) + Send>;
+
+/// How long a single read of the reader thread waits for data
+const READ_POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// Pause between reads that didn't receive anything, to hand the port to the connection
+const READ_IDLE_DELAY: Duration = Duration::from_micros(100);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(3);
+/// Frames are collected until this many bytes are waiting, or a response is read
+const WRITE_BUFFER_SIZE: usize = 0x1000;
 
 pub struct Connection {
-    serial: Box<dyn SerialPort>,
-    decoder: Decoder,
+    serial: SharedPort,
+    reader: Reader,
+    decoder: SlipDecoder,
+    baud: BaudRate,
+    capture: Option<Vec<u8>>,
+    flow_control: bool,
+    /// Frames that weren't written to the port yet
+    write_buffer: Vec<u8>,
+    /// Chunk size and delay between chunks when writing frames
+    pacing: Option<(usize, Duration)>,
+    command_hook: Option<CommandHook>,
+    /// The statistics of the running flash operation and when it started
+    stats: Option<(Instant, FlashStats)>,
+    bytes_sent: usize,
+    bytes_received: usize,
 }
 
 #[derive(Debug, Copy, Clone, BinRead)]
@@ -25,62 +64,112 @@ pub struct CommandResponse {
 }
 
 impl Connection {
-    pub fn new(serial: impl SerialPort + 'static) -> Self {
-        Connection {
-            serial: Box::new(serial),
-            decoder: Decoder::new(),
-        }
+    pub fn new(mut serial: impl SerialPort + Send + 'static) -> Result<Self, Error> {
+        serial.set_timeout(READ_POLL_INTERVAL)?;
+        let serial = SharedPort {
+            port: Arc::new(Mutex::new(Box::new(serial))),
+            waiting: Arc::new(AtomicUsize::new(0)),
+        };
+        Ok(Connection {
+            reader: Reader::spawn(serial.clone()),
+            serial,
+            decoder: SlipDecoder::new(),
+            baud: BaudRate::Baud115200,
+            capture: None,
+            flow_control: false,
+            write_buffer: Vec::with_capacity(WRITE_BUFFER_SIZE),
+            pacing: None,
+            command_hook: None,
+            stats: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+        })
     }
 
     pub fn reset(&mut self) -> Result<(), Error> {
+        self.send()?;
         sleep(Duration::from_millis(100));
 
-        self.serial.set_dtr(false)?;
-        self.serial.set_rts(true)?;
-
-        sleep(Duration::from_millis(100));
-
-        self.serial.set_rts(false)?;
-
-        Ok(())
+        self.set_flow_control(false)?;
+        hard_reset(self.serial.lock().as_mut())
     }
 
     pub fn reset_to_flash(&mut self) -> Result<(), Error> {
-        self.serial.set_dtr(false)?;
-        self.serial.set_rts(true)?;
+        // rts is used to reset the chip, so it can't be used for flow control at the same time
+        let flow_control = self.flow_control;
+        self.send()?;
+        self.set_flow_control(false)?;
+
+        self.serial.lock().set_dtr(false)?;
+        self.serial.lock().set_rts(true)?;
 
         sleep(Duration::from_millis(100));
 
-        self.serial.set_dtr(true)?;
-        self.serial.set_rts(false)?;
+        self.serial.lock().set_dtr(true)?;
+        self.serial.lock().set_rts(false)?;
 
         sleep(Duration::from_millis(50));
 
-        self.serial.set_dtr(true)?;
+        self.serial.lock().set_dtr(true)?;
+
+        self.set_flow_control(flow_control)
+    }
 
+    /// Enable or disable rts/cts flow control
+    pub fn set_flow_control(&mut self, enabled: bool) -> Result<(), Error> {
+        let flow_control = if enabled {
+            FlowControl::FlowHardware
+        } else {
+            FlowControl::FlowNone
+        };
+        self.serial
+            .lock()
+            .reconfigure(&|setup: &mut dyn SerialPortSettings| {
+                setup.set_flow_control(flow_control);
+                Ok(())
+            })?;
+        self.flow_control = enabled;
         Ok(())
     }
 
+    /// Write frames in chunks of `chunk_size` bytes, waiting `delay` after each chunk
+    pub fn set_pacing(&mut self, pacing: Option<(usize, Duration)>) {
+        self.pacing = pacing;
+    }
+
+    /// Call `hook` with the timing of every command
+    pub fn set_command_hook(&mut self, hook: Option<CommandHook>) {
+        self.command_hook = hook;
+    }
+
+    /// Set how long to wait for data from the chip
     pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
-        self.serial.set_timeout(timeout)?;
+        self.reader.timeout = timeout;
         Ok(())
     }
 
     pub fn set_baud(&mut self, speed: BaudRate) -> Result<(), Error> {
+        self.send()?;
         self.serial
+            .lock()
             .reconfigure(&|setup: &mut dyn SerialPortSettings| setup.set_baud_rate(speed))?;
+        self.baud = speed;
         Ok(())
     }
 
+    pub fn baud(&self) -> BaudRate {
+        self.baud
+    }
+
     pub fn with_timeout<T, F: FnMut(&mut Connection) -> Result<T, Error>>(
         &mut self,
         timeout: Duration,
         mut f: F,
     ) -> Result<T, Error> {
-        let old_timeout = self.serial.timeout();
-        self.serial.set_timeout(timeout)?;
+        let old_timeout = self.reader.timeout;
+        self.reader.timeout = timeout;
         let result = f(self);
-        self.serial.set_timeout(old_timeout)?;
+        self.reader.timeout = old_timeout;
         result
     }
 
@@ -90,58 +179,444 @@ impl Connection {
             return Ok(None);
         }
 
+        let length = response.len();
         let mut cursor = Cursor::new(response);
-        let header = cursor.read_le()?;
+        let header: CommandResponse = cursor.read_le()?;
 
+        // a frame that's shorter than its data was cut off
+        if length < 8 + header.return_length as usize {
+            return Ok(None);
+        }
         Ok(Some(header))
     }
 
     pub fn write_command(
         &mut self,
-        command: u8,
-        data: impl LazyBytes<Box<dyn SerialPort>>,
+        command: Command,
+        data: impl LazyBytes<Vec<u8>>,
         check: u32,
     ) -> Result<(), Error> {
-        let mut encoder = SlipEncoder::new(&mut self.serial)?;
+        // every byte might have to be escaped
+        let mut frame = Vec::with_capacity(2 * (8 + data.length() as usize) + 2);
+        let mut encoder = SlipEncoder::new(&mut frame)?;
         encoder.write(&[0])?;
-        encoder.write(&[command])?;
+        encoder.write(&[command as u8])?;
         encoder.write(&(data.length().to_le_bytes()))?;
         encoder.write(&(check.to_le_bytes()))?;
         data.write(&mut encoder)?;
         encoder.finish()?;
-        Ok(())
+        self.write_paced(&frame)
     }
 
-    pub fn command<Data: LazyBytes<Box<dyn SerialPort>>>(
+    pub fn command<Data: LazyBytes<Vec<u8>>>(
         &mut self,
-        command: u8,
+        command: Command,
         data: Data,
         check: u32,
     ) -> Result<u32, Error> {
-        self.write_command(command, data, check)?;
-
-        match self.read_response()? {
-            Some(response) if response.return_op == command as u8 => {
-                if response.status == 1 {
-                    Err(Error::RomError(RomError::from(response.error)))
-                } else {
-                    Ok(response.value)
+        self.timed(command, |connection| {
+            connection.write_command(command, data, check)?;
+            connection.read_command_response(command)
+        })
+    }
+
+    /// Wait for the response to `command` and return its value
+    ///
+    /// Frames that aren't a response to the command, like late responses to earlier commands, are skipped.
+    /// Together with [`write_command`](Self::write_command) this allows sending the next command before the
+    /// response to the previous one arrived.
+    pub fn read_command_response(&mut self, command: Command) -> Result<u32, Error> {
+        let deadline = Instant::now() + self.reader.timeout;
+        loop {
+            match self.read_response()? {
+                Some(response) if response.resp == 1 && response.return_op == command as u8 => {
+                    return if response.status == 1 {
+                        Err(Error::RomError(RomError::new(command, response.error)))
+                    } else {
+                        Ok(response.value)
+                    };
                 }
+                _ if Instant::now() < deadline => continue,
+                _ => return Err(Error::ConnectionFailed),
             }
-            _ => Err(Error::ConnectionFailed),
         }
     }
 
+    /// Send a command and return the data of the response
+    ///
+    /// The status bytes at the end of the response data, `status_length` bytes long, are checked and removed.
+    pub fn command_data<Data: LazyBytes<Vec<u8>>>(
+        &mut self,
+        command: Command,
+        data: Data,
+        check: u32,
+        status_length: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let mut response = self.timed(command, |connection| {
+            connection.write_command(command, data, check)?;
+            let deadline = Instant::now() + connection.reader.timeout;
+            loop {
+                let response = connection.read()?;
+                // skip stray frames, like late responses to earlier commands
+                if response.get(0..2) == Some(&[1, command as u8][..]) || Instant::now() >= deadline
+                {
+                    return Ok(response);
+                }
+            }
+        })?;
+        if response.len() < 8 + status_length || response[0] != 1 || response[1] != command as u8 {
+            return Err(Error::ConnectionFailed);
+        }
+
+        let length = u16::from_le_bytes([response[2], response[3]]) as usize;
+        response.drain(0..8);
+        if length < status_length || response.len() < length {
+            return Err(Error::ConnectionFailed);
+        }
+        response.truncate(length);
+
+        let status = response.split_off(length - status_length);
+        if status[0] == 1 {
+            Err(Error::RomError(RomError::new(command, status[1])))
+        } else {
+            Ok(response)
+        }
+    }
+
+    /// Run a command, reporting its timing to the command hook and the running statistics
+    pub(crate) fn timed<T>(
+        &mut self,
+        command: Command,
+        f: impl FnOnce(&mut Connection) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        if self.command_hook.is_none() && self.stats.is_none() {
+            return f(self);
+        }
+
+        let (sent, received) = (self.bytes_sent, self.bytes_received);
+        let start = Instant::now();
+        let result = f(self);
+        let timing = CommandTiming {
+            command,
+            duration: start.elapsed(),
+            bytes_sent: self.bytes_sent - sent,
+            bytes_received: self.bytes_received - received,
+        };
+        if let Some((_, stats)) = &mut self.stats {
+            stats.add(&timing);
+        }
+        if let Some(hook) = &mut self.command_hook {
+            hook(&timing);
+        }
+        result
+    }
+
+    /// Start collecting the statistics of a flash operation
+    pub(crate) fn start_stats(&mut self) {
+        self.stats = Some((Instant::now(), FlashStats::default()));
+    }
+
+    /// Count a flash block that is sent again
+    pub(crate) fn record_retry(&mut self) {
+        if let Some((_, stats)) = &mut self.stats {
+            stats.retries += 1;
+        }
+    }
+
+    /// Stop collecting statistics and return them
+    pub(crate) fn finish_stats(&mut self) -> FlashStats {
+        let (start, mut stats) = self
+            .stats
+            .take()
+            .unwrap_or((Instant::now(), FlashStats::default()));
+        stats.duration = start.elapsed();
+        stats.baud = self.baud.speed();
+        stats
+    }
+
+    /// Write a raw slip frame, outside of the command structure
+    pub fn write_frame(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut frame = Vec::with_capacity(2 * data.len() + 2);
+        let mut encoder = SlipEncoder::new(&mut frame)?;
+        encoder.write(data)?;
+        encoder.finish()?;
+        self.write_paced(&frame)
+    }
+
+    /// Queue a frame to be written
+    ///
+    /// Frames are written to the port in one go when a response is read, so commands sent without waiting
+    /// for the previous response end up in a single write instead of many small usb packets.
+    fn write_paced(&mut self, frame: &[u8]) -> Result<(), Error> {
+        self.bytes_sent += frame.len();
+        self.write_buffer.extend_from_slice(frame);
+        if self.pacing.is_some() || self.write_buffer.len() >= WRITE_BUFFER_SIZE {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    /// Write the queued frames to the port
+    fn send(&mut self) -> Result<(), Error> {
+        if self.write_buffer.is_empty() {
+            return Ok(());
+        }
+        let result = match self.pacing {
+            Some((chunk_size, delay)) => {
+                self.write_buffer
+                    .chunks(chunk_size.max(1))
+                    .try_for_each(|chunk| {
+                        self.serial.lock().write_all(chunk)?;
+                        sleep(delay);
+                        Ok(())
+                    })
+            }
+            None => self.serial.lock().write_all(&self.write_buffer),
+        };
+        self.write_buffer.clear();
+        result.map_err(Error::from)
+    }
+
+    /// Read a raw slip frame, outside of the command structure
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, Error> {
+        self.read()
+    }
+
     fn read(&mut self) -> Result<Vec<u8>, Error> {
-        let mut output = Vec::with_capacity(1024);
-        self.decoder.decode(&mut self.serial, &mut output)?;
+        self.send()?;
+        let frame = match &mut self.capture {
+            Some(capture) => {
+                let mut reader = CaptureReader {
+                    inner: &mut self.reader,
+                    capture,
+                };
+                self.decoder.decode(&mut reader)
+            }
+            None => self.decoder.decode(&mut self.reader),
+        }?;
+        self.bytes_received += frame.len();
+        Ok(frame)
+    }
+
+    /// Start recording all bytes received from the chip
+    pub fn start_capture(&mut self) {
+        self.capture = Some(Vec::new());
+    }
+
+    /// Stop recording and return the bytes received since [`start_capture`](Self::start_capture)
+    pub fn stop_capture(&mut self) -> Vec<u8> {
+        self.capture.take().unwrap_or_default()
+    }
+
+    /// Read everything the chip sends for `duration`, without slip decoding
+    pub fn read_raw(&mut self, duration: Duration) -> Result<Vec<u8>, Error> {
+        self.send()?;
+        let mut output = self.decoder.take_buffered();
+        let mut buff = [0; 256];
+        let start = Instant::now();
+        self.with_timeout(Duration::from_millis(10), |connection| {
+            while start.elapsed() < duration {
+                match connection.reader.read(&mut buff) {
+                    Ok(count) => output.extend_from_slice(&buff[..count]),
+                    Err(e) if e.kind() == ErrorKind::TimedOut => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Ok(())
+        })?;
         Ok(output)
     }
 
     pub fn flush(&mut self) -> Result<(), Error> {
-        self.serial.flush()?;
+        self.send()?;
+        self.serial.lock().flush()?;
         Ok(())
     }
+
+    /// Send several commands at once and return the values of their responses
+    ///
+    /// The commands are written together, saving a round trip for every command but the last. When a command
+    /// fails the responses to the commands after it are still read, so they aren't taken for the responses to
+    /// later commands, and the first error is returned.
+    pub fn commands(&mut self, commands: &[(Command, &[u8])]) -> Result<Vec<u32>, Error> {
+        for (command, data) in commands {
+            self.write_command(*command, *data, 0)?;
+        }
+        let mut values = Vec::with_capacity(commands.len());
+        let mut error = None;
+        for (command, _) in commands {
+            match self.timed_response(*command) {
+                Ok(value) => values.push(value),
+                Err(Error::RomError(e)) => {
+                    error.get_or_insert(Error::RomError(e));
+                }
+                // without a response, the responses to the other commands are most likely lost as well
+                Err(e) => return Err(error.unwrap_or(e)),
+            }
+        }
+        match error {
+            Some(error) => Err(error),
+            None => Ok(values),
+        }
+    }
+
+    /// Wait for the response to a command written earlier, reporting it to the command hook
+    pub fn timed_response(&mut self, command: Command) -> Result<u32, Error> {
+        self.timed(command, |connection| {
+            connection.read_command_response(command)
+        })
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // don't lose commands that don't get a response
+        let _ = self.send();
+    }
+}
+
+/// The serial port, shared between the connection and its reader thread
+///
+/// The reader thread only holds the port for a single short read and lets the connection go first when it's
+/// waiting for the port, so writing a command is never held up by reading.
+#[derive(Clone)]
+struct SharedPort {
+    port: Arc<Mutex<Box<dyn SerialPort + Send>>>,
+    /// The number of threads, other than the reader, waiting for the port
+    waiting: Arc<AtomicUsize>,
+}
+
+impl SharedPort {
+    fn lock(&self) -> MutexGuard<'_, Box<dyn SerialPort + Send>> {
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        let port = self.port.lock().unwrap_or_else(PoisonError::into_inner);
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        port
+    }
+}
+
+/// Receives the data read from the serial port by a background thread
+///
+/// The port is read continuously, so output the chip sends while nothing is waiting for it, like boot
+/// messages or stray frames, is buffered instead of overflowing the buffer of the os, and reading never
+/// blocks sending the next command.
+struct Reader {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    /// Data received from the thread that wasn't read yet
+    pending: Vec<u8>,
+    /// How long a read waits for data
+    timeout: Duration,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reader {
+    fn spawn(port: SharedPort) -> Self {
+        let (sender, receiver) = channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            spawn(move || read_port(&port, &sender, &stop))
+        };
+        Reader {
+            receiver,
+            pending: Vec::new(),
+            timeout: DEFAULT_READ_TIMEOUT,
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            self.pending = match self.receiver.recv_timeout(self.timeout) {
+                Ok(data) => data?,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(ErrorKind::TimedOut, "no data received"))
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(ErrorKind::BrokenPipe, "the reader stopped"))
+                }
+            };
+        }
+        let count = buf.len().min(self.pending.len());
+        buf[..count].copy_from_slice(&self.pending[..count]);
+        self.pending.drain(..count);
+        Ok(count)
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Forward everything read from the port until the reader is dropped or reading fails
+fn read_port(port: &SharedPort, sender: &Sender<io::Result<Vec<u8>>>, stop: &AtomicBool) {
+    let mut buff = [0; 1024];
+    while !stop.load(Ordering::SeqCst) {
+        while port.waiting.load(Ordering::SeqCst) > 0 {
+            yield_now();
+        }
+        let result = port
+            .port
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .read(&mut buff);
+        match result {
+            Ok(count) if count > 0 => {
+                if sender.send(Ok(buff[..count].to_vec())).is_err() {
+                    return;
+                }
+            }
+            Ok(_) => sleep(READ_IDLE_DELAY),
+            Err(e) if e.kind() == ErrorKind::TimedOut => sleep(READ_IDLE_DELAY),
+            Err(e) => {
+                let _ = sender.send(Err(e));
+                return;
+            }
+        }
+    }
+}
+
+/// Keeps a copy of everything read
+struct CaptureReader<'a, R> {
+    inner: &'a mut R,
+    capture: &'a mut Vec<u8>,
+}
+
+impl<R: Read> Read for CaptureReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.capture.extend_from_slice(&buf[..count]);
+        Ok(count)
+    }
+}
+
+/// Open a serial port at the 115200 baud the bootloader starts with, for the apis that take the name of a port
+#[cfg(any(feature = "cdylib", feature = "python"))]
+pub(crate) fn open_port(port: &str) -> Result<serial::SystemPort, Error> {
+    let mut serial = serial::open(port)?;
+    serial.reconfigure(&|settings| settings.set_baud_rate(BaudRate::Baud115200))?;
+    Ok(serial)
+}
+
+/// Reset the chip into the application by pulsing the EN pin, without entering the bootloader
+pub fn hard_reset(serial: &mut dyn SerialPort) -> Result<(), Error> {
+    serial.set_dtr(false)?;
+    serial.set_rts(true)?;
+
+    sleep(Duration::from_millis(100));
+
+    serial.set_rts(false)?;
+
+    Ok(())
 }
 
 pub trait LazyBytes<W: Write> {
@@ -170,3 +645,80 @@ impl<W: Write, F: Fn(&mut SlipEncoder<W>) -> Result<(), Error>> LazyBytes<W> for
         self.0
     }
 }
+
+#[test]
+fn test_unsolicited_output() {
+    use crate::testing::MockTransport;
+
+    let mock = MockTransport::new();
+    mock.set_register(0x1000, 0x1234);
+    mock.set_register(0x2000, 0x5678);
+    let mut connection = Connection::new(mock.clone()).unwrap();
+
+    // boot messages and a late response to an earlier FLASH_BEGIN
+    mock.send_output(
+        b"ets Jun  8 2016 00:22:57\r\n\xc0\x01\x02\x02\x00\x00\x00\x00\x00\x00\x00\xc0",
+    );
+    let value = connection
+        .command(Command::ReadReg, &0x1000u32.to_le_bytes()[..], 0)
+        .unwrap();
+    assert_eq!(0x1234, value);
+
+    // the second command is sent before the response to the first is read
+    for addr in [0x1000u32, 0x2000] {
+        connection
+            .write_command(Command::ReadReg, &addr.to_le_bytes()[..], 0)
+            .unwrap();
+    }
+    assert_eq!(
+        0x1234,
+        connection.read_command_response(Command::ReadReg).unwrap()
+    );
+    assert_eq!(
+        0x5678,
+        connection.read_command_response(Command::ReadReg).unwrap()
+    );
+}
+
+#[test]
+fn test_coalesced_commands() {
+    use crate::testing::MockTransport;
+
+    let mock = MockTransport::new();
+    mock.set_register(0x1000, 0x1234);
+    let mut connection = Connection::new(mock.clone()).unwrap();
+
+    let addr = 0x1000u32.to_le_bytes();
+    let values = connection
+        .commands(&[(Command::ReadReg, &addr[..]), (Command::ReadReg, &addr[..])])
+        .unwrap();
+    assert_eq!(vec![0x1234, 0x1234], values);
+    assert_eq!(1, mock.write_count());
+
+    // a failing command in the middle doesn't leave the responses to the commands after it behind
+    mock.set_register(0x2000, 0x5678);
+    let write = [0x3000u32, 1, 0xffff_ffff, 0]
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect::<Vec<u8>>();
+    let other = 0x2000u32.to_le_bytes();
+    mock.fail(Command::WriteReg, 0x05);
+    assert!(matches!(
+        connection.commands(&[
+            (Command::ReadReg, &addr[..]),
+            (Command::WriteReg, &write[..]),
+            (Command::ReadReg, &other[..]),
+        ]),
+        Err(Error::RomError(_))
+    ));
+    assert_eq!(
+        0x1234,
+        connection.command(Command::ReadReg, &addr[..], 0).unwrap()
+    );
+
+    // with pacing every chunk of the 14 byte frame is a separate write
+    let writes = mock.write_count();
+    connection.set_pacing(Some((8, Duration::from_millis(0))));
+    connection.command(Command::ReadReg, &addr[..], 0).unwrap();
+    assert_eq!(writes + 2, mock.write_count());
+}
@@ -0,0 +1,632 @@
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::chip::Chip;
+use crate::error::Error;
+use crate::flasher::Flasher;
+
+/// A field in the efuses of a chip
+#[derive(Debug, Eq, PartialEq)]
+pub struct EfuseField {
+    pub name: &'static str,
+    pub block: usize,
+    /// Position of the first bit of the field in the block
+    pub bit: u32,
+    /// Number of bits in the field
+    pub bits: u32,
+    pub description: &'static str,
+    /// Bit in the `WR_DIS` field that prevents the field from being changed
+    pub write_disable: Option<u32>,
+    /// Bit in the `RD_DIS` field that prevents the field from being read by software
+    pub read_disable: Option<u32>,
+}
+
+/// The efuse blocks of a chip
+pub struct EfuseBlocks {
+    /// Address of the first read register and number of 32 bit words of every block
+    pub blocks: &'static [(u32, usize)],
+    pub fields: &'static [EfuseField],
+}
+
+/// A field read from the efuses of a chip
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EfuseValue {
+    pub field: &'static EfuseField,
+    /// The bits of the field, least significant byte first
+    pub value: Vec<u8>,
+    pub write_protected: bool,
+    /// Read protected fields read as zero
+    pub read_protected: bool,
+}
+
+/// What a key burned into the efuses is used for
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KeyPurpose {
+    /// The key used to encrypt the flash
+    FlashEncryption,
+    /// The key used to verify the bootloader with secure boot v1
+    SecureBootV1,
+    /// The sha256 digest of the public key used to verify images with secure boot v2
+    SecureBootV2,
+}
+
+impl KeyPurpose {
+    /// The efuse field the key is stored in
+    fn block(self) -> &'static str {
+        match self {
+            KeyPurpose::FlashEncryption => "BLOCK1",
+            KeyPurpose::SecureBootV1 | KeyPurpose::SecureBootV2 => "BLOCK2",
+        }
+    }
+}
+
+/// How the download mode is locked down at the end of manufacturing
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DownloadLockdown {
+    /// Disable the uart download mode entirely, the flash can only be updated by the firmware afterwards
+    Disable,
+    /// Only allow the commands for writing the flash in download mode, see secure download mode
+    Secure,
+}
+
+impl DownloadLockdown {
+    /// The efuse field that enables the lockdown
+    fn field(self) -> &'static str {
+        match self {
+            DownloadLockdown::Disable => "UART_DOWNLOAD_DIS",
+            DownloadLockdown::Secure => "ENABLE_SECURITY_DOWNLOAD",
+        }
+    }
+}
+
+/// The key purpose wasn't recognized
+#[derive(Debug, Error)]
+#[error(
+    "unknown key purpose {0}, expected one of flash-encryption, secure-boot-v1 or secure-boot-v2"
+)]
+pub struct InvalidKeyPurpose(String);
+
+impl FromStr for KeyPurpose {
+    type Err = InvalidKeyPurpose;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flash-encryption" => Ok(KeyPurpose::FlashEncryption),
+            "secure-boot-v1" => Ok(KeyPurpose::SecureBootV1),
+            "secure-boot-v2" => Ok(KeyPurpose::SecureBootV2),
+            _ => Err(InvalidKeyPurpose(s.to_string())),
+        }
+    }
+}
+
+impl EfuseValue {
+    /// The value of fields up to 32 bits
+    pub fn as_u32(&self) -> Option<u32> {
+        if self.value.len() > 4 {
+            return None;
+        }
+        Some(to_u32(&self.value))
+    }
+}
+
+/// Formats the value as a hexadecimal number
+impl Display for EfuseValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.as_u32() {
+            Some(value) if self.field.bits == 1 => write!(f, "{}", value),
+            Some(value) => write!(f, "{:#x}", value),
+            None => {
+                write!(f, "0x")?;
+                for byte in self.value.iter().rev() {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The fields with a bit for every field that is write or read protected
+const PROTECTION_FIELDS: &[&str] = &["WR_DIS", "RD_DIS"];
+
+/// Read all efuse fields of the connected chip
+pub(crate) fn read_efuses(flasher: &mut Flasher) -> Result<Vec<EfuseValue>, Error> {
+    let efuses = flasher.chip().efuses();
+    let mut blocks = Vec::with_capacity(efuses.blocks.len());
+    for (addr, words) in efuses.blocks {
+        let block = (0..*words as u32)
+            .map(|word| flasher.read_reg(addr + word * 4))
+            .collect::<Result<Vec<u32>, Error>>()?;
+        blocks.push(block);
+    }
+
+    let protection = |name: &str| {
+        efuses
+            .fields
+            .iter()
+            .find(|field| field.name == name)
+            .map(|field| to_u32(&extract(&blocks[field.block], field.bit, field.bits)))
+            .unwrap_or(0)
+    };
+    let write_disabled = protection("WR_DIS");
+    let read_disabled = protection("RD_DIS");
+
+    Ok(efuses
+        .fields
+        .iter()
+        .map(|field| EfuseValue {
+            field,
+            value: extract(&blocks[field.block], field.bit, field.bits),
+            write_protected: field
+                .write_disable
+                .is_some_and(|bit| write_disabled & (1 << bit) != 0),
+            read_protected: field
+                .read_disable
+                .is_some_and(|bit| read_disabled & (1 << bit) != 0),
+        })
+        .collect())
+}
+
+/// Read the anti-rollback counter, `None` when the chip doesn't have one
+///
+/// The counter is stored as the number of set bits, so it can be increased without clearing any bits.
+/// Only the words holding the counter are read.
+pub(crate) fn read_secure_version(flasher: &mut Flasher) -> Result<Option<u32>, Error> {
+    let efuses = flasher.chip().efuses();
+    let field = match efuses
+        .fields
+        .iter()
+        .find(|field| field.name == "SECURE_VERSION")
+    {
+        Some(field) => field,
+        None => return Ok(None),
+    };
+
+    let (addr, _) = efuses.blocks[field.block];
+    let first = field.bit / 32;
+    let last = (field.bit + field.bits - 1) / 32;
+    let words = (first..=last)
+        .map(|word| flasher.read_reg(addr + word * 4))
+        .collect::<Result<Vec<u32>, Error>>()?;
+    let value = extract(&words, field.bit - first * 32, field.bits);
+    Ok(Some(value.iter().map(|byte| byte.count_ones()).sum()))
+}
+
+/// Burn new values into efuse fields
+///
+/// Fields that already have the value are skipped, programming a field that already has bits set fails since
+/// efuse bits can't be cleared. The exception are the protection fields, which have a bit for every protected
+/// field, bits can be added to those as long as the bits that are set stay set. The fields are read back
+/// afterwards to check they have the new values.
+pub(crate) fn burn_efuses(flasher: &mut Flasher, values: &[(&str, &[u8])]) -> Result<(), Error> {
+    let chip = flasher.chip();
+    let efuses = chip.efuses();
+    let current = read_efuses(flasher)?;
+
+    let mut blocks: Vec<Vec<u32>> = efuses
+        .blocks
+        .iter()
+        .map(|(_, words)| vec![0; *words])
+        .collect();
+    let mut burned = Vec::new();
+    for (name, value) in values {
+        let current = current
+            .iter()
+            .find(|current| current.field.name == *name)
+            .ok_or(Error::UnsupportedOperation {
+                operation: "the efuse field",
+                chip,
+            })?;
+        let field = current.field;
+        if current.value == *value {
+            continue;
+        }
+        if current.write_protected || current.read_protected {
+            return Err(Error::EfuseProtected(field.name));
+        }
+        let cleared = if PROTECTION_FIELDS.contains(&field.name) {
+            current
+                .value
+                .iter()
+                .zip(value.iter())
+                .any(|(current, new)| current & !new != 0)
+        } else {
+            current.value.iter().any(|byte| *byte != 0)
+        };
+        if cleared {
+            return Err(Error::EfuseAlreadyBurned(field.name));
+        }
+        insert(&mut blocks[field.block], field.bit, field.bits, value);
+        burned.push((field, *value));
+    }
+
+    for (block, words) in blocks.iter().enumerate() {
+        if words.iter().any(|word| *word != 0) {
+            chip.burn_efuses(flasher, block, words)?;
+        }
+    }
+
+    let written = read_efuses(flasher)?;
+    for (field, value) in burned {
+        if written
+            .iter()
+            .find(|written| written.field == field)
+            .is_none_or(|written| written.value != value)
+        {
+            return Err(Error::EfuseBurnFailed(field.name));
+        }
+    }
+    Ok(())
+}
+
+/// Burn a custom MAC address, with its CRC, for firmware that reads it with `esp_efuse_mac_get_custom`
+pub(crate) fn burn_custom_mac(flasher: &mut Flasher, mac: [u8; 6]) -> Result<(), Error> {
+    let chip = flasher.chip();
+    if !chip
+        .efuses()
+        .fields
+        .iter()
+        .any(|field| field.name == "CUSTOM_MAC")
+    {
+        return Err(Error::UnsupportedOperation {
+            operation: "custom MAC addresses",
+            chip,
+        });
+    }
+    check_coding_scheme(&read_efuses(flasher)?, chip)?;
+
+    burn_efuses(
+        flasher,
+        &[
+            ("CUSTOM_MAC", &mac),
+            ("CUSTOM_MAC_CRC", &[crc8(&mac)]),
+            ("MAC_VERSION", &[1]),
+        ],
+    )
+}
+
+/// Burn the efuse that disables or restricts the download mode, which can't be undone
+///
+/// The esp32 only supports disabling the download mode from revision 3 on, earlier revisions ignore the efuse.
+pub(crate) fn lock_download_mode(
+    flasher: &mut Flasher,
+    lockdown: DownloadLockdown,
+) -> Result<(), Error> {
+    let chip = flasher.chip();
+    let supported = chip
+        .efuses()
+        .fields
+        .iter()
+        .any(|field| field.name == lockdown.field());
+    let revision = chip.chip_revision(flasher)?.unwrap_or(0);
+    if !supported || (chip == Chip::Esp32 && revision < 3) {
+        return Err(Error::UnsupportedOperation {
+            operation: match lockdown {
+                DownloadLockdown::Disable => "disabling the download mode",
+                DownloadLockdown::Secure => "secure download mode",
+            },
+            chip,
+        });
+    }
+    check_coding_scheme(&read_efuses(flasher)?, chip)?;
+
+    burn_efuses(flasher, &[(lockdown.field(), &[1])])
+}
+
+/// Burn a 256 bit key into the key block for its purpose, refusing to overwrite a block that's already used
+///
+/// When `protect` is set the block is write protected afterwards, and read protected so only the hardware can use
+/// the key, except for the secure boot v2 digest which the bootloader has to read.
+pub(crate) fn burn_key(
+    flasher: &mut Flasher,
+    purpose: KeyPurpose,
+    key: &[u8; 32],
+    protect: bool,
+) -> Result<(), Error> {
+    let chip = flasher.chip();
+    let current = read_efuses(flasher)?;
+    check_coding_scheme(&current, chip)?;
+    let find = |name: &str| current.iter().find(|value| value.field.name == name);
+
+    let block = find(purpose.block()).ok_or(Error::UnsupportedOperation {
+        operation: "key blocks",
+        chip,
+    })?;
+    if block.read_protected || block.write_protected {
+        return Err(Error::EfuseProtected(block.field.name));
+    }
+    if block.value.iter().any(|byte| *byte != 0) {
+        return Err(Error::EfuseAlreadyBurned(block.field.name));
+    }
+
+    // the esp32 uses the bytes of keys in reverse order, the digest is compared as it is
+    let mut key = key.to_vec();
+    if purpose != KeyPurpose::SecureBootV2 {
+        key.reverse();
+    }
+    burn_efuses(flasher, &[(block.field.name, &key)])?;
+
+    if !protect {
+        return Ok(());
+    }
+    let mut protection: Vec<(&str, Vec<u8>)> = Vec::new();
+    let read_disable = block
+        .field
+        .read_disable
+        .filter(|_| purpose != KeyPurpose::SecureBootV2);
+    for (name, bit) in [
+        ("RD_DIS", read_disable),
+        ("WR_DIS", block.field.write_disable),
+    ] {
+        if let (Some(current), Some(bit)) = (find(name), bit) {
+            let mut value = current.value.clone();
+            value[(bit / 8) as usize] |= 1 << (bit % 8);
+            protection.push((name, value));
+        }
+    }
+    let protection: Vec<(&str, &[u8])> = protection
+        .iter()
+        .map(|(name, value)| (*name, value.as_slice()))
+        .collect();
+    burn_efuses(flasher, &protection)
+}
+
+/// Writing efuses with the 3/4 coding scheme isn't supported, since every block has to be written at once with
+/// the encoded values
+fn check_coding_scheme(values: &[EfuseValue], chip: Chip) -> Result<(), Error> {
+    let coding_scheme = values
+        .iter()
+        .find(|value| value.field.name == "CODING_SCHEME")
+        .and_then(|value| value.as_u32())
+        .unwrap_or(0);
+    if coding_scheme == 1 {
+        return Err(Error::UnsupportedOperation {
+            operation: "burning efuses with the 3/4 coding scheme",
+            chip,
+        });
+    }
+    Ok(())
+}
+
+/// The crc8 used for MAC addresses in the efuses, the dallas/maxim 1-wire crc
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0x8c
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// Extract `bits` bits starting at `bit` from the words of a block, least significant byte first
+fn extract(words: &[u32], bit: u32, bits: u32) -> Vec<u8> {
+    let mut value = vec![0u8; (bits as usize).div_ceil(8)];
+    for i in 0..bits {
+        let source = bit + i;
+        if words[(source / 32) as usize] & (1 << (source % 32)) != 0 {
+            value[(i / 8) as usize] |= 1 << (i % 8);
+        }
+    }
+    value
+}
+
+/// Set `bits` bits starting at `bit` in the words of a block, from a value stored least significant byte first
+fn insert(words: &mut [u32], bit: u32, bits: u32, value: &[u8]) {
+    for i in 0..bits.min(value.len() as u32 * 8) {
+        if value[(i / 8) as usize] & (1 << (i % 8)) != 0 {
+            let target = bit + i;
+            words[(target / 32) as usize] |= 1 << (target % 32);
+        }
+    }
+}
+
+fn to_u32(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| value << 8 | *byte as u32)
+}
+
+#[test]
+fn test_extract() {
+    let words = [0x12345678, 0x9abcdef0];
+    assert_eq!(vec![0x78, 0x56, 0x34, 0x12], extract(&words, 0, 32));
+    assert_eq!(vec![0x45, 0x03], extract(&words, 12, 10));
+    // spanning the words
+    assert_eq!(vec![0x12, 0xf0], extract(&words, 24, 16));
+}
+
+#[test]
+fn test_insert() {
+    let mut words = [0; 2];
+    insert(&mut words, 24, 16, &[0x12, 0xf0]);
+    assert_eq!([0x12000000, 0xf0], words);
+    assert_eq!(vec![0x12, 0xf0], extract(&words, 24, 16));
+}
+
+#[test]
+fn test_crc8() {
+    // the check value of CRC-8/MAXIM
+    assert_eq!(0xa1, crc8(b"123456789"));
+    assert_eq!(0, crc8(&[]));
+}
+
+#[test]
+fn test_burn_custom_mac() {
+    use crate::testing::MockTransport;
+    use crate::{Chip, FlashSize};
+
+    let mac = [0x02, 0x00, 0x00, 0x12, 0x34, 0x56];
+
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    // the first byte of a different custom MAC address
+    mock.set_register(0x3ff5a078, 0x1100);
+    let mut flasher = Flasher::connect(mock, None).unwrap();
+    assert!(matches!(
+        flasher.burn_custom_mac(mac),
+        Err(Error::EfuseAlreadyBurned("CUSTOM_MAC"))
+    ));
+
+    // the mock doesn't program the efuses, so the check afterwards fails
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    // commands finish immediately
+    mock.pin_register(0x3ff5a104, 0);
+    let mut flasher = Flasher::connect(mock.clone(), None).unwrap();
+    assert!(matches!(
+        flasher.burn_custom_mac(mac),
+        Err(Error::EfuseBurnFailed(_))
+    ));
+    assert_eq!(0x5aa5, mock.register(0x3ff5a0fc));
+
+    let mock = MockTransport::with_chip(Chip::Esp8266, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(mock, None).unwrap();
+    assert!(matches!(
+        flasher.burn_custom_mac(mac),
+        Err(Error::UnsupportedOperation { .. })
+    ));
+}
+
+#[test]
+fn test_burn_key() {
+    use crate::testing::MockTransport;
+    use crate::FlashSize;
+
+    let key = [0x5a; 32];
+
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    // the last word of the flash encryption key
+    mock.set_register(0x3ff5a054, 0x1);
+    let mut flasher = Flasher::connect(mock, None).unwrap();
+    assert!(matches!(
+        flasher.burn_key(KeyPurpose::FlashEncryption, &key, true),
+        Err(Error::EfuseAlreadyBurned("BLOCK1"))
+    ));
+
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    // RD_DIS bit 1 protects the secure boot key
+    mock.set_register(0x3ff5a000, 0x2 << 16);
+    let mut flasher = Flasher::connect(mock, None).unwrap();
+    assert!(matches!(
+        flasher.burn_key(KeyPurpose::SecureBootV2, &key, true),
+        Err(Error::EfuseProtected("BLOCK2"))
+    ));
+
+    assert!("secure-boot-v3".parse::<KeyPurpose>().is_err());
+}
+
+#[test]
+fn test_burn_key_success() {
+    use crate::testing::MockTransport;
+    use crate::FlashSize;
+
+    let mut key = [0; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    // WR_DIS bit 9 is already burned, protecting the key has to keep it
+    simulator.set_register(0x3ff5a000, 1 << 9);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher
+        .burn_key(KeyPurpose::FlashEncryption, &key, true)
+        .unwrap();
+
+    // the esp32 uses the bytes of the flash encryption key in reverse order
+    assert_eq!(0x1c1d1e1f, simulator.register(0x3ff5a038));
+    assert_eq!(0x00010203, simulator.register(0x3ff5a054));
+    // RD_DIS bit 0 and WR_DIS bit 7 protect BLOCK1
+    assert_eq!(1 << 16 | 1 << 9 | 1 << 7, simulator.register(0x3ff5a000));
+
+    // the secure boot v2 digest is stored as it is and stays readable
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher
+        .burn_key(KeyPurpose::SecureBootV2, &key, true)
+        .unwrap();
+    assert_eq!(0x03020100, simulator.register(0x3ff5a058));
+    assert_eq!(1 << 8, simulator.register(0x3ff5a000));
+
+    // other fields can't get more bits, even when the bits that are set stay set
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    simulator.set_register(0x3ff5a078, 0x1);
+    let mut flasher = Flasher::connect(simulator, None).unwrap();
+    assert!(matches!(
+        burn_efuses(&mut flasher, &[("CUSTOM_MAC_CRC", &[0x3])]),
+        Err(Error::EfuseAlreadyBurned("CUSTOM_MAC_CRC"))
+    ));
+}
+
+#[test]
+fn test_fields_dont_overlap() {
+    for chip in [Chip::Esp8266, Chip::Esp32] {
+        let fields = chip.efuses().fields;
+        for (i, a) in fields.iter().enumerate() {
+            for b in &fields[i + 1..] {
+                assert!(
+                    a.block != b.block || a.bit + a.bits <= b.bit || b.bit + b.bits <= a.bit,
+                    "{} overlaps {}",
+                    a.name,
+                    b.name
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_read_secure_version() {
+    use crate::flasher::Command;
+    use crate::testing::MockTransport;
+    use crate::FlashSize;
+
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    // three bits set in the 5th word of block 3, the mac version in the 6th word isn't part of the counter
+    mock.set_register(0x3ff5a088, 0x8000_0003);
+    mock.set_register(0x3ff5a08c, 0x0100_0000);
+    let mut flasher = Flasher::connect(mock.clone(), None).unwrap();
+    let reads = mock.received_count(Command::ReadReg);
+    assert_eq!(Some(3), flasher.secure_version().unwrap());
+    assert_eq!(1, mock.received_count(Command::ReadReg) - reads);
+
+    let mock = MockTransport::with_chip(Chip::Esp8266, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(mock, None).unwrap();
+    assert_eq!(None, flasher.secure_version().unwrap());
+}
+
+#[test]
+fn test_lock_download_mode() {
+    use crate::testing::MockTransport;
+    use crate::FlashSize;
+
+    // revisions before 3 ignore the efuse
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(mock, None).unwrap();
+    assert!(matches!(
+        flasher.lock_download_mode(DownloadLockdown::Disable),
+        Err(Error::UnsupportedOperation { .. })
+    ));
+
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    // the revision bits of revision 3
+    mock.set_register(0x3ff5a00c, 1 << 15);
+    mock.set_register(0x3ff5a014, 1 << 20);
+    mock.set_register(0x3ff6607c, 1 << 31);
+    mock.pin_register(0x3ff5a104, 0);
+    let mut flasher = Flasher::connect(mock.clone(), None).unwrap();
+    assert!(matches!(
+        flasher.lock_download_mode(DownloadLockdown::Secure),
+        Err(Error::UnsupportedOperation { .. })
+    ));
+    // the mock doesn't program the efuses, so the check afterwards fails
+    assert!(matches!(
+        flasher.lock_download_mode(DownloadLockdown::Disable),
+        Err(Error::EfuseBurnFailed("UART_DOWNLOAD_DIS"))
+    ));
+    assert_eq!(0x5aa5, mock.register(0x3ff5a0fc));
+}
@@ -1,15 +1,20 @@
 use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::str::FromStr;
 
-use crate::chip::Chip;
+use crate::chip::{Chip, PartitionTable};
 use crate::flasher::FlashSize;
+use rustc_demangle::demangle;
 use xmas_elf::program::{SegmentData, Type};
+use xmas_elf::sections::SectionData;
+use xmas_elf::symbol_table::{self, Entry};
 use xmas_elf::ElfFile;
 
 pub const ESP_CHECKSUM_MAGIC: u8 = 0xef;
 
-#[derive(Copy, Clone)]
-#[allow(dead_code)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serialize", serde(rename_all = "lowercase"))]
 pub enum FlashMode {
     Qio,
     Qout,
@@ -17,22 +22,78 @@ pub enum FlashMode {
     Dout,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
-#[allow(dead_code)]
 pub enum FlashFrequency {
+    #[cfg_attr(feature = "serialize", serde(rename = "40m"))]
     Flash40M = 0,
+    #[cfg_attr(feature = "serialize", serde(rename = "26m"))]
     Flash26M = 1,
+    #[cfg_attr(feature = "serialize", serde(rename = "20m"))]
     Flash20M = 2,
+    #[cfg_attr(feature = "serialize", serde(rename = "80m"))]
     Flash80M = 0xf,
 }
 
+/// The flash mode wasn't recognized
+#[derive(Debug, thiserror::Error)]
+#[error("unknown flash mode {0}, expected one of qio, qout, dio or dout")]
+pub struct InvalidFlashMode(String);
+
+impl FromStr for FlashMode {
+    type Err = InvalidFlashMode;
+
+    /// Parse a flash mode like `qio` or `dio`, ignoring case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "qio" => Ok(FlashMode::Qio),
+            "qout" => Ok(FlashMode::Qout),
+            "dio" => Ok(FlashMode::Dio),
+            "dout" => Ok(FlashMode::Dout),
+            _ => Err(InvalidFlashMode(s.to_string())),
+        }
+    }
+}
+
+/// The flash frequency wasn't recognized
+#[derive(Debug, thiserror::Error)]
+#[error("unknown flash frequency {0}, expected one of 80m, 40m, 26m or 20m")]
+pub struct InvalidFlashFrequency(String);
+
+impl FromStr for FlashFrequency {
+    type Err = InvalidFlashFrequency;
+
+    /// Parse a flash frequency like `80m` or `40m`, ignoring case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "80m" => Ok(FlashFrequency::Flash80M),
+            "40m" => Ok(FlashFrequency::Flash40M),
+            "26m" => Ok(FlashFrequency::Flash26M),
+            "20m" => Ok(FlashFrequency::Flash20M),
+            _ => Err(InvalidFlashFrequency(s.to_string())),
+        }
+    }
+}
+
 pub struct FirmwareImage<'a> {
     pub entry: u32,
     pub elf: ElfFile<'a>,
     pub flash_mode: FlashMode,
     pub flash_size: FlashSize,
     pub flash_frequency: FlashFrequency,
+    /// The lowest chip revision the image runs on, as `major * 100 + minor`
+    pub min_chip_revision: u16,
+    /// The highest chip revision the image runs on, as `major * 100 + minor`, `0xffff` for no limit
+    pub max_chip_revision: u16,
+    /// The partition table of the esp32, a single app partition when not set
+    pub partition_table: Option<PartitionTable>,
+    /// The offset of the partition table of the esp32, when not the default
+    pub partition_table_offset: Option<u32>,
+    /// The offset of the bootloader of the esp32, when not the default
+    pub bootloader_offset: Option<u32>,
+    /// The bootloader of the esp32, the bundled one when not set
+    pub bootloader: Option<Vec<u8>>,
 }
 
 impl<'a> FirmwareImage<'a> {
@@ -47,6 +108,12 @@ impl<'a> FirmwareImage<'a> {
             flash_mode: FlashMode::Dio,
             flash_size: FlashSize::Flash4Mb,
             flash_frequency: FlashFrequency::Flash40M,
+            min_chip_revision: 0,
+            max_chip_revision: 0xffff,
+            partition_table: None,
+            partition_table_offset: None,
+            bootloader_offset: None,
+            bootloader: None,
         }
     }
 
@@ -54,35 +121,51 @@ impl<'a> FirmwareImage<'a> {
         self.elf.header.pt2.entry_point() as u32
     }
 
+    /// The loadable segments of the elf, sorted by address
     pub fn segments(&'a self) -> impl Iterator<Item = CodeSegment<'a>> + 'a {
-        self.elf
-            .program_iter()
-            .filter(|header| {
-                header.file_size() > 0 && header.get_type() == Ok(Type::Load) && header.offset() > 0
-            })
-            .flat_map(move |header| {
-                let addr = header.virtual_addr() as u32;
-                let size = header.file_size() as u32;
-                let data = match header.get_data(&self.elf) {
-                    Ok(SegmentData::Undefined(data)) => data,
-                    _ => return None,
-                };
-                Some(CodeSegment { addr, data, size })
-            })
+        sorted(
+            self.elf
+                .program_iter()
+                .filter(|header| {
+                    header.file_size() > 0
+                        && header.get_type() == Ok(Type::Load)
+                        && header.offset() > 0
+                })
+                .flat_map(move |header| {
+                    let addr = header.virtual_addr() as u32;
+                    let size = header.file_size() as u32;
+                    let data = match header.get_data(&self.elf) {
+                        Ok(SegmentData::Undefined(data)) => data,
+                        _ => return None,
+                    };
+                    Some(CodeSegment { addr, data, size })
+                }),
+        )
     }
 
+    /// The segments mapped from flash, sorted by address
     pub fn rom_segments(&'a self, chip: Chip) -> impl Iterator<Item = CodeSegment<'a>> + 'a {
         self.segments()
             .filter(move |segment| chip.addr_is_flash(segment.addr))
     }
 
+    /// The segments loaded into ram, sorted by address
     pub fn ram_segments(&'a self, chip: Chip) -> impl Iterator<Item = CodeSegment<'a>> + 'a {
         self.segments()
             .filter(move |segment| !chip.addr_is_flash(segment.addr))
     }
 }
 
-#[derive(Debug, Ord, Eq)]
+/// Sort segments, so the image doesn't depend on the order of the program headers in the elf file
+fn sorted<'a>(
+    segments: impl Iterator<Item = CodeSegment<'a>>,
+) -> impl Iterator<Item = CodeSegment<'a>> {
+    let mut segments: Vec<_> = segments.collect();
+    segments.sort();
+    segments.into_iter()
+}
+
+#[derive(Debug, Eq, PartialEq)]
 /// A segment of code from the source elf
 pub struct CodeSegment<'a> {
     pub addr: u32,
@@ -90,15 +173,70 @@ pub struct CodeSegment<'a> {
     pub data: &'a [u8],
 }
 
-impl PartialEq for CodeSegment<'_> {
-    fn eq(&self, other: &Self) -> bool {
-        self.addr.eq(&other.addr)
+impl PartialOrd for CodeSegment<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CodeSegment<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.addr
+            .cmp(&other.addr)
+            .then_with(|| self.size.cmp(&other.size))
+            .then_with(|| self.data.cmp(other.data))
     }
 }
 
-impl PartialOrd for CodeSegment<'_> {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.addr.partial_cmp(&other.addr)
+/// The functions of the firmware, to show where code addresses printed by the device point to
+#[derive(Default)]
+pub struct Symbols {
+    /// Start address, size and demangled name of every function, sorted by address
+    functions: Vec<(u32, u32, String)>,
+}
+
+impl Symbols {
+    /// Read the functions from the symbol table of an elf image, stripped images have no symbols
+    pub fn from_elf(data: &[u8]) -> Self {
+        let mut functions = Vec::new();
+        let elf = match ElfFile::new(data) {
+            Ok(elf) => elf,
+            Err(_) => return Symbols::default(),
+        };
+        if let Some(Ok(SectionData::SymbolTable32(entries))) = elf
+            .find_section_by_name(".symtab")
+            .map(|section| section.get_data(&elf))
+        {
+            for entry in entries {
+                if entry.get_type() != Ok(symbol_table::Type::Func) || entry.size() == 0 {
+                    continue;
+                }
+                if let Ok(name) = entry.get_name(&elf) {
+                    let name = format!("{:#}", demangle(name));
+                    functions.push((entry.value() as u32, entry.size() as u32, name));
+                }
+            }
+        }
+        functions.sort();
+        Symbols { functions }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+
+    /// Find the function containing `addr`, with the offset of the address into it
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        let index = match self
+            .functions
+            .binary_search_by_key(&addr, |(start, _, _)| *start)
+        {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let (start, size, name) = &self.functions[index];
+        (addr - start < *size).then(|| (name.as_str(), addr - start))
     }
 }
 
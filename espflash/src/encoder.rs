@@ -1,10 +1,15 @@
-use std::io::Write;
+use std::io::{Read, Write};
+
+use crate::Error;
 
 const END: u8 = 0xC0;
 const ESC: u8 = 0xDB;
 const ESC_END: u8 = 0xDC;
 const ESC_ESC: u8 = 0xDD;
 
+/// The largest frame accepted by the decoder, larger than any response from the rom or the stub
+const MAX_FRAME_SIZE: usize = 0x4000;
+
 pub struct SlipEncoder<'a, W: Write> {
     writer: &'a mut W,
     len: usize,
@@ -17,19 +22,22 @@ impl<'a, W: Write> SlipEncoder<'a, W> {
         Ok(Self { writer, len })
     }
 
-    pub fn write(&mut self, buf: &[u8]) -> std::io::Result<()> {
-        for value in buf.iter() {
-            match *value {
-                END => {
-                    self.len += self.writer.write(&[ESC, ESC_END])?;
-                }
-                ESC => {
-                    self.len += self.writer.write(&[ESC, ESC_ESC])?;
-                }
-                _ => {
-                    self.len += self.writer.write(&[*value])?;
-                }
+    pub fn write(&mut self, mut buf: &[u8]) -> std::io::Result<()> {
+        // write the runs of bytes that don't need escaping at once
+        while !buf.is_empty() {
+            let run = buf
+                .iter()
+                .position(|&value| value == END || value == ESC)
+                .unwrap_or(buf.len());
+            self.writer.write_all(&buf[..run])?;
+            self.len += run;
+            match buf.get(run) {
+                Some(&END) => self.writer.write_all(&[ESC, ESC_END])?,
+                Some(_) => self.writer.write_all(&[ESC, ESC_ESC])?,
+                None => break,
             }
+            self.len += 2;
+            buf = &buf[run + 1..];
         }
 
         Ok(())
@@ -40,3 +48,178 @@ impl<'a, W: Write> SlipEncoder<'a, W> {
         Ok(self.len)
     }
 }
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum DecoderState {
+    /// Skipping anything until the start of a frame
+    Hunting,
+    Frame,
+    Escape,
+}
+
+/// Streaming slip decoder
+///
+/// Anything received outside of a frame, like boot messages, is skipped. After a malformed or oversized
+/// frame the decoder returns an error and resynchronizes on the next frame.
+pub struct SlipDecoder {
+    /// Data read from the source that wasn't decoded yet
+    buffer: Vec<u8>,
+    pos: usize,
+    frame: Vec<u8>,
+    state: DecoderState,
+}
+
+impl SlipDecoder {
+    pub fn new() -> Self {
+        SlipDecoder {
+            buffer: Vec::new(),
+            pos: 0,
+            frame: Vec::new(),
+            state: DecoderState::Hunting,
+        }
+    }
+
+    /// Read from `source` until a complete frame is received and return its decoded content
+    pub fn decode(&mut self, source: &mut dyn Read) -> Result<Vec<u8>, Error> {
+        loop {
+            if self.pos == self.buffer.len() {
+                if let Err(e) = self.fill(source) {
+                    // the rest of the frame can't be trusted after an interrupted read
+                    self.state = DecoderState::Hunting;
+                    return Err(e);
+                }
+            }
+            let byte = self.buffer[self.pos];
+            self.pos += 1;
+
+            if let Some(frame) = self.decode_byte(byte) {
+                return frame;
+            }
+        }
+    }
+
+    /// Decode data that was received in chunks, returning the frames that are completed by `data`
+    ///
+    /// Unlike [`SlipDecoder::decode`] this doesn't read from a source, for data that was already read, like the
+    /// recorded communication. A frame that's incomplete at the end of `data` is continued by the next call.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Result<Vec<u8>, Error>> {
+        data.iter()
+            .filter_map(|&byte| self.decode_byte(byte))
+            .collect()
+    }
+
+    /// Decode the next byte, returning the frame or the error it completes
+    fn decode_byte(&mut self, byte: u8) -> Option<Result<Vec<u8>, Error>> {
+        let result = match (self.state, byte) {
+            (DecoderState::Hunting, END) => {
+                self.frame.clear();
+                self.state = DecoderState::Frame;
+                Ok(())
+            }
+            (DecoderState::Hunting, _) => Ok(()),
+            // an end directly after the previous one starts the next frame
+            (DecoderState::Frame, END) if self.frame.is_empty() => Ok(()),
+            (DecoderState::Frame, END) => {
+                self.state = DecoderState::Hunting;
+                return Some(Ok(std::mem::take(&mut self.frame)));
+            }
+            (DecoderState::Frame, ESC) => {
+                self.state = DecoderState::Escape;
+                Ok(())
+            }
+            (DecoderState::Frame, _) => self.push(byte),
+            (DecoderState::Escape, ESC_END) => self.push(END),
+            (DecoderState::Escape, ESC_ESC) => self.push(ESC),
+            (DecoderState::Escape, _) => {
+                // an unescaped end is more likely the start of the next frame than garbage
+                self.frame.clear();
+                self.state = if byte == END {
+                    DecoderState::Frame
+                } else {
+                    DecoderState::Hunting
+                };
+                Err(Error::FramingError)
+            }
+        };
+        result.err().map(Err)
+    }
+
+    /// Take the data that was read from the source but not decoded yet
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        let buffered = self.buffer.split_off(self.pos);
+        self.buffer.clear();
+        self.pos = 0;
+        self.state = DecoderState::Hunting;
+        buffered
+    }
+
+    fn fill(&mut self, source: &mut dyn Read) -> Result<(), Error> {
+        self.buffer.resize(256, 0);
+        self.pos = 0;
+        let count = match source.read(&mut self.buffer) {
+            Ok(count) => count,
+            Err(e) => {
+                self.buffer.clear();
+                return Err(e.into());
+            }
+        };
+        self.buffer.truncate(count);
+        if count == 0 {
+            return Err(Error::FramingError);
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, byte: u8) -> Result<(), Error> {
+        if self.frame.len() >= MAX_FRAME_SIZE {
+            self.frame.clear();
+            self.state = DecoderState::Hunting;
+            return Err(Error::OverSizedPacket);
+        }
+        self.frame.push(byte);
+        self.state = DecoderState::Frame;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_decode_recovery() {
+    let mut decoder = SlipDecoder::new();
+    let mut input: &[u8] = &[
+        b'b', b'o', b'o', b't', END, 1, ESC, ESC_END, 2, END, b'x', END, END, 3, ESC, 0x00, 4, END,
+        END, 5, END,
+    ];
+
+    assert_eq!(vec![1, END, 2], decoder.decode(&mut input).unwrap());
+    assert!(matches!(
+        decoder.decode(&mut input),
+        Err(Error::FramingError)
+    ));
+    assert_eq!(vec![5], decoder.decode(&mut input).unwrap());
+
+    let mut oversized = vec![END];
+    oversized.resize(MAX_FRAME_SIZE + 10, 0);
+    oversized.extend_from_slice(&[END, END, 6, END]);
+    let mut input = oversized.as_slice();
+    assert!(matches!(
+        decoder.decode(&mut input),
+        Err(Error::OverSizedPacket)
+    ));
+    assert_eq!(vec![6], decoder.decode(&mut input).unwrap());
+}
+
+#[test]
+fn test_encode() {
+    let mut frame = Vec::new();
+    let mut encoder = SlipEncoder::new(&mut frame).unwrap();
+    encoder.write(&[1, END, 2, 3]).unwrap();
+    encoder.write(&[ESC]).unwrap();
+    assert_eq!(9, encoder.finish().unwrap());
+    assert_eq!(vec![END, 1, ESC, ESC_END, 2, 3, ESC, ESC_ESC, END], frame);
+
+    let mut decoder = SlipDecoder::new();
+    assert_eq!(
+        vec![1, END, 2, 3, ESC],
+        decoder.decode(&mut frame.as_slice()).unwrap()
+    );
+}
@@ -1,4 +1,7 @@
-use slip_codec::Error as SlipError;
+use std::fmt::{self, Display, Formatter};
+
+use crate::flasher::Command;
+use crate::Chip;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -8,6 +11,12 @@ pub enum Error {
     Serial(#[from] serial::core::Error),
     #[error("Failed to connect to the device")]
     ConnectionFailed,
+    #[error("the chip didn't enter the bootloader, {0}")]
+    WrongBootMode(String),
+    #[error(
+        "Failed to connect to the device, it sent something other than a bootloader response:\n{0}"
+    )]
+    UnexpectedOutput(DeviceOutput),
     #[error("Timeout while running command")]
     Timeout,
     #[error("Invalid SLIP framing")]
@@ -18,31 +27,117 @@ pub enum Error {
     InvalidElf,
     #[error("elf image can not be ran from ram")]
     ElfNotRamLoadable,
-    #[error("bootloader returned an error: {0:?}")]
+    #[error("invalid firmware image: {0}")]
+    InvalidImage(String),
+    #[error("bootloader returned an error for {0}")]
     RomError(RomError),
     #[error("chip not recognized, supported chip types are esp8266 and esp32")]
     UnrecognizedChip,
+    #[error("the connected {0} isn't supported, supported chip types are esp8266 and esp32")]
+    UnsupportedChip(&'static str),
+    #[error("connected chip is an {detected:?}, but an {expected:?} was expected")]
+    ChipMismatch { expected: Chip, detected: Chip },
     #[error("flash chip not supported, flash id: {0:#x}")]
     UnsupportedFlash(u8),
+    #[error("the quad enable bit of flash chip {0:#x} can't be set")]
+    QuadModeFailed(u32),
+    #[error("the {chip:?} bootloader doesn't support {operation}")]
+    UnsupportedOperation { operation: &'static str, chip: Chip },
+    #[error("the flasher stub failed to start")]
+    StubFailed,
+    #[error("invalid flasher stub: {0}")]
+    InvalidStub(String),
+    #[error("data read from the flash was corrupted during the transfer")]
+    CorruptedRead,
+    #[error("flash contents don't match the image, first difference at {0:#x}")]
+    VerifyFailed(u32),
+    #[error("efuse {0} is read or write protected")]
+    EfuseProtected(&'static str),
+    #[error("efuse {0} is already programmed with a different value")]
+    EfuseAlreadyBurned(&'static str),
+    #[error("efuse {0} doesn't have the written value after burning")]
+    EfuseBurnFailed(&'static str),
+    #[error("invalid image signature: {0}")]
+    InvalidSignature(String),
+    #[error("invalid signing key: {0}")]
+    InvalidKey(String),
+    #[error(
+        "flash at {addr:#x} is write protected, the status register of the flash chip protects {start:#x}..{end:#x}"
+    )]
+    FlashProtected { addr: u32, start: u32, end: u32 },
+    #[error("invalid partition table: {0}")]
+    InvalidPartitionTable(String),
+    #[error("invalid sdkconfig: {0}")]
+    InvalidSdkconfig(String),
+    #[error("invalid arduino build: {0}")]
+    InvalidArduinoBuild(String),
+    #[error("{addr:#x} isn't a word aligned register or ram address of the {chip:?}")]
+    InvalidAddress { addr: u32, chip: Chip },
+    #[error(
+        "spi commands send at most 64 data bytes and read at most 32 bits, not {data} bytes and {read_bits} bits"
+    )]
+    InvalidSpiCommand { data: usize, read_bits: u32 },
 }
 
-impl From<std::io::Error> for Error {
-    fn from(err: std::io::Error) -> Self {
-        Self::Serial(serial::core::Error::from(err))
+impl Error {
+    /// A short identifier for the kind of error
+    ///
+    /// Unlike the error messages, these are part of the stable interface and can be used to classify failures.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Serial(_) => "serial-io",
+            Error::ConnectionFailed => "connection-failed",
+            Error::WrongBootMode(_) => "wrong-boot-mode",
+            Error::UnexpectedOutput(_) => "unexpected-output",
+            Error::Timeout => "timeout",
+            Error::FramingError => "framing-error",
+            Error::OverSizedPacket => "oversized-packet",
+            Error::InvalidElf => "invalid-elf",
+            Error::ElfNotRamLoadable => "elf-not-ram-loadable",
+            Error::InvalidImage(_) => "invalid-image",
+            Error::RomError(_) => "rom-error",
+            Error::UnrecognizedChip => "unrecognized-chip",
+            Error::UnsupportedChip(_) => "unsupported-chip",
+            Error::ChipMismatch { .. } => "chip-mismatch",
+            Error::UnsupportedFlash(_) => "unsupported-flash",
+            Error::QuadModeFailed(_) => "quad-mode-failed",
+            Error::UnsupportedOperation { .. } => "unsupported-operation",
+            Error::StubFailed => "stub-failed",
+            Error::InvalidStub(_) => "invalid-stub",
+            Error::CorruptedRead => "corrupted-read",
+            Error::VerifyFailed(_) => "verify-failed",
+            Error::EfuseProtected(_) => "efuse-protected",
+            Error::EfuseAlreadyBurned(_) => "efuse-already-burned",
+            Error::EfuseBurnFailed(_) => "efuse-burn-failed",
+            Error::InvalidSignature(_) => "invalid-signature",
+            Error::InvalidKey(_) => "invalid-key",
+            Error::FlashProtected { .. } => "flash-protected",
+            Error::InvalidPartitionTable(_) => "invalid-partition-table",
+            Error::InvalidSdkconfig(_) => "invalid-sdkconfig",
+            Error::InvalidArduinoBuild(_) => "invalid-arduino-build",
+            Error::InvalidAddress { .. } => "invalid-address",
+            Error::InvalidSpiCommand { .. } => "invalid-spi-command",
+        }
     }
-}
 
-impl From<SlipError> for Error {
-    fn from(err: SlipError) -> Self {
-        match err {
-            SlipError::FramingError => Self::FramingError,
-            SlipError::OversizedPacket => Self::OverSizedPacket,
-            SlipError::ReadError(io) => Self::from(io),
-            SlipError::EndOfStream => Self::FramingError,
+    /// Add the offset a failed bootloader command operated on to the error
+    pub(crate) fn at_offset(self, offset: u32) -> Self {
+        match self {
+            Error::RomError(error) => Error::RomError(RomError {
+                offset: Some(offset),
+                ..error
+            }),
+            error => error,
         }
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::Serial(serial::core::Error::from(err))
+    }
+}
+
 impl From<binread::Error> for Error {
     fn from(err: binread::Error) -> Self {
         match err {
@@ -52,31 +147,104 @@ impl From<binread::Error> for Error {
     }
 }
 
+/// Data received from the chip outside of the bootloader protocol, such as boot messages
+#[derive(Clone, Debug)]
+pub struct DeviceOutput(pub Vec<u8>);
+
+/// The amount of output shown in error messages
+const DEVICE_OUTPUT_LIMIT: usize = 256;
+
+impl Display for DeviceOutput {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let shown = &self.0[..self.0.len().min(DEVICE_OUTPUT_LIMIT)];
+        for (i, line) in shown.chunks(16).enumerate() {
+            let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let text: String = line
+                .iter()
+                .map(|&byte| match byte {
+                    0x20..=0x7e => byte as char,
+                    _ => '.',
+                })
+                .collect();
+            writeln!(f, "{:08x}  {:<47}  |{}|", i * 16, hex.join(" "), text)?;
+        }
+        if self.0.len() > shown.len() {
+            writeln!(f, "... {} more bytes", self.0.len() - shown.len())?;
+        }
+
+        let text = String::from_utf8_lossy(shown);
+        let lines: Vec<&str> = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect();
+        write!(f, "as text: {}", lines.join(" / "))
+    }
+}
+
+/// An error reported by the bootloader in response to a command
 #[derive(Copy, Clone, Debug)]
+pub struct RomError {
+    /// The command that failed
+    pub command: Command,
+    /// The flash offset or memory address the command operated on, if known
+    pub offset: Option<u32>,
+    pub kind: RomErrorKind,
+}
+
+impl RomError {
+    pub(crate) fn new(command: Command, code: u8) -> Self {
+        RomError {
+            command,
+            offset: None,
+            kind: RomErrorKind::from(code),
+        }
+    }
+}
+
+impl Display for RomError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.command)?;
+        if let Some(offset) = self.offset {
+            write!(f, " at {:#x}", offset)?;
+        }
+        write!(f, ": {}", self.kind)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Error, Eq, PartialEq)]
 #[allow(dead_code)]
 #[repr(u8)]
-pub enum RomError {
+pub enum RomErrorKind {
+    #[error("invalid message")]
     InvalidMessage = 0x05,
+    #[error("failed to act on the command")]
     FailedToAct = 0x06,
+    #[error("invalid CRC")]
     InvalidCrc = 0x07,
+    #[error("writing to the flash failed")]
     FlashWriteError = 0x08,
+    #[error("reading from the flash failed")]
     FlashReadError = 0x09,
+    #[error("invalid flash read length")]
     FlashReadLengthError = 0x0a,
+    #[error("decompressing the data failed")]
     DeflateError = 0x0b,
+    #[error("unknown error")]
     Other = 0xff,
 }
 
-impl From<u8> for RomError {
+impl From<u8> for RomErrorKind {
     fn from(raw: u8) -> Self {
         match raw {
-            0x05 => RomError::InvalidMessage,
-            0x06 => RomError::FailedToAct,
-            0x07 => RomError::InvalidCrc,
-            0x08 => RomError::FlashWriteError,
-            0x09 => RomError::FlashReadError,
-            0x0a => RomError::FlashReadLengthError,
-            0x0b => RomError::DeflateError,
-            _ => RomError::Other,
+            0x05 => RomErrorKind::InvalidMessage,
+            0x06 => RomErrorKind::FailedToAct,
+            0x07 => RomErrorKind::InvalidCrc,
+            0x08 => RomErrorKind::FlashWriteError,
+            0x09 => RomErrorKind::FlashReadError,
+            0x0a => RomErrorKind::FlashReadLengthError,
+            0x0b => RomErrorKind::DeflateError,
+            _ => RomErrorKind::Other,
         }
     }
 }
@@ -0,0 +1,93 @@
+use crate::chip::{Chip, PartitionTable};
+use crate::image::ImageInfo;
+use crate::Error;
+
+const ESP_MAGIC: u8 = 0xe9;
+const BOOTLOADER_OFFSET: usize = 0x1000;
+const PARTITION_TABLE_OFFSET: usize = 0x8000;
+const PARTITION_TABLE_SIZE: usize = 0x1000;
+
+/// A single file image of the whole flash, written at offset 0
+///
+/// ESPHome, esp-web-tools and `esptool.py merge_bin` publish firmware as a "factory" image that contains the
+/// bootloader, the partition table and the app at their offsets. For the esp32 the layout is detected from the
+/// partition table embedded in the image, esp8266 images are the app itself, which starts with the bootloader.
+#[derive(Debug)]
+pub struct FactoryImage {
+    /// The partition table embedded in esp32 images
+    pub partition_table: Option<PartitionTable>,
+    pub app_offset: u32,
+    pub app: ImageInfo,
+}
+
+impl FactoryImage {
+    /// Detect the layout of a factory image for `chip` and validate the app in it
+    pub fn detect(chip: Chip, data: &[u8]) -> Result<Self, Error> {
+        if chip == Chip::Esp8266 {
+            return Ok(FactoryImage {
+                partition_table: None,
+                app_offset: 0,
+                app: ImageInfo::parse(Chip::Esp8266, data)?,
+            });
+        }
+
+        if data.first() == Some(&ESP_MAGIC) {
+            return Err(Error::InvalidImage(
+                "this is an app image without bootloader and partition table, \
+                it has to be written at the offset of its app partition"
+                    .into(),
+            ));
+        }
+        if data.len() < PARTITION_TABLE_OFFSET + PARTITION_TABLE_SIZE
+            || data[BOOTLOADER_OFFSET] != ESP_MAGIC
+        {
+            return Err(Error::InvalidImage(format!(
+                "there is no bootloader at {:#x}, this isn't a factory image",
+                BOOTLOADER_OFFSET
+            )));
+        }
+        let table = PartitionTable::from_bytes(
+            &data[PARTITION_TABLE_OFFSET..PARTITION_TABLE_OFFSET + PARTITION_TABLE_SIZE],
+        )?;
+        // from_bytes only accepts tables with an app partition
+        let app_offset = table.app_offset().unwrap();
+        let app = data.get(app_offset as usize..).ok_or_else(|| {
+            Error::InvalidImage(format!(
+                "the image ends before the app partition at {:#x}",
+                app_offset
+            ))
+        })?;
+        Ok(FactoryImage {
+            partition_table: Some(table),
+            app_offset,
+            app: ImageInfo::parse(Chip::Esp32, app)?,
+        })
+    }
+}
+
+#[test]
+fn test_factory_image() {
+    use std::fs::read;
+
+    let app = read("./tests/data/esp32.bin").unwrap();
+    let table = PartitionTable::from_csv(
+        "nvs, data, nvs, , 0x5000,\notadata, data, ota, , 0x2000,\napp0, app, ota_0, , 0x140000,\n",
+        PARTITION_TABLE_OFFSET as u32,
+    )
+    .unwrap();
+    let mut data = vec![0xff; 0x10000];
+    data[BOOTLOADER_OFFSET] = ESP_MAGIC;
+    data[PARTITION_TABLE_OFFSET..PARTITION_TABLE_OFFSET + 0xc00].copy_from_slice(&table.to_bytes());
+    data.extend_from_slice(&app);
+
+    let image = FactoryImage::detect(Chip::Esp32, &data).unwrap();
+    assert_eq!(0x10000, image.app_offset);
+    assert_eq!(app.len(), image.app.length);
+
+    // the app alone isn't a factory image
+    assert!(FactoryImage::detect(Chip::Esp32, &app).is_err());
+    assert!(FactoryImage::detect(Chip::Esp32, &data[..0x10000]).is_err());
+
+    let esp8266 = read("./tests/data/esp8266.bin").unwrap();
+    assert!(FactoryImage::detect(Chip::Esp8266, &esp8266).is_ok());
+}
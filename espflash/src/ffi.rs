@@ -0,0 +1,289 @@
+//! A small C api, for tools written in other languages that link espflash instead of running it
+//!
+//! Functions returning an `int` return 0 on success and -1 on failure, after which `espflash_last_error` describes
+//! the failure, null pointers are reported as failures as well. The declarations are in `include/espflash.h`, the
+//! library is built with `cargo rustc --lib --features cdylib --crate-type cdylib`.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr::null_mut;
+use std::slice;
+
+use serial::BaudRate;
+
+use crate::connection::open_port;
+use crate::{Flasher, ProgressCallbacks};
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// Called with the address of the segment being written, the blocks written so far and the blocks in the segment
+pub type ProgressCallback =
+    extern "C" fn(user_data: *mut c_void, addr: u32, current: usize, total: usize);
+
+struct CallbackProgress {
+    callback: ProgressCallback,
+    user_data: *mut c_void,
+    addr: u32,
+    total: usize,
+}
+
+// the caller is responsible for using the user data from the thread using the flasher
+unsafe impl Send for CallbackProgress {}
+
+impl ProgressCallbacks for CallbackProgress {
+    fn init(&mut self, addr: u32, total: usize) {
+        self.addr = addr;
+        self.total = total;
+        (self.callback)(self.user_data, addr, 0, total);
+    }
+
+    fn update(&mut self, current: usize) {
+        (self.callback)(self.user_data, self.addr, current, self.total);
+    }
+
+    fn finish(&mut self) {
+        (self.callback)(self.user_data, self.addr, self.total, self.total);
+    }
+}
+
+fn set_error(error: impl ToString) {
+    let message = error.to_string().replace('\0', " ");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(message).unwrap_or_default());
+}
+
+type CallResult = Result<(), Box<dyn std::error::Error>>;
+
+/// Run `operation`, returning 0 when it succeeds and -1 when it fails
+fn call(operation: impl FnOnce() -> CallResult) -> c_int {
+    status(operation())
+}
+
+/// The flasher behind a pointer returned by `espflash_connect`, or an error for a null pointer
+unsafe fn flasher<'a>(flasher: *mut Flasher) -> Result<&'a mut Flasher, &'static str> {
+    flasher.as_mut().ok_or("the flasher is null")
+}
+
+/// The `len` bytes at `data` to read from, which may only be null when there are none
+unsafe fn input<'a>(data: *const u8, len: usize) -> Result<&'a [u8], &'static str> {
+    match (data.is_null(), len) {
+        (true, 0) => Ok(&[]),
+        (true, _) => Err("the data is null"),
+        (false, len) => Ok(slice::from_raw_parts(data, len)),
+    }
+}
+
+/// The `len` bytes at `data` to write to, which may only be null when there are none
+unsafe fn buffer<'a>(data: *mut u8, len: usize) -> Result<&'a mut [u8], &'static str> {
+    match (data.is_null(), len) {
+        (true, 0) => Ok(&mut []),
+        (true, _) => Err("the data is null"),
+        (false, len) => Ok(slice::from_raw_parts_mut(data, len)),
+    }
+}
+
+fn status<T, E: ToString>(result: Result<T, E>) -> c_int {
+    match result {
+        Ok(_) => 0,
+        Err(error) => {
+            set_error(error);
+            -1
+        }
+    }
+}
+
+/// The description of the last failure on this thread, valid until the next call that fails
+#[no_mangle]
+pub extern "C" fn espflash_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// Connect to the chip on the serial port `port`, switching to `baud` afterwards when it isn't 0
+///
+/// Returns null when connecting fails. The flasher has to be freed with `espflash_free`.
+///
+/// # Safety
+///
+/// `port` has to be a valid nul terminated string or null.
+#[no_mangle]
+pub unsafe extern "C" fn espflash_connect(port: *const c_char, baud: u32) -> *mut Flasher {
+    if port.is_null() {
+        set_error("the port is null");
+        return null_mut();
+    }
+    let port = match CStr::from_ptr(port).to_str() {
+        Ok(port) => port,
+        Err(error) => {
+            set_error(error);
+            return null_mut();
+        }
+    };
+    let speed = Some(baud)
+        .filter(|&baud| baud > 0)
+        .map(|baud| BaudRate::from_speed(baud as usize));
+    let flasher = open_port(port).and_then(|serial| Flasher::connect(serial, speed));
+    match flasher {
+        Ok(flasher) => Box::into_raw(Box::new(flasher)),
+        Err(error) => {
+            set_error(error);
+            null_mut()
+        }
+    }
+}
+
+/// Close the connection and free the flasher
+///
+/// The chip isn't reset, writes to the flash already reset it into the new application when they finish.
+///
+/// # Safety
+///
+/// `flasher` has to be returned by `espflash_connect`, or be null, and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn espflash_free(flasher: *mut Flasher) {
+    if !flasher.is_null() {
+        drop(Box::from_raw(flasher));
+    }
+}
+
+/// Call `callback` with the progress of writes to the flash, instead of drawing a progress bar on the terminal
+///
+/// # Safety
+///
+/// `flasher` has to be returned by `espflash_connect`, `user_data` is passed to the callback as is.
+#[no_mangle]
+pub unsafe extern "C" fn espflash_set_progress_callback(
+    flasher: *mut Flasher,
+    callback: Option<ProgressCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    call(|| {
+        let callback = callback.ok_or("the callback is null")?;
+        self::flasher(flasher)?.set_progress_callbacks(CallbackProgress {
+            callback,
+            user_data,
+            addr: 0,
+            total: 0,
+        });
+        Ok(())
+    })
+}
+
+/// Build the firmware images for the elf image in `data` and write them to the flash
+///
+/// # Safety
+///
+/// `flasher` has to be returned by `espflash_connect` and `data` has to point to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn espflash_flash_elf(
+    flasher: *mut Flasher,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    call(|| {
+        let data = input(data, len)?;
+        self::flasher(flasher)?.load_elf_to_flash(data)?;
+        Ok(())
+    })
+}
+
+/// Write the binary in `data` to the flash at `addr`
+///
+/// # Safety
+///
+/// `flasher` has to be returned by `espflash_connect` and `data` has to point to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn espflash_flash_bin(
+    flasher: *mut Flasher,
+    addr: u32,
+    data: *const u8,
+    len: usize,
+) -> c_int {
+    call(|| {
+        let data = input(data, len)?;
+        self::flasher(flasher)?.write_bin_to_flash(addr, data)?;
+        Ok(())
+    })
+}
+
+/// Read `len` bytes of the flash at `addr` into `buffer`
+///
+/// # Safety
+///
+/// `flasher` has to be returned by `espflash_connect` and `buffer` has to have room for `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn espflash_read_flash(
+    flasher: *mut Flasher,
+    addr: u32,
+    buffer: *mut u8,
+    len: u32,
+) -> c_int {
+    call(|| {
+        let buffer = self::buffer(buffer, len as usize)?;
+        let data = self::flasher(flasher)?.read_flash(addr, len)?;
+        buffer.copy_from_slice(&data);
+        Ok(())
+    })
+}
+
+#[test]
+fn test_ffi() {
+    use crate::testing::MockTransport;
+    use crate::{Chip, FlashSize, ResetAfterFlash};
+
+    extern "C" fn progress(user_data: *mut c_void, _addr: u32, current: usize, total: usize) {
+        unsafe { *(user_data as *mut (usize, usize)) = (current, total) };
+    }
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    let flasher = Box::into_raw(Box::new(flasher));
+    let mut progressed = (0usize, 0usize);
+    let data = [0x5a; 0x1000];
+    let mut read = [0; 0x1000];
+    unsafe {
+        assert_eq!(
+            -1,
+            espflash_set_progress_callback(flasher, None, null_mut())
+        );
+        assert_eq!(
+            "the callback is null",
+            CStr::from_ptr(espflash_last_error()).to_str().unwrap()
+        );
+        espflash_set_progress_callback(
+            flasher,
+            Some(progress),
+            &mut progressed as *mut (usize, usize) as *mut c_void,
+        );
+        assert_eq!(
+            0,
+            espflash_flash_bin(flasher, 0x10000, data.as_ptr(), data.len())
+        );
+        assert_eq!(
+            0,
+            espflash_read_flash(flasher, 0x10000, read.as_mut_ptr(), read.len() as u32)
+        );
+        assert_eq!(-1, espflash_flash_elf(flasher, data.as_ptr(), data.len()));
+        assert!(!CStr::from_ptr(espflash_last_error()).to_bytes().is_empty());
+        assert_eq!(
+            -1,
+            espflash_flash_bin(flasher, 0x10000, std::ptr::null(), 1)
+        );
+        assert_eq!(
+            -1,
+            espflash_read_flash(null_mut(), 0x10000, read.as_mut_ptr(), read.len() as u32)
+        );
+        assert_eq!(
+            "the flasher is null",
+            CStr::from_ptr(espflash_last_error()).to_str().unwrap()
+        );
+        assert!(espflash_connect(std::ptr::null(), 0).is_null());
+        espflash_free(flasher);
+        espflash_free(null_mut());
+    }
+    assert!(progressed.1 > 0);
+    assert_eq!(progressed.0, progressed.1);
+    assert_eq!(data, read);
+}
@@ -0,0 +1,347 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+
+use crate::chip::Chip;
+use crate::elf::{FirmwareImage, FlashFrequency, FlashMode};
+use crate::image::validate_flash_segments;
+use crate::image_settings::ImageSettings;
+use crate::{Error, FlashSize};
+
+/// Data written to the flash, with the file name to save it as
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ImageFile {
+    pub addr: u32,
+    pub name: String,
+    /// The part of an esp-idf build the file is, like `bootloader` or `app`
+    pub role: Option<&'static str>,
+    pub data: Vec<u8>,
+}
+
+/// The files that are written to the flash for an elf image, with the flash settings of the images
+///
+/// Besides the files themselves, the `flash_args` and `flasher_args.json` files of esp-idf builds can be generated,
+/// so tooling built around esptool can flash the files without knowing the offsets or settings.
+pub struct FlashFiles {
+    chip: Chip,
+    flash_mode: FlashMode,
+    flash_size: FlashSize,
+    flash_frequency: FlashFrequency,
+    /// The files in flash order, the same ones a [`Flasher`](crate::Flasher) writes for the image
+    pub files: Vec<ImageFile>,
+}
+
+impl FlashFiles {
+    /// Build and validate the firmware images for an elf image, for a 4MB flash unless set in `settings`
+    pub fn from_elf(chip: Chip, elf_data: &[u8], settings: &ImageSettings) -> Result<Self, Error> {
+        let mut image = FirmwareImage::from_data(elf_data).map_err(|_| Error::InvalidElf)?;
+        settings.apply(&mut image);
+        let segments = chip
+            .get_flash_segments(&image)
+            .collect::<Result<Vec<_>, Error>>()?;
+        validate_flash_segments(chip, &segments)?;
+
+        let files = segments
+            .into_iter()
+            .enumerate()
+            .map(|(i, segment)| {
+                let role = file_role(chip, i);
+                let name = match role {
+                    Some(role) => format!("{}.bin", role),
+                    None => format!("app-{:#07x}.bin", segment.addr),
+                };
+                ImageFile {
+                    addr: segment.addr,
+                    name,
+                    role,
+                    data: segment.data.into_owned(),
+                }
+            })
+            .collect();
+
+        Ok(FlashFiles {
+            chip,
+            flash_mode: image.flash_mode,
+            flash_size: image.flash_size,
+            flash_frequency: image.flash_frequency,
+            files,
+        })
+    }
+
+    fn settings(&self) -> [(&'static str, &'static str); 3] {
+        [
+            ("flash_mode", flash_mode_name(self.flash_mode)),
+            ("flash_size", flash_size_name(self.flash_size)),
+            ("flash_freq", flash_frequency_name(self.flash_frequency)),
+        ]
+    }
+
+    /// The `flash_args` file, the arguments for `esptool.py write_flash @flash_args`
+    pub fn flash_args(&self) -> String {
+        let settings: Vec<String> = self
+            .settings()
+            .iter()
+            .map(|(name, value)| format!("--{} {}", name, value))
+            .collect();
+        let mut args = settings.join(" ");
+        args.push('\n');
+        for file in &self.files {
+            writeln!(args, "{:#x} {}", file.addr, file.name).unwrap();
+        }
+        args
+    }
+
+    /// The `flasher_args.json` file, in the format written by esp-idf builds
+    pub fn flasher_args_json(&self) -> String {
+        let settings = self.settings();
+        let args = FlasherArgs {
+            write_flash_args: settings
+                .iter()
+                .flat_map(|(name, value)| [format!("--{}", name), value.to_string()])
+                .collect(),
+            flash_settings: FlashSettings {
+                flash_mode: settings[0].1,
+                flash_size: settings[1].1,
+                flash_freq: settings[2].1,
+            },
+            flash_files: FileOffsets(&self.files),
+            roles: self
+                .files
+                .iter()
+                .filter_map(|file| {
+                    let role = file.role?;
+                    Some((
+                        role,
+                        RoleFile {
+                            offset: format!("{:#x}", file.addr),
+                            file: &file.name,
+                            encrypted: "false",
+                        },
+                    ))
+                })
+                .collect(),
+            extra_esptool_args: ExtraEsptoolArgs {
+                after: "hard_reset",
+                before: "default_reset",
+                stub: true,
+                chip: chip_name(self.chip),
+            },
+        };
+        // serializing structs of strings doesn't fail
+        let mut json = serde_json::to_string_pretty(&args).unwrap();
+        json.push('\n');
+        json
+    }
+
+    /// All files combined into a single image that is written at offset 0, the gaps between them are erased flash
+    pub fn merged(&self) -> Vec<u8> {
+        let mut merged = Vec::new();
+        for file in &self.files {
+            merged.resize(file.addr as usize, 0xff);
+            merged.extend_from_slice(&file.data);
+        }
+        merged
+    }
+
+    /// The `manifest.json` for esp-web-tools, which installs the merged image saved as `merged_name`
+    ///
+    /// Installing the merged image replaces everything on the flash, so esp-web-tools asks to erase the device first.
+    pub fn web_manifest(&self, name: &str, version: &str, merged_name: &str) -> String {
+        let chip_family = match self.chip {
+            Chip::Esp8266 => "ESP8266",
+            Chip::Esp32 => "ESP32",
+        };
+        let manifest = WebManifest {
+            name,
+            version,
+            new_install_prompt_erase: true,
+            builds: vec![WebBuild {
+                chip_family,
+                parts: vec![WebPart {
+                    path: merged_name,
+                    offset: 0,
+                }],
+            }],
+        };
+        // serializing strings and numbers doesn't fail
+        let mut json = serde_json::to_string_pretty(&manifest).unwrap();
+        json.push('\n');
+        json
+    }
+}
+
+/// The contents of `flasher_args.json`, the files are also listed by their role in the build
+#[derive(Serialize)]
+struct FlasherArgs<'a> {
+    write_flash_args: Vec<String>,
+    flash_settings: FlashSettings,
+    flash_files: FileOffsets<'a>,
+    #[serde(flatten)]
+    roles: BTreeMap<&'static str, RoleFile<'a>>,
+    extra_esptool_args: ExtraEsptoolArgs,
+}
+
+#[derive(Serialize)]
+struct FlashSettings {
+    flash_mode: &'static str,
+    flash_size: &'static str,
+    flash_freq: &'static str,
+}
+
+#[derive(Serialize)]
+struct RoleFile<'a> {
+    offset: String,
+    file: &'a str,
+    encrypted: &'static str,
+}
+
+#[derive(Serialize)]
+struct ExtraEsptoolArgs {
+    after: &'static str,
+    before: &'static str,
+    stub: bool,
+    chip: &'static str,
+}
+
+/// The files by their offset, in the order they're written
+struct FileOffsets<'a>(&'a [ImageFile]);
+
+impl Serialize for FileOffsets<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for file in self.0 {
+            map.serialize_entry(&format!("{:#x}", file.addr), &file.name)?;
+        }
+        map.end()
+    }
+}
+
+/// The `manifest.json` of esp-web-tools
+#[derive(Serialize)]
+struct WebManifest<'a> {
+    name: &'a str,
+    version: &'a str,
+    new_install_prompt_erase: bool,
+    builds: Vec<WebBuild<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WebBuild<'a> {
+    chip_family: &'static str,
+    parts: Vec<WebPart<'a>>,
+}
+
+#[derive(Serialize)]
+struct WebPart<'a> {
+    path: &'a str,
+    offset: u32,
+}
+
+/// The role of the segment at `index`, the esp32 has the bootloader, partition table and app in that order
+fn file_role(chip: Chip, index: usize) -> Option<&'static str> {
+    match chip {
+        Chip::Esp32 => ["bootloader", "partition-table", "app"].get(index).copied(),
+        Chip::Esp8266 => None,
+    }
+}
+
+fn chip_name(chip: Chip) -> &'static str {
+    match chip {
+        Chip::Esp8266 => "esp8266",
+        Chip::Esp32 => "esp32",
+    }
+}
+
+fn flash_mode_name(mode: FlashMode) -> &'static str {
+    match mode {
+        FlashMode::Qio => "qio",
+        FlashMode::Qout => "qout",
+        FlashMode::Dio => "dio",
+        FlashMode::Dout => "dout",
+    }
+}
+
+fn flash_frequency_name(frequency: FlashFrequency) -> &'static str {
+    match frequency {
+        FlashFrequency::Flash40M => "40m",
+        FlashFrequency::Flash26M => "26m",
+        FlashFrequency::Flash20M => "20m",
+        FlashFrequency::Flash80M => "80m",
+    }
+}
+
+fn flash_size_name(size: FlashSize) -> &'static str {
+    match size {
+        FlashSize::Flash256Kb => "256KB",
+        FlashSize::Flash512Kb => "512KB",
+        FlashSize::Flash1Mb => "1MB",
+        FlashSize::Flash2Mb => "2MB",
+        FlashSize::Flash4Mb => "4MB",
+        FlashSize::Flash8Mb => "8MB",
+        FlashSize::Flash16Mb => "16MB",
+        FlashSize::FlashRetry => "detect",
+    }
+}
+
+#[test]
+fn test_flash_args() {
+    use std::fs::read;
+
+    let elf = read("./tests/data/esp32").unwrap();
+    let files = FlashFiles::from_elf(Chip::Esp32, &elf, &ImageSettings::default()).unwrap();
+    crate::ImageInfo::parse(Chip::Esp32, &files.files[2].data).unwrap();
+    assert_eq!(
+        "--flash_mode dio --flash_size 4MB --flash_freq 40m\n\
+         0x1000 bootloader.bin\n\
+         0x8000 partition-table.bin\n\
+         0x10000 app.bin\n",
+        files.flash_args()
+    );
+    let json: serde_json::Value = serde_json::from_str(&files.flasher_args_json()).unwrap();
+    assert_eq!(
+        serde_json::json!([
+            "--flash_mode",
+            "dio",
+            "--flash_size",
+            "4MB",
+            "--flash_freq",
+            "40m"
+        ]),
+        json["write_flash_args"]
+    );
+    assert_eq!("4MB", json["flash_settings"]["flash_size"]);
+    assert_eq!("app.bin", json["flash_files"]["0x10000"]);
+    assert_eq!(
+        serde_json::json!({ "offset": "0x10000", "file": "app.bin", "encrypted": "false" }),
+        json["app"]
+    );
+    assert_eq!("esp32", json["extra_esptool_args"]["chip"]);
+
+    let merged = files.merged();
+    assert_eq!(0x10000 + files.files[2].data.len(), merged.len());
+    assert!(merged[..0x1000].iter().all(|&byte| byte == 0xff));
+    assert_eq!(
+        files.files[1].data,
+        &merged[0x8000..0x8000 + files.files[1].data.len()]
+    );
+    let manifest: serde_json::Value =
+        serde_json::from_str(&files.web_manifest("blink \"test\"", "1.0", "blink.factory.bin"))
+            .unwrap();
+    assert_eq!("blink \"test\"", manifest["name"]);
+    assert_eq!(true, manifest["new_install_prompt_erase"]);
+    assert_eq!(
+        serde_json::json!([{
+            "chipFamily": "ESP32",
+            "parts": [{ "path": "blink.factory.bin", "offset": 0 }]
+        }]),
+        manifest["builds"]
+    );
+
+    let elf = read("./tests/data/esp8266").unwrap();
+    let files = FlashFiles::from_elf(Chip::Esp8266, &elf, &ImageSettings::default()).unwrap();
+    let names: Vec<&str> = files.files.iter().map(|file| file.name.as_str()).collect();
+    assert_eq!(vec!["app-0x00000.bin"], names);
+}
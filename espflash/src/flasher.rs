@@ -1,33 +1,83 @@
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::fmt::{self, Display, Formatter};
+use std::iter::once;
 use std::mem::size_of;
+use std::ops::Range;
+use std::str::FromStr;
 
-use crate::chip::Chip;
-use crate::connection::Connection;
-use crate::elf::FirmwareImage;
+use crate::chip::{Chip, ChipFeature, FlashVoltage, PartitionTable, Workarounds};
+use crate::connection::{CommandTiming, Connection};
+use crate::efuse::{self, DownloadLockdown, EfuseValue, KeyPurpose};
+use crate::elf::{FirmwareImage, FlashFrequency, FlashMode, RomSegment};
 use crate::encoder::SlipEncoder;
-use crate::error::RomError;
+use crate::error::{DeviceOutput, RomError, RomErrorKind};
+use crate::flash_args::ImageFile;
+use crate::image::{validate_flash_segments, ESP_MAGIC};
+use crate::image_settings::ImageSettings;
+use crate::stats::FlashStats;
+use crate::stub::Stub;
 use crate::Error;
 use bytemuck::__core::time::Duration;
 use bytemuck::{bytes_of, Pod, Zeroable};
 use indicatif::{ProgressBar, ProgressStyle};
 use serial::{BaudRate, SerialPort};
-use std::thread::sleep;
+use std::sync::mpsc;
+use std::thread::{self, sleep};
 
-type Encoder<'a> = SlipEncoder<'a, Box<dyn SerialPort>>;
+type Encoder<'a> = SlipEncoder<'a, Vec<u8>>;
 
 const MAX_RAM_BLOCK_SIZE: usize = 0x1800;
-const FLASH_SECTOR_SIZE: usize = 0x1000;
+/// The size of the sectors of the flash, the smallest part that can be erased
+pub const FLASH_SECTOR_SIZE: usize = 0x1000;
 const FLASH_BLOCK_SIZE: usize = 0x100;
 const FLASH_SECTORS_PER_BLOCK: usize = FLASH_SECTOR_SIZE / FLASH_BLOCK_SIZE;
-const FLASH_WRITE_SIZE: usize = 0x400;
-
-// registers used for chip detect
-const UART_DATE_REG_ADDR: u32 = 0x60000078;
-const UART_DATE_REG2_ADDR: u32 = 0x3f400074;
-
-#[derive(Copy, Clone, Debug)]
+/// The size of the blocks erased with a single command by the loader
+const FLASH_ERASE_BLOCK_SIZE: u32 = 0x10000;
+const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+const PARTITION_TABLE_LENGTH: u32 = 0xc00;
+/// All bits of the status register are writable
+const FLASH_STATUS_MASK: u32 = 0xffff;
+/// The size of the blocks written to the flash by the rom bootloader
+const ROM_FLASH_WRITE_SIZE: usize = 0x400;
+/// The stub accepts larger blocks, which saves a round trip for every 16KB instead of every 1KB
+const STUB_FLASH_WRITE_SIZE: usize = 0x4000;
+
+const SPI_FLASH_READ: u8 = 0x03;
+const SPI_FLASH_READ_STATUS: u8 = 0x05;
+const SPI_FLASH_READ_STATUS2: u8 = 0x35;
+const SPI_FLASH_WRITE_STATUS: u8 = 0x01;
+const SPI_FLASH_WRITE_STATUS2: u8 = 0x31;
+const SPI_FLASH_WRITE_ENABLE: u8 = 0x06;
+/// The largest divider of the spi clock, the counter of the clock register has 6 bits
+const MAX_SPI_CLOCK_DIVIDER: u8 = 64;
+/// How often the status register is polled for the end of a status register write, which takes up to 15ms
+const SPI_FLASH_BUSY_POLLS: usize = 50;
+
+// the number of status bytes at the end of a response from the esp32 rom
+const ROM_STATUS_LENGTH: usize = 4;
+// the number of status bytes at the end of a response from the flasher stub
+const STUB_STATUS_LENGTH: usize = 2;
+
+const READ_FLASH_SECTOR_SIZE: u32 = 0x1000;
+const READ_FLASH_PACKETS_IN_FLIGHT: u32 = 64;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+/// How often the bootloader is synced after resetting the chip before giving up
+const SYNC_ATTEMPTS: usize = 10;
+/// How long to listen for boot messages when the chip doesn't respond
+const BOOT_LOG_DURATION: Duration = Duration::from_millis(500);
+/// How often a block that failed to write is retried before giving up
+const DEFAULT_BLOCK_RETRIES: usize = 3;
+
+// register used for chip detect
+pub(crate) const UART_DATE_REG_ADDR: u32 = 0x60000078;
+
+/// A command of the bootloader protocol
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 #[repr(u8)]
 #[allow(dead_code)]
-enum Command {
+pub enum Command {
     FlashBegin = 0x02,
     FlashData = 0x03,
     FlashEnd = 0x04,
@@ -40,19 +90,59 @@ enum Command {
     SpiSetParams = 0x0B,
     SpiAttach = 0x0D,
     ChangeBaud = 0x0F,
+    SpiFlashMd5 = 0x13,
+    GetSecurityInfo = 0x14,
+    EraseFlash = 0xd0,
+    EraseRegion = 0xd1,
+    ReadFlash = 0xd2,
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Command::FlashBegin => "FLASH_BEGIN",
+            Command::FlashData => "FLASH_DATA",
+            Command::FlashEnd => "FLASH_END",
+            Command::MemBegin => "MEM_BEGIN",
+            Command::MemEnd => "MEM_END",
+            Command::MemData => "MEM_DATA",
+            Command::Sync => "SYNC",
+            Command::WriteReg => "WRITE_REG",
+            Command::ReadReg => "READ_REG",
+            Command::SpiSetParams => "SPI_SET_PARAMS",
+            Command::SpiAttach => "SPI_ATTACH",
+            Command::ChangeBaud => "CHANGE_BAUDRATE",
+            Command::SpiFlashMd5 => "SPI_FLASH_MD5",
+            Command::GetSecurityInfo => "GET_SECURITY_INFO",
+            Command::EraseFlash => "ERASE_FLASH",
+            Command::EraseRegion => "ERASE_REGION",
+            Command::ReadFlash => "READ_FLASH",
+        };
+        f.write_str(name)
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 #[allow(dead_code)]
 #[repr(u8)]
 pub enum FlashSize {
+    #[cfg_attr(feature = "serialize", serde(rename = "256KB"))]
     Flash256Kb = 0x12,
+    #[cfg_attr(feature = "serialize", serde(rename = "512KB"))]
     Flash512Kb = 0x13,
+    #[cfg_attr(feature = "serialize", serde(rename = "1MB"))]
     Flash1Mb = 0x14,
+    #[cfg_attr(feature = "serialize", serde(rename = "2MB"))]
     Flash2Mb = 0x15,
+    #[cfg_attr(feature = "serialize", serde(rename = "4MB"))]
     Flash4Mb = 0x16,
+    #[cfg_attr(feature = "serialize", serde(rename = "8MB"))]
     Flash8Mb = 0x17,
+    #[cfg_attr(feature = "serialize", serde(rename = "16MB"))]
     Flash16Mb = 0x18,
+    /// Serialized as `unknown`, which isn't accepted when deserializing
+    #[cfg_attr(feature = "serialize", serde(rename = "unknown", skip_deserializing))]
     FlashRetry = 0xFF, // used to hint that alternate detection should be tried
 }
 
@@ -70,19 +160,75 @@ impl FlashSize {
             _ => Err(Error::UnsupportedFlash(value)),
         }
     }
+
+    /// The size of the flash in bytes, `None` for a flash of unknown size
+    pub fn size_in_bytes(self) -> Option<u32> {
+        match self {
+            FlashSize::Flash256Kb => Some(0x40000),
+            FlashSize::Flash512Kb => Some(0x80000),
+            FlashSize::Flash1Mb => Some(0x100000),
+            FlashSize::Flash2Mb => Some(0x200000),
+            FlashSize::Flash4Mb => Some(0x400000),
+            FlashSize::Flash8Mb => Some(0x800000),
+            FlashSize::Flash16Mb => Some(0x1000000),
+            FlashSize::FlashRetry => None,
+        }
+    }
+}
+
+impl Display for FlashSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FlashSize::Flash256Kb => "256KB",
+            FlashSize::Flash512Kb => "512KB",
+            FlashSize::Flash1Mb => "1MB",
+            FlashSize::Flash2Mb => "2MB",
+            FlashSize::Flash4Mb => "4MB",
+            FlashSize::Flash8Mb => "8MB",
+            FlashSize::Flash16Mb => "16MB",
+            FlashSize::FlashRetry => "unknown",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The flash size wasn't recognized
+#[derive(Debug, thiserror::Error)]
+#[error("unknown flash size {0}, expected one of 256KB, 512KB, 1MB, 2MB, 4MB, 8MB or 16MB")]
+pub struct InvalidFlashSize(String);
+
+impl FromStr for FlashSize {
+    type Err = InvalidFlashSize;
+
+    /// Parse a flash size like `4MB` or `512KB`, ignoring case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "256KB" => Ok(FlashSize::Flash256Kb),
+            "512KB" => Ok(FlashSize::Flash512Kb),
+            "1MB" => Ok(FlashSize::Flash1Mb),
+            "2MB" => Ok(FlashSize::Flash2Mb),
+            "4MB" => Ok(FlashSize::Flash4Mb),
+            "8MB" => Ok(FlashSize::Flash8Mb),
+            "16MB" => Ok(FlashSize::Flash16Mb),
+            _ => Err(InvalidFlashSize(s.to_string())),
+        }
+    }
 }
 
-#[derive(Copy, Clone)]
+/// The gpio pins the spi flash is connected to, passed to the bootloader when attaching the flash
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 #[repr(C)]
-struct SpiAttachParams {
-    clk: u8,
-    q: u8,
-    d: u8,
-    hd: u8,
-    cs: u8,
+pub struct SpiAttachParams {
+    pub clk: u8,
+    pub q: u8,
+    pub d: u8,
+    pub hd: u8,
+    pub cs: u8,
 }
 
 impl SpiAttachParams {
+    /// The pins of the internal flash, as configured by the efuses
     pub const fn default() -> Self {
         SpiAttachParams {
             clk: 0,
@@ -93,6 +239,17 @@ impl SpiAttachParams {
         }
     }
 
+    /// The pins of the HSPI peripheral, which the rom selects with the value 1
+    pub const fn hspi() -> Self {
+        SpiAttachParams {
+            clk: 1,
+            q: 0,
+            d: 0,
+            hd: 0,
+            cs: 0,
+        }
+    }
+
     pub const fn esp32_pico_d4() -> Self {
         SpiAttachParams {
             clk: 6,
@@ -155,36 +312,370 @@ struct EntryParams {
     entry: u32,
 }
 
+/// Information about the connected board
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize))]
+pub struct BoardInfo {
+    pub chip: Chip,
+    pub revision: Option<u32>,
+    /// Crystal frequency in MHz
+    pub crystal_frequency: u32,
+    pub mac_address: [u8; 6],
+    pub features: Vec<ChipFeature>,
+    /// JEDEC manufacturer id of the flash chip
+    pub flash_manufacturer: u8,
+    /// JEDEC device id of the flash chip
+    pub flash_device: u16,
+    pub flash_size: FlashSize,
+    /// The voltage the chip supplies the flash with, `None` when the flash has its own supply
+    pub flash_voltage: Option<FlashVoltage>,
+    pub psram: PsramStatus,
+}
+
+/// Whether a board has psram, see [`Flasher::set_probe_psram`]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub enum PsramStatus {
+    Present(Psram),
+    /// No psram answered the probe
+    Absent,
+    /// The psram wasn't probed, or probing it failed
+    Unknown,
+}
+
+/// The psram found on a board
+///
+/// The psram of the esp32 is a quad spi chip that shares the data lines of the flash, with its own chip select
+/// and, on most modules, its own clock pin.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct Psram {
+    /// Size in bytes
+    pub size: u32,
+    /// Whether the psram is in the package of the chip, instead of a separate chip on the module
+    pub embedded: bool,
+    /// The GPIO of the chip select line
+    pub cs_pin: u8,
+    /// The GPIO of the clock line
+    pub clk_pin: u8,
+}
+
+/// What to do with the chip after writing to the flash
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ResetAfterFlash {
+    /// Reset the chip, running the newly flashed application
+    HardReset,
+    /// Leave the chip in the bootloader, so further commands can be sent
+    NoReset,
+}
+
+/// How to get the chip into the bootloader when connecting
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ResetBeforeConnect {
+    /// Reset the chip into the bootloader, unless it's still in the bootloader and answers right away
+    DefaultReset,
+    /// The chip is known to be in the bootloader, it's only synced, without toggling dtr and rts
+    NoReset,
+}
+
+/// The saved spi user registers, restored when the guard is dropped, see [`Flasher::spi_guard`]
+pub struct SpiGuard<'a> {
+    flasher: &'a mut Flasher,
+    /// The saved user and data length registers with their values
+    registers: Vec<(u32, u32)>,
+    restored: bool,
+}
+
+impl SpiGuard<'_> {
+    /// Send a command to the spi flash, like [`Flasher::spi_command`] but without restoring the registers
+    pub fn command(&mut self, command: u8, data: &[u8], read_bits: u32) -> Result<u32, Error> {
+        self.flasher.run_spi_command(command, data, read_bits)
+    }
+
+    /// Restore the spi user registers, reporting errors unlike dropping the guard
+    pub fn restore(mut self) -> Result<(), Error> {
+        self.restored = true;
+        self.write_registers()
+    }
+
+    fn write_registers(&mut self) -> Result<(), Error> {
+        self.flasher.write_regs(&self.registers)
+    }
+}
+
+impl Drop for SpiGuard<'_> {
+    fn drop(&mut self) {
+        if !self.restored {
+            let _ = self.write_registers();
+        }
+    }
+}
+
+/// A milestone of the connection to the chip, reported to the hook set with [`ConnectOptions::event_hook`]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ConnectionEvent {
+    /// The chip was reset into the bootloader
+    Reset,
+    /// The bootloader answered the sync
+    Synced,
+    /// The type of the chip was detected
+    ChipDetected(Chip),
+    /// The flasher stub was started
+    StubLoaded,
+    /// The baud rate was changed
+    BaudChanged(usize),
+}
+
+type EventHook = Box<dyn FnMut(ConnectionEvent) + Send>;
+
+/// How to connect to a chip, see [`Flasher::connect_with_options`]
+///
+/// ```no_run
+/// # fn main() -> Result<(), espflash::Error> {
+/// # let serial = serial::open("/dev/ttyUSB0")?;
+/// use espflash::{ConnectOptions, FlashSize, Flasher, ResetBeforeConnect};
+///
+/// let options = ConnectOptions::new()
+///     .reset(ResetBeforeConnect::NoReset)
+///     .flash_size(Some(FlashSize::Flash4Mb))
+///     .event_hook(|event| println!("{:?}", event));
+/// let flasher = Flasher::connect_with_options(serial, options)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ConnectOptions {
+    speed: Option<BaudRate>,
+    reset: ResetBeforeConnect,
+    spi_connection: Option<SpiAttachParams>,
+    flash_size: Option<FlashSize>,
+    event_hook: Option<EventHook>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            speed: None,
+            reset: ResetBeforeConnect::DefaultReset,
+            spi_connection: None,
+            flash_size: None,
+            event_hook: None,
+        }
+    }
+}
+
+impl ConnectOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The baud rate to switch to after connecting, the connection starts at 115200
+    pub fn speed(mut self, speed: Option<BaudRate>) -> Self {
+        self.speed = speed;
+        self
+    }
+
+    /// How the chip is brought into the bootloader
+    ///
+    /// A chip that was left in the bootloader, like after flashing with [`ResetAfterFlash::NoReset`], is
+    /// detected and used without resetting it in either case. With [`ResetBeforeConnect::NoReset`] the chip is
+    /// never reset, which saves the reset and the retries on lines where the chip is put in the bootloader
+    /// by other means, and leaves dtr and rts alone.
+    pub fn reset(mut self, reset: ResetBeforeConnect) -> Self {
+        self.reset = reset;
+        self
+    }
+
+    /// The pins the flash is on, instead of detecting them
+    ///
+    /// This attaches an external flash, or a flash that isn't on the pins set by the efuses, which the detection
+    /// only finds for the default pins and the pins of the esp32-pico-d4. The esp8266 only supports the default
+    /// pins.
+    pub fn spi_connection(mut self, spi_connection: Option<SpiAttachParams>) -> Self {
+        self.spi_connection = spi_connection;
+        self
+    }
+
+    /// The size of the flash, used when it can't be detected
+    ///
+    /// Some flash chips, often on cheap clones, report a size in their id that isn't known. Without a flash size
+    /// connecting fails for them, with one a warning is printed and the flash is used with the given size.
+    pub fn flash_size(mut self, flash_size: Option<FlashSize>) -> Self {
+        self.flash_size = flash_size;
+        self
+    }
+
+    /// Call `hook` for the milestones of the connection
+    ///
+    /// Applications can show what the connection is doing with these, instead of waiting for the connection as a
+    /// whole. The hook keeps receiving events after connecting, like when the stub is loaded or the baud rate
+    /// changes.
+    pub fn event_hook(mut self, hook: impl FnMut(ConnectionEvent) + Send + 'static) -> Self {
+        self.event_hook = Some(Box::new(hook));
+        self
+    }
+}
+
+/// Receives progress updates while writing to or reading from the flash
+///
+/// When no callbacks are set, progress is shown as a progress bar on the terminal.
+pub trait ProgressCallbacks {
+    /// A new segment of `total` blocks is being written at `addr`
+    fn init(&mut self, addr: u32, total: usize);
+    /// `current` blocks of the segment have been written
+    fn update(&mut self, current: usize);
+    /// The segment has been written
+    fn finish(&mut self);
+}
+
+/// Draws a progress bar on the terminal for every segment
+#[derive(Default)]
+struct ProgressBarCallbacks {
+    addr: u32,
+    bar: Option<ProgressBar>,
+}
+
+impl ProgressCallbacks for ProgressBarCallbacks {
+    fn init(&mut self, addr: u32, total: usize) {
+        let bar = ProgressBar::new(total as u64);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
+                .progress_chars("#>-"),
+        );
+        bar.set_message(&format!("segment 0x{:X} writing chunks", addr));
+        self.addr = addr;
+        self.bar = Some(bar);
+    }
+
+    fn update(&mut self, current: usize) {
+        if let Some(bar) = &self.bar {
+            bar.set_position(current as u64);
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(bar) = self.bar.take() {
+            bar.finish_with_message(&format!("segment 0x{:X}", self.addr));
+        }
+    }
+}
+
+/// A connection to the bootloader of a chip, for flashing and other operations on it
+///
+/// The flasher is `Send`, so an application can connect on a worker thread or move the flasher to one, like a gui
+/// that keeps its event loop responsive while flashing. It isn't `Sync`, every operation needs `&mut self`, so it's
+/// used from one thread at a time, wrapped in a `Mutex` when several threads share it. The serial port, the
+/// progress callbacks and the hooks have to be `Send` for this, they're called on the thread using the flasher.
 pub struct Flasher {
     connection: Connection,
     chip: Chip,
     flash_size: FlashSize,
+    flash_id: u32,
     spi_params: SpiAttachParams,
+    reset_after_flash: ResetAfterFlash,
+    progress: Box<dyn ProgressCallbacks + Send>,
+    stub_active: bool,
+    block_retries: usize,
+    resume: bool,
+    probe_psram: bool,
+    image_settings: ImageSettings,
+    /// The parameters the flash is currently attached with, it stays attached until the chip is reset
+    attached: Option<SpiAttachParams>,
+    /// Divider of the 80MHz spi clock, set after attaching the flash
+    spi_clock_divider: Option<u8>,
+    event_hook: EventHook,
 }
 
 impl Flasher {
     pub fn connect(
-        serial: impl SerialPort + 'static,
+        serial: impl SerialPort + Send + 'static,
         speed: Option<BaudRate>,
     ) -> Result<Self, Error> {
+        Self::connect_with_options(serial, ConnectOptions::new().speed(speed))
+    }
+
+    /// Connect to a chip, with the options for how it's brought into the bootloader and how its flash is found
+    pub fn connect_with_options(
+        serial: impl SerialPort + Send + 'static,
+        options: ConnectOptions,
+    ) -> Result<Self, Error> {
+        let ConnectOptions {
+            speed,
+            reset,
+            spi_connection,
+            flash_size,
+            event_hook,
+        } = options;
         let mut flasher = Flasher {
-            connection: Connection::new(serial), // default baud is always 115200
-            chip: Chip::Esp8266,                 // dummy, set properly later
+            connection: Connection::new(serial)?, // default baud is always 115200
+            chip: Chip::Esp8266,                  // dummy, set properly later
             flash_size: FlashSize::Flash4Mb,
+            flash_id: 0,
+            reset_after_flash: ResetAfterFlash::HardReset,
+            progress: Box::new(ProgressBarCallbacks::default()),
+            stub_active: false,
+            block_retries: DEFAULT_BLOCK_RETRIES,
+            resume: false,
+            probe_psram: false,
+            image_settings: ImageSettings::default(),
+            attached: None,
+            spi_clock_divider: None,
             spi_params: SpiAttachParams::default(), // may be set when trying to attach to flash
+            event_hook: event_hook.unwrap_or_else(|| Box::new(|_| {})),
         };
-        flasher.start_connection()?;
-        flasher.connection.set_timeout(Duration::from_secs(3))?;
+        flasher.start_connection(reset)?;
+        flasher.event(ConnectionEvent::Synced);
+        flasher.connection.set_timeout(DEFAULT_TIMEOUT)?;
         flasher.chip_detect()?;
-        flasher.spi_autodetect()?;
+        flasher.event(ConnectionEvent::ChipDetected(flasher.chip));
+        match spi_connection {
+            Some(spi_params) if spi_params != SpiAttachParams::default() => {
+                if flasher.chip == Chip::Esp8266 {
+                    return Err(Error::UnsupportedOperation {
+                        operation: "attaching the flash on other pins",
+                        chip: flasher.chip,
+                    });
+                }
+                flasher.spi_params = spi_params;
+            }
+            _ => {}
+        }
+        let detected = if spi_connection.is_some() {
+            flasher.enable_flash(flasher.spi_params)?;
+            flasher.flash_detect().and_then(|found| match found {
+                true => Ok(()),
+                false => Err(Error::UnsupportedFlash(FlashSize::FlashRetry as u8)),
+            })
+        } else {
+            flasher.spi_autodetect()
+        };
+        match (detected, flash_size) {
+            (Err(Error::UnsupportedFlash(size_id)), Some(flash_size)) => {
+                eprintln!(
+                    "Warning: the flash chip reports the unknown size id {:#04x}, using the given size of {}",
+                    size_id, flash_size
+                );
+                flasher.flash_size = flash_size;
+                // the detection might have left other pins attached
+                flasher.enable_flash(flasher.spi_params)?;
+            }
+            (detected, _) => detected?,
+        }
+        flasher.set_flash_params()?;
 
         if let Some(b) = speed {
             match flasher.chip {
                 Chip::Esp8266 => (), /* Not available */
                 Chip::Esp32 => {
                     if b.speed() > BaudRate::Baud115200.speed() {
-                        println!("WARN setting baud rate higher than 115200 can cause issues.");
-                        flasher.change_baud(b)?;
+                        if flasher.workarounds()?.unreliable_high_baud {
+                            eprintln!(
+                                "Warning: the rom loader of this chip revision isn't reliable above 115200 baud, \
+                                staying at 115200 baud"
+                            );
+                        } else {
+                            flasher.change_baud(b)?;
+                        }
                     }
                 }
             }
@@ -197,10 +688,19 @@ impl Flasher {
         // loop over all available spi params until we find one that successfully reads the flash size
         for spi_params in TRY_SPI_PARAMS.iter().copied() {
             self.enable_flash(spi_params)?;
-            if self.flash_detect()? {
-                // flash detect successful, save these spi params
-                self.spi_params = spi_params;
-                return Ok(());
+            match self.flash_detect() {
+                Ok(true) => {
+                    // flash detect successful, save these spi params
+                    self.spi_params = spi_params;
+                    return Ok(());
+                }
+                Ok(false) => {}
+                Err(e @ Error::UnsupportedFlash(_)) => {
+                    // a flash that reports an unknown size is still on these pins
+                    self.spi_params = spi_params;
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
             }
         }
 
@@ -209,67 +709,159 @@ impl Flasher {
     }
 
     fn chip_detect(&mut self) -> Result<(), Error> {
-        let reg1 = self.read_reg(UART_DATE_REG_ADDR)?;
-        let reg2 = self.read_reg(UART_DATE_REG2_ADDR)?;
-        let chip = Chip::from_regs(reg1, reg2).ok_or(Error::UnrecognizedChip)?;
+        let date = match self.read_reg(UART_DATE_REG_ADDR) {
+            Err(Error::RomError(error)) if error.kind == RomErrorKind::InvalidMessage => {
+                return self.secure_chip_detect();
+            }
+            result => result?,
+        };
+        let chip = Chip::from_regs(date).ok_or(Error::UnrecognizedChip)?;
 
         self.chip = chip;
         Ok(())
     }
 
+    /// Registers can't be read in secure download mode, the chip is identified by its security info instead
+    ///
+    /// Only the ESP32-S2 and later chips have a secure download mode, which espflash doesn't support yet, so they're
+    /// reported by name.
+    fn secure_chip_detect(&mut self) -> Result<(), Error> {
+        let info = self.connection.command_data(
+            Command::GetSecurityInfo,
+            &[][..],
+            0,
+            ROM_STATUS_LENGTH,
+        )?;
+        Err(secure_chip_name(&info).map_or(Error::UnrecognizedChip, Error::UnsupportedChip))
+    }
+
     fn flash_detect(&mut self) -> Result<bool, Error> {
         let flash_id = self.spi_command(0x9f, &[], 24)?;
         let size_id = flash_id >> 16;
 
+        self.flash_id = flash_id;
         self.flash_size = FlashSize::from(size_id as u8)?;
         Ok(self.flash_size != FlashSize::FlashRetry)
     }
 
+    /// Tell the loader the geometry of the flash, it assumes a 4MB flash otherwise
+    ///
+    /// The loader checks writes and erases against the size, which goes wrong for the embedded flash of the
+    /// esp8285 and the esp32-d2wd, and other flash chips that differ from the default.
+    fn set_flash_params(&mut self) -> Result<(), Error> {
+        let params = [
+            0, // flash id
+            self.flash_size_in_bytes()?,
+            FLASH_ERASE_BLOCK_SIZE,
+            FLASH_SECTOR_SIZE as u32,
+            FLASH_BLOCK_SIZE as u32,
+            FLASH_STATUS_MASK,
+        ];
+        let data: Vec<u8> = params
+            .iter()
+            .flat_map(|param| param.to_le_bytes())
+            .collect();
+        self.connection
+            .command(Command::SpiSetParams, data.as_slice(), 0)?;
+        Ok(())
+    }
+
     fn sync(&mut self) -> Result<(), Error> {
         self.connection
             .with_timeout(Duration::from_millis(100), |connection| {
-                let data = &[
-                    0x07u8, 0x07, 0x012, 0x20, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
-                    0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
-                    0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
-                ][..];
-
-                connection.write_command(Command::Sync as u8, data, 0)?;
-
-                for _ in 0..10 {
-                    match connection.read_response()? {
-                        Some(response) if response.return_op == Command::Sync as u8 => {
-                            if response.status == 1 {
-                                return Err(Error::RomError(RomError::from(response.error)));
-                            } else {
-                                break;
+                connection.timed(Command::Sync, |connection| {
+                    let data = &[
+                        0x07u8, 0x07, 0x012, 0x20, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+                        0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+                        0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55, 0x55,
+                    ][..];
+
+                    connection.write_command(Command::Sync, data, 0)?;
+
+                    for _ in 0..10 {
+                        match connection.read_response()? {
+                            Some(response) if response.return_op == Command::Sync as u8 => {
+                                if response.status == 1 {
+                                    return Err(Error::RomError(RomError::new(
+                                        Command::Sync,
+                                        response.error,
+                                    )));
+                                } else {
+                                    break;
+                                }
                             }
+                            _ => continue,
                         }
-                        _ => continue,
                     }
-                }
 
-                for _ in 0..7 {
-                    loop {
-                        match connection.read_response()? {
-                            Some(_) => break,
-                            _ => continue,
+                    for _ in 0..7 {
+                        loop {
+                            match connection.read_response()? {
+                                Some(_) => break,
+                                _ => continue,
+                            }
                         }
                     }
-                }
-                Ok(())
+                    Ok(())
+                })
             })
     }
 
-    fn start_connection(&mut self) -> Result<(), Error> {
+    fn start_connection(&mut self, reset: ResetBeforeConnect) -> Result<(), Error> {
+        // a chip that's still in the bootloader answers the first sync
+        if self.sync().is_ok() {
+            return Ok(());
+        }
+        if reset == ResetBeforeConnect::NoReset {
+            for _ in 0..SYNC_ATTEMPTS {
+                self.connection.flush()?;
+                if self.sync().is_ok() {
+                    return Ok(());
+                }
+            }
+            return Err(Error::ConnectionFailed);
+        }
+
+        self.connection.start_capture();
         self.connection.reset_to_flash()?;
-        for _ in 0..10 {
+        self.event(ConnectionEvent::Reset);
+        for _ in 0..SYNC_ATTEMPTS {
             self.connection.flush()?;
             if self.sync().is_ok() {
+                self.connection.stop_capture();
                 return Ok(());
             }
         }
-        Err(Error::ConnectionFailed)
+        let output = outside_slip_frames(&self.connection.stop_capture());
+
+        if let Some(problem) = boot_problem(&String::from_utf8_lossy(&output)) {
+            return Err(Error::WrongBootMode(problem));
+        }
+        match self.read_boot_problem() {
+            Ok(Some(problem)) => Err(Error::WrongBootMode(problem)),
+            _ if !output.is_empty() => Err(Error::UnexpectedOutput(DeviceOutput(output))),
+            _ => Err(Error::ConnectionFailed),
+        }
+    }
+
+    /// Reset the chip again and look for a reason it didn't enter the bootloader in its boot messages
+    fn read_boot_problem(&mut self) -> Result<Option<String>, Error> {
+        let baud = self.connection.baud();
+
+        // the esp32 prints its boot messages at 115200 baud, the esp8266 at 74880 baud
+        let mut problem = None;
+        for &log_baud in &[BaudRate::Baud115200, BaudRate::BaudOther(74880)] {
+            self.connection.set_baud(log_baud)?;
+            self.connection.reset_to_flash()?;
+            let log = self.connection.read_raw(BOOT_LOG_DURATION)?;
+            problem = boot_problem(&String::from_utf8_lossy(&log));
+            if problem.is_some() {
+                break;
+            }
+        }
+
+        self.connection.set_baud(baud)?;
+        Ok(problem)
     }
 
     fn begin_command(
@@ -286,8 +878,16 @@ impl Flasher {
             block_size,
             offset,
         };
+        // the bootloader erases the flash region before replying
+        let timeout = match command {
+            Command::FlashBegin => erase_timeout(size),
+            _ => DEFAULT_TIMEOUT,
+        };
         self.connection
-            .command(command as u8, bytes_of(&params), 0)?;
+            .with_timeout(timeout, |connection| {
+                connection.command(command, bytes_of(&params), 0)
+            })
+            .map_err(|e| e.at_offset(offset))?;
         Ok(())
     }
 
@@ -299,32 +899,10 @@ impl Flasher {
         padding_byte: u8,
         sequence: u32,
     ) -> Result<(), Error> {
-        let params = BlockParams {
-            size: (data.len() + padding) as u32,
-            sequence,
-            dummy1: 0,
-            dummy2: 0,
-        };
-
-        let length = size_of::<BlockParams>() + data.len() + padding;
-
-        let mut check = checksum(&data, CHECKSUM_INIT);
-
-        for _ in 0..padding {
-            check = checksum(&[padding_byte], check);
-        }
-
-        self.connection.command(
-            command as u8,
-            (length as u16, |encoder: &mut Encoder| {
-                encoder.write(bytes_of(&params))?;
-                encoder.write(&data)?;
-                let padding = &[padding_byte; FLASH_WRITE_SIZE][0..padding];
-                encoder.write(padding)?;
-                Ok(())
-            }),
-            check as u32,
-        )?;
+        self.connection.timed(command, |connection| {
+            write_block_command(connection, command, data, padding, padding_byte, sequence)?;
+            connection.read_command_response(command)
+        })?;
         Ok(())
     }
 
@@ -333,39 +911,90 @@ impl Flasher {
             no_entry: (entry == 0) as u32,
             entry,
         };
-        self.connection
-            .write_command(Command::MemEnd as u8, bytes_of(&params), 0)?;
-        Ok(())
+        // the response isn't waited for, the stub starts running before the rom sends it
+        self.connection.timed(Command::MemEnd, |connection| {
+            connection.write_command(Command::MemEnd, bytes_of(&params), 0)
+        })
     }
 
     fn flash_finish(&mut self, reboot: bool) -> Result<(), Error> {
-        self.connection
-            .write_command(Command::FlashEnd as u8, &[(!reboot) as u8][..], 0)?;
-        Ok(())
+        self.connection.timed(Command::FlashEnd, |connection| {
+            connection.write_command(Command::FlashEnd, &[(!reboot) as u8][..], 0)
+        })
     }
 
     fn enable_flash(&mut self, spi_attach_params: SpiAttachParams) -> Result<(), Error> {
         match self.chip {
             Chip::Esp8266 => {
-                self.begin_command(Command::FlashBegin, 0, 0, FLASH_WRITE_SIZE as u32, 0)?;
+                let block_size = self.flash_write_size() as u32;
+                self.begin_command(Command::FlashBegin, 0, 0, block_size, 0)?;
             }
+            Chip::Esp32 if self.attached == Some(spi_attach_params) => return Ok(()),
             Chip::Esp32 => {
                 let spi_params = spi_attach_params.encode();
                 self.connection
-                    .command(Command::SpiAttach as u8, spi_params.as_slice(), 0)?;
+                    .command(Command::SpiAttach, spi_params.as_slice(), 0)?;
+                self.attached = Some(spi_attach_params);
             }
         }
+        if let Some(divider) = self.spi_clock_divider {
+            let clock = self.chip.spi_registers().clock();
+            self.write_reg(clock, spi_clock(divider), None)?;
+        }
         Ok(())
     }
 
-    fn spi_command(&mut self, command: u8, data: &[u8], read_bits: u32) -> Result<u32, Error> {
-        assert!(read_bits < 32);
-        assert!(data.len() < 64);
+    /// Send a command to the spi flash and read up to 32 bits of its response
+    ///
+    /// The `data` is sent after the command, before `read_bits` of the response are read. This can be used for
+    /// flash operations that espflash doesn't implement, like reading the SFDP tables or the unique id. The spi
+    /// user registers are restored afterwards, also when the command fails, see [`Flasher::spi_guard`].
+    ///
+    /// # Errors
+    ///
+    /// [`Error::InvalidSpiCommand`] when more than 32 bits are read or more than 64 bytes of data are sent.
+    pub fn spi_command(&mut self, command: u8, data: &[u8], read_bits: u32) -> Result<u32, Error> {
+        let mut guard = self.spi_guard()?;
+        let result = guard.command(command, data, read_bits)?;
+        guard.restore()?;
+        Ok(result)
+    }
 
+    /// Save the spi user registers for a sequence of spi commands, they're restored when the guard is dropped
+    ///
+    /// The rom and the stub use the same registers for reading and writing the flash, they have to be restored
+    /// before other flash operations. The user registers and the data length registers, which are part of the
+    /// `usr1` register on the esp8266, are restored. The data registers aren't, the rom and stub set them for
+    /// every operation. Errors while restoring them when the guard is dropped, like during a panic, are ignored,
+    /// [`SpiGuard::restore`] reports them.
+    pub fn spi_guard(&mut self) -> Result<SpiGuard<'_>, Error> {
         let spi_registers = self.chip.spi_registers();
+        let mut addresses = vec![spi_registers.usr(), spi_registers.usr2()];
+        match (spi_registers.mosi_length(), spi_registers.miso_length()) {
+            (Some(mosi_length), Some(miso_length)) => addresses.extend([mosi_length, miso_length]),
+            _ => addresses.push(spi_registers.usr1()),
+        }
+        let registers = addresses
+            .into_iter()
+            .map(|addr| Ok((addr, self.read_reg(addr)?)))
+            .collect::<Result<_, Error>>()?;
+        Ok(SpiGuard {
+            flasher: self,
+            registers,
+            restored: false,
+        })
+    }
 
-        let old_spi_usr = self.read_reg(spi_registers.usr())?;
-        let old_spi_usr2 = self.read_reg(spi_registers.usr2())?;
+    fn run_spi_command(&mut self, command: u8, data: &[u8], read_bits: u32) -> Result<u32, Error> {
+        // the command is sent from usr2, the data from the 16 data registers
+        if data.len() > 64 || read_bits > 32 {
+            return Err(Error::InvalidSpiCommand {
+                data: data.len(),
+                read_bits,
+            });
+        }
+
+        let spi_registers = self.chip.spi_registers();
 
         let mut flags = 1 << 31;
         if !data.is_empty() {
@@ -375,17 +1004,20 @@ impl Flasher {
             flags |= 1 << 28;
         }
 
-        self.write_reg(spi_registers.usr(), flags, None)?;
-        self.write_reg(spi_registers.usr2(), 7 << 28 | command as u32, None)?;
+        // the registers are set up with a single write to the port
+        let mut registers = vec![
+            (spi_registers.usr(), flags),
+            (spi_registers.usr2(), 7 << 28 | command as u32),
+        ];
 
         if let (Some(mosi_data_length), Some(miso_data_length)) =
             (spi_registers.mosi_length(), spi_registers.miso_length())
         {
             if !data.is_empty() {
-                self.write_reg(mosi_data_length, data.len() as u32 * 8 - 1, None)?;
+                registers.push((mosi_data_length, data.len() as u32 * 8 - 1));
             }
             if read_bits > 0 {
-                self.write_reg(miso_data_length, read_bits - 1, None)?;
+                registers.push((miso_data_length, read_bits - 1));
             }
         } else {
             let mosi_mask = if data.is_empty() {
@@ -394,26 +1026,22 @@ impl Flasher {
                 data.len() as u32 * 8 - 1
             };
             let miso_mask = if read_bits == 0 { 0 } else { read_bits - 1 };
-            self.write_reg(spi_registers.usr1(), miso_mask << 8 | mosi_mask << 17, None)?;
+            registers.push((spi_registers.usr1(), miso_mask << 8 | mosi_mask << 17));
         }
 
         if data.is_empty() {
-            self.write_reg(spi_registers.w0(), 0, None)?;
+            registers.push((spi_registers.w0(), 0));
         } else {
-            for (i, bytes) in data.chunks(4).enumerate() {
-                let mut data_bytes = [0; 4];
-                data_bytes[0..bytes.len()].copy_from_slice(bytes);
-                let data = u32::from_le_bytes(data_bytes);
-                self.write_reg(spi_registers.w0() + i as u32, data, None)?;
-            }
+            registers.extend(spi_data_registers(spi_registers.w0(), data));
         }
+        self.write_regs(&registers)?;
 
         self.write_reg(spi_registers.cmd(), 1 << 18, None)?;
 
         let mut i = 0;
         loop {
             sleep(Duration::from_millis(1));
-            if self.read_reg(spi_registers.usr())? & (1 << 18) == 0 {
+            if self.read_reg(spi_registers.cmd())? & (1 << 18) == 0 {
                 break;
             }
             i += 1;
@@ -422,19 +1050,43 @@ impl Flasher {
             }
         }
 
-        let result = self.read_reg(spi_registers.w0())?;
-        self.write_reg(spi_registers.usr(), old_spi_usr, None)?;
-        self.write_reg(spi_registers.usr2(), old_spi_usr2, None)?;
-
-        Ok(result)
+        self.read_reg(spi_registers.w0())
     }
 
-    fn read_reg(&mut self, reg: u32) -> Result<u32, Error> {
+    /// Read a word of memory or a register through the bootloader
+    ///
+    /// The address isn't checked, reading an address that isn't mapped can hang the bootloader until the chip is
+    /// reset. [`Chip::check_reg_addr`] checks that an address is a register or in the ram of the chip.
+    pub fn read_reg(&mut self, reg: u32) -> Result<u32, Error> {
         self.connection
-            .command(Command::ReadReg as u8, &reg.to_le_bytes()[..], 0)
+            .command(Command::ReadReg, &reg.to_le_bytes()[..], 0)
+            .map_err(|e| e.at_offset(reg))
+    }
+
+    /// Write several registers, sending all commands before waiting for the responses
+    fn write_regs(&mut self, registers: &[(u32, u32)]) -> Result<(), Error> {
+        let params: Vec<WriteRegParams> = registers
+            .iter()
+            .map(|&(addr, value)| WriteRegParams {
+                addr,
+                value,
+                mask: 0xFFFFFFFF,
+                delay_us: 0,
+            })
+            .collect();
+        let commands: Vec<(Command, &[u8])> = params
+            .iter()
+            .map(|params| (Command::WriteReg, bytes_of(params)))
+            .collect();
+        self.connection.commands(&commands)?;
+        Ok(())
     }
 
-    fn write_reg(&mut self, addr: u32, value: u32, mask: Option<u32>) -> Result<(), Error> {
+    /// Write a word of memory or a register through the bootloader
+    ///
+    /// With a `mask` only the bits set in the mask are changed, the bootloader reads the old value to keep the
+    /// others. Like [`Flasher::read_reg`], the address isn't checked.
+    pub fn write_reg(&mut self, addr: u32, value: u32, mask: Option<u32>) -> Result<(), Error> {
         let params = WriteRegParams {
             addr,
             value,
@@ -442,7 +1094,8 @@ impl Flasher {
             delay_us: 0,
         };
         self.connection
-            .command(Command::WriteReg as u8, bytes_of(&params), 0)?;
+            .command(Command::WriteReg, bytes_of(&params), 0)
+            .map_err(|e| e.at_offset(addr))?;
         Ok(())
     }
 
@@ -451,128 +1104,2350 @@ impl Flasher {
         self.chip
     }
 
-    /// The flash size of the board that the flasher is connected to
-    pub fn flash_size(&self) -> FlashSize {
-        self.flash_size
+    /// Warn when the image runs the flash faster than is reliable at the voltage of the flash
+    ///
+    /// Modules with a 1.8V flash, like the 1.8V variants of the WROOM and WROVER modules, don't all read the
+    /// flash reliably at 80MHz, which shows up as random crashes or corrupted data instead of a clear error.
+    fn check_flash_voltage(&mut self, image: &FirmwareImage) -> Result<(), Error> {
+        if image.flash_frequency != FlashFrequency::Flash80M {
+            return Ok(());
+        }
+        let chip = self.chip;
+        if chip.flash_voltage(self)? == Some(FlashVoltage::V1_8) {
+            eprintln!(
+                "Warning: the board has a 1.8V flash, which isn't reliable at 80MHz on every module, \
+                use a flash frequency of 40MHz if the firmware crashes or reads corrupted data"
+            );
+        }
+        Ok(())
     }
 
-    /// Load an elf image to ram and execute it
-    ///
-    /// Note that this will not touch the flash on the device
-    pub fn load_elf_to_ram(&mut self, elf_data: &[u8]) -> Result<(), Error> {
-        let image = FirmwareImage::from_data(elf_data).map_err(|_| Error::InvalidElf)?;
+    /// The workarounds for the revision of the connected chip
+    pub fn workarounds(&mut self) -> Result<Workarounds, Error> {
+        let chip = self.chip;
+        let revision = chip.chip_revision(self)?;
+        Ok(chip.workarounds(revision))
+    }
 
-        if image.rom_segments(self.chip).next().is_some() {
-            return Err(Error::ElfNotRamLoadable);
-        }
+    /// The frequency of the crystal in MHz
+    pub fn crystal_frequency(&mut self) -> Result<u32, Error> {
+        let chip = self.chip;
+        chip.crystal_frequency(self)
+    }
 
-        for segment in image.ram_segments(self.chip) {
-            let padding = 4 - segment.data.len() % 4;
-            let block_count =
-                (segment.data.len() + padding + MAX_RAM_BLOCK_SIZE - 1) / MAX_RAM_BLOCK_SIZE;
-            self.begin_command(
-                Command::MemBegin,
-                segment.data.len() as u32,
-                block_count as u32,
-                MAX_RAM_BLOCK_SIZE as u32,
-                segment.addr,
-            )?;
+    /// The baud rate the rom prints its boot messages at
+    ///
+    /// The rom assumes a 40MHz crystal, with the 26MHz crystal of most esp8266 boards it prints at 74880 baud
+    /// instead of 115200 baud.
+    pub fn boot_log_baud(&mut self) -> Result<BaudRate, Error> {
+        let crystal = self.crystal_frequency()?;
+        Ok(BaudRate::from_speed(115_200 * crystal as usize / 40))
+    }
 
-            for (i, block) in segment.data.chunks(MAX_RAM_BLOCK_SIZE).enumerate() {
-                let block_padding = if i == block_count - 1 { padding } else { 0 };
-                self.block_command(Command::MemData, &block, block_padding, 0, i as u32)?;
-            }
-        }
+    /// The flash size of the board that the flasher is connected to
+    pub fn flash_size(&self) -> FlashSize {
+        self.flash_size
+    }
 
-        self.mem_finish(image.entry())?;
+    /// The size of the flash in bytes, an error if the size of the flash chip is unknown
+    pub fn flash_size_in_bytes(&self) -> Result<u32, Error> {
+        self.flash_size
+            .size_in_bytes()
+            .ok_or(Error::UnsupportedFlash(self.flash_size as u8))
+    }
 
-        Ok(())
+    /// Set what to do with the chip after writing to the flash, the chip is reset by default
+    pub fn set_reset_after_flash(&mut self, reset: ResetAfterFlash) {
+        self.reset_after_flash = reset;
     }
 
-    /// Load an elf image to flash and execute it
-    pub fn load_elf_to_flash(&mut self, elf_data: &[u8]) -> Result<(), Error> {
-        self.enable_flash(self.spi_params)?;
-        let mut image = FirmwareImage::from_data(elf_data).map_err(|_| Error::InvalidElf)?;
-        image.flash_size = self.flash_size();
+    pub fn reset_after_flash(&self) -> ResetAfterFlash {
+        self.reset_after_flash
+    }
 
-        for segment in self.chip.get_flash_segments(&image) {
-            let segment = segment?;
-            let addr = segment.addr;
-            let block_count = (segment.data.len() + FLASH_WRITE_SIZE - 1) / FLASH_WRITE_SIZE;
+    /// Resume an interrupted flash, only writing each segment from the first sector that doesn't match
+    ///
+    /// The sectors are compared using the md5 of the flash, which the esp8266 rom can't calculate, without the stub
+    /// everything is written on the esp8266.
+    pub fn set_resume(&mut self, resume: bool) {
+        self.resume = resume;
+    }
 
-            let erase_size = match self.chip {
-                Chip::Esp32 => segment.data.len() as u32,
-                Chip::Esp8266 => get_erase_size(addr as usize, segment.data.len()) as u32,
-            };
+    /// Probe for psram on the module when reading the board info, not only for psram in the package of the chip
+    ///
+    /// Probing routes the spi chip select and clock to the pins of the psram, GPIO16 and GPIO17 on most modules,
+    /// which drives whatever a board without psram connects to them. The probe is off by default.
+    pub fn set_probe_psram(&mut self, probe: bool) {
+        self.probe_psram = probe;
+    }
 
-            self.begin_command(
-                Command::FlashBegin,
-                erase_size,
-                block_count as u32,
-                FLASH_WRITE_SIZE as u32,
-                addr,
-            )?;
+    /// Divide the 80MHz clock of the spi flash by `divider` while flashing, between 1 and 64
+    ///
+    /// Slowing down the flash clock helps on boards where the flash is connected with long wires or flex cables,
+    /// where the default clock corrupts data. The divider is applied whenever the flash is attached.
+    pub fn set_spi_clock_divider(&mut self, divider: u8) {
+        self.spi_clock_divider = Some(divider.clamp(1, MAX_SPI_CLOCK_DIVIDER));
+        // attach again to apply the divider
+        self.attached = None;
+    }
 
-            let chunks = segment.data.chunks(FLASH_WRITE_SIZE);
+    /// Build the images of elf files with `settings`, like the ones from the sdkconfig of an esp-idf project
+    pub fn set_image_settings(&mut self, settings: ImageSettings) {
+        self.image_settings = settings;
+    }
 
-            let (_, chunk_size) = chunks.size_hint();
-            let chunk_size = chunk_size.unwrap_or(0) as u64;
-            let pb_chunk = ProgressBar::new(chunk_size);
-            pb_chunk.set_style(
-                ProgressStyle::default_bar()
-                    .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
-                    .progress_chars("#>-"),
-            );
+    /// The settings elf files are built into images with
+    pub fn image_settings(&self) -> &ImageSettings {
+        &self.image_settings
+    }
 
-            for (i, block) in chunks.enumerate() {
-                pb_chunk.set_message(&format!("segment 0x{:X} writing chunks", addr));
-                let block_padding = FLASH_WRITE_SIZE - block.len();
-                self.block_command(Command::FlashData, &block, block_padding, 0xff, i as u32)?;
-                pb_chunk.inc(1);
-            }
-            pb_chunk.finish_with_message(&format!("segment 0x{:X}", addr));
-        }
+    /// Report flashing progress to `progress` instead of drawing a progress bar
+    pub fn set_progress_callbacks(&mut self, progress: impl ProgressCallbacks + Send + 'static) {
+        self.progress = Box::new(progress);
+    }
 
-        self.flash_finish(false)?;
+    /// Call `hook` with the duration and size of every bootloader command, to measure where time is spent
+    pub fn set_command_hook(&mut self, hook: impl FnMut(&CommandTiming) + Send + 'static) {
+        self.connection.set_command_hook(Some(Box::new(hook)));
+    }
 
-        self.connection.reset()?;
+    /// Set how often a block that failed to write is retried before giving up, defaults to 3
+    pub fn set_block_retries(&mut self, retries: usize) {
+        self.block_retries = retries;
+    }
 
-        Ok(())
+    /// Use rts/cts flow control, for serial adapters that lose data at high baud rates
+    ///
+    /// Flow control is turned off while resetting the chip, since rts is connected to the reset circuit on most boards.
+    /// On those boards the adapter driving rts for flow control pulls EN low and keeps the chip in reset, so it's
+    /// only usable on boards where rts isn't connected to EN.
+    pub fn set_flow_control(&mut self, enabled: bool) -> Result<(), Error> {
+        self.connection.set_flow_control(enabled)
     }
 
-    pub fn change_baud(&mut self, speed: BaudRate) -> Result<(), Error> {
-        self.connection.command(
-            Command::ChangeBaud as u8,
-            &(speed.speed()).to_le_bytes()[..],
-            0,
-        )?;
-        self.connection.set_baud(speed)?;
-        Ok(())
+    /// Pause for `delay` after every `chunk_size` bytes sent to the chip
+    ///
+    /// This keeps cheap serial adapters, which drop bytes at high sustained rates, from overflowing.
+    pub fn set_write_pacing(&mut self, chunk_size: usize, delay: Duration) {
+        self.connection.set_pacing(Some((chunk_size, delay)));
     }
-}
 
-fn get_erase_size(offset: usize, size: usize) -> usize {
-    let sector_count = (size + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
-    let start_sector = offset / FLASH_SECTOR_SIZE;
+    /// The baud rate currently used to communicate with the chip
+    pub fn baud(&self) -> BaudRate {
+        self.connection.baud()
+    }
 
-    let head_sectors = usize::min(
-        FLASH_SECTORS_PER_BLOCK - (start_sector % FLASH_SECTORS_PER_BLOCK),
-        sector_count,
-    );
+    /// Read information about the connected board
+    pub fn board_info(&mut self) -> Result<BoardInfo, Error> {
+        let chip = self.chip;
+        Ok(BoardInfo {
+            chip,
+            revision: chip.chip_revision(self)?,
+            crystal_frequency: chip.crystal_frequency(self)?,
+            mac_address: chip.mac_address(self)?,
+            features: chip.chip_features(self)?,
+            flash_manufacturer: self.flash_id as u8,
+            flash_device: ((self.flash_id >> 8) as u16).swap_bytes(),
+            flash_size: self.flash_size,
+            flash_voltage: chip.flash_voltage(self)?,
+            psram: chip
+                .psram(self, self.probe_psram)
+                .unwrap_or(PsramStatus::Unknown),
+        })
+    }
 
-    if sector_count < 2 * head_sectors {
-        (sector_count + 1) / 2 * FLASH_SECTOR_SIZE
-    } else {
-        (sector_count - head_sectors) * FLASH_SECTOR_SIZE
+    /// Read the features of the connected chip, such as bluetooth support or embedded flash
+    pub fn chip_features(&mut self) -> Result<Vec<ChipFeature>, Error> {
+        let chip = self.chip;
+        chip.chip_features(self)
     }
-}
 
-const CHECKSUM_INIT: u8 = 0xEF;
+    /// Read all efuse fields of the connected chip
+    pub fn read_efuses(&mut self) -> Result<Vec<EfuseValue>, Error> {
+        efuse::read_efuses(self)
+    }
 
-pub fn checksum(data: &[u8], mut checksum: u8) -> u8 {
-    for byte in data {
-        checksum ^= *byte;
+    /// Read the anti-rollback counter of the device, the bootloader refuses apps with a lower secure version
+    ///
+    /// Returns `None` when the chip doesn't support anti-rollback.
+    pub fn secure_version(&mut self) -> Result<Option<u32>, Error> {
+        efuse::read_secure_version(self)
     }
 
-    checksum
+    /// Permanently program a custom MAC address into the efuses, with its CRC
+    ///
+    /// Fails when a different custom MAC address was already programmed.
+    pub fn burn_custom_mac(&mut self, mac: [u8; 6]) -> Result<(), Error> {
+        efuse::burn_custom_mac(self, mac)
+    }
+
+    /// Permanently disable the download mode, or restrict it to writing the flash
+    ///
+    /// Once burned the chip can't be recovered over the serial port if the firmware stops working.
+    pub fn lock_download_mode(&mut self, lockdown: DownloadLockdown) -> Result<(), Error> {
+        efuse::lock_download_mode(self, lockdown)
+    }
+
+    /// Permanently program a 256 bit key into the efuse key block for its purpose
+    ///
+    /// Fails when the key block is already used. With `protect` the key block is write protected afterwards,
+    /// and read protected unless it holds a secure boot v2 digest.
+    pub fn burn_key(
+        &mut self,
+        purpose: KeyPurpose,
+        key: &[u8; 32],
+        protect: bool,
+    ) -> Result<(), Error> {
+        efuse::burn_key(self, purpose, key, protect)
+    }
+
+    /// Load an elf image to ram and execute it
+    ///
+    /// Note that this will not touch the flash on the device
+    pub fn load_elf_to_ram(&mut self, elf_data: &[u8]) -> Result<(), Error> {
+        let image = FirmwareImage::from_data(elf_data).map_err(|_| Error::InvalidElf)?;
+
+        if image.rom_segments(self.chip).next().is_some() {
+            return Err(Error::ElfNotRamLoadable);
+        }
+
+        for segment in image.ram_segments(self.chip) {
+            self.write_ram(segment.addr, segment.data)?;
+        }
+
+        self.mem_finish(image.entry())?;
+
+        Ok(())
+    }
+
+    /// Load a flasher stub into ram and start it
+    ///
+    /// Once the stub is running, operations that the stub supports faster than the rom bootloader use the stub.
+    pub fn load_stub(&mut self, stub: &Stub) -> Result<(), Error> {
+        self.write_ram(stub.text_start, &stub.text)?;
+        if !stub.data.is_empty() {
+            self.write_ram(stub.data_start, &stub.data)?;
+        }
+        self.mem_finish(stub.entry)?;
+
+        // the stub announces itself after the bootloader acknowledged the jump
+        for _ in 0..4 {
+            match self.connection.read_frame() {
+                Ok(frame) if frame == b"OHAI" => {
+                    self.stub_active = true;
+                    // the stub has to attach the flash itself
+                    self.attached = None;
+                    self.event(ConnectionEvent::StubLoaded);
+                    return self.set_flash_params();
+                }
+                Ok(_) => continue,
+                Err(_) => break,
+            }
+        }
+
+        Err(Error::StubFailed)
+    }
+
+    /// Whether a flasher stub is running on the chip
+    pub fn stub_active(&self) -> bool {
+        self.stub_active
+    }
+
+    /// Timeout for the response to a flash block, sending a stub block takes over a second at low baud rates
+    fn block_timeout(&self, size: usize) -> Duration {
+        // ten bits per byte and every byte might be escaped
+        let transfer = Duration::from_secs(20) * size as u32 / self.baud().speed() as u32;
+        DEFAULT_TIMEOUT + transfer
+    }
+
+    fn flash_write_size(&self) -> usize {
+        if self.stub_active {
+            STUB_FLASH_WRITE_SIZE
+        } else {
+            ROM_FLASH_WRITE_SIZE
+        }
+    }
+
+    fn write_ram(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        let padding = 4 - data.len() % 4;
+        let block_count = (data.len() + padding).div_ceil(MAX_RAM_BLOCK_SIZE);
+        self.begin_command(
+            Command::MemBegin,
+            data.len() as u32,
+            block_count as u32,
+            MAX_RAM_BLOCK_SIZE as u32,
+            addr,
+        )?;
+
+        for (i, block) in data.chunks(MAX_RAM_BLOCK_SIZE).enumerate() {
+            let block_padding = if i == block_count - 1 { padding } else { 0 };
+            self.block_command(Command::MemData, block, block_padding, 0, i as u32)
+                .map_err(|e| e.at_offset(addr + (i * MAX_RAM_BLOCK_SIZE) as u32))?;
+        }
+
+        Ok(())
+    }
+
+    /// Load an elf image to flash and execute it
+    pub fn load_elf_to_flash(&mut self, elf_data: &[u8]) -> Result<FlashStats, Error> {
+        self.with_stats(|flasher| flasher.write_elf(elf_data))
+    }
+
+    fn write_elf(&mut self, elf_data: &[u8]) -> Result<(), Error> {
+        let mut image = FirmwareImage::from_data(elf_data).map_err(|_| Error::InvalidElf)?;
+        image.flash_size = self.flash_size();
+        self.image_settings.apply(&mut image);
+        let chip = self.chip;
+
+        // The segments are built and validated on another thread, while the ones that are done are checked against
+        // the flash, so building a large app doesn't hold up the serial link.
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            let image = &image;
+            scope.spawn(move || {
+                for segment in chip.get_flash_segments(image) {
+                    let segment = segment.and_then(|segment| {
+                        validate_flash_segments(chip, std::slice::from_ref(&segment))?;
+                        Ok(segment)
+                    });
+                    if sender.send(segment).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            self.enable_flash(self.spi_params)?;
+            if image.flash_mode == FlashMode::Qio {
+                self.enable_quad_mode()?;
+            }
+            self.check_flash_voltage(image)?;
+
+            // every segment is checked before anything is erased, so an image that can't be written doesn't
+            // leave the flash half written
+            let mut segments = Vec::new();
+            for segment in receiver {
+                let segment = segment?;
+                let skip = self.prepare_flash_write(segment.addr, &segment.data)?;
+                segments.push((segment, skip));
+            }
+            let writes: Vec<_> = segments
+                .iter()
+                .map(|(segment, skip)| {
+                    split_erased(segment.addr + *skip as u32, &segment.data[*skip..])
+                })
+                .filter(|writes| !writes.is_empty())
+                .collect();
+
+            for segment_writes in &writes {
+                self.erase_skipped(segment_writes)?;
+            }
+            // a segment is written once the previous one is done, its flash begin is sent with the last block
+            if let Some(first) = writes.first() {
+                self.flash_begin(first[0])?;
+            }
+            for (i, segment_writes) in writes.iter().enumerate() {
+                let next = writes.get(i + 1).map(|next| next[0]);
+                self.write_flash_parts(segment_writes, next)?;
+            }
+            Ok::<_, Error>(())
+        })?;
+
+        self.finish_flashing()
+    }
+
+    /// Write a raw binary to flash at the given offset and execute it
+    pub fn write_bin_to_flash(&mut self, addr: u32, data: &[u8]) -> Result<FlashStats, Error> {
+        self.with_stats(|flasher| {
+            flasher.enable_flash(flasher.spi_params)?;
+            flasher.enable_bootloader_quad_mode(addr, data)?;
+
+            flasher.write_flash_segment(RomSegment {
+                addr,
+                data: Cow::Borrowed(data),
+            })?;
+
+            flasher.finish_flashing()
+        })
+    }
+
+    /// Write several binary files to the flash, resetting the chip once afterwards
+    pub fn write_files_to_flash(&mut self, files: &[ImageFile]) -> Result<FlashStats, Error> {
+        self.with_stats(|flasher| {
+            flasher.enable_flash(flasher.spi_params)?;
+            for file in files {
+                flasher.enable_bootloader_quad_mode(file.addr, &file.data)?;
+            }
+
+            for file in files {
+                flasher.write_flash_segment(RomSegment {
+                    addr: file.addr,
+                    data: Cow::Borrowed(&file.data),
+                })?;
+            }
+
+            flasher.finish_flashing()
+        })
+    }
+
+    /// Run a flash operation, collecting its statistics
+    fn with_stats(
+        &mut self,
+        operation: impl FnOnce(&mut Self) -> Result<(), Error>,
+    ) -> Result<FlashStats, Error> {
+        self.connection.start_stats();
+        let result = operation(self);
+        let stats = self.connection.finish_stats();
+        result.map(|()| stats)
+    }
+
+    /// Erase a region of the flash
+    ///
+    /// The region is rounded up to whole flash sectors.
+    pub fn erase_region(&mut self, offset: u32, size: u32) -> Result<(), Error> {
+        self.enable_flash(self.spi_params)?;
+        self.check_write_protection(offset, size)?;
+        if self.stub_active {
+            return self.stub_erase_region(offset, size);
+        }
+
+        let erase_size = match self.chip {
+            // the esp8266 rom erases more than requested
+            Chip::Esp8266 => get_erase_size(offset as usize, size as usize) as u32,
+            _ => size,
+        };
+
+        let block_size = self.flash_write_size() as u32;
+        self.begin_command(Command::FlashBegin, erase_size, 0, block_size, offset)?;
+
+        Ok(())
+    }
+
+    /// Erase the sectors covering a region with the stub
+    ///
+    /// The stub only erases the region of a begin command as data for it is written, so erasing needs its own
+    /// command.
+    fn stub_erase_region(&mut self, offset: u32, size: u32) -> Result<(), Error> {
+        let sector_size = FLASH_SECTOR_SIZE as u32;
+        let start = offset / sector_size * sector_size;
+        let size = (offset + size).div_ceil(sector_size) * sector_size - start;
+        let mut params = start.to_le_bytes().to_vec();
+        params.extend_from_slice(&size.to_le_bytes());
+        self.connection
+            .with_timeout(erase_timeout(size), |connection| {
+                connection.command(Command::EraseRegion, params.as_slice(), 0)
+            })
+            .map_err(|e| e.at_offset(start))?;
+        Ok(())
+    }
+
+    /// Read a region of the flash
+    ///
+    /// When the flasher stub is running the flash is streamed by the stub, otherwise the flash is read
+    /// over spi one word at a time, which makes it slow for large regions.
+    pub fn read_flash(&mut self, offset: u32, size: u32) -> Result<Vec<u8>, Error> {
+        self.enable_flash(self.spi_params)?;
+        if self.stub_active {
+            return self.read_flash_stream(offset, size);
+        }
+
+        let mut data = Vec::with_capacity(size as usize + 4);
+        let mut addr = offset;
+        self.progress
+            .init(offset, size.div_ceil(READ_FLASH_SECTOR_SIZE) as usize);
+        while data.len() < size as usize {
+            let word = self.spi_command(
+                SPI_FLASH_READ,
+                &[(addr >> 16) as u8, (addr >> 8) as u8, addr as u8],
+                32,
+            )?;
+            data.extend_from_slice(&word.to_le_bytes());
+            addr += 4;
+            if data.len() % READ_FLASH_SECTOR_SIZE as usize == 0 {
+                self.progress
+                    .update(data.len() / READ_FLASH_SECTOR_SIZE as usize);
+            }
+        }
+        self.progress.finish();
+        data.truncate(size as usize);
+
+        Ok(data)
+    }
+
+    fn read_flash_stream(&mut self, offset: u32, size: u32) -> Result<Vec<u8>, Error> {
+        let params = [
+            offset,
+            size,
+            READ_FLASH_SECTOR_SIZE,
+            READ_FLASH_PACKETS_IN_FLIGHT,
+        ];
+        let params: Vec<u8> = params
+            .iter()
+            .flat_map(|param| param.to_le_bytes())
+            .collect();
+        // the streamed data is timed as part of the read command
+        let progress = &mut self.progress;
+        self.connection.timed(Command::ReadFlash, |connection| {
+            connection.write_command(Command::ReadFlash, params.as_slice(), 0)?;
+            connection
+                .read_command_response(Command::ReadFlash)
+                .map_err(|e| e.at_offset(offset))?;
+
+            let mut data = Vec::with_capacity(size as usize);
+            progress.init(offset, size.div_ceil(READ_FLASH_SECTOR_SIZE) as usize);
+            while data.len() < size as usize {
+                let packet = connection.read_frame()?;
+                if packet.is_empty() || data.len() + packet.len() > size as usize {
+                    return Err(Error::CorruptedRead);
+                }
+                data.extend_from_slice(&packet);
+                // acknowledge the received data, which lets the stub send more
+                connection.write_frame(&(data.len() as u32).to_le_bytes())?;
+                progress.update(data.len() / READ_FLASH_SECTOR_SIZE as usize);
+            }
+            progress.finish();
+
+            let digest = connection.read_frame()?;
+            if digest != md5::compute(&data).0 {
+                return Err(Error::CorruptedRead);
+            }
+
+            Ok(data)
+        })
+    }
+
+    /// Calculate the md5 hash of a region of the flash
+    ///
+    /// This is done by the bootloader, only the hash is transferred.
+    pub fn flash_md5(&mut self, offset: u32, size: u32) -> Result<[u8; 16], Error> {
+        if !self.can_hash() {
+            return Err(Error::UnsupportedOperation {
+                operation: "calculating the md5 of the flash",
+                chip: self.chip,
+            });
+        }
+        self.enable_flash(self.spi_params)?;
+
+        let params = [offset, size, 0, 0];
+        let params: Vec<u8> = params
+            .iter()
+            .flat_map(|param| param.to_le_bytes())
+            .collect();
+        let timeout = md5_timeout(size);
+        let status_length = if self.stub_active {
+            STUB_STATUS_LENGTH
+        } else {
+            ROM_STATUS_LENGTH
+        };
+        let response = self
+            .connection
+            .with_timeout(timeout, |connection| {
+                connection.command_data(Command::SpiFlashMd5, params.as_slice(), 0, status_length)
+            })
+            .map_err(|e| e.at_offset(offset))?;
+
+        // the stub sends the hash as raw bytes, the rom as hex
+        if self.stub_active {
+            return response.try_into().map_err(|_| Error::ConnectionFailed);
+        }
+        let hex = std::str::from_utf8(&response).map_err(|_| Error::ConnectionFailed)?;
+        let mut md5 = [0; 16];
+        for (i, byte) in md5.iter_mut().enumerate() {
+            *byte = hex
+                .get(i * 2..i * 2 + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or(Error::ConnectionFailed)?;
+        }
+
+        Ok(md5)
+    }
+
+    /// Check that the flash contains `data` at `addr`
+    ///
+    /// Fails with [`Error::VerifyFailed`] with the first offset where the flash differs.
+    pub fn verify_bin(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        if self.can_hash() {
+            self.verify_md5(addr, data)
+        } else {
+            self.verify_read(addr, data)
+        }
+    }
+
+    /// Check that the flash contains the elf image, as written by [`Flasher::load_elf_to_flash`]
+    pub fn verify_elf(&mut self, elf_data: &[u8]) -> Result<(), Error> {
+        for (addr, data) in self.flash_segments(elf_data)? {
+            self.verify_bin(addr, &data)?;
+        }
+
+        Ok(())
+    }
+
+    /// The data written to the flash for an elf image, with the offsets it's written at
+    pub fn flash_segments(&self, elf_data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+        let mut image = FirmwareImage::from_data(elf_data).map_err(|_| Error::InvalidElf)?;
+        image.flash_size = self.flash_size();
+        self.image_settings.apply(&mut image);
+
+        self.chip
+            .get_flash_segments(&image)
+            .map(|segment| segment.map(|segment| (segment.addr, segment.data.into_owned())))
+            .collect()
+    }
+
+    /// Find the flash sectors that differ from `data` at `addr`
+    ///
+    /// Returns the offsets of the differing sectors, `data` doesn't need to be sector aligned.
+    pub fn diff_sectors(&mut self, addr: u32, data: &[u8]) -> Result<Vec<u32>, Error> {
+        let can_hash = self.can_hash();
+
+        let mut differing = Vec::new();
+        for (pos, len) in sector_chunks(addr, data.len()) {
+            let offset = addr + pos as u32;
+            let expected = &data[pos..pos + len];
+
+            let matches = if can_hash {
+                self.flash_md5(offset, len as u32)? == md5::compute(expected).0
+            } else {
+                self.read_flash(offset, len as u32)? == expected
+            };
+            if !matches {
+                differing.push(offset - offset % FLASH_SECTOR_SIZE as u32);
+            }
+        }
+
+        Ok(differing)
+    }
+
+    /// Whether the bootloader can calculate the md5 of the flash
+    fn can_hash(&self) -> bool {
+        self.chip != Chip::Esp8266 || self.stub_active
+    }
+
+    /// The number of bytes at the start of `data` that are already in the flash, in whole sectors
+    fn written_prefix(&mut self, addr: u32, data: &[u8]) -> Result<usize, Error> {
+        for (pos, len) in sector_chunks(addr, data.len()) {
+            let expected = &data[pos..pos + len];
+            if self.flash_md5(addr + pos as u32, len as u32)? != md5::compute(expected).0 {
+                return Ok(pos);
+            }
+        }
+
+        Ok(data.len())
+    }
+
+    /// Verify using md5 hashes, narrowing down the first difference by hashing ever smaller regions
+    fn verify_md5(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        let mut start = 0;
+        let mut len = data.len();
+        if self.flash_md5(addr, len as u32)? == md5::compute(data).0 {
+            return Ok(());
+        }
+
+        while len > 1 {
+            let half = len / 2;
+            let expected = md5::compute(&data[start..start + half]).0;
+            if self.flash_md5(addr + start as u32, half as u32)? == expected {
+                start += half;
+                len -= half;
+            } else {
+                len = half;
+            }
+        }
+
+        Err(Error::VerifyFailed(addr + start as u32))
+    }
+
+    /// Verify by reading back the flash, for bootloaders that can't hash the flash
+    fn verify_read(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        for (i, chunk) in data.chunks(FLASH_BLOCK_SIZE).enumerate() {
+            let offset = addr + (i * FLASH_BLOCK_SIZE) as u32;
+            let flash = self.read_flash(offset, chunk.len() as u32)?;
+            if let Some(pos) = flash.iter().zip(chunk).position(|(a, b)| a != b) {
+                return Err(Error::VerifyFailed(offset + pos as u32));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Erase the entire flash
+    pub fn erase_flash(&mut self) -> Result<(), Error> {
+        let size = self.flash_size_in_bytes()?;
+        if !self.stub_active {
+            return self.erase_region(0, size);
+        }
+
+        self.enable_flash(self.spi_params)?;
+        self.check_write_protection(0, size)?;
+        self.connection
+            .with_timeout(erase_timeout(size), |connection| {
+                connection.command(Command::EraseFlash, &[][..], 0)
+            })?;
+        Ok(())
+    }
+
+    /// Read the partition table from the flash, at the offset of the image settings or else at 0x8000
+    pub fn read_partition_table(&mut self) -> Result<PartitionTable, Error> {
+        if self.chip == Chip::Esp8266 {
+            return Err(Error::UnsupportedOperation {
+                operation: "reading the partition table",
+                chip: self.chip,
+            });
+        }
+        let offset = self
+            .image_settings
+            .partition_table_offset
+            .unwrap_or(PARTITION_TABLE_OFFSET);
+        let data = self.read_flash(offset, PARTITION_TABLE_LENGTH)?;
+        PartitionTable::from_bytes(&data)
+    }
+
+    /// Erase the nvs and otadata partitions from the partition table on the flash
+    ///
+    /// This clears the settings of the app, and makes the bootloader boot the factory app, or the first ota app
+    /// when the table has no factory app. The table is returned to tell which partitions were erased.
+    pub fn factory_reset(&mut self) -> Result<PartitionTable, Error> {
+        let table = self.read_partition_table()?;
+        let regions: Vec<(u32, u32)> = table.nvs().into_iter().chain(table.ota_data()).collect();
+        if regions.is_empty() {
+            return Err(Error::InvalidPartitionTable(
+                "the table doesn't have nvs or otadata partitions".into(),
+            ));
+        }
+        for (offset, size) in regions {
+            self.erase_region(offset, size)?;
+        }
+        Ok(table)
+    }
+
+    /// Reset the chip, running the application from flash
+    pub fn hard_reset(&mut self) -> Result<(), Error> {
+        self.attached = None;
+        self.connection.reset()
+    }
+
+    fn finish_flashing(&mut self) -> Result<(), Error> {
+        self.flash_finish(false)?;
+
+        if self.reset_after_flash == ResetAfterFlash::HardReset {
+            self.hard_reset()?;
+        }
+
+        Ok(())
+    }
+
+    /// Set the quad enable bit of the flash chip, which the qio flash mode needs
+    ///
+    /// The status register that holds the bit, and how it's written, differ between manufacturers, they're looked
+    /// up from the JEDEC id of the flash chip.
+    pub fn enable_quad_mode(&mut self) -> Result<(), Error> {
+        let quad_enable = quad_enable(self.flash_id);
+        let mask = 1 << quad_enable.bit;
+        let status = self.read_flash_status(quad_enable.access)?;
+        if status & mask != 0 {
+            return Ok(());
+        }
+
+        self.write_flash_status(quad_enable.access, status | mask)?;
+        if self.read_flash_status(quad_enable.access)? & mask == 0 {
+            return Err(Error::QuadModeFailed(self.flash_id));
+        }
+        Ok(())
+    }
+
+    /// Enable quad mode when `data` written at `addr` contains a bootloader that uses the qio flash mode
+    fn enable_bootloader_quad_mode(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        let bootloader_offset: u32 = match self.chip {
+            Chip::Esp8266 => 0,
+            Chip::Esp32 => 0x1000,
+        };
+        let header = bootloader_offset
+            .checked_sub(addr)
+            .and_then(|start| data.get(start as usize..start as usize + 4));
+        match header {
+            Some(&[ESP_MAGIC, _, flash_mode, _]) if flash_mode == FlashMode::Qio as u8 => {
+                self.enable_quad_mode()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn read_flash_status(&mut self, access: StatusAccess) -> Result<u16, Error> {
+        Ok(match access {
+            StatusAccess::Status1 => self.spi_command(SPI_FLASH_READ_STATUS, &[], 8)? as u16,
+            StatusAccess::Status16 => {
+                let low = self.spi_command(SPI_FLASH_READ_STATUS, &[], 8)? as u16;
+                let high = self.spi_command(SPI_FLASH_READ_STATUS2, &[], 8)? as u16;
+                high << 8 | low
+            }
+            StatusAccess::Status2 => self.spi_command(SPI_FLASH_READ_STATUS2, &[], 8)? as u16,
+        })
+    }
+
+    fn write_flash_status(&mut self, access: StatusAccess, status: u16) -> Result<(), Error> {
+        self.spi_command(SPI_FLASH_WRITE_ENABLE, &[], 0)?;
+        match access {
+            StatusAccess::Status1 => {
+                self.spi_command(SPI_FLASH_WRITE_STATUS, &[status as u8], 0)?
+            }
+            StatusAccess::Status16 => {
+                self.spi_command(SPI_FLASH_WRITE_STATUS, &status.to_le_bytes(), 0)?
+            }
+            StatusAccess::Status2 => {
+                self.spi_command(SPI_FLASH_WRITE_STATUS2, &[status as u8], 0)?
+            }
+        };
+
+        // the write in progress bit is cleared once the status register is written
+        for _ in 0..SPI_FLASH_BUSY_POLLS {
+            if self.spi_command(SPI_FLASH_READ_STATUS, &[], 8)? & 1 == 0 {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(1));
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Fail when the block protection bits of the flash chip protect part of a region that will be written
+    ///
+    /// The flash chip silently ignores writes to protected regions, so the bootloader reports success while
+    /// nothing was written.
+    fn check_write_protection(&mut self, addr: u32, size: u32) -> Result<(), Error> {
+        if size == 0 {
+            return Ok(());
+        }
+        let status = self.spi_command(SPI_FLASH_READ_STATUS, &[], 8)? as u8;
+        match protected_region(status, self.flash_size_in_bytes()?) {
+            Some(protected) if addr < protected.end && addr + size > protected.start => {
+                Err(Error::FlashProtected {
+                    addr: addr.max(protected.start),
+                    start: protected.start,
+                    end: protected.end,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn write_flash_segment(&mut self, segment: RomSegment) -> Result<(), Error> {
+        let skip = self.prepare_flash_write(segment.addr, &segment.data)?;
+        let writes = split_erased(segment.addr + skip as u32, &segment.data[skip..]);
+        self.write_flash(&writes)
+    }
+
+    /// Find the part of a segment that has to be written and check that it can be written
+    ///
+    /// Returns the length of the start of the segment that is already written.
+    fn prepare_flash_write(&mut self, addr: u32, data: &[u8]) -> Result<usize, Error> {
+        if self.resume && !self.can_hash() {
+            eprintln!(
+                "Resuming needs the md5 of the flash, which the esp8266 rom can't calculate, writing everything"
+            );
+            self.resume = false;
+        }
+        let skip = if self.resume {
+            self.written_prefix(addr, data)?
+        } else {
+            0
+        };
+        if skip < data.len() {
+            self.check_write_protection(addr + skip as u32, (data.len() - skip) as u32)?;
+        }
+        Ok(skip)
+    }
+
+    /// Erase and write the parts of one or more segments
+    fn write_flash(&mut self, writes: &[FlashWrite]) -> Result<(), Error> {
+        self.erase_skipped(writes)?;
+        if let Some(&first) = writes.first() {
+            self.flash_begin(first)?;
+        }
+        self.write_flash_parts(writes, None)
+    }
+
+    /// Write the parts after the first one was begun, beginning `next` together with the last block
+    fn write_flash_parts(
+        &mut self,
+        writes: &[FlashWrite],
+        next: Option<FlashWrite>,
+    ) -> Result<(), Error> {
+        if writes.is_empty() {
+            return match next {
+                Some(next) => self.flash_begin(next),
+                None => Ok(()),
+            };
+        }
+        for (i, &write) in writes.iter().enumerate() {
+            self.write_flash_data(write, writes.get(i + 1).copied().or(next))?;
+        }
+        Ok(())
+    }
+
+    /// Erase the sectors the stub skips, see [`split_erased`]
+    fn erase_skipped(&mut self, writes: &[FlashWrite]) -> Result<(), Error> {
+        if !self.stub_active {
+            return Ok(());
+        }
+        for write in writes
+            .iter()
+            .filter(|write| write.erase_size > write.data.len())
+        {
+            let skipped = write.addr + write.data.len() as u32;
+            self.stub_erase_region(skipped, (write.erase_size - write.data.len()) as u32)?;
+        }
+        Ok(())
+    }
+
+    fn flash_begin_params(&self, write: FlashWrite) -> BeginParams {
+        let erase_size = match self.chip {
+            Chip::Esp8266 if !self.stub_active => {
+                get_erase_size(write.addr as usize, write.erase_size) as u32
+            }
+            _ => write.erase_size as u32,
+        };
+        let block_size = self.flash_write_size();
+        BeginParams {
+            size: erase_size,
+            blocks: write.data.len().div_ceil(block_size) as u32,
+            block_size: block_size as u32,
+            offset: write.addr,
+        }
+    }
+
+    fn flash_begin(&mut self, write: FlashWrite) -> Result<(), Error> {
+        let params = self.flash_begin_params(write);
+        self.begin_command(
+            Command::FlashBegin,
+            params.size,
+            params.blocks,
+            params.block_size,
+            params.offset,
+        )
+    }
+
+    /// Write the data of a part after the flash was erased by [`flash_begin`](Self::flash_begin)
+    ///
+    /// When `next` is set the begin command of the next part, which erases its region, is sent right after the
+    /// last block without waiting for the response to the block. This saves a round trip for every part, the
+    /// bootloader still handles the commands one after the other.
+    fn write_flash_data(
+        &mut self,
+        write: FlashWrite,
+        next: Option<FlashWrite>,
+    ) -> Result<(), Error> {
+        let FlashWrite { addr, data, .. } = write;
+        if data.is_empty() {
+            // only erased
+            return match next {
+                Some(next) => self.flash_begin(next),
+                None => Ok(()),
+            };
+        }
+
+        let block_size = self.flash_write_size();
+        let block_count = data.len().div_ceil(block_size);
+
+        self.progress.init(addr, block_count);
+        for (i, block) in data.chunks(block_size).enumerate() {
+            match next {
+                Some(next) if i + 1 == block_count => self.write_last_flash_block(write, next)?,
+                _ => {
+                    let block_padding = block_size - block.len();
+                    self.write_flash_block(block, block_padding, i as u32)
+                        .map_err(|e| e.at_offset(addr + (i * block_size) as u32))?
+                }
+            }
+            self.progress.update(i + 1);
+        }
+        self.progress.finish();
+
+        Ok(())
+    }
+
+    /// Write the last block of a part together with the begin command for the next part
+    fn write_last_flash_block(&mut self, write: FlashWrite, next: FlashWrite) -> Result<(), Error> {
+        let FlashWrite { addr, data, .. } = write;
+        let block_size = self.flash_write_size();
+        let offset = (data.len() - 1) / block_size * block_size;
+        let block = &data[offset..];
+        let block_addr = addr + offset as u32;
+        let params = self.flash_begin_params(next);
+
+        let padding = block_size - block.len();
+        let sequence = (offset / block_size) as u32;
+        let timeout = self.block_timeout(block_size);
+        // the begin command is sent before the response to the block arrives, its bytes count towards the block
+        match self.connection.timed(Command::FlashData, |connection| {
+            write_block_command(
+                connection,
+                Command::FlashData,
+                block,
+                padding,
+                0xff,
+                sequence,
+            )?;
+            connection.write_command(Command::FlashBegin, bytes_of(&params), 0)?;
+            connection.with_timeout(timeout, |connection| {
+                connection.read_command_response(Command::FlashData)
+            })
+        }) {
+            Ok(_) => {}
+            Err(e) if is_transfer_error(&e) => {
+                // the bootloader might have started on the next part already, so the sector containing the
+                // block is erased and written again, before erasing the next part once more
+                self.connection.flush()?;
+                self.sync()?;
+                let start = (block_addr as usize / FLASH_SECTOR_SIZE * FLASH_SECTOR_SIZE)
+                    .max(addr as usize) as u32;
+                let skip = (start - addr) as usize;
+                let rest = FlashWrite {
+                    addr: start,
+                    data: &data[skip..],
+                    erase_size: write.erase_size - skip,
+                };
+                self.flash_begin(rest)?;
+                for (i, block) in rest.data.chunks(block_size).enumerate() {
+                    self.write_flash_block(block, block_size - block.len(), i as u32)
+                        .map_err(|e| e.at_offset(start + (i * block_size) as u32))?;
+                }
+                return self.flash_begin(next);
+            }
+            Err(e) => return Err(e.at_offset(block_addr)),
+        }
+
+        self.connection
+            .with_timeout(erase_timeout(params.size), |connection| {
+                connection.timed_response(Command::FlashBegin)
+            })
+            .map_err(|e| e.at_offset(next.addr))?;
+        Ok(())
+    }
+
+    /// Write a block of flash data, retrying it when the transfer fails
+    fn write_flash_block(
+        &mut self,
+        block: &[u8],
+        padding: usize,
+        sequence: u32,
+    ) -> Result<(), Error> {
+        let timeout = self.block_timeout(block.len() + padding);
+        let mut attempt = 0;
+        loop {
+            let result = self.connection.with_timeout(timeout, |connection| {
+                connection.timed(Command::FlashData, |connection| {
+                    write_block_command(
+                        connection,
+                        Command::FlashData,
+                        block,
+                        padding,
+                        0xff,
+                        sequence,
+                    )?;
+                    connection.read_command_response(Command::FlashData)
+                })
+            });
+            match result.map(|_| ()) {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < self.block_retries && is_transfer_error(&e) => {
+                    attempt += 1;
+                    self.connection.record_retry();
+                    // drop any partial response and make sure the bootloader is still listening
+                    self.connection.flush()?;
+                    self.sync()?;
+                }
+                Err(e) if is_transfer_error(&e) && self.fall_back_baud() => attempt = 0,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Drop back to the default baud rate after repeated failures at a higher baud rate
+    ///
+    /// Returns whether the baud rate was lowered.
+    fn fall_back_baud(&mut self) -> bool {
+        let baud = self.connection.baud();
+        if baud.speed() <= BaudRate::Baud115200.speed() {
+            return false;
+        }
+
+        eprintln!(
+            "Writing failed repeatedly at {} baud, continuing at 115200 baud",
+            baud.speed()
+        );
+        for _ in 0..=self.block_retries {
+            if self.connection.flush().is_ok() && self.change_baud(BaudRate::Baud115200).is_ok() {
+                return true;
+            }
+            // the chip might have switched with the response getting lost, so find the rate it listens at
+            for speed in [BaudRate::Baud115200, baud] {
+                if self.connection.set_baud(speed).is_ok() && self.sync().is_ok() {
+                    if speed == BaudRate::Baud115200 {
+                        self.event(ConnectionEvent::BaudChanged(speed.speed()));
+                        return true;
+                    }
+                    break;
+                }
+            }
+        }
+        false
+    }
+
+    pub fn change_baud(&mut self, speed: BaudRate) -> Result<(), Error> {
+        // the stub needs the current baud rate to calculate the new clock divider
+        let old_speed = if self.stub_active {
+            self.connection.baud().speed() as u32
+        } else {
+            0
+        };
+        let mut params = (speed.speed() as u32).to_le_bytes().to_vec();
+        params.extend_from_slice(&old_speed.to_le_bytes());
+        self.connection
+            .command(Command::ChangeBaud, params.as_slice(), 0)?;
+        self.connection.set_baud(speed)?;
+        self.event(ConnectionEvent::BaudChanged(speed.speed()));
+        Ok(())
+    }
+
+    fn event(&mut self, event: ConnectionEvent) {
+        (self.event_hook)(event);
+    }
+}
+
+/// The name of a chip in secure download mode, from the security info reported by its rom
+///
+/// The flags, flash encryption counter and key purposes take 12 bytes, the ESP32-S2 stops there, later chips
+/// append the chip id used in image headers and the api version.
+fn secure_chip_name(info: &[u8]) -> Option<&'static str> {
+    let chip_id = match info.get(12..16) {
+        Some(id) => u32::from_le_bytes([id[0], id[1], id[2], id[3]]),
+        None if info.len() == 12 => return Some("ESP32-S2"),
+        None => return None,
+    };
+    match chip_id {
+        2 => Some("ESP32-S2"),
+        5 => Some("ESP32-C3"),
+        9 => Some("ESP32-S3"),
+        12 => Some("ESP32-C2"),
+        13 => Some("ESP32-C6"),
+        16 => Some("ESP32-H2"),
+        18 => Some("ESP32-P4"),
+        _ => None,
+    }
+}
+
+/// A part of a segment, written after erasing `erase_size` bytes from `addr`
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FlashWrite<'a> {
+    addr: u32,
+    data: &'a [u8],
+    erase_size: usize,
+}
+
+/// Split the data for the flash at the sectors that only contain 0xff
+///
+/// Erased flash reads as 0xff, so those sectors only have to be erased. The rom does that with the begin command
+/// of the part before them, the stub only erases sectors as data is written to them, so they're erased with their
+/// own command instead. Parts start at a sector boundary, so erasing for one part never erases data of another.
+fn split_erased(addr: u32, data: &[u8]) -> Vec<FlashWrite<'_>> {
+    let mut writes: Vec<FlashWrite> = Vec::new();
+    for (pos, len) in sector_chunks(addr, data.len()) {
+        let sector = &data[pos..pos + len];
+        let erased = len == FLASH_SECTOR_SIZE && sector.iter().all(|&byte| byte == 0xff);
+        match writes.last_mut() {
+            Some(write) if erased => write.erase_size += len,
+            // continue the last part if it didn't end with erased sectors
+            Some(write) if write.data.len() == write.erase_size => {
+                let start = write.addr - addr;
+                write.data = &data[start as usize..pos + len];
+                write.erase_size = write.data.len();
+            }
+            _ => writes.push(FlashWrite {
+                addr: addr + pos as u32,
+                data: if erased { &[] } else { sector },
+                erase_size: len,
+            }),
+        }
+    }
+    writes
+}
+
+/// Write a block command without waiting for the response
+fn write_block_command(
+    connection: &mut Connection,
+    command: Command,
+    data: &[u8],
+    padding: usize,
+    padding_byte: u8,
+    sequence: u32,
+) -> Result<(), Error> {
+    let params = BlockParams {
+        size: (data.len() + padding) as u32,
+        sequence,
+        dummy1: 0,
+        dummy2: 0,
+    };
+
+    let length = size_of::<BlockParams>() + data.len() + padding;
+
+    let mut check = checksum(data, CHECKSUM_INIT);
+
+    for _ in 0..padding {
+        check = checksum(&[padding_byte], check);
+    }
+
+    let padding = vec![padding_byte; padding];
+    connection.write_command(
+        command,
+        (length as u16, |encoder: &mut Encoder| {
+            encoder.write(bytes_of(&params))?;
+            encoder.write(data)?;
+            encoder.write(&padding)?;
+            Ok(())
+        }),
+        check as u32,
+    )
+}
+
+/// The region protected by the block protection bits in the first status register of a flash chip
+///
+/// Uses the layout most flash chips share: BP0-BP2 select the size of the region, TB protects the bottom instead
+/// of the top of the flash and SEC selects 4KB sectors instead of fractions of the flash. The complement bit in
+/// the second status register isn't supported by every chip and is assumed to be cleared.
+fn protected_region(status: u8, flash_size: u32) -> Option<Range<u32>> {
+    let block_protect = (status >> 2) & 0x7;
+    let bottom = status & (1 << 5) != 0;
+    let sectors = status & (1 << 6) != 0;
+
+    let size = match block_protect {
+        0 => return None,
+        7 => flash_size,
+        bp if sectors => (FLASH_SECTOR_SIZE as u32) << (bp - 1).min(3),
+        bp => (flash_size / 64) << (bp - 1),
+    };
+    let size = size.min(flash_size);
+    if bottom {
+        Some(0..size)
+    } else {
+        Some(flash_size - size..flash_size)
+    }
+}
+
+/// The value of the spi clock register for dividing the 80MHz clock by `divider`, as set by the rom
+///
+/// The clock is high for the first half of the `divider` cycles of the counter and low for the rest.
+fn spi_clock(divider: u8) -> u32 {
+    const SPI_CLK_EQU_SYSCLK: u32 = 1 << 31;
+    if divider <= 1 {
+        return SPI_CLK_EQU_SYSCLK;
+    }
+    let n = divider as u32 - 1;
+    let high = divider as u32 / 2 - 1;
+    n << 12 | high << 6 | n
+}
+
+/// How the status register that holds the quad enable bit of a flash chip is accessed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum StatusAccess {
+    /// The first status register, written with WRSR
+    Status1,
+    /// Both status registers as 16 bits, written together with WRSR
+    Status16,
+    /// The second status register, written on its own with WRSR2
+    Status2,
+}
+
+/// Where the quad enable bit of the flash chips of a manufacturer is
+#[derive(Debug)]
+struct QuadEnable {
+    manufacturer: u8,
+    device: u16,
+    device_mask: u16,
+    access: StatusAccess,
+    /// The position of the bit in the accessed status register
+    bit: u8,
+}
+
+/// The flash chips known to the esp-idf bootloader, chips that aren't listed use [`DEFAULT_QUAD_ENABLE`]
+const QUAD_ENABLE: &[QuadEnable] = &[
+    // MXIC
+    QuadEnable {
+        manufacturer: 0xc2,
+        device: 0x2000,
+        device_mask: 0xff00,
+        access: StatusAccess::Status1,
+        bit: 6,
+    },
+    // ISSI IS25LP and IS25WP
+    QuadEnable {
+        manufacturer: 0x9d,
+        device: 0x4000,
+        device_mask: 0xcf00,
+        access: StatusAccess::Status1,
+        bit: 6,
+    },
+    // Winbond
+    QuadEnable {
+        manufacturer: 0xef,
+        device: 0x4000,
+        device_mask: 0xff00,
+        access: StatusAccess::Status16,
+        bit: 9,
+    },
+    // GigaDevice GD25LQ, the other GigaDevice chips have a separate WRSR2
+    QuadEnable {
+        manufacturer: 0xc8,
+        device: 0x6000,
+        device_mask: 0xff00,
+        access: StatusAccess::Status16,
+        bit: 9,
+    },
+    // TH
+    QuadEnable {
+        manufacturer: 0xcd,
+        device: 0x6000,
+        device_mask: 0xff00,
+        access: StatusAccess::Status16,
+        bit: 9,
+    },
+];
+
+/// Bit 1 of the second status register written with WRSR2, used by GigaDevice, XMC and most other chips
+const DEFAULT_QUAD_ENABLE: QuadEnable = QuadEnable {
+    manufacturer: 0,
+    device: 0,
+    device_mask: 0,
+    access: StatusAccess::Status2,
+    bit: 1,
+};
+
+/// Look up the quad enable bit for the flash chip with the JEDEC id read by RDID
+fn quad_enable(flash_id: u32) -> &'static QuadEnable {
+    let manufacturer = flash_id as u8;
+    let device = ((flash_id >> 8) as u16).swap_bytes();
+    QUAD_ENABLE
+        .iter()
+        .find(|chip| chip.manufacturer == manufacturer && device & chip.device_mask == chip.device)
+        .unwrap_or(&DEFAULT_QUAD_ENABLE)
+}
+
+fn get_erase_size(offset: usize, size: usize) -> usize {
+    let sector_count = size.div_ceil(FLASH_SECTOR_SIZE);
+    let start_sector = offset / FLASH_SECTOR_SIZE;
+
+    let head_sectors = usize::min(
+        FLASH_SECTORS_PER_BLOCK - (start_sector % FLASH_SECTORS_PER_BLOCK),
+        sector_count,
+    );
+
+    if sector_count < 2 * head_sectors {
+        sector_count.div_ceil(2) * FLASH_SECTOR_SIZE
+    } else {
+        (sector_count - head_sectors) * FLASH_SECTOR_SIZE
+    }
+}
+
+/// Timeout for hashing `size` bytes of flash, hashing takes roughly up to 8 seconds per MB
+fn md5_timeout(size: u32) -> Duration {
+    let timeout = Duration::from_secs(8) * size / 0x100000;
+    timeout.max(DEFAULT_TIMEOUT)
+}
+
+/// Timeout for commands that erase `size` bytes of flash, erasing takes roughly up to 30 seconds per MB
+fn erase_timeout(size: u32) -> Duration {
+    let timeout = Duration::from_secs(30) * size / 0x100000;
+    timeout.max(DEFAULT_TIMEOUT)
+}
+
+/// The data registers and their values for the data of an spi command, every register holds 4 bytes
+fn spi_data_registers(w0: u32, data: &[u8]) -> impl Iterator<Item = (u32, u32)> + '_ {
+    data.chunks(4).enumerate().map(move |(i, bytes)| {
+        let mut data_bytes = [0; 4];
+        data_bytes[0..bytes.len()].copy_from_slice(bytes);
+        (w0 + i as u32 * 4, u32::from_le_bytes(data_bytes))
+    })
+}
+
+pub(crate) const CHECKSUM_INIT: u8 = 0xEF;
+const SLIP_END: u8 = 0xC0;
+
+/// Split `len` bytes starting at `addr` into chunks that don't cross a sector boundary, as `(position, length)`
+fn sector_chunks(addr: u32, len: usize) -> impl Iterator<Item = (usize, usize)> {
+    let sector_size = FLASH_SECTOR_SIZE as u32;
+    let first = usize::min((sector_size - addr % sector_size) as usize, len);
+    once((0, first))
+        .chain(
+            (first..len)
+                .step_by(FLASH_SECTOR_SIZE)
+                .map(move |pos| (pos, usize::min(FLASH_SECTOR_SIZE, len - pos))),
+        )
+        .filter(|&(_, len)| len > 0)
+}
+
+/// The bytes that aren't part of a slip frame, dropping the bootloader responses from the data received
+fn outside_slip_frames(data: &[u8]) -> Vec<u8> {
+    let mut in_frame = false;
+    data.iter()
+        .copied()
+        .filter(|&byte| {
+            if byte == SLIP_END {
+                in_frame = !in_frame;
+                return false;
+            }
+            !in_frame
+        })
+        .collect()
+}
+
+/// Find out why the chip didn't enter the bootloader from its boot messages
+fn boot_problem(log: &str) -> Option<String> {
+    if log.matches("rst:").count() > 1 || log.matches("rst cause:").count() > 1 {
+        return Some(String::from(
+            "it resets repeatedly, check the power supply and the EN pin",
+        ));
+    }
+
+    // esp8266: "rst cause:2, boot mode:(3,6)", the mode is made up of GPIO15, GPIO0 and GPIO2
+    if let Some(rest) = log.split("boot mode:(").nth(1) {
+        let mode: u8 = rest.split(',').next()?.trim().parse().ok()?;
+        let problem = if mode & 0b100 != 0 {
+            "GPIO15 was high during reset"
+        } else if mode & 0b001 == 0 {
+            "GPIO2 was low during reset"
+        } else if mode & 0b010 != 0 {
+            "GPIO0 was high during reset"
+        } else {
+            return None;
+        };
+        return Some(format!("it started in boot mode {}, {}", mode, problem));
+    }
+
+    // esp32: "rst:0x1 (POWERON_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)", bit 4 is GPIO0
+    if let Some(rest) = log.split("boot:0x").nth(1) {
+        let strapping = u8::from_str_radix(rest.split_whitespace().next()?, 16).ok()?;
+        if strapping & 0x10 != 0 {
+            let name = rest.split(['(', ')']).nth(1).unwrap_or("flash boot");
+            return Some(format!(
+                "it started in {} mode, GPIO0 was high during reset",
+                name
+            ));
+        }
+    }
+
+    None
+}
+
+/// Whether an error was caused by a glitch in the transfer, as opposed to the bootloader rejecting the command
+fn is_transfer_error(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::Serial(_)
+            | Error::Timeout
+            | Error::FramingError
+            | Error::OverSizedPacket
+            | Error::RomError(RomError {
+                kind: RomErrorKind::InvalidCrc,
+                ..
+            })
+    )
+}
+
+pub fn checksum(data: &[u8], mut checksum: u8) -> u8 {
+    for byte in data {
+        checksum ^= *byte;
+    }
+
+    checksum
+}
+
+#[test]
+fn test_spi_data_registers() {
+    assert_eq!(
+        vec![(0x80, 0x04030201), (0x84, 0x08070605), (0x88, 0x09)],
+        spi_data_registers(0x80, &[1, 2, 3, 4, 5, 6, 7, 8, 9]).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_boot_problem() {
+    assert_eq!(
+        boot_problem(" ets Jan  8 2013,rst cause:2, boot mode:(3,6)\r\n").unwrap(),
+        "it started in boot mode 3, GPIO0 was high during reset"
+    );
+    assert_eq!(
+        boot_problem(" ets Jan  8 2013,rst cause:2, boot mode:(1,6)"),
+        None
+    );
+    assert_eq!(
+        boot_problem("rst:0x1 (POWERON_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)\r\n").unwrap(),
+        "it started in SPI_FAST_FLASH_BOOT mode, GPIO0 was high during reset"
+    );
+    assert_eq!(
+        boot_problem(
+            "rst:0x1 (POWERON_RESET),boot:0x3 (DOWNLOAD_BOOT(UART0/UART1/SDIO_REI_REO_V2))"
+        ),
+        None
+    );
+    assert!(
+        boot_problem("rst:0xc (SW_CPU_RESET),boot:0x13\nrst:0xc (SW_CPU_RESET),boot:0x13")
+            .unwrap()
+            .contains("resets repeatedly")
+    );
+}
+
+#[test]
+fn test_secure_download_mode() {
+    use crate::testing::MockTransport;
+
+    let connect = |security_info: &[u8]| {
+        let mock = MockTransport::new();
+        // registers can't be read, the security info identifies the chip
+        mock.fail(Command::ReadReg, RomErrorKind::InvalidMessage as u8);
+        mock.respond(Command::GetSecurityInfo, 0, security_info);
+        Flasher::connect(mock, None).map(drop)
+    };
+    // the ESP32-S2 doesn't report a chip id
+    assert!(matches!(
+        connect(&[0; 12]),
+        Err(Error::UnsupportedChip("ESP32-S2"))
+    ));
+    let mut info = [0; 20];
+    info[12] = 5;
+    assert!(matches!(
+        connect(&info),
+        Err(Error::UnsupportedChip("ESP32-C3"))
+    ));
+    // the esp32 has no secure download mode, its id isn't taken for one
+    assert!(matches!(connect(&[0; 20]), Err(Error::UnrecognizedChip)));
+
+    // the esp32 rom doesn't know the command
+    let mock = MockTransport::new();
+    mock.fail(Command::ReadReg, RomErrorKind::InvalidMessage as u8);
+    mock.fail(Command::GetSecurityInfo, RomErrorKind::InvalidMessage as u8);
+    assert!(matches!(
+        Flasher::connect(mock, None),
+        Err(Error::RomError(_))
+    ));
+}
+
+#[test]
+fn test_flash_write_protection() {
+    use crate::testing::MockTransport;
+
+    let size = FlashSize::Flash4Mb.size_in_bytes().unwrap();
+    assert_eq!(None, protected_region(0x00, size));
+    // BP0: the upper 64th
+    assert_eq!(Some(0x3f0000..0x400000), protected_region(0x04, size));
+    // BP0 and BP1 with TB: the lower 16th
+    assert_eq!(Some(0..0x40000), protected_region(0x2c, size));
+    // BP1 with SEC: the upper 8KB
+    assert_eq!(Some(0x3fe000..0x400000), protected_region(0x48, size));
+    assert_eq!(Some(0..size), protected_region(0x1c, size));
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    simulator.set_flash_status(0x04);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    flasher.write_bin_to_flash(0x10000, &[0xaa; 0x100]).unwrap();
+    assert!(matches!(
+        flasher.write_bin_to_flash(0x3ef000, &[0xaa; 0x2000]),
+        Err(Error::FlashProtected {
+            addr: 0x3f0000,
+            start: 0x3f0000,
+            end: 0x400000
+        })
+    ));
+    assert_eq!(0xff, simulator.flash()[0x3ef000]);
+    assert!(matches!(
+        flasher.erase_flash(),
+        Err(Error::FlashProtected { .. })
+    ));
+}
+
+#[test]
+fn test_pipelined_erase() {
+    use crate::testing::MockTransport;
+    use std::sync::{Arc, Mutex};
+
+    let elf = std::fs::read("./tests/data/esp32").unwrap();
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+
+    let begins = Arc::new(Mutex::new(Vec::new()));
+    let hook_begins = begins.clone();
+    flasher.set_command_hook(move |timing| {
+        if timing.command == Command::FlashBegin {
+            hook_begins.lock().unwrap().push(timing.bytes_sent);
+        }
+    });
+    flasher.load_elf_to_flash(&elf).unwrap();
+
+    // the bootloader, partition table and app, the last two are sent together with the block before them
+    let begins = begins.lock().unwrap();
+    assert_eq!(3, begins.len());
+    assert!(begins[0] > 0);
+    assert_eq!(&[0, 0], &begins[1..]);
+    drop(begins);
+    flasher.verify_elf(&elf).unwrap();
+
+    // every segment is checked against the flash while the image is built, nothing is left to write
+    let data_blocks = simulator.received_count(Command::FlashData);
+    flasher.set_resume(true);
+    flasher.load_elf_to_flash(&elf).unwrap();
+    assert_eq!(data_blocks, simulator.received_count(Command::FlashData));
+}
+
+#[test]
+fn test_elf_checked_before_writing() {
+    use crate::testing::MockTransport;
+
+    let elf = std::fs::read("./tests/data/esp32").unwrap();
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    flasher.write_bin_to_flash(0x1000, &[0x5a; 0x8000]).unwrap();
+
+    // the app is the last segment, it's placed in the protected upper half of the flash
+    let table = PartitionTable::from_csv(
+        "nvs, data, nvs, 0x9000, 0x6000,\nfactory, app, factory, 0x200000, 1M,\n",
+        0x8000,
+    )
+    .unwrap();
+    flasher.set_image_settings(ImageSettings {
+        partition_table: Some(table),
+        ..ImageSettings::default()
+    });
+    simulator.set_flash_status(0x18);
+    let begins = simulator.received_count(Command::FlashBegin);
+    assert!(matches!(
+        flasher.load_elf_to_flash(&elf),
+        Err(Error::FlashProtected { addr: 0x200000, .. })
+    ));
+
+    // the bootloader and partition table weren't erased
+    assert_eq!(begins, simulator.received_count(Command::FlashBegin));
+    assert_eq!(
+        vec![0x5a; 0x8000],
+        simulator.flash()[0x1000..0x9000].to_vec()
+    );
+}
+
+#[test]
+fn test_command_timings() {
+    use crate::testing::MockTransport;
+    use std::sync::{Arc, Mutex};
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    let commands = Arc::new(Mutex::new(Vec::new()));
+    let hook_commands = commands.clone();
+    flasher.set_command_hook(move |timing| hook_commands.lock().unwrap().push(timing.command));
+
+    // the sync before the retried block and the end command that gets no response are timed as well
+    simulator.fail(Command::FlashData, 0x07);
+    flasher.write_bin_to_flash(0x10000, &[0xaa; 0x900]).unwrap();
+    flasher
+        .load_stub(&Stub {
+            text: vec![0; 0x10],
+            text_start: 0x4009_0000,
+            data: Vec::new(),
+            data_start: 0,
+            entry: 0x4009_0000,
+        })
+        .unwrap();
+    let commands = commands.lock().unwrap();
+    for command in [Command::Sync, Command::FlashEnd, Command::MemEnd] {
+        assert!(commands.contains(&command), "{:?} wasn't timed", command);
+    }
+    let count = |command| commands.iter().filter(|&&c| c == command).count();
+    assert_eq!(
+        simulator.received_count(Command::FlashData),
+        count(Command::FlashData)
+    );
+    assert_eq!(
+        simulator.received_count(Command::FlashBegin),
+        count(Command::FlashBegin)
+    );
+}
+
+#[test]
+fn test_block_retries() {
+    use crate::testing::MockTransport;
+
+    let data: Vec<u8> = (0..0x900u32).map(|i| (i * 7) as u8).collect();
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+
+    // the blocks with a bad checksum are sent again
+    simulator.fail(Command::FlashData, 0x07);
+    simulator.fail(Command::FlashData, 0x07);
+    let stats = flasher.write_bin_to_flash(0x10000, &data).unwrap();
+    assert_eq!(2, stats.retries);
+    assert_eq!(&data[..], &simulator.flash()[0x10000..0x10900]);
+
+    flasher.set_block_retries(1);
+    simulator.fail(Command::FlashData, 0x07);
+    simulator.fail(Command::FlashData, 0x07);
+    assert!(matches!(
+        flasher.write_bin_to_flash(0x10000, &data),
+        Err(Error::RomError(RomError {
+            kind: RomErrorKind::InvalidCrc,
+            ..
+        }))
+    ));
+
+    // the bootloader not answering the sync before the retry is an error of its own
+    flasher.set_block_retries(3);
+    simulator.fail(Command::FlashData, 0x07);
+    simulator.fail(Command::Sync, 0x06);
+    assert!(matches!(
+        flasher.write_bin_to_flash(0x10000, &data),
+        Err(Error::RomError(RomError {
+            kind: RomErrorKind::FailedToAct,
+            ..
+        }))
+    ));
+    assert!(simulator.verify().is_ok());
+}
+
+#[test]
+fn test_resume() {
+    use crate::testing::MockTransport;
+
+    let data: Vec<u8> = (0..0x3000u32).map(|i| (i * 7) as u8).collect();
+
+    // only the sectors from the first one that differs are written again
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    flasher.write_bin_to_flash(0x10000, &data).unwrap();
+    simulator.set_flash(0x11000, &[0xff; 0x10]);
+    let data_blocks = simulator.received_count(Command::FlashData);
+    flasher.set_resume(true);
+    flasher.write_bin_to_flash(0x10000, &data).unwrap();
+    assert_eq!(
+        data_blocks + 0x2000 / ROM_FLASH_WRITE_SIZE,
+        simulator.received_count(Command::FlashData)
+    );
+    flasher.verify_bin(0x10000, &data).unwrap();
+
+    // the esp8266 rom can't hash the flash, everything is written
+    let simulator = MockTransport::simulator(Chip::Esp8266, FlashSize::Flash1Mb);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    flasher.write_bin_to_flash(0x1000, &data).unwrap();
+    let data_blocks = simulator.received_count(Command::FlashData);
+    flasher.set_resume(true);
+    flasher.write_bin_to_flash(0x1000, &data).unwrap();
+    assert_eq!(
+        data_blocks + data.len() / ROM_FLASH_WRITE_SIZE,
+        simulator.received_count(Command::FlashData)
+    );
+    assert_eq!(&data[..], &simulator.flash()[0x1000..0x4000]);
+}
+
+#[test]
+fn test_stub_write_size() {
+    use crate::testing::MockTransport;
+
+    let data: Vec<u8> = (0..0x9000u32).map(|i| (i * 7) as u8).collect();
+    let stub = Stub {
+        text: vec![0; 0x10],
+        text_start: 0x4009_0000,
+        data: Vec::new(),
+        data_start: 0,
+        entry: 0x4009_0000,
+    };
+
+    let simulator = MockTransport::simulator(Chip::Esp8266, FlashSize::Flash1Mb);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    flasher.write_bin_to_flash(0x10000, &data).unwrap();
+    assert_eq!(0x24, simulator.received_count(Command::FlashData));
+
+    flasher.load_stub(&stub).unwrap();
+    assert!(flasher.stub_active());
+    flasher.write_bin_to_flash(0x20000, &data).unwrap();
+    assert_eq!(0x24 + 3, simulator.received_count(Command::FlashData));
+    assert_eq!(&data[..], &simulator.flash()[0x20000..0x29000]);
+    flasher.verify_bin(0x20000, &data).unwrap();
+}
+
+#[test]
+fn test_skip_erased() {
+    use crate::testing::MockTransport;
+
+    let mut data = vec![0xaa; 0x6800];
+    data[0x1800..0x4000].fill(0xff);
+    data[0x5000..].fill(0xff);
+    let writes = split_erased(0x10800, &data);
+    // the sectors at 0x12000, 0x13000 and 0x16000 are only erased
+    assert_eq!(
+        vec![(0x10800, 0x1800, 0x3800), (0x14000, 0x2000, 0x3000)],
+        writes
+            .iter()
+            .map(|write| (write.addr, write.data.len(), write.erase_size))
+            .collect::<Vec<_>>()
+    );
+    let blank = split_erased(0x10000, &[0xff; 0x2000]);
+    assert_eq!(&[] as &[u8], blank[0].data);
+    assert_eq!(0x2000, blank[0].erase_size);
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    simulator.set_flash(0x10000, &[0; 0x8000]);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    flasher.write_bin_to_flash(0x10800, &data).unwrap();
+    assert_eq!(6 + 8, simulator.received_count(Command::FlashData));
+    assert_eq!(&data[..], &simulator.flash()[0x10800..0x17000]);
+    assert_eq!(&[0; 0x1000][..], &simulator.flash()[0x17000..0x18000]);
+
+    // the stub erases lazily, so the skipped sectors are erased with their own command
+    let stub = Stub {
+        text: vec![0; 0x10],
+        text_start: 0x4009_0000,
+        data: Vec::new(),
+        data_start: 0,
+        entry: 0x4009_0000,
+    };
+    flasher.load_stub(&stub).unwrap();
+    simulator.set_flash(0x10000, &[0; 0x8000]);
+    flasher.write_bin_to_flash(0x10800, &data).unwrap();
+    assert_eq!(2, simulator.received_count(Command::EraseRegion));
+    assert_eq!(&data[..], &simulator.flash()[0x10800..0x17000]);
+    assert_eq!(&[0; 0x1000][..], &simulator.flash()[0x17000..0x18000]);
+
+    flasher.erase_region(0x10800, 0x100).unwrap();
+    assert_eq!(&[0xff; 0x1000][..], &simulator.flash()[0x10000..0x11000]);
+    assert_eq!(0xaa, simulator.flash()[0x11000]);
+}
+
+#[test]
+fn test_connect_in_bootloader() {
+    use crate::testing::MockTransport;
+
+    // the mock answers the first sync, like a chip that's still in the bootloader
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    Flasher::connect(mock.clone(), None).unwrap();
+    assert_eq!(0, mock.reset_count());
+
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    mock.fail(Command::Sync, 0x05);
+    Flasher::connect(mock.clone(), None).unwrap();
+    assert_eq!(1, mock.reset_count());
+
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    mock.fail(Command::Sync, 0x05);
+    let options = ConnectOptions::new().reset(ResetBeforeConnect::NoReset);
+    Flasher::connect_with_options(mock.clone(), options).unwrap();
+    assert_eq!(0, mock.reset_count());
+}
+
+#[test]
+fn test_quad_enable() {
+    use crate::testing::MockTransport;
+
+    // Winbond W25Q32, ISSI IS25WP032 and GigaDevice GD25Q32 and GD25LQ32
+    assert_eq!(StatusAccess::Status16, quad_enable(0x1640ef).access);
+    assert_eq!(9, quad_enable(0x1640ef).bit);
+    assert_eq!(StatusAccess::Status1, quad_enable(0x16709d).access);
+    assert_eq!(6, quad_enable(0x16709d).bit);
+    assert_eq!(StatusAccess::Status2, quad_enable(0x1640c8).access);
+    assert_eq!(StatusAccess::Status16, quad_enable(0x1660c8).access);
+
+    for (flash_id, status2) in [(0x1640ef, 0x02), (0x1640c8, 0x02), (0x16709d, 0x00)] {
+        let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+        simulator.set_flash_id(flash_id);
+        let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+        flasher.enable_quad_mode().unwrap();
+        assert_eq!(status2, simulator.flash_status2(), "{:#x}", flash_id);
+    }
+
+    // binaries enable it when they contain a bootloader using qio, like factory images written at 0
+    let mut factory = vec![0xff; 0x2000];
+    factory[0x1000..0x1004].copy_from_slice(&[0xe9, 1, FlashMode::Qio as u8, 0x20]);
+    for (addr, data, status2) in [
+        (0x1000, &factory[0x1000..], 0x02),
+        (0, &factory[..], 0x02),
+        (0x10000, &factory[0x1000..], 0x00),
+        (0x1000, &[0xe9, 1, FlashMode::Dio as u8, 0x20][..], 0x00),
+    ] {
+        let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+        simulator.set_flash_id(0x1640c8);
+        let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+        flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+        flasher.write_bin_to_flash(addr, data).unwrap();
+        assert_eq!(status2, simulator.flash_status2(), "{:#x}", addr);
+    }
+}
+
+#[test]
+fn test_spi_clock_divider() {
+    use crate::testing::MockTransport;
+
+    assert_eq!(1 << 31, spi_clock(1));
+    // 20MHz: the counter wraps at 3 and the clock goes low at 1
+    assert_eq!(3 << 12 | 1 << 6 | 3, spi_clock(4));
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    flasher.set_spi_clock_divider(100);
+    flasher.write_bin_to_flash(0x10000, &[0xaa; 0x100]).unwrap();
+    assert_eq!(spi_clock(64), simulator.register(0x3ff42018));
+}
+
+#[test]
+fn test_spi_connection() {
+    use crate::testing::MockTransport;
+
+    let custom = SpiAttachParams {
+        clk: 14,
+        q: 12,
+        d: 13,
+        hd: 4,
+        cs: 15,
+    };
+    let packed: u32 = 4 << 24 | 15 << 18 | 13 << 12 | 12 << 6 | 14;
+    assert_eq!(packed.to_le_bytes().to_vec(), custom.encode());
+    assert_eq!(vec![1, 0, 0, 0], SpiAttachParams::hspi().encode());
+
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    let options = ConnectOptions::new().spi_connection(Some(custom));
+    Flasher::connect_with_options(mock.clone(), options).unwrap();
+    let attach: Vec<Vec<u8>> = mock
+        .received()
+        .into_iter()
+        .filter(|received| received.command == Command::SpiAttach as u8)
+        .map(|received| received.data)
+        .collect();
+    assert_eq!(vec![custom.encode()], attach);
+
+    let mock = MockTransport::with_chip(Chip::Esp8266, FlashSize::Flash4Mb);
+    assert!(matches!(
+        Flasher::connect_with_options(mock, ConnectOptions::new().spi_connection(Some(custom))),
+        Err(Error::UnsupportedOperation { .. })
+    ));
+}
+
+#[test]
+fn test_psram() {
+    use crate::testing::MockTransport;
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    // external psram is only probed when asked for
+    simulator.set_psram_id(0x205d0d);
+    let writes = simulator.received_count(Command::WriteReg);
+    assert_eq!(PsramStatus::Unknown, flasher.board_info().unwrap().psram);
+    assert_eq!(writes, simulator.received_count(Command::WriteReg));
+
+    flasher.set_probe_psram(true);
+    simulator.set_psram_id(0);
+    assert_eq!(PsramStatus::Absent, flasher.board_info().unwrap().psram);
+
+    // an ESP-PSRAM32 with the 32Mbit size in the eid byte
+    simulator.set_psram_id(0x205d0d);
+    let psram = flasher.board_info().unwrap().psram;
+    assert_eq!(
+        PsramStatus::Present(Psram {
+            size: 4 * 1024 * 1024,
+            embedded: false,
+            cs_pin: 16,
+            clk_pin: 17,
+        }),
+        psram
+    );
+    // the flash is selected again afterwards
+    let spi_registers = Chip::Esp32.spi_registers();
+    assert_eq!(0, simulator.register(spi_registers.pin()));
+    assert_eq!(0, simulator.register(0x3ff44020));
+    assert_eq!(0x164020, flasher.spi_command(0x9f, &[], 24).unwrap());
+
+    // a probe that fails is reported as unknown, and the pins are restored
+    let io_mux = 0x3ff4904c;
+    let mux = simulator.register(io_mux);
+    simulator.pin_register(spi_registers.cmd(), 1 << 18);
+    assert_eq!(PsramStatus::Unknown, flasher.board_info().unwrap().psram);
+    assert_eq!(0, simulator.register(spi_registers.pin()));
+    assert_eq!(0, simulator.register(0x3ff44020));
+    assert_eq!(mux, simulator.register(io_mux));
+
+    let simulator = MockTransport::simulator(Chip::Esp8266, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(simulator, None).unwrap();
+    flasher.set_probe_psram(true);
+    assert_eq!(PsramStatus::Absent, flasher.board_info().unwrap().psram);
+}
+
+#[test]
+fn test_boot_log_baud() {
+    use crate::testing::MockTransport;
+
+    // the rom sets the uart divider for 115200 baud from twice the crystal frequency
+    let transport = MockTransport::with_chip(Chip::Esp8266, FlashSize::Flash4Mb);
+    transport.pin_register(0x60000014, 52_000_000 / 115_200);
+    let mut flasher = Flasher::connect(transport, None).unwrap();
+    assert_eq!(BaudRate::BaudOther(74880), flasher.boot_log_baud().unwrap());
+
+    let transport = MockTransport::with_chip(Chip::Esp8266, FlashSize::Flash4Mb);
+    transport.pin_register(0x60000014, 80_000_000 / 115_200);
+    let mut flasher = Flasher::connect(transport, None).unwrap();
+    assert_eq!(BaudRate::Baud115200, flasher.boot_log_baud().unwrap());
+}
+
+#[test]
+fn test_workarounds() {
+    use crate::testing::MockTransport;
+
+    assert!(Chip::Esp32.workarounds(None).unreliable_high_baud);
+    assert!(!Chip::Esp8266.workarounds(None).unreliable_high_baud);
+
+    let transport = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(transport.clone(), None).unwrap();
+    assert!(flasher.workarounds().unwrap().unreliable_high_baud);
+
+    // the revision bits of revision 3, in the efuses and the apb date register
+    transport.set_register(0x3ff5a00c, 1 << 15);
+    transport.set_register(0x3ff5a014, 1 << 20);
+    transport.set_register(0x3ff6607c, 1 << 31);
+    assert!(!flasher.workarounds().unwrap().unreliable_high_baud);
+
+    // revision 0 stays at 115200 baud
+    let speed = Some(BaudRate::BaudOther(921_600));
+    let transport = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    Flasher::connect(transport.clone(), speed).unwrap();
+    assert_eq!(0, transport.received_count(Command::ChangeBaud));
+
+    let transport = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    transport.set_register(0x3ff5a00c, 1 << 15);
+    transport.set_register(0x3ff5a014, 1 << 20);
+    transport.set_register(0x3ff6607c, 1 << 31);
+    Flasher::connect(transport.clone(), speed).unwrap();
+    assert_eq!(1, transport.received_count(Command::ChangeBaud));
+}
+
+#[test]
+fn test_fall_back_baud() {
+    use crate::testing::MockTransport;
+
+    let transport = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(transport.clone(), None).unwrap();
+    flasher.change_baud(BaudRate::BaudOther(921600)).unwrap();
+    assert!(flasher.fall_back_baud());
+    assert_eq!(BaudRate::Baud115200, flasher.connection.baud());
+    assert!(!flasher.fall_back_baud());
+
+    // the chip switched, but the response to the baud change got lost
+    flasher.change_baud(BaudRate::BaudOther(921600)).unwrap();
+    transport.fail(Command::ChangeBaud, 0x05);
+    let syncs = transport.received_count(Command::Sync);
+    assert!(flasher.fall_back_baud());
+    assert_eq!(BaudRate::Baud115200, flasher.connection.baud());
+    assert_eq!(syncs + 1, transport.received_count(Command::Sync));
+}
+
+#[test]
+fn test_set_flash_params() {
+    use crate::testing::MockTransport;
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash2Mb);
+    Flasher::connect(simulator.clone(), None).unwrap();
+    let params = simulator
+        .received()
+        .into_iter()
+        .find(|received| received.command == Command::SpiSetParams as u8)
+        .unwrap();
+    let expected: Vec<u8> = [0, 0x200000, 0x10000, 0x1000, 0x100, 0xffff]
+        .iter()
+        .flat_map(|param: &u32| param.to_le_bytes())
+        .collect();
+    assert_eq!(expected, params.data);
+}
+
+#[test]
+fn test_flash_size_fallback() {
+    use crate::testing::MockTransport;
+
+    for flash_id in [0xff4020, 0x424020] {
+        let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash2Mb);
+        simulator.set_flash_id(flash_id);
+        assert!(Flasher::connect(simulator, None).is_err());
+
+        let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash2Mb);
+        simulator.set_flash_id(flash_id);
+        let options = ConnectOptions::new().flash_size(Some(FlashSize::Flash2Mb));
+        let flasher = Flasher::connect_with_options(simulator, options).unwrap();
+        assert_eq!(FlashSize::Flash2Mb, flasher.flash_size());
+    }
+
+    // the pins the flash was found on are kept
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash2Mb);
+    simulator.set_flash_id(0x424020);
+    simulator.set_flash_pins(SpiAttachParams::esp32_pico_d4());
+    let options = ConnectOptions::new().flash_size(Some(FlashSize::Flash2Mb));
+    let flasher = Flasher::connect_with_options(simulator, options).unwrap();
+    assert_eq!(SpiAttachParams::esp32_pico_d4(), flasher.spi_params);
+}
+
+#[test]
+fn test_flash_voltage() {
+    use crate::testing::MockTransport;
+
+    let transport = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(transport.clone(), None).unwrap();
+    assert_eq!(
+        Some(FlashVoltage::V3_3),
+        flasher.board_info().unwrap().flash_voltage
+    );
+
+    // MTDI high at reset
+    transport.set_register(0x3ff44038, 1 << 5);
+    assert_eq!(
+        Some(FlashVoltage::V1_8),
+        flasher.board_info().unwrap().flash_voltage
+    );
+
+    // the efuses override the strapping pin
+    transport.set_register(0x3ff5a010, 1 << 16 | 1 << 15 | 1 << 14);
+    assert_eq!(
+        Some(FlashVoltage::V3_3),
+        flasher.board_info().unwrap().flash_voltage
+    );
+    transport.set_register(0x3ff5a010, 1 << 16);
+    assert_eq!(None, flasher.board_info().unwrap().flash_voltage);
+}
+
+#[test]
+fn test_chip_features() {
+    use crate::testing::MockTransport;
+
+    let transport = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(transport.clone(), None).unwrap();
+    assert_eq!(
+        vec![
+            ChipFeature::Wifi,
+            ChipFeature::Bluetooth,
+            ChipFeature::DualCore,
+            ChipFeature::Cpu240Mhz
+        ],
+        flasher.chip_features().unwrap()
+    );
+
+    // bluetooth and the app cpu disabled
+    transport.set_register(0x3ff5a00c, 1 << 1 | 1 << 0);
+    let features = flasher.chip_features().unwrap();
+    assert_eq!(
+        vec![
+            ChipFeature::Wifi,
+            ChipFeature::SingleCore,
+            ChipFeature::Cpu240Mhz
+        ],
+        features
+    );
+    assert_eq!("Single Core", features[1].to_string());
+}
+
+#[cfg(feature = "serialize")]
+#[test]
+fn test_serialize_flash_size() {
+    for size in [
+        FlashSize::Flash256Kb,
+        FlashSize::Flash1Mb,
+        FlashSize::Flash4Mb,
+        FlashSize::Flash16Mb,
+    ] {
+        let json = serde_json::to_string(&size).unwrap();
+        assert_eq!(format!("\"{}\"", size), json);
+        assert_eq!(size, serde_json::from_str::<FlashSize>(&json).unwrap());
+    }
+    assert_eq!(
+        "\"unknown\"",
+        serde_json::to_string(&FlashSize::FlashRetry).unwrap()
+    );
+    assert!(serde_json::from_str::<FlashSize>("\"unknown\"").is_err());
+}
+
+#[test]
+fn test_flash_size_str() {
+    for size in [
+        FlashSize::Flash256Kb,
+        FlashSize::Flash512Kb,
+        FlashSize::Flash1Mb,
+        FlashSize::Flash2Mb,
+        FlashSize::Flash4Mb,
+        FlashSize::Flash8Mb,
+        FlashSize::Flash16Mb,
+    ] {
+        assert_eq!(size, size.to_string().parse().unwrap());
+    }
+    assert_eq!("512KB", FlashSize::Flash512Kb.to_string());
+    assert_eq!(FlashSize::Flash4Mb, "4mb".parse().unwrap());
+    assert_eq!(
+        Some(0x80000),
+        "512KB".parse::<FlashSize>().unwrap().size_in_bytes()
+    );
+    assert_eq!(None, FlashSize::FlashRetry.size_in_bytes());
+    assert!("3MB".parse::<FlashSize>().is_err());
+    assert!("4M".parse::<FlashSize>().is_err());
+}
+
+#[test]
+fn test_event_hook() {
+    use crate::testing::MockTransport;
+    use std::sync::{Arc, Mutex};
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let hook_events = events.clone();
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    // the chip doesn't answer the first sync, so it's reset into the bootloader
+    simulator.fail(Command::Sync, 0x05);
+    let options =
+        ConnectOptions::new().event_hook(move |event| hook_events.lock().unwrap().push(event));
+    let mut flasher = Flasher::connect_with_options(simulator, options).unwrap();
+    flasher
+        .load_stub(&Stub {
+            text: vec![0; 0x10],
+            text_start: 0x4009_0000,
+            data: Vec::new(),
+            data_start: 0,
+            entry: 0x4009_0000,
+        })
+        .unwrap();
+    flasher.change_baud(BaudRate::BaudOther(921_600)).unwrap();
+    assert_eq!(
+        vec![
+            ConnectionEvent::Reset,
+            ConnectionEvent::Synced,
+            ConnectionEvent::ChipDetected(Chip::Esp32),
+            ConnectionEvent::StubLoaded,
+            ConnectionEvent::BaudChanged(921_600),
+        ],
+        *events.lock().unwrap()
+    );
+}
+
+#[test]
+fn test_spi_guard() {
+    use crate::testing::MockTransport;
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    let spi_registers = Chip::Esp32.spi_registers();
+    let miso_length = spi_registers.miso_length().unwrap();
+    simulator.set_register(spi_registers.usr(), 0x1234);
+    simulator.set_register(miso_length, 0x1f);
+
+    let mut guard = flasher.spi_guard().unwrap();
+    assert_eq!(0x164020, guard.command(0x9f, &[], 24).unwrap());
+    assert_ne!(0x1234, simulator.register(spi_registers.usr()));
+    assert_eq!(23, simulator.register(miso_length));
+    guard.restore().unwrap();
+    assert_eq!(0x1234, simulator.register(spi_registers.usr()));
+    assert_eq!(0x1f, simulator.register(miso_length));
+
+    // the registers are also restored when the command fails
+    let mut guard = flasher.spi_guard().unwrap();
+    simulator.fail(Command::ReadReg, 0x05);
+    assert!(guard.command(0x9f, &[], 24).is_err());
+    drop(guard);
+    assert_eq!(0x1234, simulator.register(spi_registers.usr()));
+}
+
+#[test]
+fn test_spi_command() {
+    use crate::testing::MockTransport;
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    simulator.set_flash(0x100, &[0x11, 0x22, 0x33, 0x44]);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    let spi_registers = Chip::Esp32.spi_registers();
+
+    // every data word goes into its own register, w0 is overwritten by the response
+    flasher
+        .spi_command(0x02, &[1, 2, 3, 4, 5, 6, 7, 8, 9], 0)
+        .unwrap();
+    assert_eq!(0x08070605, simulator.register(spi_registers.w0() + 4));
+    assert_eq!(0x09, simulator.register(spi_registers.w0() + 8));
+
+    // a whole word can be read
+    assert_eq!(
+        0x44332211,
+        flasher.spi_command(0x03, &[0x00, 0x01, 0x00], 32).unwrap()
+    );
+
+    // the data registers hold 64 bytes, and a word can be read
+    flasher.spi_command(0x02, &[0x5a; 64], 0).unwrap();
+    assert_eq!(0x5a5a5a5a, simulator.register(spi_registers.w0() + 60));
+    assert!(matches!(
+        flasher.spi_command(0x02, &[0; 65], 0),
+        Err(Error::InvalidSpiCommand {
+            data: 65,
+            read_bits: 0
+        })
+    ));
+    assert!(matches!(
+        flasher.spi_command(0x03, &[0x00, 0x01, 0x00], 33),
+        Err(Error::InvalidSpiCommand {
+            data: 3,
+            read_bits: 33
+        })
+    ));
+
+    // the command register reports when the command is done
+    simulator.pin_register(spi_registers.cmd(), 1 << 18);
+    assert!(matches!(
+        flasher.spi_command(0x9f, &[], 24),
+        Err(Error::Timeout)
+    ));
+}
+
+#[test]
+fn test_flasher_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<Flasher>();
+    assert_send::<crate::Session>();
+}
+
+#[test]
+fn test_factory_reset() {
+    use crate::testing::MockTransport;
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    let table = PartitionTable::from_csv(
+        "nvs, data, nvs, , 0x5000,\notadata, data, ota, , 0x2000,\nphy_init, data, phy, , 0x1000,\n\
+         factory, app, factory, , 1M,\nota_0, app, ota_0, , 1M,\n",
+        0x8000,
+    )
+    .unwrap();
+    flasher
+        .write_bin_to_flash(0x8000, &table.to_bytes())
+        .unwrap();
+    // nvs, otadata and phy_init
+    flasher.write_bin_to_flash(0x9000, &[0x5a; 0x8000]).unwrap();
+
+    let table = flasher.factory_reset().unwrap();
+    assert!(table.has_factory_app());
+    // the start and end of nvs, otadata and the untouched phy_init partition
+    for (addr, expected) in [
+        (0x9000, 0xff),
+        (0xdf00, 0xff),
+        (0xff00, 0xff),
+        (0x10000, 0x5a),
+    ] {
+        assert_eq!(
+            vec![expected; 0x100],
+            flasher.read_flash(addr, 0x100).unwrap()
+        );
+    }
+
+    // without a partition table there is nothing to reset
+    flasher.erase_region(0x8000, 0x1000).unwrap();
+    assert!(matches!(
+        flasher.factory_reset(),
+        Err(Error::InvalidPartitionTable(_))
+    ));
 }
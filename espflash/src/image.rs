@@ -0,0 +1,364 @@
+use std::convert::TryInto;
+
+use sha2::{Digest, Sha256};
+
+use crate::chip::Chip;
+use crate::elf::{FirmwareImage, RomSegment, ESP_CHECKSUM_MAGIC};
+use crate::flash_args::FlashFiles;
+use crate::image_settings::ImageSettings;
+use crate::{Error, FlashSize};
+
+pub(crate) const ESP_MAGIC: u8 = 0xe9;
+const HEADER_LENGTH: usize = 8;
+const EXTENDED_HEADER_LENGTH: usize = 16;
+const SEGMENT_HEADER_LENGTH: usize = 8;
+const MAX_SEGMENTS: u8 = 16;
+
+/// Information about a firmware image, in the format loaded by the bootloader
+#[derive(Clone, Debug)]
+pub struct ImageInfo {
+    pub entry: u32,
+    pub flash_mode: u8,
+    pub flash_config: u8,
+    pub segments: Vec<ImageSegment>,
+    pub checksum: u8,
+    /// The chip revisions the image runs on, from the extended header of the esp32
+    pub revisions: Option<ChipRevisions>,
+    /// The sha256 digest appended to the image, if any
+    pub digest: Option<[u8; 32]>,
+    /// Length of the image, up to and including the checksum and digest
+    pub length: usize,
+}
+
+/// A segment of a firmware image
+#[derive(Clone, Copy, Debug)]
+pub struct ImageSegment {
+    pub addr: u32,
+    pub length: u32,
+}
+
+/// The range of chip revisions an image runs on, revisions are `major * 100 + minor`
+///
+/// Bootloaders from esp-idf 5.0 on refuse to start images for revisions outside of the range.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChipRevisions {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl ChipRevisions {
+    /// The highest revision the image runs on, `None` if there is no limit
+    ///
+    /// Like in the esp-idf bootloader both 0 and `0xffff` mean unset, images made by older tools leave it at 0.
+    pub fn max_revision(&self) -> Option<u16> {
+        match self.max {
+            0 | 0xffff => None,
+            max => Some(max),
+        }
+    }
+}
+
+impl ImageInfo {
+    /// Parse and validate a firmware image
+    ///
+    /// This checks the header, the segment table, the checksum and the appended digest, if present.
+    pub fn parse(chip: Chip, data: &[u8]) -> Result<Self, Error> {
+        if data.len() < HEADER_LENGTH || data[0] != ESP_MAGIC {
+            return Err(invalid("missing image header"));
+        }
+        let segment_count = data[1];
+        if segment_count == 0 || segment_count > MAX_SEGMENTS {
+            return Err(invalid(format!("invalid segment count {}", segment_count)));
+        }
+        let entry = read_u32(data, 4)?;
+
+        let mut pos = HEADER_LENGTH;
+        let mut append_digest = false;
+        let mut revisions = None;
+        if chip == Chip::Esp32 {
+            let extended = data
+                .get(pos..pos + EXTENDED_HEADER_LENGTH)
+                .ok_or_else(|| invalid("truncated extended header"))?;
+            let chip_id = u16::from_le_bytes([extended[4], extended[5]]);
+            if Chip::from_chip_id(chip_id as u32) != Some(chip) {
+                return Err(invalid(format!("image is for chip id {}", chip_id)));
+            }
+            revisions = Some(ChipRevisions {
+                min: u16::from_le_bytes([extended[7], extended[8]]),
+                max: u16::from_le_bytes([extended[9], extended[10]]),
+            });
+            append_digest = extended[15] == 1;
+            pos += EXTENDED_HEADER_LENGTH;
+        }
+
+        let mut segments = Vec::with_capacity(segment_count as usize);
+        let mut checksum = ESP_CHECKSUM_MAGIC;
+        for i in 0..segment_count {
+            let addr = read_u32(data, pos)?;
+            let length = read_u32(data, pos + 4)?;
+            pos += SEGMENT_HEADER_LENGTH;
+
+            let segment_data = data.get(pos..pos + length as usize).ok_or_else(|| {
+                invalid(format!("segment {} extends past the end of the image", i))
+            })?;
+            checksum = segment_data.iter().fold(checksum, |sum, byte| sum ^ byte);
+            pos += length as usize;
+
+            segments.push(ImageSegment { addr, length });
+        }
+
+        // the checksum is stored in the last byte of a 16 byte aligned block
+        let checksum_pos = pos + 15 - pos % 16;
+        let stored_checksum = *data
+            .get(checksum_pos)
+            .ok_or_else(|| invalid("missing checksum"))?;
+        if stored_checksum != checksum {
+            return Err(invalid(format!(
+                "checksum mismatch, stored {:#04x} but calculated {:#04x}",
+                stored_checksum, checksum
+            )));
+        }
+
+        let mut length = checksum_pos + 1;
+        let digest = if append_digest {
+            let stored: [u8; 32] = data
+                .get(checksum_pos + 1..checksum_pos + 33)
+                .ok_or_else(|| invalid("missing sha256 digest"))?
+                .try_into()
+                .unwrap();
+            let calculated: [u8; 32] = Sha256::digest(&data[..checksum_pos + 1]).into();
+            if stored != calculated {
+                return Err(invalid("sha256 digest mismatch"));
+            }
+            length += 32;
+            Some(stored)
+        } else {
+            None
+        };
+
+        Ok(ImageInfo {
+            entry,
+            flash_mode: data[2],
+            flash_config: data[3],
+            segments,
+            checksum,
+            revisions,
+            digest,
+            length,
+        })
+    }
+
+    /// Parse and validate a firmware image for an unknown chip, returning the chip it's for
+    ///
+    /// esp32 images have an extended header with the chip id after the image header, esp8266 images start the
+    /// segment table there. Images that don't parse as an esp32 image are parsed as an esp8266 image.
+    pub fn detect(data: &[u8]) -> Result<(Chip, Self), Error> {
+        match ImageInfo::parse(Chip::Esp32, data) {
+            Ok(info) => Ok((Chip::Esp32, info)),
+            Err(err) => match ImageInfo::parse(Chip::Esp8266, data) {
+                Ok(info) => Ok((Chip::Esp8266, info)),
+                // report why the image is invalid for the chip its header claims
+                Err(_) if header_chip(data) == Some(Chip::Esp32) => Err(err),
+                Err(err) => Err(err),
+            },
+        }
+    }
+
+    /// Build the firmware images for an elf image and parse them, together with their flash offsets
+    pub fn from_elf(chip: Chip, elf_data: &[u8]) -> Result<Vec<(u32, Self)>, Error> {
+        let image = FirmwareImage::from_data(elf_data).map_err(|_| Error::InvalidElf)?;
+        let segments = chip
+            .get_flash_segments(&image)
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        image_segments(chip, &segments)
+            .map(|segment| Ok((segment.addr, ImageInfo::parse(chip, &segment.data)?)))
+            .collect()
+    }
+}
+
+/// Build the complete contents of a flash chip of `flash_size` with the elf image written to it
+///
+/// The unused parts of the flash are left erased, this can be used as the flash of an emulator.
+pub fn flash_image(chip: Chip, elf_data: &[u8], flash_size: FlashSize) -> Result<Vec<u8>, Error> {
+    let settings = ImageSettings {
+        flash_size: Some(flash_size),
+        ..ImageSettings::default()
+    };
+    let files = FlashFiles::from_elf(chip, elf_data, &settings)?.files;
+
+    let size = flash_size
+        .size_in_bytes()
+        .ok_or(Error::UnsupportedFlash(flash_size as u8))?;
+    let mut flash = vec![0xff; size as usize];
+    for file in files {
+        let start = file.addr as usize;
+        let target = flash
+            .get_mut(start..start + file.data.len())
+            .ok_or_else(|| invalid(format!("segment at {:#x} doesn't fit in the flash", start)))?;
+        target.copy_from_slice(&file.data);
+    }
+
+    Ok(flash)
+}
+
+/// Validate the firmware images among the segments that are about to be written to flash
+pub(crate) fn validate_flash_segments(chip: Chip, segments: &[RomSegment]) -> Result<(), Error> {
+    for segment in image_segments(chip, segments) {
+        ImageInfo::parse(chip, &segment.data)?;
+    }
+
+    Ok(())
+}
+
+/// The segments that contain a firmware image, as opposed to raw data like the partition table
+///
+/// The offsets of the esp32 images depend on the partition table, so they're recognized by their header instead.
+fn image_segments<'a>(
+    chip: Chip,
+    segments: &'a [RomSegment<'a>],
+) -> impl Iterator<Item = &'a RomSegment<'a>> {
+    segments.iter().filter(move |segment| match chip {
+        Chip::Esp8266 => segment.addr == 0,
+        Chip::Esp32 => segment.data.first() == Some(&ESP_MAGIC),
+    })
+}
+
+/// The crc32 of the esp roms, `crc` is the result of the previous calculation, or the initial value
+pub(crate) fn crc32_le(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, byte| {
+        (0..8).fold(crc ^ *byte as u32, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+/// The chip in the extended header of an esp32 image, `None` for images without one
+fn header_chip(data: &[u8]) -> Option<Chip> {
+    let id = data.get(HEADER_LENGTH + 4..HEADER_LENGTH + 6)?;
+    Chip::from_chip_id(u16::from_le_bytes([id[0], id[1]]) as u32)
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, Error> {
+    data.get(pos..pos + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| invalid("truncated segment header"))
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidImage(reason.into())
+}
+
+#[test]
+fn test_parse_esp32_image() {
+    use std::fs::read;
+
+    let data = read("./tests/data/esp32.bin").unwrap();
+    let info = ImageInfo::parse(Chip::Esp32, &data).unwrap();
+    assert_eq!(7, info.segments.len());
+    assert!(info.digest.is_some());
+    assert_eq!(data.len(), info.length);
+
+    let mut corrupted = data.clone();
+    corrupted[0x100] ^= 1;
+    assert!(ImageInfo::parse(Chip::Esp32, &corrupted).is_err());
+}
+
+#[test]
+fn test_flash_image() {
+    use std::fs::read;
+
+    let elf = read("./tests/data/esp32").unwrap();
+    let flash = flash_image(Chip::Esp32, &elf, FlashSize::Flash4Mb).unwrap();
+    assert_eq!(0x400000, flash.len());
+    let app = ImageInfo::parse(Chip::Esp32, &flash[0x10000..]).unwrap();
+    assert_eq!(
+        Some(ChipRevisions {
+            min: 0,
+            max: 0xffff
+        }),
+        app.revisions
+    );
+    assert!(flash_image(Chip::Esp32, &elf, FlashSize::Flash256Kb).is_err());
+    assert!(matches!(
+        flash_image(Chip::Esp32, &elf, FlashSize::FlashRetry),
+        Err(Error::UnsupportedFlash(0xff))
+    ));
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(0xcbf43926, crc32_le(0, b"123456789"));
+    assert_eq!(0xd202d277, crc32_le(0xffffffff, b"123456789"));
+}
+
+#[test]
+fn test_extended_header() {
+    use std::fs::read;
+
+    let elf = read("./tests/data/esp32").unwrap();
+    let mut image = FirmwareImage::from_data(&elf).unwrap();
+    image.min_chip_revision = 301;
+    let segments = Chip::Esp32
+        .get_flash_segments(&image)
+        .collect::<Result<Vec<_>, Error>>()
+        .unwrap();
+    let app = &segments[2].data;
+    // chip id, legacy major revision, min and max revision
+    assert_eq!(&[0, 0, 3, 0x2d, 0x01, 0xff, 0xff], &app[12..19]);
+    assert_eq!(1, app[23]);
+    let info = ImageInfo::parse(Chip::Esp32, app).unwrap();
+    assert_eq!(
+        Some(ChipRevisions {
+            min: 301,
+            max: 0xffff
+        }),
+        info.revisions
+    );
+
+    assert_eq!(None, info.revisions.unwrap().max_revision());
+    assert_eq!(None, ChipRevisions { min: 0, max: 0 }.max_revision());
+    assert_eq!(Some(399), ChipRevisions { min: 0, max: 399 }.max_revision());
+
+    let mut other_chip = app.to_vec();
+    other_chip[12] = 2;
+    assert!(ImageInfo::parse(Chip::Esp32, &other_chip).is_err());
+}
+
+#[test]
+fn test_build_images_reproducible() {
+    use std::fs::read;
+
+    // the same elf with its program headers in reverse order
+    fn reverse_program_headers(elf: &[u8]) -> Vec<u8> {
+        let offset = read_u32(elf, 0x1c).unwrap() as usize;
+        let size = u16::from_le_bytes([elf[0x2a], elf[0x2b]]) as usize;
+        let count = u16::from_le_bytes([elf[0x2c], elf[0x2d]]) as usize;
+        let mut reversed = elf.to_vec();
+        for (i, header) in elf[offset..offset + size * count]
+            .chunks(size)
+            .rev()
+            .enumerate()
+        {
+            reversed[offset + i * size..offset + (i + 1) * size].copy_from_slice(header);
+        }
+        assert_ne!(elf, reversed.as_slice());
+        reversed
+    }
+
+    for (chip, path) in [
+        (Chip::Esp32, "./tests/data/esp32"),
+        (Chip::Esp8266, "./tests/data/esp8266"),
+    ] {
+        let elf = read(path).unwrap();
+        let settings = ImageSettings::default();
+        let build = |elf: &[u8]| FlashFiles::from_elf(chip, elf, &settings).unwrap().files;
+        let files = build(&elf);
+        assert_eq!(files, build(&elf));
+        assert_eq!(files, build(&reverse_program_headers(&elf)));
+    }
+}
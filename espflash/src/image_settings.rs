@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::Path;
+
+use crate::chip::PartitionTable;
+use crate::elf::{FirmwareImage, FlashFrequency, FlashMode};
+use crate::{Error, FlashSize};
+
+const DEFAULT_PARTITION_TABLE_OFFSET: u32 = 0x8000;
+
+/// The builtin partition tables of esp-idf that can be selected in the sdkconfig
+const BUILTIN_PARTITION_TABLES: &[(&str, &str)] = &[
+    (
+        "partitions_singleapp.csv",
+        "nvs, data, nvs, , 0x6000,\nphy_init, data, phy, , 0x1000,\nfactory, app, factory, , 1M,\n",
+    ),
+    (
+        "partitions_two_ota.csv",
+        "nvs, data, nvs, , 0x4000,\notadata, data, ota, , 0x2000,\nphy_init, data, phy, , 0x1000,\n\
+         factory, app, factory, , 1M,\nota_0, app, ota_0, , 1M,\nota_1, app, ota_1, , 1M,\n",
+    ),
+];
+
+/// Settings for the firmware images built from elf images, settings that aren't set keep their defaults
+///
+/// The partition table and the offsets only apply to the esp32.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct ImageSettings {
+    pub flash_mode: Option<FlashMode>,
+    pub flash_frequency: Option<FlashFrequency>,
+    /// The flash size in the image header, the size of the connected flash when flashing otherwise
+    pub flash_size: Option<FlashSize>,
+    /// The partition table, a single app partition by default
+    pub partition_table: Option<PartitionTable>,
+    pub partition_table_offset: Option<u32>,
+    pub bootloader_offset: Option<u32>,
+    /// The bootloader, the bundled one when not set
+    ///
+    /// The bundled bootloader reads the partition table from 0x8000, other partition table or bootloader offsets
+    /// need the bootloader that was built with them.
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    pub bootloader: Option<Vec<u8>>,
+    /// The crystal frequency in MHz the firmware is built for, not part of the image but checked against the board
+    pub crystal_frequency: Option<u32>,
+}
+
+impl ImageSettings {
+    /// Read the settings from the `sdkconfig` of an esp-idf project
+    ///
+    /// A custom partition table is read relative to the directory of the sdkconfig, which is the project
+    /// directory for esp-idf builds.
+    pub fn from_sdkconfig(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let sdkconfig = read_to_string(path).map_err(|err| {
+            Error::InvalidSdkconfig(format!("failed to read \"{}\": {}", path.display(), err))
+        })?;
+        Self::parse_sdkconfig(&sdkconfig, path.parent().unwrap_or_else(|| Path::new(".")))
+    }
+
+    /// Parse the contents of an sdkconfig, custom partition tables are read relative to `project_dir`
+    pub fn parse_sdkconfig(sdkconfig: &str, project_dir: &Path) -> Result<Self, Error> {
+        let options: HashMap<&str, &str> = sdkconfig
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(name, value)| (name.trim(), value.trim().trim_matches('"')))
+            .collect();
+        let option = |name: &str| options.get(name).copied().filter(|value| !value.is_empty());
+        let offset = |name: &str| -> Result<Option<u32>, Error> {
+            option(name)
+                .map(|value| {
+                    let hex = value.strip_prefix("0x").unwrap_or(value);
+                    u32::from_str_radix(hex, 16).map_err(|_| invalid(name, value))
+                })
+                .transpose()
+        };
+
+        let flash_mode = option("CONFIG_ESPTOOLPY_FLASHMODE")
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| invalid("CONFIG_ESPTOOLPY_FLASHMODE", value))
+            })
+            .transpose()?;
+        let flash_frequency = option("CONFIG_ESPTOOLPY_FLASHFREQ")
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| invalid("CONFIG_ESPTOOLPY_FLASHFREQ", value))
+            })
+            .transpose()?;
+        let flash_size = option("CONFIG_ESPTOOLPY_FLASHSIZE")
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| invalid("CONFIG_ESPTOOLPY_FLASHSIZE", value))
+            })
+            .transpose()?;
+        // esp-idf calls it CONFIG_XTAL_FREQ on newer and CONFIG_ESP32_XTAL_FREQ on older versions, 0 is autodetect,
+        // the esp8266 rtos sdk only has a choice between the crystals
+        let crystal_frequency =
+            match option("CONFIG_XTAL_FREQ").or_else(|| option("CONFIG_ESP32_XTAL_FREQ")) {
+                Some(value) => match value.parse() {
+                    Ok(0) => None,
+                    Ok(frequency @ (26 | 40)) => Some(frequency),
+                    _ => return Err(invalid("CONFIG_XTAL_FREQ", value)),
+                },
+                None if option("CONFIG_CRYSTAL_USED_40MHZ") == Some("y") => Some(40),
+                None if option("CONFIG_CRYSTAL_USED_26MHZ") == Some("y") => Some(26),
+                None => None,
+            };
+        let partition_table_offset = offset("CONFIG_PARTITION_TABLE_OFFSET")?;
+        let bootloader_offset = offset("CONFIG_BOOTLOADER_OFFSET_IN_FLASH")?;
+
+        let table_offset = partition_table_offset.unwrap_or(DEFAULT_PARTITION_TABLE_OFFSET);
+        let partition_table = if option("CONFIG_PARTITION_TABLE_CUSTOM") == Some("y") {
+            let name = option("CONFIG_PARTITION_TABLE_CUSTOM_FILENAME")
+                .ok_or_else(|| invalid("CONFIG_PARTITION_TABLE_CUSTOM_FILENAME", ""))?;
+            let path = project_dir.join(name);
+            let csv = read_to_string(&path).map_err(|err| {
+                Error::InvalidSdkconfig(format!(
+                    "failed to read the partition table \"{}\": {}",
+                    path.display(),
+                    err
+                ))
+            })?;
+            Some(PartitionTable::from_csv(&csv, table_offset)?)
+        } else if let Some(name) = option("CONFIG_PARTITION_TABLE_FILENAME") {
+            let (_, csv) = BUILTIN_PARTITION_TABLES
+                .iter()
+                .find(|(builtin, _)| *builtin == name)
+                .ok_or_else(|| {
+                    Error::InvalidSdkconfig(format!(
+                        "unsupported builtin partition table \"{}\", use a custom partition table",
+                        name
+                    ))
+                })?;
+            Some(PartitionTable::from_csv(csv, table_offset)?)
+        } else {
+            None
+        };
+
+        Ok(ImageSettings {
+            flash_mode,
+            flash_frequency,
+            flash_size,
+            partition_table,
+            partition_table_offset,
+            bootloader_offset,
+            bootloader: None,
+            crystal_frequency,
+        })
+    }
+
+    pub(crate) fn apply(&self, image: &mut FirmwareImage) {
+        if let Some(flash_mode) = self.flash_mode {
+            image.flash_mode = flash_mode;
+        }
+        if let Some(flash_frequency) = self.flash_frequency {
+            image.flash_frequency = flash_frequency;
+        }
+        if let Some(flash_size) = self.flash_size {
+            image.flash_size = flash_size;
+        }
+        image.partition_table = self.partition_table.clone();
+        image.partition_table_offset = self.partition_table_offset;
+        image.bootloader_offset = self.bootloader_offset;
+        image.bootloader = self.bootloader.clone();
+    }
+}
+
+fn invalid(name: &str, value: &str) -> Error {
+    Error::InvalidSdkconfig(format!("unsupported value \"{}\" for {}", value, name))
+}
+
+#[test]
+fn test_sdkconfig() {
+    use crate::chip::Chip;
+    use std::fs::read;
+
+    let settings = ImageSettings::from_sdkconfig("./tests/data/sdkconfig").unwrap();
+    assert_eq!(Some(FlashSize::Flash8Mb), settings.flash_size);
+    assert_eq!(Some(0x9000), settings.partition_table_offset);
+    assert_eq!(Some(0x1000), settings.bootloader_offset);
+    assert_eq!(Some(40), settings.crystal_frequency);
+    assert_eq!(
+        Some(0x20000),
+        settings.partition_table.as_ref().unwrap().app_offset()
+    );
+
+    let elf = read("./tests/data/esp32").unwrap();
+    let mut image = FirmwareImage::from_data(&elf).unwrap();
+    settings.apply(&mut image);
+    // the bundled bootloader can't find the partition table at 0x9000
+    assert!(matches!(
+        Chip::Esp32
+            .get_flash_segments(&image)
+            .collect::<Result<Vec<_>, Error>>(),
+        Err(Error::InvalidImage(_))
+    ));
+
+    let mut settings = settings;
+    settings.bootloader = Some(vec![0xe9; 0x20]);
+    settings.apply(&mut image);
+    let segments = Chip::Esp32
+        .get_flash_segments(&image)
+        .collect::<Result<Vec<_>, Error>>()
+        .unwrap();
+    let addrs: Vec<u32> = segments.iter().map(|segment| segment.addr).collect();
+    assert_eq!(vec![0x1000, 0x9000, 0x20000], addrs);
+    assert_eq!(&[0xe9; 0x20][..], &segments[0].data[..]);
+    // qio, 80MHz and 8MB in the image header
+    assert_eq!(&[0, 0x3f], &segments[2].data[2..4]);
+
+    let settings =
+        ImageSettings::parse_sdkconfig("CONFIG_PARTITION_TABLE_SINGLE_APP=y\nCONFIG_PARTITION_TABLE_FILENAME=\"partitions_singleapp.csv\"\n", Path::new(".")).unwrap();
+    assert_eq!(
+        Some(0x10000),
+        settings.partition_table.as_ref().unwrap().app_offset()
+    );
+    assert!(
+        ImageSettings::parse_sdkconfig("CONFIG_ESPTOOLPY_FLASHSIZE=\"3MB\"\n", Path::new("."))
+            .is_err()
+    );
+
+    let crystal = |sdkconfig: &str| {
+        ImageSettings::parse_sdkconfig(sdkconfig, Path::new("."))
+            .map(|settings| settings.crystal_frequency)
+    };
+    assert_eq!(Some(26), crystal("CONFIG_ESP32_XTAL_FREQ=26\n").unwrap());
+    assert_eq!(None, crystal("CONFIG_XTAL_FREQ=0\n").unwrap());
+    assert_eq!(Some(26), crystal("CONFIG_CRYSTAL_USED_26MHZ=y\n").unwrap());
+    assert!(crystal("CONFIG_XTAL_FREQ=24\n").is_err());
+}
+
+#[cfg(feature = "serialize")]
+#[test]
+fn test_serialize_settings() {
+    let settings = ImageSettings::from_sdkconfig("./tests/data/sdkconfig").unwrap();
+    // going through a value puts the partition table after the other settings, as toml requires
+    let serialized = toml::to_string(&toml::Value::try_from(&settings).unwrap()).unwrap();
+    assert!(serialized.contains("flash_mode = \"qio\""));
+    assert!(serialized.contains("flash_frequency = \"80m\""));
+    assert!(serialized.contains("flash_size = \"8MB\""));
+
+    let deserialized: ImageSettings = toml::from_str(&serialized).unwrap();
+    assert_eq!(Some(FlashSize::Flash8Mb), deserialized.flash_size);
+    assert_eq!(Some(0x9000), deserialized.partition_table_offset);
+    assert_eq!(
+        settings.partition_table.unwrap().to_bytes(),
+        deserialized.partition_table.unwrap().to_bytes()
+    );
+}
@@ -0,0 +1,78 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+
+/// The contents of an input file like an elf image
+///
+/// With the `mmap` feature the file is memory mapped instead of read, so only the parts that are used, like the
+/// loadable segments, are loaded from disk. Debug builds can be many megabytes of which only a small part ends
+/// up on the flash. Changes to the contents stay in memory and don't change the file.
+pub struct InputFile {
+    data: Data,
+}
+
+enum Data {
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::MmapMut),
+    Read(Vec<u8>),
+}
+
+impl InputFile {
+    /// Open a file, when mapped the file shouldn't be changed until the `InputFile` is dropped
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let length = file.metadata()?.len();
+
+        // empty files can't be mapped
+        #[cfg(feature = "mmap")]
+        if length > 0 {
+            // SAFETY: the mapping is private, the file being changed by another process while it's mapped can
+            // change or truncate the contents, which is documented on `open`
+            let map = unsafe { memmap2::MmapOptions::new().map_copy(&file)? };
+            return Ok(InputFile {
+                data: Data::Mapped(map),
+            });
+        }
+
+        let mut data = Vec::with_capacity(length as usize);
+        file.read_to_end(&mut data)?;
+        Ok(InputFile {
+            data: Data::Read(data),
+        })
+    }
+}
+
+impl Deref for InputFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.data {
+            #[cfg(feature = "mmap")]
+            Data::Mapped(map) => map,
+            Data::Read(data) => data,
+        }
+    }
+}
+
+impl DerefMut for InputFile {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match &mut self.data {
+            #[cfg(feature = "mmap")]
+            Data::Mapped(map) => map,
+            Data::Read(data) => data,
+        }
+    }
+}
+
+#[test]
+fn test_input_file() {
+    let mut elf = InputFile::open("./tests/data/esp32").unwrap();
+    assert_eq!(std::fs::read("./tests/data/esp32").unwrap(), &elf[..]);
+
+    // changes don't end up in the file
+    elf[0] = 0;
+    assert_eq!(0x7f, std::fs::read("./tests/data/esp32").unwrap()[0]);
+
+    assert!(InputFile::open("./tests/data/missing").is_err());
+}
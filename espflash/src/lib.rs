@@ -1,12 +1,60 @@
+mod app_desc;
+mod arduino;
+mod capture;
 mod chip;
+pub mod cli;
 mod config;
 mod connection;
+mod efuse;
 mod elf;
 mod encoder;
 mod error;
+mod factory_image;
+#[cfg(feature = "cdylib")]
+pub mod ffi;
+mod flash_args;
 mod flasher;
+mod image;
+mod image_settings;
+mod input;
+mod monitor;
+mod nvs;
+#[cfg(feature = "python")]
+mod python;
+mod session;
+mod signature;
+mod stats;
+mod stub;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
-pub use chip::Chip;
+pub use app_desc::{set_secure_version, AppDescriptor};
+pub use arduino::ArduinoBuild;
+pub use capture::{WireCapture, DIRECTION_FROM_CHIP, DIRECTION_TO_CHIP};
+pub use chip::{Chip, ChipFeature, FlashVoltage, PartitionTable, Workarounds};
 pub use config::Config;
-pub use error::Error;
-pub use flasher::Flasher;
+pub use connection::{hard_reset, CommandTiming};
+pub use efuse::{
+    DownloadLockdown, EfuseBlocks, EfuseField, EfuseValue, InvalidKeyPurpose, KeyPurpose,
+};
+pub use elf::{FlashFrequency, FlashMode, InvalidFlashFrequency, InvalidFlashMode};
+pub use error::{DeviceOutput, Error, RomError, RomErrorKind};
+pub use factory_image::FactoryImage;
+pub use flash_args::{FlashFiles, ImageFile};
+pub use flasher::{
+    BoardInfo, Command, ConnectOptions, ConnectionEvent, FlashSize, Flasher, InvalidFlashSize,
+    ProgressCallbacks, Psram, PsramStatus, ResetAfterFlash, ResetBeforeConnect, SpiAttachParams,
+    SpiGuard, FLASH_SECTOR_SIZE,
+};
+pub use image::{flash_image, ChipRevisions, ImageInfo, ImageSegment};
+pub use image_settings::ImageSettings;
+pub use input::InputFile;
+pub use monitor::{
+    BacktraceFrame, Decoder, EspIdfDecoder, LogLevel, LogRecord, Monitor, MonitorBuilder,
+    MonitorEvent,
+};
+pub use nvs::NvsKeys;
+pub use session::Session;
+pub use signature::PublicKey;
+pub use stats::{CommandStats, FlashStats};
+pub use stub::Stub;
@@ -1,69 +1,1091 @@
-use std::fs::read;
+use std::convert::TryInto;
+use std::fs::{create_dir_all, read, read_to_string, write, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use color_eyre::{eyre::WrapErr, Result};
-use espflash::{Config, Flasher};
+use color_eyre::{
+    eyre::{bail, eyre, WrapErr},
+    Result,
+};
+use espflash::cli::{
+    self, backup, capture_port, configure_connection, confirm, confirm_boot_region, dump_mem,
+    efuse_summary, exit_with, gdb_server, image_settings, open_serial, ota_flash, parse_flash_size,
+    parse_mac, parse_spi_clock_divider, parse_spi_connection, parse_u32, print_board_info,
+    print_flash_stats, read_mem, repl, restore, serve, spi_connection, wait_for_serial,
+    warn_anti_rollback, warn_crystal_mismatch, write_mem, AdapterQuirks, CliConfig, DebugConfig,
+    ExitCode, MachineProgress, Monitor, MonitorOptions, Plan, Watcher,
+};
+use espflash::{
+    flash_image, hard_reset, set_secure_version, AppDescriptor, ArduinoBuild, Chip, ConnectOptions,
+    DownloadLockdown, Error, FactoryImage, FlashFiles, FlashSize, Flasher, ImageFile, ImageInfo,
+    InputFile, KeyPurpose, NvsKeys, PublicKey, ResetAfterFlash, ResetBeforeConnect, Session,
+    FLASH_SECTOR_SIZE,
+};
 use pico_args::Arguments;
-use serial::{BaudRate, SerialPort};
+use serial::SystemPort;
+
+const USAGE: &str = "\
+Usage: espflash [OPTIONS] [--board-info] [--ram] [--no-run] [--resume] [--monitor] [--watch] [--secure-version N] <serial> <elf image>
+       espflash [OPTIONS] [--no-run] [--resume] [--monitor] <serial> <arduino build directory>
+       espflash [OPTIONS] [--no-run] [--resume] [--monitor] <serial> <factory image>
+       espflash [OPTIONS] board-info [--probe-psram] [--debug-config {openocd,probe-rs}] <serial>
+       espflash [OPTIONS] run [--monitor] <serial>
+       espflash [OPTIONS] write-bin [-y|--force] [--no-run] [--resume] [--public-key FILE] <serial> <offset> <bin file>
+       espflash [OPTIONS] verify [--offset OFFSET] <serial> <image>
+       espflash image-info --chip {esp32,esp8266} [--public-key FILE] <image>
+       espflash qemu-image [--flash-size SIZE] <elf image> <output file>
+       espflash save-image --chip {esp32,esp8266} [--flash-size SIZE] [--flash-args] [--web-manifest] <elf image> <output directory>
+       espflash nvs-keys [--keys FILE] <output file>
+       espflash [OPTIONS] diff [--offset OFFSET] <serial> <image>
+       espflash [OPTIONS] checksum <serial> <offset> <size>
+       espflash [OPTIONS] backup <serial> <file>
+       espflash [OPTIONS] restore [-y|--force] <serial> <file>
+       espflash [OPTIONS] erase-flash [-y|--force] <serial>
+       espflash [OPTIONS] factory-reset [-y|--force] [--no-run] <serial>
+       espflash [OPTIONS] plan [-y|--force] <serial> <plan file>
+       espflash [OPTIONS] read-mem <serial> <address>
+       espflash [OPTIONS] write-mem <serial> <address> <value> [mask]
+       espflash [OPTIONS] dump-mem <serial> <address> <size> <file>
+       espflash [OPTIONS] repl <serial>
+       espflash [OPTIONS] gdb [--listen ADDRESS] <serial>
+       espflash [OPTIONS] efuse summary <serial>
+       espflash [OPTIONS] efuse burn-custom-mac [-y|--force] <serial> <mac>
+       espflash [OPTIONS] efuse burn-key [-y|--force] [--no-protect] <serial> <purpose> <key file>
+       espflash [OPTIONS] efuse lock-download [-y|--force] [--secure] --i-know-this-is-irreversible <serial>
+       espflash daemon [--listen ADDRESS] [--token TOKEN]
+       espflash ota [--chip {esp32,esp8266}] [--port PORT] [--auth PASSWORD] <host> <image>
+       espflash udev-rule
+
+Options:
+    --wait SECONDS    Wait for the serial port to appear
+    --timings         Print how long the bootloader commands took
+    --retries N       Send a block that failed to write again up to N times, 3 by default
+    --flow-control    Use rts/cts flow control, only together with --no-reset, see the readme
+    --pacing-chunk-size N
+                      Pause after every N bytes written, for serial adapters that drop data at high rates
+    --pacing-delay-us US
+                      The pause after every chunk in microseconds, 1000 by default
+    --capture FILE    Record the serial communication to a pcap file
+    --stub-path FILE  Load a flasher stub in the esptool json format after connecting
+    --no-reset        The chip is already in the bootloader, don't reset it when connecting
+    --no-adapter-quirks
+                      Don't apply the settings for known usb serial adapters, like pacing CH340 writes
+    --flash-size SIZE
+                      The size of the flash, for the images and for flash chips that report an unknown size
+    --spi-connection {SPI,HSPI,CLK,Q,D,HD,CS}
+                      Attach the flash on these pins, like an external flash, instead of detecting them
+    --spi-clock-divider N
+                      Divide the 80MHz spi flash clock by N, for boards where the flash corrupts data
+    --sdkconfig FILE  Read the flash settings and partition table from the sdkconfig of an esp-idf project
+    --bootloader FILE Write this bootloader instead of the bundled one, like the one built with the sdkconfig
+    --machine-progress TARGET
+                      Report the progress as json lines to stdout, stderr or a file, instead of a progress bar
+
+Monitor options:
+    --mirror {ADDRESS,pty}   Mirror the output to a tcp socket or a pseudo-terminal
+    --output {text,raw,hex}  Print the output as text (default), unchanged or as a timestamped hexdump
+    --strip-ansi             Remove ansi escape sequences, like colors, from the text output
+    --no-reconnect           Exit when the connection is lost, instead of waiting for the port to come back
+    --gdb-listen ADDRESS     Forward gdb connections on the address to the gdb stub of panicked firmware
+    --core-dump-dir DIR      Save core dumps in the directory instead of the current directory
+    --exit-on PATTERN        Exit when a line of output matches the regular expression
+    --fail-on PATTERN        Exit with an error when a line of output matches the regular expression
+    --highlight PATTERN      Highlight lines of output that match the regular expression";
 
 #[allow(clippy::unnecessary_wraps)]
 fn help() -> Result<()> {
-    println!("Usage: espflash [--board-info] [--ram] <serial> <elf image>");
+    println!("{}", USAGE);
     Ok(())
 }
 
-fn main() -> Result<()> {
+fn usage_error() -> ! {
+    eprintln!("{}", USAGE);
+    ExitCode::Usage.exit()
+}
+
+fn main() {
+    exit_with(run())
+}
+
+fn run() -> Result<()> {
+    cli::install()?;
+
     let mut args = Arguments::from_env();
-    let config = Config::load();
+    let mut config = CliConfig::load();
 
     if args.contains(["-h", "--help"]) {
         return help();
     }
 
+    if let Some(wait) = args.opt_value_from_str("--wait")? {
+        config.connection.wait = Some(wait);
+    }
+    if args.contains("--timings") {
+        config.connection.timings = true;
+    }
+    if let Some(retries) = args.opt_value_from_str("--retries")? {
+        config.connection.retries = Some(retries);
+    }
+    if args.contains("--flow-control") {
+        config.connection.flow_control = true;
+    }
+    if let Some(chunk_size) = args.opt_value_from_str("--pacing-chunk-size")? {
+        config.connection.pacing_chunk_size = Some(chunk_size);
+    }
+    if let Some(delay) = args.opt_value_from_str("--pacing-delay-us")? {
+        config.connection.pacing_delay_us = Some(delay);
+    }
+    config.capture = args.opt_value_from_str("--capture")?;
+    config.progress = args.opt_value_from_str("--machine-progress")?;
+    if let Some(stub) = args.opt_value_from_str("--stub-path")? {
+        config.connection.stub = Some(stub);
+    }
+    if args.contains("--no-reset") {
+        config.connection.no_reset = true;
+    }
+    if args.contains("--no-adapter-quirks") {
+        config.connection.no_adapter_quirks = true;
+    }
+    if let Some(sdkconfig) = args.opt_value_from_str("--sdkconfig")? {
+        config.build.sdkconfig = Some(sdkconfig);
+    }
+    if let Some(bootloader) = args.opt_value_from_str("--bootloader")? {
+        config.build.bootloader = Some(bootloader);
+    }
+    if let Some(flash_size) = args.opt_value_from_str::<_, String>("--flash-size")? {
+        parse_flash_size(&flash_size)?;
+        config.build.flash_size = Some(flash_size);
+    }
+    if let Some(spi_connection) = args.opt_value_from_str::<_, String>("--spi-connection")? {
+        parse_spi_connection(&spi_connection)?;
+        config.connection.spi_connection = Some(spi_connection);
+    }
+    if let Some(divider) = args.opt_value_from_fn("--spi-clock-divider", parse_spi_clock_divider)? {
+        config.connection.spi_clock_divider = Some(divider);
+    }
+
+    let subcommand = args.subcommand()?;
+    match subcommand.as_deref() {
+        Some("board-info") => board_info(args, config),
+        Some("run") => run_app(args, config),
+        Some("write-bin") => write_bin(args, config),
+        Some("verify") => verify(args, config),
+        Some("diff") => diff(args, config),
+        Some("checksum") => checksum(args, config),
+        Some("image-info") => image_info(args),
+        Some("qemu-image") => qemu_image(args, config),
+        Some("save-image") => save_image(args, config),
+        Some("nvs-keys") => nvs_keys(args),
+        Some("backup") => run_backup(args, config),
+        Some("restore") => run_restore(args, config),
+        Some("erase-flash") => erase_flash(args, config),
+        Some("factory-reset") => factory_reset(args, config),
+        Some("plan") => run_plan(args, config),
+        Some("read-mem") => run_read_mem(args, config),
+        Some("write-mem") => run_write_mem(args, config),
+        Some("dump-mem") => run_dump_mem(args, config),
+        Some("repl") => run_repl(args, config),
+        Some("gdb") => gdb(args, config),
+        Some("efuse") => efuse(args, config),
+        Some("daemon") => daemon(args),
+        Some("ota") => ota(args),
+        #[cfg(target_os = "linux")]
+        Some("udev-rule") => {
+            print!("{}", cli::UDEV_RULE);
+            Ok(())
+        }
+        _ => flash(subcommand, args, config),
+    }
+}
+
+fn open(serial: &str, config: &CliConfig) -> Result<SystemPort> {
+    match config.connection.wait {
+        Some(wait) => wait_for_serial(serial, Duration::from_secs(wait)),
+        None => open_serial(serial),
+    }
+}
+
+fn connect(serial: &str, config: &CliConfig) -> Result<Flasher> {
+    let progress = config
+        .progress
+        .as_deref()
+        .map(|target| MachineProgress::open(target, serial))
+        .transpose()?;
+    if let Some(progress) = &progress {
+        progress.connecting();
+    }
+    let port = open(serial, config)?;
+    let reset = reset_before_connect(config);
+    let spi = spi_connection(&config.connection)?;
+    let settings = image_settings(&config.build)?;
+    let flash_size = cli::flash_size(&config.build)?;
+    let events = progress.clone();
+    let options = ConnectOptions::new()
+        .reset(reset)
+        .spi_connection(spi)
+        .flash_size(flash_size)
+        .event_hook(move |event| {
+            if let Some(progress) = &events {
+                progress.event(event);
+            }
+        });
+    let mut flasher = match &config.capture {
+        Some(path) => Flasher::connect_with_options(capture_port(port, path)?, options)?,
+        None => Flasher::connect_with_options(port, options)?,
+    };
+    if let Some(progress) = progress {
+        progress.connected(flasher.chip());
+        flasher.set_progress_callbacks(progress);
+    }
+    configure_connection(&mut flasher, &config.connection)?;
+    if let Some(quirks) = AdapterQuirks::for_port(serial, &config.connection) {
+        quirks.apply(&mut flasher, serial, &config.connection);
+    }
+    flasher.set_image_settings(settings);
+    Ok(flasher)
+}
+
+fn reset_before_connect(config: &CliConfig) -> ResetBeforeConnect {
+    if config.connection.no_reset {
+        ResetBeforeConnect::NoReset
+    } else {
+        ResetBeforeConnect::DefaultReset
+    }
+}
+
+/// Split the remaining arguments into the serial port and `count` positional arguments
+///
+/// The serial port can be omitted when it's set in the config file.
+fn positional_args(args: Arguments, config: &CliConfig, count: usize) -> (String, Vec<String>) {
+    let mut free: Vec<String> = args
+        .finish()
+        .into_iter()
+        .map(|arg| arg.into_string().unwrap_or_else(|_| usage_error()))
+        .collect();
+
+    let serial = if free.len() == count + 1 {
+        free.remove(0)
+    } else {
+        match &config.connection.serial {
+            Some(serial) if free.len() == count => serial.clone(),
+            _ => usage_error(),
+        }
+    };
+
+    (serial, free)
+}
+
+fn flash(first_arg: Option<String>, mut args: Arguments, config: CliConfig) -> Result<()> {
     let ram = args.contains("--ram");
     let board_info = args.contains("--board-info");
+    let no_run = args.contains("--no-run");
+    let resume = args.contains("--resume");
+    let attach_monitor = args.contains("--monitor");
+    let mut monitor_options = MonitorOptions::from_args(&mut args)?;
+    monitor_options.hooks.extend(config.monitor.hooks.clone());
+    let watch = args.contains("--watch");
+    let secure_version = args
+        .opt_value_from_fn("--secure-version", parse_u32)
+        .wrap_err("Invalid secure version")?;
 
-    let mut serial: Option<String> = args.opt_free_from_str()?;
+    let mut serial: Option<String> = match first_arg {
+        Some(arg) => Some(arg),
+        None => args.opt_free_from_str()?,
+    };
     let mut elf: Option<String> = args.opt_free_from_str()?;
 
     if elf.is_none() && config.connection.serial.is_some() {
         elf = serial.take();
-        serial = config.connection.serial;
+        serial = config.connection.serial.clone();
     }
 
     let serial: String = match serial {
         Some(serial) => serial,
-        _ => return help(),
+        _ => usage_error(),
     };
 
-    let mut serial =
-        serial::open(&serial).wrap_err_with(|| format!("Failed to open serial port {}", serial))?;
-    serial.reconfigure(&|settings| {
-        settings.set_baud_rate(BaudRate::Baud115200)?;
-
-        Ok(())
-    })?;
-
-    let mut flasher = Flasher::connect(serial, None)?;
-
     if board_info {
-        println!("Chip type: {:?}", flasher.chip());
-        println!("Flash size: {:?}", flasher.flash_size());
+        let mut flasher = connect(&serial, &config)?;
+        print_board_info(&flasher.board_info()?);
 
         return Ok(());
     }
 
     let input: String = match elf {
         Some(input) => input,
-        _ => return help(),
+        _ => usage_error(),
+    };
+
+    let mut monitor = if attach_monitor {
+        Some(Monitor::new(&monitor_options)?)
+    } else {
+        None
+    };
+    if Path::new(&input).is_dir() {
+        if ram || watch || secure_version.is_some() {
+            bail!("--ram, --watch and --secure-version need an elf image, not an arduino build");
+        }
+        return flash_arduino(&serial, &input, no_run, resume, monitor, &config);
+    }
+    if !is_elf(&input)? {
+        if ram || watch || secure_version.is_some() {
+            bail!("--ram, --watch and --secure-version need an elf image, not a factory image");
+        }
+        return flash_factory_image(&serial, &input, no_run, resume, monitor, &config);
+    }
+    let mut watcher = Watcher::new(vec![input.clone().into()]);
+    loop {
+        let mut input_bytes = InputFile::open(&input)
+            .wrap_err_with(|| format!("Failed to open elf image \"{}\"", input))?;
+        if let Some(secure_version) = secure_version {
+            set_secure_version(&mut input_bytes, secure_version)?;
+        }
+
+        let mut flasher = connect(&serial, &config)?;
+        warn_crystal_mismatch(&mut flasher)?;
+        if let Some(monitor) = &mut monitor {
+            monitor.set_boot_baud(flasher.boot_log_baud()?);
+        }
+        if ram {
+            flasher.load_elf_to_ram(&input_bytes)?;
+        } else {
+            // the bootloader only checks the secure version of apps booted from flash
+            warn_anti_rollback(&mut flasher, AppDescriptor::from_elf(&input_bytes).as_ref())?;
+            if no_run {
+                flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+            }
+            flasher.set_resume(resume);
+            let stats = flasher.load_elf_to_flash(&input_bytes)?;
+            print_flash_stats(&config.connection, &stats);
+        }
+        drop(flasher);
+        if let Some(monitor) = &mut monitor {
+            monitor.load_elf(&input_bytes);
+        }
+        // the image is rewritten when it changes
+        drop(input_bytes);
+
+        match (watch, &mut monitor) {
+            (false, None) => return Ok(()),
+            (false, Some(monitor)) => return monitor.run(open(&serial, &config)?, &serial),
+            (true, None) => watcher.wait(),
+            (true, Some(monitor)) => {
+                monitor.run_until(open(&serial, &config)?, &serial, || watcher.changed())?
+            }
+        }
+
+        eprintln!("{} changed, flashing again", input);
+    }
+}
+
+fn is_elf(path: &str) -> Result<bool> {
+    let mut magic = [0; 4];
+    let mut file =
+        File::open(path).wrap_err_with(|| format!("Failed to open image \"{}\"", path))?;
+    Ok(file.read_exact(&mut magic).is_ok() && &magic == b"\x7fELF")
+}
+
+/// Flash the binaries of an arduino build directory at the offsets the arduino core uses
+fn flash_arduino(
+    serial: &str,
+    dir: &str,
+    no_run: bool,
+    resume: bool,
+    monitor: Option<Monitor>,
+    config: &CliConfig,
+) -> Result<()> {
+    let build = ArduinoBuild::open(dir)?;
+
+    flash_bins(serial, no_run, resume, monitor, config, |flasher| {
+        if flasher.chip() != build.chip {
+            return Err(Error::ChipMismatch {
+                expected: build.chip,
+                detected: flasher.chip(),
+            }
+            .into());
+        }
+        // the app is always the last file
+        let app = build.files.last().map(|file| file.data.as_slice());
+        let app = app.and_then(|app| AppDescriptor::from_image(build.chip, app));
+        warn_anti_rollback(flasher, app.as_ref())?;
+        Ok((build.files, build.elf))
+    })
+}
+
+/// Flash a factory image, like the ones published by ESPHome, which covers the flash from offset 0
+fn flash_factory_image(
+    serial: &str,
+    path: &str,
+    no_run: bool,
+    resume: bool,
+    monitor: Option<Monitor>,
+    config: &CliConfig,
+) -> Result<()> {
+    let data = read(path).wrap_err_with(|| format!("Failed to open image \"{}\"", path))?;
+
+    flash_bins(serial, no_run, resume, monitor, config, |flasher| {
+        let image = factory_image(flasher, &data)?;
+        let app = AppDescriptor::from_image(flasher.chip(), &data[image.app_offset as usize..]);
+        warn_anti_rollback(flasher, app.as_ref())?;
+        let file = ImageFile {
+            addr: 0,
+            name: path.to_string(),
+            role: None,
+            data,
+        };
+        Ok((vec![file], None))
+    })
+}
+
+/// Detect the layout of a factory image for the connected chip and check that it fits in the flash
+fn factory_image(flasher: &mut Flasher, data: &[u8]) -> Result<FactoryImage> {
+    let image = FactoryImage::detect(flasher.chip(), data)?;
+    if let Some(table) = &image.partition_table {
+        eprintln!("Partition table of the image:\n{}", table.to_csv());
+    }
+    let flash_size = flasher.flash_size_in_bytes()?;
+    if data.len() > flash_size as usize {
+        bail!(
+            "the image is {:#x} bytes, but the flash of the device is only {:#x} bytes",
+            data.len(),
+            flash_size
+        );
+    }
+    Ok(image)
+}
+
+/// Write binaries built outside of espflash, `prepare` checks them against the connected chip
+fn flash_bins(
+    serial: &str,
+    no_run: bool,
+    resume: bool,
+    mut monitor: Option<Monitor>,
+    config: &CliConfig,
+    prepare: impl FnOnce(&mut Flasher) -> Result<(Vec<ImageFile>, Option<PathBuf>)>,
+) -> Result<()> {
+    let mut flasher = connect(serial, config)?;
+    warn_crystal_mismatch(&mut flasher)?;
+    if let Some(monitor) = &mut monitor {
+        monitor.set_boot_baud(flasher.boot_log_baud()?);
+    }
+    let (files, elf) = prepare(&mut flasher)?;
+
+    if no_run {
+        flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    }
+    flasher.set_resume(resume);
+    for file in &files {
+        eprintln!("Writing {} at {:#x}", file.name, file.addr);
+    }
+    let stats = flasher.write_files_to_flash(&files)?;
+    print_flash_stats(&config.connection, &stats);
+    drop(flasher);
+
+    if let Some(mut monitor) = monitor {
+        if let Some(elf) = &elf {
+            monitor.load_elf(&InputFile::open(elf)?);
+        }
+        return monitor.run(open(serial, config)?, serial);
+    }
+    Ok(())
+}
+
+fn board_info(mut args: Arguments, config: CliConfig) -> Result<()> {
+    let debug_config: Option<DebugConfig> = args.opt_value_from_str("--debug-config")?;
+    let probe_psram = args.contains("--probe-psram");
+    let (serial, _) = positional_args(args, &config, 0);
+
+    let mut flasher = connect(&serial, &config)?;
+    flasher.set_probe_psram(probe_psram);
+    let info = flasher.board_info()?;
+    match debug_config {
+        // only the configuration is printed, so the output can be redirected to the configuration file
+        Some(debug_config) => print!("{}", debug_config.generate(&info, &serial)?),
+        None => print_board_info(&info),
+    }
+
+    Ok(())
+}
+
+fn run_app(mut args: Arguments, config: CliConfig) -> Result<()> {
+    let attach_monitor = args.contains("--monitor");
+    let mut monitor_options = MonitorOptions::from_args(&mut args)?;
+    monitor_options.hooks.extend(config.monitor.hooks.clone());
+    let (serial, _) = positional_args(args, &config, 0);
+
+    let mut port = open(&serial, &config)?;
+    hard_reset(&mut port)?;
+
+    if attach_monitor {
+        Monitor::new(&monitor_options)?.run(port, &serial)?;
+    }
+
+    Ok(())
+}
+
+fn write_bin(mut args: Arguments, config: CliConfig) -> Result<()> {
+    let force = args.contains(["-y", "--force"]);
+    let no_run = args.contains("--no-run");
+    let resume = args.contains("--resume");
+    let public_key = args
+        .opt_value_from_str::<_, String>("--public-key")?
+        .map(|path| read_public_key(&path))
+        .transpose()?;
+    let (serial, free) = positional_args(args, &config, 2);
+    let offset = parse_u32(&free[0]).wrap_err("Invalid offset")?;
+    let bin = &free[1];
+
+    let data = read(bin).wrap_err_with(|| format!("Failed to open binary \"{}\"", bin))?;
+
+    let mut flasher = connect(&serial, &config)?;
+    warn_crystal_mismatch(&mut flasher)?;
+    if let Some(public_key) = public_key {
+        public_key.verify(flasher.chip(), &data)?;
+    }
+    let app = AppDescriptor::from_image(flasher.chip(), &data);
+    warn_anti_rollback(&mut flasher, app.as_ref())?;
+
+    confirm_boot_region(flasher.chip(), offset, data.len() as u64, force)?;
+
+    if no_run {
+        flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    }
+    flasher.set_resume(resume);
+    let stats = flasher.write_bin_to_flash(offset, &data)?;
+    print_flash_stats(&config.connection, &stats);
+
+    Ok(())
+}
+
+fn verify(mut args: Arguments, config: CliConfig) -> Result<()> {
+    let offset: Option<String> = args.opt_value_from_str("--offset")?;
+    let (serial, free) = positional_args(args, &config, 1);
+    let image = &free[0];
+
+    let data = read(image).wrap_err_with(|| format!("Failed to open image \"{}\"", image))?;
+
+    let mut flasher = connect(&serial, &config)?;
+    if data.starts_with(b"\x7fELF") {
+        flasher.verify_elf(&data)?;
+    } else {
+        let offset = match offset {
+            Some(offset) => parse_u32(&offset).wrap_err("Invalid offset")?,
+            None => {
+                factory_image(&mut flasher, &data).wrap_err(
+                    "Pass --offset to verify a binary image that isn't a factory image",
+                )?;
+                0
+            }
+        };
+        flasher.verify_bin(offset, &data)?;
+    }
+
+    println!("Flash contents match {}", image);
+    Ok(())
+}
+
+fn image_info(mut args: Arguments) -> Result<()> {
+    let chip: Chip = args.value_from_str("--chip")?;
+    let public_key = args
+        .opt_value_from_str::<_, String>("--public-key")?
+        .map(|path| read_public_key(&path))
+        .transpose()?;
+    let image: String = args.free_from_str()?;
+    if !args.finish().is_empty() {
+        usage_error();
+    }
+
+    let data =
+        InputFile::open(&image).wrap_err_with(|| format!("Failed to open image \"{}\"", image))?;
+    if public_key.is_some() && data.starts_with(b"\x7fELF") {
+        bail!("Only binary images can be signed, pass the signed image instead of the elf image");
+    }
+    let images = if data.starts_with(b"\x7fELF") {
+        ImageInfo::from_elf(chip, &data)?
+    } else {
+        vec![(0, ImageInfo::parse(chip, &data)?)]
     };
-    let input_bytes =
-        read(&input).wrap_err_with(|| format!("Failed to open elf image \"{}\"", input))?;
 
-    if ram {
-        flasher.load_elf_to_ram(&input_bytes)?;
+    for (offset, info) in images {
+        if data.starts_with(b"\x7fELF") {
+            println!("Image at {:#x}", offset);
+        }
+        println!("Entry point: {:#010x}", info.entry);
+        println!(
+            "Flash mode: {:#04x}, flash config: {:#04x}",
+            info.flash_mode, info.flash_config
+        );
+        if let Some(revisions) = info.revisions {
+            match (revisions.min, revisions.max_revision()) {
+                (0, None) => println!("Chip revisions: any"),
+                (min, None) => println!("Chip revisions: {} and later", min),
+                (min, Some(max)) => println!("Chip revisions: {} to {}", min, max),
+            }
+        }
+        println!("{} segments", info.segments.len());
+        for (i, segment) in info.segments.iter().enumerate() {
+            println!(
+                "    {}: {:#010x} ({} bytes)",
+                i, segment.addr, segment.length
+            );
+        }
+        println!("Checksum: {:#04x} (valid)", info.checksum);
+        if let Some(digest) = info.digest {
+            let digest: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+            println!("Sha256 digest: {} (valid)", digest);
+        }
+        if let Some(public_key) = &public_key {
+            public_key.verify(chip, &data)?;
+            println!("Secure boot v2 signature: valid");
+        }
+        let app = if data.starts_with(b"\x7fELF") {
+            AppDescriptor::from_elf(&data)
+        } else {
+            AppDescriptor::from_image(chip, &data)
+        };
+        if let Some(app) = app {
+            println!(
+                "App: {} {}, esp-idf {}, built {} {}",
+                app.project_name, app.version, app.idf_version, app.date, app.time
+            );
+            println!("Secure version: {}", app.secure_version);
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+fn read_public_key(path: &str) -> Result<PublicKey> {
+    let pem =
+        read_to_string(path).wrap_err_with(|| format!("Failed to open public key \"{}\"", path))?;
+    Ok(PublicKey::from_pem(&pem)?)
+}
+
+fn diff(mut args: Arguments, config: CliConfig) -> Result<()> {
+    let offset: Option<String> = args.opt_value_from_str("--offset")?;
+    let (serial, free) = positional_args(args, &config, 1);
+    let image = &free[0];
+
+    let data = read(image).wrap_err_with(|| format!("Failed to open image \"{}\"", image))?;
+
+    let mut flasher = connect(&serial, &config)?;
+    let regions = if data.starts_with(b"\x7fELF") {
+        flasher.flash_segments(&data)?
     } else {
-        flasher.load_elf_to_flash(&input_bytes)?;
+        let offset = match offset {
+            Some(offset) => parse_u32(&offset).wrap_err("Invalid offset")?,
+            None => bail!("Pass --offset to compare a binary image"),
+        };
+        vec![(offset, data)]
+    };
+
+    let mut total = 0;
+    let mut differing = Vec::new();
+    for (offset, data) in regions {
+        total += (offset as usize % FLASH_SECTOR_SIZE + data.len()).div_ceil(FLASH_SECTOR_SIZE);
+        differing.extend(flasher.diff_sectors(offset, &data)?);
+    }
+
+    // merge adjacent sectors into ranges
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for sector in &differing {
+        match ranges.last_mut() {
+            Some((_, end)) if *end == *sector => *end += FLASH_SECTOR_SIZE as u32,
+            _ => ranges.push((*sector, *sector + FLASH_SECTOR_SIZE as u32)),
+        }
+    }
+    for (start, end) in &ranges {
+        println!(
+            "{:#08x}..{:#08x} differs ({} sectors)",
+            start,
+            end,
+            (end - start) as usize / FLASH_SECTOR_SIZE
+        );
+    }
+    println!("{} of {} sectors differ", differing.len(), total);
+
+    if !differing.is_empty() {
+        ExitCode::Verification.exit();
+    }
+    Ok(())
+}
+
+fn checksum(args: Arguments, config: CliConfig) -> Result<()> {
+    let (serial, free) = positional_args(args, &config, 2);
+    let offset = parse_u32(&free[0]).wrap_err("Invalid offset")?;
+    let size = parse_u32(&free[1]).wrap_err("Invalid size")?;
+
+    let mut flasher = connect(&serial, &config)?;
+    let md5 = match flasher.flash_md5(offset, size) {
+        Err(Error::UnsupportedOperation { .. }) => {
+            eprintln!("The bootloader can't hash the flash, reading it back instead");
+            md5::compute(flasher.read_flash(offset, size)?).0
+        }
+        result => result?,
+    };
+
+    let md5: String = md5.iter().map(|byte| format!("{:02x}", byte)).collect();
+    println!("{}  {:#x}..{:#x}", md5, offset, offset as u64 + size as u64);
+    Ok(())
+}
+
+fn qemu_image(mut args: Arguments, config: CliConfig) -> Result<()> {
+    // `--flash-size` is taken from the options into the config
+    let flash_size = match &config.build.flash_size {
+        Some(flash_size) => parse_flash_size(flash_size)?,
+        None => FlashSize::Flash4Mb,
+    };
+    let elf: String = args.free_from_str()?;
+    let output: String = args.free_from_str()?;
+    if !args.finish().is_empty() {
+        usage_error();
+    }
+
+    // the espressif qemu fork only emulates the esp32, with these flash sizes
+    if !matches!(
+        flash_size,
+        FlashSize::Flash2Mb | FlashSize::Flash4Mb | FlashSize::Flash8Mb | FlashSize::Flash16Mb
+    ) {
+        bail!("qemu only supports flash sizes of 2MB, 4MB, 8MB and 16MB");
+    }
+
+    let data =
+        InputFile::open(&elf).wrap_err_with(|| format!("Failed to open elf image \"{}\"", elf))?;
+    let image = flash_image(Chip::Esp32, &data, flash_size)?;
+    write(&output, image).wrap_err_with(|| format!("Failed to write \"{}\"", output))?;
+
+    println!(
+        "Run it with: qemu-system-xtensa -nographic -machine esp32 -drive file={},if=mtd,format=raw",
+        output
+    );
+    Ok(())
+}
+
+fn save_image(mut args: Arguments, config: CliConfig) -> Result<()> {
+    let chip: Chip = args.value_from_str("--chip")?;
+    // `--flash-size` is taken from the options into the config
+    let settings = image_settings(&config.build)?;
+    let flash_args = args.contains("--flash-args");
+    let web_manifest = args.contains("--web-manifest");
+    let elf: String = args.free_from_str()?;
+    let output: PathBuf = args.free_from_str()?;
+    if !args.finish().is_empty() {
+        usage_error();
+    }
+
+    let data =
+        InputFile::open(&elf).wrap_err_with(|| format!("Failed to open elf image \"{}\"", elf))?;
+    let files = FlashFiles::from_elf(chip, &data, &settings)?;
+
+    create_dir_all(&output)
+        .wrap_err_with(|| format!("Failed to create \"{}\"", output.display()))?;
+    let mut outputs: Vec<(String, Vec<u8>)> = files
+        .files
+        .iter()
+        .map(|file| (file.name.clone(), file.data.clone()))
+        .collect();
+    if flash_args {
+        outputs.push(("flash_args".into(), files.flash_args().into_bytes()));
+        outputs.push((
+            "flasher_args.json".into(),
+            files.flasher_args_json().into_bytes(),
+        ));
+    }
+    if web_manifest {
+        // esp-web-tools shows the name and version of the firmware before installing it
+        let (name, version) = match AppDescriptor::from_elf(&data) {
+            Some(app) => (app.project_name, app.version),
+            None => (
+                Path::new(&elf)
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| elf.clone()),
+                String::from("unknown"),
+            ),
+        };
+        outputs.push(("factory.bin".into(), files.merged()));
+        outputs.push((
+            "manifest.json".into(),
+            files
+                .web_manifest(&name, &version, "factory.bin")
+                .into_bytes(),
+        ));
+    }
+    for (name, data) in outputs {
+        let path = output.join(name);
+        write(&path, data).wrap_err_with(|| format!("Failed to write \"{}\"", path.display()))?;
+    }
+
+    for file in &files.files {
+        println!("{:#07x} {}", file.addr, file.name);
+    }
+    Ok(())
+}
+
+fn nvs_keys(mut args: Arguments) -> Result<()> {
+    let keys: Option<String> = args.opt_value_from_str("--keys")?;
+    let output: String = args.free_from_str()?;
+    if !args.finish().is_empty() {
+        usage_error();
+    }
+
+    let keys = match keys {
+        Some(path) => {
+            let data = read(&path).wrap_err_with(|| format!("Failed to open \"{}\"", path))?;
+            NvsKeys::from_bytes(&data).ok_or_else(|| {
+                eyre!(
+                    "\"{}\" doesn't contain nvs keys, expected the 64 byte keys or a valid nvs_keys partition",
+                    path
+                )
+            })?
+        }
+        None => NvsKeys::generate()?,
+    };
+    write(&output, keys.to_partition())
+        .wrap_err_with(|| format!("Failed to write \"{}\"", output))?;
+    Ok(())
+}
+
+fn run_backup(args: Arguments, config: CliConfig) -> Result<()> {
+    let (serial, free) = positional_args(args, &config, 1);
+
+    let mut flasher = connect(&serial, &config)?;
+    backup(&mut flasher, Path::new(&free[0]))
+}
+
+fn run_restore(mut args: Arguments, config: CliConfig) -> Result<()> {
+    let force = args.contains(["-y", "--force"]);
+    let (serial, free) = positional_args(args, &config, 1);
+
+    let mut flasher = connect(&serial, &config)?;
+    restore(&mut flasher, Path::new(&free[0]), force)
+}
+
+fn erase_flash(mut args: Arguments, config: CliConfig) -> Result<()> {
+    let force = args.contains(["-y", "--force"]);
+    let (serial, _) = positional_args(args, &config, 0);
+
+    confirm("erase the entire flash", force)?;
+
+    let mut flasher = connect(&serial, &config)?;
+    flasher.erase_flash()?;
+
+    Ok(())
+}
+
+fn factory_reset(mut args: Arguments, config: CliConfig) -> Result<()> {
+    let force = args.contains(["-y", "--force"]);
+    let no_run = args.contains("--no-run");
+    let (serial, _) = positional_args(args, &config, 0);
+
+    confirm("erase the nvs and otadata partitions", force)?;
+
+    let mut flasher = connect(&serial, &config)?;
+    let table = flasher.factory_reset()?;
+    for (offset, size) in table.nvs() {
+        println!("Erased nvs at {:#x} ({:#x} bytes)", offset, size);
+    }
+    if let Some((offset, size)) = table.ota_data() {
+        println!("Erased otadata at {:#x} ({:#x} bytes)", offset, size);
+    }
+    if !table.has_factory_app() {
+        eprintln!(
+            "Warning: the partition table has no factory app, the bootloader will boot the first ota app"
+        );
+    }
+    if !no_run {
+        flasher.hard_reset()?;
     }
 
     Ok(())
 }
+
+fn run_read_mem(args: Arguments, config: CliConfig) -> Result<()> {
+    let (serial, free) = positional_args(args, &config, 1);
+    let addr = parse_u32(&free[0]).wrap_err("Invalid address")?;
+
+    let mut flasher = connect(&serial, &config)?;
+    read_mem(&mut flasher, addr)
+}
+
+fn run_dump_mem(args: Arguments, config: CliConfig) -> Result<()> {
+    let (serial, free) = positional_args(args, &config, 3);
+    let addr = parse_u32(&free[0]).wrap_err("Invalid address")?;
+    let size = parse_u32(&free[1]).wrap_err("Invalid size")?;
+
+    let mut flasher = connect(&serial, &config)?;
+    dump_mem(&mut flasher, addr, size, &free[2])
+}
+
+fn run_write_mem(args: Arguments, config: CliConfig) -> Result<()> {
+    let mut free: Vec<String> = args
+        .finish()
+        .into_iter()
+        .map(|arg| arg.into_string().unwrap_or_else(|_| usage_error()))
+        .collect();
+
+    // the mask is optional, a leading argument that isn't a number is the serial port
+    let serial = match (free.len(), &config.connection.serial) {
+        (4, _) => free.remove(0),
+        (3, _) if parse_u32(&free[0]).is_err() => free.remove(0),
+        (2, Some(serial)) | (3, Some(serial)) => serial.clone(),
+        _ => usage_error(),
+    };
+    let addr = parse_u32(&free[0]).wrap_err("Invalid address")?;
+    let value = parse_u32(&free[1]).wrap_err("Invalid value")?;
+    let mask = free
+        .get(2)
+        .map(|mask| parse_u32(mask))
+        .transpose()
+        .wrap_err("Invalid mask")?;
+
+    let mut flasher = connect(&serial, &config)?;
+    write_mem(&mut flasher, addr, value, mask)
+}
+
+fn run_repl(args: Arguments, config: CliConfig) -> Result<()> {
+    let (serial, _) = positional_args(args, &config, 0);
+
+    let mut flasher = connect(&serial, &config)?;
+    repl(&mut flasher)
+}
+
+fn gdb(mut args: Arguments, config: CliConfig) -> Result<()> {
+    let listen: Option<String> = args.opt_value_from_str("--listen")?;
+    let (serial, _) = positional_args(args, &config, 0);
+
+    let mut flasher = connect(&serial, &config)?;
+    gdb_server(&mut flasher, listen.as_deref().unwrap_or("127.0.0.1:3333"))
+}
+
+fn efuse(mut args: Arguments, config: CliConfig) -> Result<()> {
+    match args.subcommand()?.as_deref() {
+        Some("summary") => {
+            let (serial, _) = positional_args(args, &config, 0);
+
+            let mut flasher = connect(&serial, &config)?;
+            efuse_summary(&mut flasher)
+        }
+        Some("burn-custom-mac") => {
+            let force = args.contains(["-y", "--force"]);
+            let (serial, free) = positional_args(args, &config, 1);
+            let mac = parse_mac(&free[0])?;
+
+            confirm(
+                &format!("permanently program the custom MAC address {}", free[0]),
+                force,
+            )?;
+
+            let mut flasher = connect(&serial, &config)?;
+            flasher.burn_custom_mac(mac)?;
+            Ok(())
+        }
+        Some("burn-key") => {
+            let force = args.contains(["-y", "--force"]);
+            let protect = !args.contains("--no-protect");
+            let (serial, free) = positional_args(args, &config, 2);
+            let purpose: KeyPurpose = free[0].parse()?;
+            let key: [u8; 32] = read(&free[1])
+                .wrap_err_with(|| format!("Failed to read key file {}", free[1]))?
+                .try_into()
+                .map_err(|_| eyre!("The key file must contain exactly 32 bytes"))?;
+
+            confirm(&format!("permanently program the {} key", free[0]), force)?;
+
+            let mut flasher = connect(&serial, &config)?;
+            flasher.burn_key(purpose, &key, protect)?;
+            Ok(())
+        }
+        Some("lock-download") => {
+            let force = args.contains(["-y", "--force"]);
+            let irreversible = args.contains("--i-know-this-is-irreversible");
+            let lockdown = if args.contains("--secure") {
+                DownloadLockdown::Secure
+            } else {
+                DownloadLockdown::Disable
+            };
+            let (serial, _) = positional_args(args, &config, 0);
+            if !irreversible {
+                bail!("Locking the download mode can't be undone, pass --i-know-this-is-irreversible to continue");
+            }
+
+            let (action, consequence) = match lockdown {
+                DownloadLockdown::Disable => (
+                    "disable the download mode",
+                    "make it impossible to recover the device over the serial port if the firmware stops working",
+                ),
+                DownloadLockdown::Secure => (
+                    "restrict the download mode to writing the flash",
+                    "make it impossible to read the flash, registers and efuses over the serial port",
+                ),
+            };
+            confirm(&format!("permanently {} on {}", action, serial), force)?;
+            confirm(consequence, force)?;
+
+            let mut flasher = connect(&serial, &config)?;
+            flasher.lock_download_mode(lockdown)?;
+            Ok(())
+        }
+        _ => usage_error(),
+    }
+}
+
+fn daemon(mut args: Arguments) -> Result<()> {
+    let listen: Option<String> = args.opt_value_from_str("--listen")?;
+    let token: Option<String> = args.opt_value_from_str("--token")?;
+    if !args.finish().is_empty() {
+        usage_error();
+    }
+
+    serve(listen.as_deref().unwrap_or("127.0.0.1:7878"), token)
+}
+
+fn ota(mut args: Arguments) -> Result<()> {
+    let chip: Option<Chip> = args.opt_value_from_str("--chip")?;
+    let port: Option<u16> = args.opt_value_from_str("--port")?;
+    let password: Option<String> = args.opt_value_from_str("--auth")?;
+    let host: String = args.free_from_str()?;
+    let image: String = args.free_from_str()?;
+    if !args.finish().is_empty() {
+        usage_error();
+    }
+
+    let data = read(&image).wrap_err_with(|| format!("Failed to open image \"{}\"", image))?;
+    let data = if data.starts_with(b"\x7fELF") {
+        match chip {
+            Some(chip) => chip.app_image(&data)?,
+            None => bail!("Pass --chip to send an elf image"),
+        }
+    } else {
+        data
+    };
+
+    // the default ports used by the arduino ota updater
+    let port = port.unwrap_or(match chip {
+        Some(Chip::Esp32) => 3232,
+        _ => 8266,
+    });
+
+    let name = Path::new(&image)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    ota_flash(&host, port, password.as_deref(), &name, &data)
+}
+
+fn run_plan(mut args: Arguments, config: CliConfig) -> Result<()> {
+    let force = args.contains(["-y", "--force"]);
+    let (serial, free) = positional_args(args, &config, 1);
+
+    let plan = Plan::load(Path::new(&free[0]))?;
+
+    let mut session = Session::new(connect(&serial, &config)?);
+    if plan.writes_to(session.chip().boot_region()) {
+        confirm("overwrite the bootloader region of the flash", force)?;
+    }
+
+    plan.execute(&mut session)
+}
@@ -0,0 +1,299 @@
+use std::borrow::Cow;
+use std::io::ErrorKind;
+use std::mem::take;
+
+use serial::SerialPort;
+
+use crate::elf::Symbols;
+use crate::error::Error;
+
+/// Something the device printed, recognized by the monitor
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MonitorEvent {
+    /// A complete line of output, without the line ending
+    Line(String),
+    /// A line printed by a logging framework
+    Log(LogRecord),
+    /// The firmware crashed, with the reason it printed
+    Panic(String),
+    /// A frame of a backtrace printed after a crash
+    BacktraceFrame(BacktraceFrame),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Verbose,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    /// Milliseconds since the device started
+    pub timestamp: Option<u64>,
+    /// The component that logged the message
+    pub tag: String,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BacktraceFrame {
+    pub pc: u32,
+    /// The function containing the pc, with the offset of the pc into it, when the elf image is known
+    pub function: Option<(String, u32)>,
+}
+
+/// Recognizes events in the lines of output
+///
+/// A `Line` event is emitted for every line regardless of the decoder.
+pub trait Decoder {
+    fn decode(&mut self, line: &str) -> Vec<MonitorEvent>;
+}
+
+/// Decodes the output of esp-idf firmware: log lines, panics and backtraces
+#[derive(Default)]
+pub struct EspIdfDecoder;
+
+impl Decoder for EspIdfDecoder {
+    fn decode(&mut self, line: &str) -> Vec<MonitorEvent> {
+        let line = plain(line);
+        let line = line.trim();
+
+        if let Some(frames) = line.strip_prefix("Backtrace:") {
+            // pairs of `<pc>:<sp>`
+            return frames
+                .split_whitespace()
+                .filter_map(|frame| frame.split(':').next()?.strip_prefix("0x"))
+                .filter_map(|pc| u32::from_str_radix(pc, 16).ok())
+                .map(|pc| MonitorEvent::BacktraceFrame(BacktraceFrame { pc, function: None }))
+                .collect();
+        }
+        if line.starts_with("Guru Meditation Error:")
+            || line.starts_with("abort() was called")
+            || line.contains("panicked at")
+        {
+            return vec![MonitorEvent::Panic(line.to_string())];
+        }
+        parse_log(line).map(MonitorEvent::Log).into_iter().collect()
+    }
+}
+
+/// Parse a log line in the esp-idf format, `I (1234) tag: message`
+fn parse_log(line: &str) -> Option<LogRecord> {
+    let mut chars = line.chars();
+    let level = match chars.next()? {
+        'E' => LogLevel::Error,
+        'W' => LogLevel::Warn,
+        'I' => LogLevel::Info,
+        'D' => LogLevel::Debug,
+        'V' => LogLevel::Verbose,
+        _ => return None,
+    };
+    let rest = chars.as_str().strip_prefix(" (")?;
+    let (timestamp, rest) = rest.split_once(") ")?;
+    let (tag, message) = rest.split_once(": ")?;
+    Some(LogRecord {
+        level,
+        // the timestamp can also be the wall clock time
+        timestamp: timestamp.parse().ok(),
+        tag: tag.to_string(),
+        message: message.to_string(),
+    })
+}
+
+/// Remove ansi escape sequences, so colored output can be matched
+pub(crate) fn plain(line: &str) -> Cow<'_, str> {
+    if !line.contains('\x1b') {
+        return Cow::Borrowed(line);
+    }
+    let mut plain = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            plain.push(c);
+        } else if chars.next() == Some('[') {
+            // skip the parameters of the control sequence, up to and including the final byte
+            for c in chars.by_ref() {
+                if ('\x40'..='\x7e').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    Cow::Owned(plain)
+}
+
+/// Collects the output of the device into lines, for everything in the monitor that works on whole lines
+#[derive(Default)]
+pub(crate) struct LineBuffer {
+    line: Vec<u8>,
+}
+
+impl LineBuffer {
+    /// Add output, returns the lines completed by it, with their line endings
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut lines = Vec::new();
+        for &byte in data {
+            self.line.push(byte);
+            if byte == b'\n' {
+                lines.push(take(&mut self.line));
+            }
+        }
+        lines
+    }
+
+    /// Take the incomplete line received so far
+    pub fn take(&mut self) -> Vec<u8> {
+        take(&mut self.line)
+    }
+}
+
+/// Turns the output of the device into events, for tools that embed the serial monitor
+///
+/// ```no_run
+/// # fn main() -> Result<(), espflash::Error> {
+/// # let elf = Vec::new();
+/// # let mut serial = serial::open("/dev/ttyUSB0")?;
+/// let mut monitor = espflash::Monitor::builder().elf(&elf).build();
+/// loop {
+///     for event in monitor.read(&mut serial)? {
+///         println!("{:?}", event);
+///     }
+/// }
+/// # }
+/// ```
+pub struct Monitor {
+    decoder: Box<dyn Decoder + Send>,
+    symbols: Symbols,
+    lines: LineBuffer,
+}
+
+#[derive(Default)]
+pub struct MonitorBuilder {
+    decoder: Option<Box<dyn Decoder + Send>>,
+    symbols: Symbols,
+}
+
+impl MonitorBuilder {
+    /// Use the symbols of the elf image running on the device, to find the functions of backtrace frames
+    pub fn elf(mut self, data: &[u8]) -> Self {
+        self.symbols = Symbols::from_elf(data);
+        self
+    }
+
+    /// Use a different decoder than the esp-idf one
+    pub fn decoder(mut self, decoder: impl Decoder + Send + 'static) -> Self {
+        self.decoder = Some(Box::new(decoder));
+        self
+    }
+
+    pub fn build(self) -> Monitor {
+        Monitor {
+            decoder: self.decoder.unwrap_or_else(|| Box::new(EspIdfDecoder)),
+            symbols: self.symbols,
+            lines: LineBuffer::default(),
+        }
+    }
+}
+
+impl Monitor {
+    pub fn builder() -> MonitorBuilder {
+        MonitorBuilder::default()
+    }
+
+    /// Use the symbols of a newly flashed elf image
+    pub fn load_elf(&mut self, data: &[u8]) {
+        self.symbols = Symbols::from_elf(data);
+    }
+
+    pub(crate) fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    /// Process output of the device, returning the events for the lines completed by it
+    pub fn feed(&mut self, data: &[u8]) -> Vec<MonitorEvent> {
+        let mut events = Vec::new();
+        for line in self.lines.feed(data) {
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(&['\r', '\n'][..]).to_string();
+
+            let decoded = self.decoder.decode(&line);
+            events.push(MonitorEvent::Line(line));
+            events.extend(decoded.into_iter().map(|event| {
+                match event {
+                    MonitorEvent::BacktraceFrame(frame) => {
+                        MonitorEvent::BacktraceFrame(BacktraceFrame {
+                            function: self
+                                .symbols
+                                .lookup(frame.pc)
+                                .map(|(name, offset)| (name.to_string(), offset)),
+                            ..frame
+                        })
+                    }
+                    event => event,
+                }
+            }));
+        }
+        events
+    }
+
+    /// Read the available output from the serial port, returning the events for the lines completed by it
+    ///
+    /// No events are returned when nothing arrived before the timeout of the port.
+    pub fn read(&mut self, serial: &mut impl SerialPort) -> Result<Vec<MonitorEvent>, Error> {
+        let mut buff = [0; 1024];
+        match serial.read(&mut buff) {
+            Ok(count) => Ok(self.feed(&buff[0..count])),
+            Err(e) if e.kind() == ErrorKind::TimedOut => Ok(Vec::new()),
+            Err(e) => Err(Error::Serial(e.into())),
+        }
+    }
+}
+
+#[test]
+fn test_monitor_events() {
+    let mut monitor = Monitor::builder().build();
+    let events = monitor.feed(b"\x1b[0;32mI (312) wifi: connected\x1b[0m\r\nGuru Meditation");
+    assert_eq!(
+        vec![
+            MonitorEvent::Line(String::from("\x1b[0;32mI (312) wifi: connected\x1b[0m")),
+            MonitorEvent::Log(LogRecord {
+                level: LogLevel::Info,
+                timestamp: Some(312),
+                tag: String::from("wifi"),
+                message: String::from("connected"),
+            }),
+        ],
+        events
+    );
+
+    let events = monitor.feed(b" Error: Core  0 panic'ed (LoadProhibited)\nBacktrace: 0x400d1b2a:0x3ffb5f80 0x400d1c3e:0x3ffb5fa0\n");
+    assert_eq!(
+        MonitorEvent::Panic(String::from(
+            "Guru Meditation Error: Core  0 panic'ed (LoadProhibited)"
+        )),
+        events[1]
+    );
+    assert_eq!(
+        MonitorEvent::BacktraceFrame(BacktraceFrame {
+            pc: 0x400d1c3e,
+            function: None,
+        }),
+        events[4]
+    );
+}
+
+#[test]
+fn test_line_buffer() {
+    let mut lines = LineBuffer::default();
+    assert!(lines.feed(b"I (31").is_empty());
+    assert_eq!(
+        vec![b"I (312) wifi\r\n".to_vec(), b"\n".to_vec()],
+        lines.feed(b"2) wifi\r\n\nGuru")
+    );
+    assert_eq!(b"Guru".to_vec(), lines.take());
+    assert!(lines.take().is_empty());
+}
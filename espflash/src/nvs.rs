@@ -0,0 +1,78 @@
+use crate::error::Error;
+use crate::image::crc32_le;
+
+/// Size of the `nvs_keys` partition content, one flash sector
+const NVS_KEYS_SIZE: usize = 0x1000;
+
+/// The keys esp-idf uses to encrypt the nvs partitions, stored in the `nvs_keys` partition
+///
+/// The nvs entries are encrypted with AES-XTS, which uses two 256 bit keys.
+#[derive(Clone, Eq, PartialEq)]
+pub struct NvsKeys {
+    pub encryption: [u8; 32],
+    pub tweak: [u8; 32],
+}
+
+impl NvsKeys {
+    /// Generate a new pair of random keys
+    pub fn generate() -> Result<Self, Error> {
+        let mut keys = NvsKeys {
+            encryption: [0; 32],
+            tweak: [0; 32],
+        };
+        getrandom::getrandom(&mut keys.encryption).map_err(std::io::Error::from)?;
+        getrandom::getrandom(&mut keys.tweak).map_err(std::io::Error::from)?;
+        Ok(keys)
+    }
+
+    /// Use the keys from a key file, either the 64 byte encryption and tweak keys or the partition content
+    ///
+    /// The partition content is the key file format of `nvs_partition_gen.py`, returns `None` when its crc is invalid.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        match data.len() {
+            64 => {}
+            NVS_KEYS_SIZE if data[64..68] == crc32_le(0xffffffff, &data[..64]).to_le_bytes() => {}
+            _ => return None,
+        }
+        let mut keys = NvsKeys {
+            encryption: [0; 32],
+            tweak: [0; 32],
+        };
+        keys.encryption.copy_from_slice(&data[..32]);
+        keys.tweak.copy_from_slice(&data[32..64]);
+        Some(keys)
+    }
+
+    /// The content of the `nvs_keys` partition: both keys followed by their crc32, padded with 0xff
+    ///
+    /// The partition has to be flash encrypted, esp-idf refuses to read the keys otherwise.
+    pub fn to_partition(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(NVS_KEYS_SIZE);
+        data.extend_from_slice(&self.encryption);
+        data.extend_from_slice(&self.tweak);
+        // esp-idf starts the crc from 0xffffffff, unlike the usual crc32
+        let crc = crc32_le(0xffffffff, &data);
+        data.extend_from_slice(&crc.to_le_bytes());
+        data.resize(NVS_KEYS_SIZE, 0xff);
+        data
+    }
+}
+
+#[test]
+fn test_nvs_keys_partition() {
+    let mut data = [0x11; 64];
+    data[32..].fill(0x22);
+    let keys = NvsKeys::from_bytes(&data).unwrap();
+    let partition = keys.to_partition();
+    assert_eq!(NVS_KEYS_SIZE, partition.len());
+    assert_eq!(&data[..], &partition[..64]);
+    assert_eq!(
+        &crc32_le(0xffffffff, &data).to_le_bytes(),
+        &partition[64..68]
+    );
+    assert_eq!(0xff, partition[68]);
+
+    assert!(keys == NvsKeys::from_bytes(&partition).unwrap());
+    assert!(NvsKeys::from_bytes(&[0; 32]).is_none());
+    assert!(NvsKeys::from_bytes(&[0; NVS_KEYS_SIZE]).is_none());
+}
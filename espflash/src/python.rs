@@ -0,0 +1,257 @@
+//! The `espflash` python module, for provisioning scripts that want to flash without running esptool
+//!
+//! Errors are raised as `espflash.EspflashError`. The gil is released while talking to the chip, so other python
+//! threads keep running while flashing.
+
+use std::sync::Mutex;
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use serial::BaudRate;
+
+use crate::connection::open_port;
+use crate::{Chip, Error, FlashFiles, Flasher, ImageSettings, ProgressCallbacks};
+
+create_exception!(espflash, EspflashError, PyException);
+
+fn py_err(error: impl ToString) -> PyErr {
+    EspflashError::new_err(error.to_string())
+}
+
+fn chip_name(chip: Chip) -> String {
+    format!("{:?}", chip).to_lowercase()
+}
+
+fn parse_chip(chip: &str) -> PyResult<Chip> {
+    chip.parse().map_err(py_err)
+}
+
+/// Calls a python callable with the address, the blocks written so far and the blocks in the segment
+struct PyProgress {
+    callback: PyObject,
+    addr: u32,
+    total: usize,
+}
+
+impl PyProgress {
+    fn call(&self, current: usize) {
+        Python::with_gil(|py| {
+            if let Err(error) = self.callback.call1(py, (self.addr, current, self.total)) {
+                error.print(py);
+            }
+        })
+    }
+}
+
+impl ProgressCallbacks for PyProgress {
+    fn init(&mut self, addr: u32, total: usize) {
+        self.addr = addr;
+        self.total = total;
+        self.call(0);
+    }
+
+    fn update(&mut self, current: usize) {
+        self.call(current);
+    }
+
+    fn finish(&mut self) {
+        self.call(self.total);
+    }
+}
+
+/// A connection to a chip, see [`crate::Flasher`]
+#[pyclass(name = "Flasher")]
+struct PyFlasher(Mutex<Flasher>);
+
+impl PyFlasher {
+    fn flasher(&self) -> std::sync::MutexGuard<'_, Flasher> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Run `f` with the gil released, so other python threads keep running while it talks to the chip
+    fn run<T: Send>(
+        &self,
+        py: Python<'_>,
+        f: impl FnOnce(&mut Flasher) -> Result<T, Error> + Send,
+    ) -> PyResult<T> {
+        py.allow_threads(|| f(&mut self.flasher())).map_err(py_err)
+    }
+}
+
+#[pymethods]
+impl PyFlasher {
+    /// Connect to the chip on the serial port `port`, switching to `baud` afterwards when given
+    #[new]
+    #[pyo3(signature = (port, baud = None))]
+    fn new(py: Python<'_>, port: &str, baud: Option<usize>) -> PyResult<Self> {
+        let serial = open_port(port).map_err(py_err)?;
+        let speed = baud.map(BaudRate::from_speed);
+        py.allow_threads(|| Flasher::connect(serial, speed))
+            .map(|flasher| PyFlasher(Mutex::new(flasher)))
+            .map_err(py_err)
+    }
+
+    #[getter]
+    fn chip(&self) -> String {
+        chip_name(self.flasher().chip())
+    }
+
+    #[getter]
+    fn flash_size(&self) -> String {
+        self.flasher().flash_size().to_string()
+    }
+
+    /// The chip, revision, crystal frequency, mac address, features and flash size as a dict
+    fn board_info(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let info = self.run(py, |flasher| flasher.board_info())?;
+        let dict = pyo3::types::PyDict::new(py);
+        dict.set_item("chip", chip_name(info.chip))?;
+        dict.set_item("revision", info.revision)?;
+        dict.set_item("crystal_frequency", info.crystal_frequency)?;
+        dict.set_item(
+            "mac_address",
+            info.mac_address
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(":"),
+        )?;
+        dict.set_item(
+            "features",
+            info.features
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+        )?;
+        dict.set_item("flash_size", info.flash_size.to_string())?;
+        Ok(dict.into())
+    }
+
+    /// Call `callback(addr, current, total)` with the progress of writes to the flash
+    fn set_progress_callback(&self, callback: PyObject) {
+        self.flasher().set_progress_callbacks(PyProgress {
+            callback,
+            addr: 0,
+            total: 0,
+        });
+    }
+
+    /// Build the firmware images for an elf image and write them to the flash
+    fn flash_elf(&self, py: Python<'_>, elf_data: &[u8]) -> PyResult<()> {
+        self.run(py, |flasher| flasher.load_elf_to_flash(elf_data))
+            .map(drop)
+    }
+
+    /// Write a binary to the flash at `addr`
+    fn flash_bin(&self, py: Python<'_>, addr: u32, data: &[u8]) -> PyResult<()> {
+        self.run(py, |flasher| flasher.write_bin_to_flash(addr, data))
+            .map(drop)
+    }
+
+    fn read_flash<'py>(
+        &self,
+        py: Python<'py>,
+        addr: u32,
+        size: u32,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let data = self.run(py, |flasher| flasher.read_flash(addr, size))?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    fn erase_region(&self, py: Python<'_>, addr: u32, size: u32) -> PyResult<()> {
+        self.run(py, |flasher| flasher.erase_region(addr, size))
+    }
+
+    fn erase_flash(&self, py: Python<'_>) -> PyResult<()> {
+        self.run(py, |flasher| flasher.erase_flash())
+    }
+
+    fn hard_reset(&self, py: Python<'_>) -> PyResult<()> {
+        self.run(py, |flasher| flasher.hard_reset())
+    }
+}
+
+/// A partition table, see [`crate::PartitionTable`]
+#[pyclass(name = "PartitionTable")]
+struct PyPartitionTable(crate::PartitionTable);
+
+#[pymethods]
+impl PyPartitionTable {
+    /// Parse a partition table in the csv format of esp-idf, placed at `table_offset`
+    #[staticmethod]
+    #[pyo3(signature = (csv, table_offset = 0x8000))]
+    fn from_csv(csv: &str, table_offset: u32) -> PyResult<Self> {
+        crate::PartitionTable::from_csv(csv, table_offset)
+            .map(PyPartitionTable)
+            .map_err(py_err)
+    }
+
+    /// Parse a partition table in the binary format that's written to the flash
+    #[staticmethod]
+    fn from_bytes(data: &[u8]) -> PyResult<Self> {
+        crate::PartitionTable::from_bytes(data)
+            .map(PyPartitionTable)
+            .map_err(py_err)
+    }
+
+    fn to_csv(&self) -> String {
+        self.0.to_csv()
+    }
+
+    fn to_bytes<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.0.to_bytes())
+    }
+
+    fn app_offset(&self) -> Option<u32> {
+        self.0.app_offset()
+    }
+
+    /// The offset and size of the otadata partition
+    fn ota_data(&self) -> Option<(u32, u32)> {
+        self.0.ota_data()
+    }
+}
+
+/// Build the files to write to the flash for an elf image, as a list of `(addr, name, data)` tuples
+///
+/// The flash mode, size and frequency are set in the image header, like `"dio"`, `"4MB"` and `"40m"`.
+#[pyfunction]
+#[pyo3(signature = (chip, elf_data, flash_mode = None, flash_size = None, flash_freq = None))]
+fn build_images(
+    py: Python<'_>,
+    chip: &str,
+    elf_data: &[u8],
+    flash_mode: Option<&str>,
+    flash_size: Option<&str>,
+    flash_freq: Option<&str>,
+) -> PyResult<Vec<(u32, String, PyObject)>> {
+    let chip = parse_chip(chip)?;
+    let settings = ImageSettings {
+        flash_mode: flash_mode.map(str::parse).transpose().map_err(py_err)?,
+        flash_size: flash_size.map(str::parse).transpose().map_err(py_err)?,
+        flash_frequency: flash_freq.map(str::parse).transpose().map_err(py_err)?,
+        ..ImageSettings::default()
+    };
+    let files = FlashFiles::from_elf(chip, elf_data, &settings).map_err(py_err)?;
+    Ok(files
+        .files
+        .into_iter()
+        .map(|file| {
+            let data = PyBytes::new(py, &file.data).into_any().unbind();
+            (file.addr, file.name, data)
+        })
+        .collect())
+}
+
+#[pymodule]
+fn espflash(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("EspflashError", m.py().get_type::<EspflashError>())?;
+    m.add_class::<PyFlasher>()?;
+    m.add_class::<PyPartitionTable>()?;
+    m.add_function(wrap_pyfunction!(build_images, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,94 @@
+use std::ops::{Deref, DerefMut};
+
+use crate::error::Error;
+use crate::flasher::{Flasher, ResetAfterFlash};
+
+/// Keeps the chip in the bootloader across several operations
+///
+/// Writing the flash normally resets the chip afterwards, which ends the connection to the bootloader.
+/// Operations done trough a session leave the chip in the bootloader instead and reuse the connection and the
+/// attached flash, so a sequence of writes, reads, erases, efuse operations and verifications doesn't have to
+/// connect and attach the flash again for every step.
+///
+/// The chip is reset once when the session is finished, dropping the session leaves the chip in the bootloader.
+///
+/// ```no_run
+/// # fn main() -> Result<(), espflash::Error> {
+/// # let serial = serial::open("/dev/ttyUSB0")?;
+/// # let (bootloader, app) = (Vec::new(), Vec::new());
+/// let mut session = espflash::Session::new(espflash::Flasher::connect(serial, None)?);
+/// session.erase_region(0x9000, 0x6000)?;
+/// session.write_bin_to_flash(0x1000, &bootloader)?;
+/// session.load_elf_to_flash(&app)?;
+/// session.verify_elf(&app)?;
+/// session.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Session {
+    flasher: Flasher,
+    /// What to do when the session is finished
+    reset_after_flash: ResetAfterFlash,
+}
+
+impl Session {
+    pub fn new(mut flasher: Flasher) -> Self {
+        let reset_after_flash = flasher.reset_after_flash();
+        flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+        Session {
+            flasher,
+            reset_after_flash,
+        }
+    }
+
+    /// End the session, resetting the chip into the application unless the flasher was set up not to
+    pub fn finish(self) -> Result<Flasher, Error> {
+        let Session {
+            mut flasher,
+            reset_after_flash,
+        } = self;
+        flasher.set_reset_after_flash(reset_after_flash);
+        if reset_after_flash == ResetAfterFlash::HardReset {
+            flasher.hard_reset()?;
+        }
+        Ok(flasher)
+    }
+}
+
+impl Deref for Session {
+    type Target = Flasher;
+
+    fn deref(&self) -> &Flasher {
+        &self.flasher
+    }
+}
+
+impl DerefMut for Session {
+    fn deref_mut(&mut self) -> &mut Flasher {
+        &mut self.flasher
+    }
+}
+
+#[test]
+fn test_session() {
+    use crate::flasher::Command;
+    use crate::testing::MockTransport;
+    use crate::{Chip, FlashSize};
+
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut session = Session::new(Flasher::connect(mock.clone(), None).unwrap());
+    let attached = mock.received_count(Command::SpiAttach);
+
+    // the flash stays attached between the operations
+    session.erase_region(0x9000, 0x1000).unwrap();
+    session.write_bin_to_flash(0x10000, &[0xaa; 0x100]).unwrap();
+    session.write_bin_to_flash(0x20000, &[0xaa; 0x100]).unwrap();
+    session.read_efuses().unwrap();
+    assert_eq!(attached, mock.received_count(Command::SpiAttach));
+
+    let mut flasher = session.finish().unwrap();
+    assert_eq!(ResetAfterFlash::HardReset, flasher.reset_after_flash());
+    // the flash has to be attached again after the reset
+    flasher.erase_region(0x9000, 0x1000).unwrap();
+    assert_eq!(attached + 1, mock.received_count(Command::SpiAttach));
+}
@@ -0,0 +1,119 @@
+use std::convert::TryInto;
+
+use rsa::pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use rsa::sha2::{Digest, Sha256};
+use rsa::traits::PublicKeyParts;
+use rsa::{BigUint, Pss, RsaPrivateKey, RsaPublicKey};
+
+use crate::chip::Chip;
+use crate::error::Error;
+use crate::image::{crc32_le, ImageInfo};
+
+/// The signature block is stored at the start of the sector following the image
+const SIGNATURE_SECTOR_SIZE: usize = 0x1000;
+const SIGNATURE_BLOCK_LENGTH: usize = 1216;
+const SIGNATURE_MAGIC: u8 = 0xe7;
+const SIGNATURE_VERSION: u8 = 0x02;
+/// The crc covers the block up to the crc itself
+const SIGNATURE_CRC_OFFSET: usize = 1196;
+/// Secure boot v2 uses RSA-3072 keys
+const RSA_KEY_LENGTH: usize = 384;
+
+/// A public key used to verify secure boot v2 signatures
+pub struct PublicKey(RsaPublicKey);
+
+impl PublicKey {
+    /// Load an RSA-3072 public key from a pem file
+    ///
+    /// The private signing key is accepted as well, since that's usually what's at hand.
+    pub fn from_pem(pem: &str) -> Result<Self, Error> {
+        let key = RsaPublicKey::from_public_key_pem(pem)
+            .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem))
+            .or_else(|_| RsaPrivateKey::from_pkcs8_pem(pem).map(|key| key.to_public_key()))
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem).map(|key| key.to_public_key()))
+            .map_err(|_| Error::InvalidKey(String::from("not an RSA key in the pem format")))?;
+        if key.size() != RSA_KEY_LENGTH {
+            return Err(Error::InvalidKey(format!(
+                "secure boot v2 uses 3072 bit keys, not {} bit",
+                key.size() * 8
+            )));
+        }
+        Ok(PublicKey(key))
+    }
+
+    /// Check that an image has a secure boot v2 signature made with this key
+    pub fn verify(&self, chip: Chip, data: &[u8]) -> Result<(), Error> {
+        if chip != Chip::Esp32 {
+            return Err(Error::UnsupportedOperation {
+                operation: "secure boot",
+                chip,
+            });
+        }
+
+        let info = ImageInfo::parse(chip, data)?;
+        let offset = info.length.div_ceil(SIGNATURE_SECTOR_SIZE) * SIGNATURE_SECTOR_SIZE;
+        let block = data
+            .get(offset..offset + SIGNATURE_BLOCK_LENGTH)
+            .filter(|block| block[0] == SIGNATURE_MAGIC && block[1] == SIGNATURE_VERSION)
+            .ok_or_else(|| invalid("the image doesn't have a secure boot v2 signature block"))?;
+        let crc = u32::from_le_bytes(
+            block[SIGNATURE_CRC_OFFSET..SIGNATURE_CRC_OFFSET + 4]
+                .try_into()
+                .unwrap(),
+        );
+        if crc32_le(0, &block[..SIGNATURE_CRC_OFFSET]) != crc {
+            return Err(invalid("the signature block is corrupted"));
+        }
+
+        let digest = &block[4..36];
+        if Sha256::digest(&data[..offset])[..] != *digest {
+            return Err(invalid("the image was changed after it was signed"));
+        }
+
+        // the numbers are stored least significant byte first
+        let modulus = BigUint::from_bytes_le(&block[36..36 + RSA_KEY_LENGTH]);
+        let exponent = u32::from_le_bytes(block[420..424].try_into().unwrap());
+        if &modulus != self.0.n() || &BigUint::from(exponent) != self.0.e() {
+            return Err(invalid("the image is signed with a different key"));
+        }
+
+        let mut signature = block[812..812 + RSA_KEY_LENGTH].to_vec();
+        signature.reverse();
+        self.0
+            .verify(Pss::new_with_salt::<Sha256>(32), digest, &signature)
+            .map_err(|_| invalid("the signature doesn't match the signed digest"))
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidSignature(reason.to_string())
+}
+
+#[test]
+fn test_verify_signature() {
+    use std::fs::{read, read_to_string};
+
+    let mut image = read("./tests/data/esp32.bin").unwrap();
+    let key =
+        PublicKey::from_pem(&read_to_string("./tests/data/signing_key.pub.pem").unwrap()).unwrap();
+    assert!(matches!(
+        key.verify(Chip::Esp32, &image),
+        Err(Error::InvalidSignature(_))
+    ));
+
+    image.resize(image.len().div_ceil(0x1000) * 0x1000, 0xff);
+    image.extend(read("./tests/data/esp32.bin.sig").unwrap());
+    key.verify(Chip::Esp32, &image).unwrap();
+
+    let other =
+        PublicKey::from_pem(&read_to_string("./tests/data/other_key.pub.pem").unwrap()).unwrap();
+    assert!(other.verify(Chip::Esp32, &image).is_err());
+
+    // changing the padding after the image changes the signed digest
+    let padding = image.len() - SIGNATURE_BLOCK_LENGTH - 1;
+    image[padding] = 0;
+    assert!(key.verify(Chip::Esp32, &image).is_err());
+
+    assert!(PublicKey::from_pem("not a key").is_err());
+}
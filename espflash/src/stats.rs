@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use crate::connection::CommandTiming;
+use crate::flasher::Command;
+
+/// Totals of the commands of one kind
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct CommandStats {
+    pub count: usize,
+    /// Sum of the round trip times
+    pub total: Duration,
+    /// The longest round trip
+    pub max: Duration,
+}
+
+impl CommandStats {
+    /// The average round trip time
+    pub fn average(&self) -> Duration {
+        match self.count {
+            0 => Duration::default(),
+            count => self.total / count as u32,
+        }
+    }
+}
+
+/// How a flash operation went, returned by [`Flasher::load_elf_to_flash`](crate::Flasher::load_elf_to_flash)
+/// and [`Flasher::write_bin_to_flash`](crate::Flasher::write_bin_to_flash)
+///
+/// Slower round trips, a lower utilization or more retries than other stations flashing the same image point
+/// at a degrading usb adapter or cable.
+#[derive(Clone, Debug, Default)]
+pub struct FlashStats {
+    /// The round trip times per command, in the order the commands were first sent
+    pub commands: Vec<(Command, CommandStats)>,
+    /// Time taken by the whole operation
+    pub duration: Duration,
+    /// Bytes sent, including the slip framing
+    pub bytes_sent: usize,
+    /// Bytes received, after slip decoding
+    pub bytes_received: usize,
+    /// The number of flash blocks sent again after a transfer error
+    pub retries: usize,
+    /// The baud rate at the end of the operation, lower than requested when it fell back after errors
+    pub baud: usize,
+}
+
+impl FlashStats {
+    pub(crate) fn add(&mut self, timing: &CommandTiming) {
+        let stats = match self
+            .commands
+            .iter_mut()
+            .position(|(command, _)| *command == timing.command)
+        {
+            Some(index) => &mut self.commands[index].1,
+            None => {
+                self.commands
+                    .push((timing.command, CommandStats::default()));
+                &mut self.commands.last_mut().unwrap().1
+            }
+        };
+        stats.count += 1;
+        stats.total += timing.duration;
+        stats.max = stats.max.max(timing.duration);
+        self.bytes_sent += timing.bytes_sent;
+        self.bytes_received += timing.bytes_received;
+    }
+
+    /// The totals of `command`
+    pub fn command(&self, command: Command) -> Option<&CommandStats> {
+        self.commands
+            .iter()
+            .find(|(stats_command, _)| *stats_command == command)
+            .map(|(_, stats)| stats)
+    }
+
+    /// The part of the operation the link was busy sending, between 0 and 1
+    ///
+    /// Every byte takes 10 bits on the wire, the remaining time is spent waiting for the chip.
+    pub fn utilization(&self) -> f64 {
+        if self.baud == 0 || self.duration.is_zero() {
+            return 0.0;
+        }
+        let busy = (self.bytes_sent * 10) as f64 / self.baud as f64;
+        (busy / self.duration.as_secs_f64()).min(1.0)
+    }
+}
+
+#[test]
+fn test_flash_stats() {
+    use crate::testing::MockTransport;
+    use crate::{Chip, FlashSize, Flasher, ResetAfterFlash};
+
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(mock.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+
+    let stats = flasher.write_bin_to_flash(0x10000, &[0xaa; 0x900]).unwrap();
+    let data = stats.command(Command::FlashData).unwrap();
+    assert_eq!(3, data.count);
+    assert!(data.max <= data.total);
+    assert!(stats.bytes_sent > 0x900);
+    assert_eq!(0, stats.retries);
+    assert_eq!(115_200, stats.baud);
+    assert!(stats.utilization() > 0.0 && stats.utilization() <= 1.0);
+    // the read of the status register for the write protection check
+    assert!(stats.command(Command::WriteReg).is_some());
+
+    // the block with the bad checksum is sent again
+    mock.fail(Command::FlashData, 0x07);
+    let stats = flasher.write_bin_to_flash(0x10000, &[0xaa; 0x100]).unwrap();
+    assert_eq!(1, stats.retries);
+    assert_eq!(2, stats.command(Command::FlashData).unwrap().count);
+}
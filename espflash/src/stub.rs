@@ -0,0 +1,83 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+
+use crate::Error;
+
+/// A flasher stub, a small program that is loaded into ram and takes over from the rom bootloader
+///
+/// The stub implements the same protocol as the rom bootloader, extended with faster and more capable
+/// commands, such as streaming flash reads.
+/// Stubs are built per chip, see [`Flasher::load_stub`](crate::Flasher::load_stub).
+#[derive(Clone, Debug)]
+pub struct Stub {
+    /// The code of the stub
+    pub text: Vec<u8>,
+    /// The ram address to load the code to
+    pub text_start: u32,
+    /// The data used by the stub
+    pub data: Vec<u8>,
+    /// The ram address to load the data to
+    pub data_start: u32,
+    /// The address to start executing the stub at
+    pub entry: u32,
+}
+
+impl Stub {
+    /// Parse a stub in the json format used by esptool
+    ///
+    /// The `text` and `data` segments are base64 encoded, `data` and `data_start` are optional.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let stub: StubJson = serde_json::from_str(json).map_err(|e| invalid(e.to_string()))?;
+        let segment = |name: &str, value: &str| {
+            STANDARD
+                .decode(value)
+                .map_err(|_| invalid(format!("`{}` isn't valid base64", name)))
+        };
+
+        Ok(Stub {
+            text: segment("text", &stub.text)?,
+            text_start: stub.text_start,
+            data: match &stub.data {
+                Some(data) => segment("data", data)?,
+                None => Vec::new(),
+            },
+            data_start: stub.data_start.unwrap_or(0),
+            entry: stub.entry,
+        })
+    }
+}
+
+/// A stub file as written by esptool, other fields like `bss_start` are ignored
+#[derive(Deserialize)]
+struct StubJson {
+    text: String,
+    text_start: u32,
+    data: Option<String>,
+    data_start: Option<u32>,
+    entry: u32,
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidStub(reason.into())
+}
+
+#[test]
+fn test_stub_from_json() {
+    let stub = Stub::from_json(
+        r#"{
+            "entry": 1074521516,
+            "text": "aGVsbG8gc3R1Yg==",
+            "text_start": 1074520064,
+            "data": "AAEC",
+            "data_start": 1073605544,
+            "bss_start": null
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(0x400be5ac, stub.entry);
+    assert_eq!(b"hello stub", stub.text.as_slice());
+    assert_eq!(vec![0, 1, 2], stub.data);
+    assert!(Stub::from_json(r#"{"text": "AAEC", "entry": 1}"#).is_err());
+    assert!(Stub::from_json(r#"{"text": "not base64!", "text_start": 0, "entry": 1}"#).is_err());
+}
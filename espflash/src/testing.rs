@@ -0,0 +1,889 @@
+//! Tools for testing code that uses the [`Flasher`](crate::Flasher) without hardware
+//!
+//! This module is only available with the `testing` feature.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use serial::{
+    BaudRate, CharSize, FlowControl, Parity, PortSettings, SerialPort, SerialPortSettings, StopBits,
+};
+
+use crate::chip::{ChipType, Esp32, Esp8266};
+use crate::encoder::SlipEncoder;
+use crate::flasher::{checksum, Command, CHECKSUM_INIT, UART_DATE_REG_ADDR};
+use crate::{Chip, FlashSize, SpiAttachParams};
+
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+/// The bootloader answers a sync command multiple times
+const SYNC_RESPONSES: usize = 8;
+
+const FLASH_SECTOR_SIZE: u32 = 0x1000;
+const FLASH_SECTORS_PER_BLOCK: u32 = 16;
+/// Bit in the spi cmd register that starts a user command, cleared when the command is done
+const SPI_CMD_USR: u32 = 1 << 18;
+
+/// The efuse command register of the esp32, and the command that programs the write registers into the efuses
+const ESP32_EFUSE_CMD_REG: u32 = 0x3ff5a104;
+const ESP32_EFUSE_CMD_WRITE: u32 = 0x2;
+/// The write registers, the read registers and the number of words of the efuse blocks of the esp32
+const ESP32_EFUSE_BLOCKS: [(u32, u32, u32); 4] = [
+    (0x3ff5a01c, 0x3ff5a000, 7),
+    (0x3ff5a098, 0x3ff5a038, 8),
+    (0x3ff5a0b8, 0x3ff5a058, 8),
+    (0x3ff5a0d8, 0x3ff5a078, 8),
+];
+
+// error codes sent by the rom
+const ROM_INVALID_MESSAGE: u8 = 0x05;
+const ROM_FAILED_TO_ACT: u8 = 0x06;
+const ROM_INVALID_CRC: u8 = 0x07;
+
+/// A command received by a [`MockTransport`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReceivedCommand {
+    pub command: u8,
+    pub data: Vec<u8>,
+}
+
+/// A scriptable serial port that imitates the rom bootloader
+///
+/// By default every command succeeds, register writes are stored and register reads return the stored
+/// value, or 0. Responses for specific commands can be queued with [`respond`](Self::respond) and
+/// [`fail`](Self::fail), the data the flasher should send with [`expect`](Self::expect), and every received
+/// command is recorded for inspection.
+///
+/// The transport can be cloned, all clones share the same state. This allows passing one clone to the
+/// [`Flasher`](crate::Flasher) while inspecting the received commands through another.
+#[derive(Clone)]
+pub struct MockTransport {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    /// Bytes written by the flasher that don't form a complete frame yet
+    input: Vec<u8>,
+    /// Encoded responses waiting to be read by the flasher
+    output: VecDeque<u8>,
+    responses: HashMap<u8, VecDeque<(u8, u32, Vec<u8>)>>,
+    expected: HashMap<u8, VecDeque<Vec<u8>>>,
+    /// Received commands that didn't match the expected data
+    mismatches: Vec<String>,
+    registers: HashMap<u32, u32>,
+    pinned_registers: HashMap<u32, u32>,
+    received: Vec<ReceivedCommand>,
+    writes: usize,
+    /// The number of times rts was asserted, which resets the chip
+    resets: usize,
+    settings: PortSettings,
+    timeout: Duration,
+    simulation: Option<Simulation>,
+}
+
+/// The state of a simulated rom loader
+struct Simulation {
+    chip: Chip,
+    flash: Vec<u8>,
+    flash_id: u32,
+    /// The packed pins the flash is connected to, and the pins that are attached
+    flash_pins: u32,
+    attached: u32,
+    /// The first and second status register of the flash chip
+    flash_status: u8,
+    flash_status2: u8,
+    /// The id of the psram chip, which answers when the chip select of the psram is enabled instead of the flash
+    psram_id: Option<u32>,
+    /// The flash or memory write started by the last begin command
+    write: Option<PendingWrite>,
+    /// Whether code was started in ram, which is taken to be the flasher stub
+    stub: bool,
+}
+
+struct PendingWrite {
+    command: u8,
+    offset: u32,
+    block_size: u32,
+    sequence: u32,
+    /// The end of the region that was erased for the write, the stub erases as data is written
+    erased: u32,
+    /// The end of the region to erase for the write
+    erase_end: u32,
+}
+
+impl Default for MockTransport {
+    fn default() -> Self {
+        let state = State {
+            input: Vec::new(),
+            output: VecDeque::new(),
+            responses: HashMap::new(),
+            expected: HashMap::new(),
+            mismatches: Vec::new(),
+            registers: HashMap::new(),
+            pinned_registers: HashMap::new(),
+            received: Vec::new(),
+            writes: 0,
+            resets: 0,
+            settings: PortSettings {
+                baud_rate: BaudRate::Baud115200,
+                char_size: CharSize::Bits8,
+                parity: Parity::ParityNone,
+                stop_bits: StopBits::Stop1,
+                flow_control: FlowControl::FlowNone,
+            },
+            timeout: Duration::from_secs(1),
+            simulation: None,
+        };
+        MockTransport {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a transport that is detected as `chip`, with a flash chip of `flash_size`
+    pub fn with_chip(chip: Chip, flash_size: FlashSize) -> Self {
+        let (date, w0) = match chip {
+            Chip::Esp8266 => (Esp8266::DATE_REG1_VALUE, chip.spi_registers().w0()),
+            Chip::Esp32 => (Esp32::DATE_REG1_VALUE, chip.spi_registers().w0()),
+        };
+        let transport = Self::new();
+        transport.pin_register(UART_DATE_REG_ADDR, date);
+        // the flash id read during detection, with the size in the third byte
+        transport.pin_register(w0, (flash_size as u32) << 16 | 0x4020);
+        // spi commands are done right away
+        transport.pin_register(chip.spi_registers().cmd(), 0);
+        transport
+    }
+
+    /// Create a transport that simulates the rom loader of `chip` with a flash chip of `flash_size`
+    ///
+    /// Unlike the plain mock, flash writes and erases are applied to a simulated flash, which can be
+    /// read back with [`flash`](Self::flash), and the block sequence numbers and checksums are checked.
+    /// The flash is read trough the simulated spi peripheral, as on the real chip. Quirks of the rom
+    /// loaders are simulated as well: the esp8266 rom sends shorter status bytes, erases more than
+    /// requested and doesn't support `SPI_ATTACH` and `SPI_FLASH_MD5`.
+    ///
+    /// Starting code loaded into ram starts a simulated flasher stub, which attaches the flash and hashes it on
+    /// every chip, sends the md5 as raw bytes and doesn't erase more than requested. Streaming reads aren't
+    /// simulated.
+    pub fn simulator(chip: Chip, flash_size: FlashSize) -> Self {
+        let transport = Self::new();
+        let date = match chip {
+            Chip::Esp8266 => Esp8266::DATE_REG1_VALUE,
+            Chip::Esp32 => Esp32::DATE_REG1_VALUE,
+        };
+        transport.pin_register(UART_DATE_REG_ADDR, date);
+        transport.state().simulation = Some(Simulation {
+            chip,
+            flash: vec![0xff; flash_size.size_in_bytes().unwrap_or_default() as usize],
+            flash_id: (flash_size as u32) << 16 | 0x4020,
+            flash_pins: 0,
+            attached: 0,
+            flash_status: 0,
+            flash_status2: 0,
+            psram_id: None,
+            write: None,
+            stub: false,
+        });
+        transport
+    }
+
+    /// The contents of the simulated flash
+    ///
+    /// Empty if the transport isn't a [`simulator`](Self::simulator).
+    pub fn flash(&self) -> Vec<u8> {
+        self.state()
+            .simulation
+            .as_ref()
+            .map(|simulation| simulation.flash.clone())
+            .unwrap_or_default()
+    }
+
+    /// Overwrite part of the simulated flash, without erasing it first
+    pub fn set_flash(&self, offset: u32, data: &[u8]) {
+        if let Some(simulation) = self.state().simulation.as_mut() {
+            let start = (offset as usize).min(simulation.flash.len());
+            let end = (start + data.len()).min(simulation.flash.len());
+            simulation.flash[start..end].copy_from_slice(&data[..end - start]);
+        }
+    }
+
+    /// Set the status register of the simulated flash chip, for example to enable block protection
+    pub fn set_flash_status(&self, status: u8) {
+        if let Some(simulation) = self.state().simulation.as_mut() {
+            simulation.flash_status = status;
+        }
+    }
+
+    /// The second status register of the simulated flash chip, which holds the quad enable bit of most chips
+    pub fn flash_status2(&self) -> u8 {
+        self.state()
+            .simulation
+            .as_ref()
+            .map(|simulation| simulation.flash_status2)
+            .unwrap_or_default()
+    }
+
+    /// Set the JEDEC id of the simulated flash chip, the manufacturer is in the lowest byte
+    pub fn set_flash_id(&self, flash_id: u32) {
+        if let Some(simulation) = self.state().simulation.as_mut() {
+            simulation.flash_id = flash_id;
+        }
+    }
+
+    /// Connect the flash chip to other pins, it doesn't answer when the flasher attaches the flash on other pins
+    pub fn set_flash_pins(&self, pins: SpiAttachParams) {
+        if let Some(simulation) = self.state().simulation.as_mut() {
+            let encoded = pins.encode();
+            simulation.flash_pins =
+                u32::from_le_bytes([encoded[0], encoded[1], encoded[2], encoded[3]]);
+        }
+    }
+
+    /// Add a psram chip with the id it answers the read id command with, the size is in the third byte
+    pub fn set_psram_id(&self, psram_id: u32) {
+        if let Some(simulation) = self.state().simulation.as_mut() {
+            simulation.psram_id = Some(psram_id);
+        }
+    }
+
+    /// Set the value of a register or memory location, which can be changed by the flasher
+    pub fn set_register(&self, addr: u32, value: u32) {
+        self.state().registers.insert(addr, value);
+    }
+
+    /// Make reads of a register always return `value`, ignoring writes
+    pub fn pin_register(&self, addr: u32, value: u32) {
+        self.state().pinned_registers.insert(addr, value);
+    }
+
+    /// The current value of a register or memory location
+    pub fn register(&self, addr: u32) -> u32 {
+        self.state().read_register(addr)
+    }
+
+    /// Answer the next `command` with `value` and `data`, instead of the default response
+    pub fn respond(&self, command: Command, value: u32, data: &[u8]) {
+        self.queue(command, 0, value, data);
+    }
+
+    /// Answer the next `command` with an error
+    pub fn fail(&self, command: Command, error: u8) {
+        self.queue(command, error, 0, &[]);
+    }
+
+    /// Expect the next `command` to be sent with `data`, mismatches are reported by [`verify`](Self::verify)
+    ///
+    /// Expectations are independent of the responses, the command is answered as usual.
+    pub fn expect(&self, command: Command, data: &[u8]) {
+        self.state()
+            .expected
+            .entry(command as u8)
+            .or_default()
+            .push_back(data.to_vec());
+    }
+
+    /// Send `data` to the flasher outside of any response, like the boot messages of the chip
+    pub fn send_output(&self, data: &[u8]) {
+        self.state().output.extend(data);
+    }
+
+    /// The commands received so far
+    pub fn received(&self) -> Vec<ReceivedCommand> {
+        self.state().received.clone()
+    }
+
+    /// The number of times `command` was received
+    pub fn received_count(&self, command: Command) -> usize {
+        self.state()
+            .received
+            .iter()
+            .filter(|received| received.command == command as u8)
+            .count()
+    }
+
+    /// The number of writes to the transport, every write is a separate usb packet on a real bridge
+    pub fn write_count(&self) -> usize {
+        self.state().writes
+    }
+
+    /// The number of times the chip was reset trough the rts line
+    pub fn reset_count(&self) -> usize {
+        self.state().resets
+    }
+
+    /// Check that all queued responses were used and all expected commands were received with the expected data
+    pub fn verify(&self) -> Result<(), String> {
+        let state = self.state();
+        let mut problems = state.mismatches.clone();
+        let unused: usize = state.responses.values().map(VecDeque::len).sum();
+        if unused > 0 {
+            problems.push(format!("{} queued responses weren't used", unused));
+        }
+        let missing: usize = state.expected.values().map(VecDeque::len).sum();
+        if missing > 0 {
+            problems.push(format!("{} expected commands weren't received", missing));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join(", "))
+        }
+    }
+
+    fn queue(&self, command: Command, error: u8, value: u32, data: &[u8]) {
+        self.state()
+            .responses
+            .entry(command as u8)
+            .or_default()
+            .push_back((error, value, data.to_vec()));
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl State {
+    fn read_register(&self, addr: u32) -> u32 {
+        lookup_register(&self.pinned_registers, &self.registers, addr)
+    }
+
+    /// Collect complete frames from the written bytes and answer them
+    fn process_input(&mut self) {
+        while let Some(start) = self.input.iter().position(|&byte| byte == SLIP_END) {
+            let end = match self.input[start + 1..]
+                .iter()
+                .position(|&byte| byte == SLIP_END)
+            {
+                Some(end) => start + 1 + end,
+                None => return,
+            };
+            let frame: Vec<u8> = self.input.drain(..=end).collect();
+            let frame = unslip(&frame[start + 1..frame.len() - 1]);
+            // empty frames are used to resynchronize
+            if frame.len() >= 8 {
+                let check = u32::from_le_bytes([frame[4], frame[5], frame[6], frame[7]]);
+                self.handle_command(frame[1], frame[8..].to_vec(), check);
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: u8, data: Vec<u8>, check: u32) {
+        self.received.push(ReceivedCommand {
+            command,
+            data: data.clone(),
+        });
+        if let Some(expected) = self
+            .expected
+            .get_mut(&command)
+            .and_then(VecDeque::pop_front)
+        {
+            if expected != data {
+                self.mismatches.push(format!(
+                    "command {:#04x} was sent with {:02x?} instead of {:02x?}",
+                    command, data, expected
+                ));
+            }
+        }
+
+        if let Some(response) = self
+            .responses
+            .get_mut(&command)
+            .and_then(VecDeque::pop_front)
+        {
+            let (error, value, data) = response;
+            self.send_response(command, error, value, &data);
+            return;
+        }
+
+        if self.simulation.is_some() && self.simulate(command, &data, check) {
+            return;
+        }
+
+        let word = |offset: usize| {
+            data.get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .unwrap_or(0)
+        };
+        match command {
+            // the flasher doesn't wait for the response to these
+            c if c == Command::FlashEnd as u8 || c == Command::MemEnd as u8 => {}
+            c if c == Command::Sync as u8 => {
+                for _ in 0..SYNC_RESPONSES {
+                    self.send_response(command, 0, 0, &[]);
+                }
+            }
+            c if c == Command::ReadReg as u8 => {
+                let value = self.read_register(word(0));
+                self.send_response(command, 0, value, &[]);
+            }
+            c if c == Command::WriteReg as u8 => {
+                let (addr, value, mask) = (word(0), word(4), word(8));
+                let old = self.read_register(addr);
+                self.registers.insert(addr, (old & !mask) | (value & mask));
+                self.send_response(command, 0, 0, &[]);
+            }
+            _ => self.send_response(command, 0, 0, &[]),
+        }
+    }
+
+    /// Handle a command the way the simulated rom does, returns false for commands that get the default response
+    fn simulate(&mut self, command: u8, data: &[u8], check: u32) -> bool {
+        let word = |offset: usize| {
+            data.get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .unwrap_or(0)
+        };
+        let simulation = match self.simulation.as_mut() {
+            Some(simulation) => simulation,
+            None => return false,
+        };
+        let chip = simulation.chip;
+
+        let error = match command {
+            c if c == Command::FlashBegin as u8 || c == Command::MemBegin as u8 => {
+                let (size, block_size, offset) = (word(0), word(8), word(12));
+                // the rom erases the whole region up front, the stub only as data is written
+                if c == Command::FlashBegin as u8 && !simulation.stub {
+                    simulation.erase(offset, size);
+                }
+                let data_command = if c == Command::FlashBegin as u8 {
+                    Command::FlashData
+                } else {
+                    Command::MemData
+                };
+                simulation.write = Some(PendingWrite {
+                    command: data_command as u8,
+                    offset,
+                    block_size,
+                    sequence: 0,
+                    erased: offset / FLASH_SECTOR_SIZE * FLASH_SECTOR_SIZE,
+                    erase_end: offset + size,
+                });
+                0
+            }
+            c if c == Command::FlashData as u8 || c == Command::MemData as u8 => {
+                let (size, sequence) = (word(0), word(4));
+                let block = data.get(16..).unwrap_or_default();
+                match simulation.write.as_mut() {
+                    Some(write) if write.command == c && write.sequence == sequence => {
+                        if block.len() != size as usize
+                            || checksum(block, CHECKSUM_INIT) as u32 != check
+                        {
+                            ROM_INVALID_CRC
+                        } else {
+                            let offset = write.offset + sequence * write.block_size;
+                            write.sequence += 1;
+                            if c == Command::FlashData as u8 {
+                                let end = (offset + block.len() as u32).min(write.erase_end);
+                                let erased = write.erased;
+                                if simulation.stub && end > erased {
+                                    write.erased =
+                                        end.div_ceil(FLASH_SECTOR_SIZE) * FLASH_SECTOR_SIZE;
+                                    simulation.erase(erased, end - erased);
+                                }
+                                simulation.program(offset, block);
+                            } else {
+                                for (i, bytes) in block.chunks(4).enumerate() {
+                                    let mut value = [0; 4];
+                                    value[..bytes.len()].copy_from_slice(bytes);
+                                    self.registers
+                                        .insert(offset + i as u32 * 4, u32::from_le_bytes(value));
+                                }
+                            }
+                            0
+                        }
+                    }
+                    _ => ROM_FAILED_TO_ACT,
+                }
+            }
+            // jumping to the loaded code starts the stub, which announces itself after the response
+            c if c == Command::MemEnd as u8 && word(0) == 0 => {
+                simulation.stub = true;
+                0
+            }
+            c if c == Command::EraseRegion as u8 && simulation.stub => {
+                let (offset, size) = (word(0), word(4));
+                if offset % FLASH_SECTOR_SIZE != 0 || size % FLASH_SECTOR_SIZE != 0 {
+                    ROM_INVALID_MESSAGE
+                } else {
+                    simulation.erase(offset, size);
+                    0
+                }
+            }
+            c if c == Command::EraseFlash as u8 && simulation.stub => {
+                simulation.flash.fill(0xff);
+                0
+            }
+            c if c == Command::SpiAttach as u8 && (chip == Chip::Esp32 || simulation.stub) => {
+                simulation.attached = word(0);
+                0
+            }
+            c if c == Command::SpiFlashMd5 as u8 && (chip == Chip::Esp32 || simulation.stub) => {
+                let (offset, size) = (word(0) as usize, word(4) as usize);
+                // the stub sends the hash as raw bytes, the rom as hex
+                let stub = simulation.stub;
+                match simulation.flash.get(offset..offset + size) {
+                    Some(region) => {
+                        let digest = md5::compute(region);
+                        let hash = if stub {
+                            digest.to_vec()
+                        } else {
+                            format!("{:x}", digest).into_bytes()
+                        };
+                        self.send_response(command, 0, 0, &hash);
+                        return true;
+                    }
+                    None => ROM_FAILED_TO_ACT,
+                }
+            }
+            c if c == Command::WriteReg as u8
+                && chip == Chip::Esp32
+                && word(0) == ESP32_EFUSE_CMD_REG =>
+            {
+                // efuse bits can only be set, the commands finish immediately
+                if word(4) == ESP32_EFUSE_CMD_WRITE {
+                    for (write, read, words) in ESP32_EFUSE_BLOCKS {
+                        for i in 0..words {
+                            let programmed = self.read_register(write + 4 * i);
+                            let current = self.read_register(read + 4 * i);
+                            self.registers.insert(read + 4 * i, current | programmed);
+                        }
+                    }
+                }
+                self.registers.insert(ESP32_EFUSE_CMD_REG, 0);
+                0
+            }
+            c if c == Command::WriteReg as u8 => {
+                let (addr, value) = (word(0), word(4));
+                let registers = chip.spi_registers();
+                if addr != registers.cmd() || value & SPI_CMD_USR == 0 {
+                    return false;
+                }
+                // run the spi command set up in the other registers and store the result in w0
+                let (pinned, stored) = (&self.pinned_registers, &self.registers);
+                let spi_command = lookup_register(pinned, stored, registers.usr2()) as u8;
+                let w0 = lookup_register(pinned, stored, registers.w0());
+                let mosi_bits = match registers.mosi_length() {
+                    Some(mosi_length) => lookup_register(pinned, stored, mosi_length) + 1,
+                    None => (lookup_register(pinned, stored, registers.usr1()) >> 17) + 1,
+                };
+                // the pin register disables the chip select of the flash and enables the one of the psram
+                let psram = lookup_register(pinned, stored, registers.pin()) & 0b11 == 0b01;
+                let result = match spi_command {
+                    0x9f if psram => simulation.psram_id,
+                    _ if psram => None,
+                    // without a flash on the pins, the data line floats high
+                    0x9f if simulation.attached != simulation.flash_pins => Some(0xffffff),
+                    0x9f => Some(simulation.flash_id),
+                    0x05 => Some(simulation.flash_status as u32),
+                    0x35 => Some(simulation.flash_status2 as u32),
+                    0x01 => {
+                        simulation.flash_status = w0 as u8;
+                        if mosi_bits == 16 {
+                            simulation.flash_status2 = (w0 >> 8) as u8;
+                        }
+                        None
+                    }
+                    0x31 => {
+                        simulation.flash_status2 = w0 as u8;
+                        None
+                    }
+                    0x03 => {
+                        let [high, mid, low, _] = w0.to_le_bytes();
+                        let offset = u32::from_be_bytes([0, high, mid, low]) as usize;
+                        simulation.flash.get(offset..offset + 4).map(|bytes| {
+                            u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+                        })
+                    }
+                    _ => None,
+                };
+                self.registers.insert(registers.w0(), result.unwrap_or(0));
+                self.registers.insert(registers.cmd(), value & !SPI_CMD_USR);
+                0
+            }
+            // only supported by the stub, or not by the esp8266 rom
+            c if c == Command::SpiAttach as u8
+                || c == Command::SpiFlashMd5 as u8
+                || c == Command::ReadFlash as u8
+                || c == Command::EraseRegion as u8
+                || c == Command::EraseFlash as u8 =>
+            {
+                ROM_INVALID_MESSAGE
+            }
+            _ => return false,
+        };
+
+        self.send_response(command, error, 0, &[]);
+        if command == Command::MemEnd as u8 {
+            self.send_frame(b"OHAI");
+        }
+        true
+    }
+
+    fn send_response(&mut self, command: u8, error: u8, value: u32, data: &[u8]) {
+        // the esp32 rom appends 4 status bytes: the status, the error code and 2 reserved bytes,
+        // the esp8266 rom and the stub only the first 2
+        let status_length = match &self.simulation {
+            Some(simulation) if simulation.stub || simulation.chip == Chip::Esp8266 => 2,
+            _ => 4,
+        };
+        let status = &[(error != 0) as u8, error, 0, 0][..status_length];
+        let mut response = vec![1, command];
+        response.extend_from_slice(&((data.len() + status.len()) as u16).to_le_bytes());
+        response.extend_from_slice(&value.to_le_bytes());
+        response.extend_from_slice(data);
+        response.extend_from_slice(status);
+        self.send_frame(&response);
+    }
+
+    fn send_frame(&mut self, data: &[u8]) {
+        let mut frame = Vec::new();
+        let mut encoder = SlipEncoder::new(&mut frame).unwrap();
+        encoder.write(data).unwrap();
+        encoder.finish().unwrap();
+        self.output.extend(frame);
+    }
+}
+
+fn lookup_register(pinned: &HashMap<u32, u32>, registers: &HashMap<u32, u32>, addr: u32) -> u32 {
+    pinned
+        .get(&addr)
+        .or_else(|| registers.get(&addr))
+        .copied()
+        .unwrap_or(0)
+}
+
+impl Simulation {
+    /// Erase the sectors covering `size` bytes at `offset`
+    fn erase(&mut self, offset: u32, size: u32) {
+        let start = offset / FLASH_SECTOR_SIZE;
+        let mut sectors = size.div_ceil(FLASH_SECTOR_SIZE);
+        if self.chip == Chip::Esp8266 && !self.stub {
+            // the esp8266 rom erases the sectors up to the end of the first 64KB block twice
+            let head = FLASH_SECTORS_PER_BLOCK - start % FLASH_SECTORS_PER_BLOCK;
+            sectors += sectors.min(head);
+        }
+        let start = (start * FLASH_SECTOR_SIZE) as usize;
+        let end = start + (sectors * FLASH_SECTOR_SIZE) as usize;
+        let len = self.flash.len();
+        self.flash[start.min(len)..end.min(len)].fill(0xff);
+    }
+
+    /// Program the flash, which can only clear bits
+    fn program(&mut self, offset: u32, data: &[u8]) {
+        let flash = self.flash.iter_mut().skip(offset as usize);
+        for (byte, value) in flash.zip(data) {
+            *byte &= value;
+        }
+    }
+}
+
+fn unslip(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut escaped = false;
+    for &byte in data {
+        match (escaped, byte) {
+            (false, SLIP_ESC) => escaped = true,
+            (true, SLIP_ESC_END) => {
+                output.push(SLIP_END);
+                escaped = false;
+            }
+            (true, SLIP_ESC_ESC) => {
+                output.push(SLIP_ESC);
+                escaped = false;
+            }
+            _ => {
+                output.push(byte);
+                escaped = false;
+            }
+        }
+    }
+    output
+}
+
+impl Read for MockTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        if state.output.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no response queued",
+            ));
+        }
+        let count = buf.len().min(state.output.len());
+        for (byte, value) in buf.iter_mut().zip(state.output.drain(..count)) {
+            *byte = value;
+        }
+        Ok(count)
+    }
+}
+
+impl Write for MockTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        state.writes += 1;
+        state.input.extend_from_slice(buf);
+        state.process_input();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for MockTransport {
+    fn configure(&mut self, settings: &PortSettings) -> serial::Result<()> {
+        self.state().settings = *settings;
+        Ok(())
+    }
+
+    fn reconfigure(
+        &mut self,
+        setup: &dyn Fn(&mut dyn SerialPortSettings) -> serial::Result<()>,
+    ) -> serial::Result<()> {
+        let mut settings = self.state().settings;
+        setup(&mut settings)?;
+        self.state().settings = settings;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.state().timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serial::Result<()> {
+        self.state().timeout = timeout;
+        Ok(())
+    }
+
+    fn set_rts(&mut self, level: bool) -> serial::Result<()> {
+        if level {
+            self.state().resets += 1;
+        }
+        Ok(())
+    }
+
+    fn set_dtr(&mut self, _level: bool) -> serial::Result<()> {
+        Ok(())
+    }
+
+    fn read_cts(&mut self) -> serial::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_dsr(&mut self) -> serial::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_ri(&mut self) -> serial::Result<bool> {
+        Ok(false)
+    }
+
+    fn read_cd(&mut self) -> serial::Result<bool> {
+        Ok(false)
+    }
+}
+
+#[test]
+fn test_mock_flash() {
+    use crate::{CommandTiming, Flasher, ResetAfterFlash};
+
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(mock.clone(), None).unwrap();
+    assert_eq!(Chip::Esp32, flasher.chip());
+    assert_eq!(FlashSize::Flash4Mb, flasher.flash_size());
+
+    let timings = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let hook_timings = timings.clone();
+    flasher.set_command_hook(move |timing| hook_timings.lock().unwrap().push(*timing));
+
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    flasher.write_bin_to_flash(0x10000, &[0xaa; 0x900]).unwrap();
+    assert_eq!(3, mock.received_count(Command::FlashData));
+
+    let data_timings: Vec<CommandTiming> = timings
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|timing| timing.command == Command::FlashData)
+        .copied()
+        .collect();
+    assert_eq!(3, data_timings.len());
+    assert!(data_timings[0].bytes_sent > 0x400);
+
+    mock.fail(Command::FlashBegin, 0x06);
+    assert!(flasher.write_bin_to_flash(0x10000, &[0xaa; 0x100]).is_err());
+    assert!(mock.verify().is_ok());
+}
+
+#[test]
+fn test_expected_frames() {
+    use crate::{Flasher, ResetAfterFlash};
+
+    let words =
+        |words: &[u32]| -> Vec<u8> { words.iter().flat_map(|word| word.to_le_bytes()).collect() };
+
+    let mock = MockTransport::with_chip(Chip::Esp32, FlashSize::Flash4Mb);
+    let mut flasher = Flasher::connect(mock.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+
+    // size, number of blocks, block size and offset
+    mock.expect(Command::FlashBegin, &words(&[0x900, 3, 0x400, 0x10000]));
+    flasher.write_bin_to_flash(0x10000, &[0xaa; 0x900]).unwrap();
+    assert_eq!(Ok(()), mock.verify());
+
+    mock.expect(Command::FlashBegin, &words(&[0x100, 1, 0x400, 0x20000]));
+    flasher.write_bin_to_flash(0x10000, &[0xaa; 0x100]).unwrap();
+    assert!(mock.verify().unwrap_err().contains("instead of"));
+
+    let mock = MockTransport::new();
+    mock.expect(Command::Sync, &[]);
+    assert_eq!(
+        Err("1 expected commands weren't received".to_string()),
+        mock.verify()
+    );
+}
+
+#[test]
+fn test_simulated_flash() {
+    use crate::{Flasher, ResetAfterFlash};
+
+    let data: Vec<u8> = (0..0x2100u32).map(|i| (i * 7) as u8).collect();
+
+    let simulator = MockTransport::simulator(Chip::Esp32, FlashSize::Flash2Mb);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    assert_eq!(FlashSize::Flash2Mb, flasher.flash_size());
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    flasher.write_bin_to_flash(0x10000, &data).unwrap();
+    assert_eq!(&data[..], &simulator.flash()[0x10000..0x12100]);
+    flasher.verify_bin(0x10000, &data).unwrap();
+
+    // the esp8266 rom erases more than requested, which the flasher has to compensate for
+    let simulator = MockTransport::simulator(Chip::Esp8266, FlashSize::Flash1Mb);
+    simulator.set_flash(0x4000, &[0; 0x2000]);
+    let mut flasher = Flasher::connect(simulator.clone(), None).unwrap();
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+    flasher.write_bin_to_flash(0x1000, &data).unwrap();
+    let flash = simulator.flash();
+    assert_eq!(&data[..], &flash[0x1000..0x3100]);
+    assert_eq!(&[0; 0x1000][..], &flash[0x5000..0x6000]);
+    flasher.verify_bin(0x1000, &data[..0x40]).unwrap();
+    assert!(matches!(
+        flasher.verify_bin(0x1000, &[0; 0x10]),
+        Err(crate::Error::VerifyFailed(0x1001))
+    ));
+    assert!(flasher.flash_md5(0x1000, 0x100).is_err());
+}
@@ -0,0 +1,109 @@
+//! Tests against a real board, enabled with the `hardware-tests` feature
+//!
+//! The board is configured with the `ESPFLASH_TEST_PORT` and `ESPFLASH_TEST_CHIP` environment variables:
+//!
+//! ```bash
+//! ESPFLASH_TEST_PORT=/dev/ttyUSB0 ESPFLASH_TEST_CHIP=esp32 cargo test --features hardware-tests --test hardware
+//! ```
+//!
+//! Only the last 64KB of the flash are written and erased, the rest of the flash is left untouched.
+#![cfg(feature = "hardware-tests")]
+
+use std::env::var;
+use std::sync::{Mutex, MutexGuard};
+
+use espflash::cli::open_serial;
+use espflash::{Chip, Flasher, ResetAfterFlash};
+
+/// The size of the region at the end of the flash used by the tests
+const SCRATCH_SIZE: u32 = 0x10000;
+
+/// All tests use the same board, so they can't run in parallel
+static BOARD: Mutex<()> = Mutex::new(());
+
+struct Board {
+    flasher: Flasher,
+    scratch: u32,
+    _lock: MutexGuard<'static, ()>,
+}
+
+fn connect() -> Board {
+    let lock = BOARD.lock().unwrap_or_else(|e| e.into_inner());
+    let port = var("ESPFLASH_TEST_PORT").expect("ESPFLASH_TEST_PORT not set");
+    let chip: Chip = var("ESPFLASH_TEST_CHIP")
+        .expect("ESPFLASH_TEST_CHIP not set")
+        .parse()
+        .expect("ESPFLASH_TEST_CHIP should be esp32 or esp8266");
+
+    let mut flasher = Flasher::connect(open_serial(&port).unwrap(), None).unwrap();
+    assert_eq!(chip, flasher.chip());
+    flasher.set_reset_after_flash(ResetAfterFlash::NoReset);
+
+    let scratch = flasher.flash_size_in_bytes().unwrap() - SCRATCH_SIZE;
+    Board {
+        flasher,
+        scratch,
+        _lock: lock,
+    }
+}
+
+/// Data that differs between runs, so a previous run can't make a test pass
+fn test_data(len: usize) -> Vec<u8> {
+    let seed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .subsec_nanos();
+    (0..len as u32)
+        .map(|i| (i.wrapping_mul(31) ^ seed) as u8)
+        .collect()
+}
+
+#[test]
+fn test_connect() {
+    let mut board = connect();
+    let info = board.flasher.board_info().unwrap();
+    assert_eq!(board.flasher.chip(), info.chip);
+}
+
+#[test]
+fn test_flash_and_verify() {
+    let mut board = connect();
+    let data = test_data(0x1100);
+
+    board
+        .flasher
+        .write_bin_to_flash(board.scratch, &data)
+        .unwrap();
+    board.flasher.verify_bin(board.scratch, &data).unwrap();
+
+    let mut changed = data.clone();
+    changed[0xabc] ^= 1;
+    assert!(board.flasher.verify_bin(board.scratch, &changed).is_err());
+}
+
+#[test]
+fn test_read() {
+    let mut board = connect();
+    let data = test_data(0x100);
+
+    board
+        .flasher
+        .write_bin_to_flash(board.scratch, &data)
+        .unwrap();
+    let read = board.flasher.read_flash(board.scratch, 0x100).unwrap();
+    assert_eq!(data, read);
+}
+
+#[test]
+fn test_erase() {
+    let mut board = connect();
+    let data = test_data(0x100);
+
+    board
+        .flasher
+        .write_bin_to_flash(board.scratch, &data)
+        .unwrap();
+    board.flasher.erase_region(board.scratch, 0x1000).unwrap();
+    let read = board.flasher.read_flash(board.scratch, 0x100).unwrap();
+    assert_eq!(vec![0xff; 0x100], read);
+}
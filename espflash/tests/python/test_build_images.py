@@ -0,0 +1,40 @@
+# Tests for the python module, run with `pytest espflash/tests/python` after building the module as described in
+# the python section of the readme, with the module on the python path.
+import os
+
+import pytest
+
+import espflash
+
+DATA = os.path.join(os.path.dirname(__file__), "..", "data")
+
+
+def read(name):
+    with open(os.path.join(DATA, name), "rb") as file:
+        return file.read()
+
+
+def test_build_images():
+    files = espflash.build_images("esp32", read("esp32"))
+    assert [addr for addr, _, _ in files] == [0x1000, 0x8000, 0x10000]
+    addr, name, app = files[2]
+    assert name == "app.bin"
+    assert app[0] == 0xE9
+
+
+def test_build_images_flash_settings():
+    files = espflash.build_images("esp32", read("esp32"), flash_mode="qio", flash_size="8MB", flash_freq="80m")
+    _, _, app = files[2]
+    # the flash mode, then the flash size in the upper and the frequency in the lower nibble
+    assert (app[2], app[3]) == (0, 0x3F)
+
+    files = espflash.build_images("esp32", read("esp32"), flash_mode="dio", flash_size="2MB", flash_freq="40m")
+    _, _, app = files[2]
+    assert (app[2], app[3]) == (2, 0x10)
+
+
+def test_build_images_invalid_settings():
+    with pytest.raises(espflash.EspflashError):
+        espflash.build_images("esp32", read("esp32"), flash_mode="fast")
+    with pytest.raises(espflash.EspflashError):
+        espflash.build_images("esp32", read("esp32"), flash_size="3MB")